@@ -1,21 +1,158 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request as http_outcall, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as OutcallResponse, TransformArgs, TransformContext,
+};
 use ic_cdk::{caller, export_candid};
+use ic_llm::{ChatMessage, Model};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 // Constants for AMM parameters
-const INITIAL_LIQUIDITY: u64 = 500; // Initial YES and NO tokens when creating a market
+// Every ICP amount (balances, pools, fees, trade sizes) is denominated in e8s, like the ICP ledger
+const E8S_PER_ICP: u64 = 100_000_000; // 1 ICP = 10^8 e8s
 const TRADE_FEE: u64 = 3; // 0.3% trading fee (in basis points, 3/1000)
-const MIN_DEPOSIT: u64 = 1000; // Minimum ICP deposit amount
+const MAX_DYNAMIC_FEE_BPS: u64 = 1_000; // Highest fee rate a dynamic fee may be capped at (10%)
+const MAX_FEE_TIERS: usize = 8;
+const MIN_DEPOSIT: u64 = E8S_PER_ICP / 100; // Minimum deposit and market seed in e8s (0.01 ICP)
+const DEFAULT_MIN_TRADE_SIZE: u64 = 10_000; // Smallest value in e8s a trade may move (0.0001 ICP)
+const DEFAULT_MIN_TRADE_FEE: u64 = 1; // Flat fee floor in e8s so small trades are never fee-free
+const DEFAULT_CREATOR_FEE_BPS: u64 = 100; // Creator's share of each trading fee (1% of the fee)
+const MAX_CREATOR_FEE_BPS: u64 = 5_000; // Creator can never take more than half of the fee
+const MAX_CRITERIA_FIELD_LEN: usize = 512; // Cap on each free-text resolution criteria field
+const MAX_MARKET_RULES_LEN: usize = 4_000; // Characters of resolution rules text in market metadata
+const MAX_MARKET_LINKS: usize = 5; // External links per market
+const DEFAULT_MAX_TITLE_LEN: u64 = 200; // Characters in a market title unless the admin changes it
+const DEFAULT_MAX_DESCRIPTION_LEN: u64 = 5_000; // Characters in a market description
+const MAX_CATEGORY_NAME_LEN: u64 = 50; // Characters in a registered category's display name
+const MAX_FEATURED_MARKETS: usize = 20; // Entries on the featured list at once
+const MAX_RESOLUTION_EVIDENCE_LEN: usize = 2_000; // Cap on the evidence recorded with a resolution
+const MAX_EXIT_HAIRCUT_BPS: u64 = 10_000; // A haircut can forfeit at most all unclaimed fees
+const STOP_LOSS_MAX_SLIPPAGE_BPS: u64 = 500; // A stop-loss never sells more than 5% below its trigger
+const PROTOCOL_FEE_BPS: u64 = 1_000; // Treasury's share of each trading fee (10% of the fee)
+const REFERRAL_SHARE_BPS: u64 = 2_000; // Referrer's kickback out of the protocol cut (20% of the cut)
+const OUTCOME_TX_WINDOW_NS: u64 = NANOS_PER_DAY; // ICRC-1 deduplication window for `created_at_time`
+const OUTCOME_TX_DRIFT_NS: u64 = 60_000_000_000; // Clock drift tolerated on `created_at_time`
+const LLM_CANISTER_ID: &str = "w36hm-eqaaa-aaaal-qr76a-cai"; // Served by the ic_llm crate's LLM canister
+const LLM_MODEL: Model = Model::Llama3_1_8B;
+const ANALYSIS_PRICE_POINTS: usize = 24; // Most recent price observations given to the model
+const ANALYSIS_TRADES: usize = 20; // Most recent trades given to the model
+const VAGUE_QUESTION_TERMS: [&str; 8] = [
+    "significant",
+    "soon",
+    "likely",
+    "a lot",
+    "major",
+    "popular",
+    "substantial",
+    "eventually",
+];
+const RESOLUTION_SOURCE_TERMS: [&str; 9] = [
+    "according to",
+    "as reported",
+    "source",
+    "official",
+    "http",
+    "www.",
+    "data from",
+    "published by",
+    "announced by",
+];
+const DEADLINE_TERMS: [&str; 16] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may ",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+    "tomorrow",
+    "today",
+    "end of",
+    "deadline",
+];
+const ANALYSIS_SYSTEM_PROMPT: &str = "You are an analyst for an AMM prediction market. \
+Using only the market data provided, explain what the prices imply, how sentiment moved \
+recently, and the liquidity risks for a trader. Be concise and do not give financial advice.";
+const QUESTION_REVIEW_PROMPT: &str = "You review prediction market questions. Check that the \
+question is unambiguous, names a verifiable resolution source and has a clear deadline. \
+Answer with exactly these lines, then one line per suggested improvement:\n\
+UNAMBIGUOUS: yes|no\nRESOLUTION_SOURCE: yes|no\nDEADLINE: yes|no\nSUGGESTION: <text>";
+const RESOLUTION_DRAFT_PROMPT: &str = "You help resolve prediction markets. Decide from the \
+market description and the evidence whether the question resolved YES or NO. If the evidence \
+does not settle it, answer UNCLEAR. Answer with exactly two lines:\n\
+OUTCOME: YES|NO|UNCLEAR\nREASONING: <one paragraph>";
+const MAX_EVIDENCE_LEN: usize = 4_000; // Cap on evidence text forwarded to the model
+const MAX_COMMENT_LEN: usize = 1_000; // Characters per market comment
+const HTTP_JSON_CONTENT_TYPE: &str = "application/json; charset=utf-8";
+const HTTP_METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4"; // Prometheus text exposition format
+const CERTIFIED_HTTP_LABEL: &[u8] = b"http_assets"; // Subtree the HTTP gateway looks paths up in
+const MAX_LIST_RESULTS: usize = 200; // Cap on items per list query, keeps responses far below the 2MB limit
+const AIRDROP_CHUNK_SIZE: usize = 500; // Recipients credited per airdrop call
+const FEE_ACC_PRECISION: u128 = 1_000_000_000_000; // Fixed-point scale for per-share fee accumulators
+const TRIGGER_CHECK_INTERVAL_NS: u64 = 60_000_000_000; // Price triggers are re-evaluated every minute
+const NANOS_PER_HOUR: u64 = 3_600_000_000_000;
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const ROLLING_WINDOW_HOURS: u64 = 24; // Hourly buckets kept in each market's rolling stats ring
+const PLATFORM_TOP_MARKETS: usize = 5; // Largest markets listed in the platform stats
+const PRICE_BAND_SIZES: [u64; 3] = [E8S_PER_ICP / 100, E8S_PER_ICP / 10, E8S_PER_ICP]; // Trade sizes in e8s probed by the price band check (0.01, 0.1 and 1 ICP)
+const MAX_BULK_QUOTES: usize = 100; // Quotes evaluated per get_quotes or get_quotes_bulk call
+const NEAR_CLOSE_PERCENT: u64 = 80; // Share of the trading window after which NearClose fires
+const MAX_CALLBACK_METHOD_LEN: usize = 64;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 1_024; // Only the status is kept, so cap the response tightly
+const WEBHOOK_CYCLES: u128 = 100_000_000; // Covers a small POST on a 13-node subnet; unused cycles are refunded
+const LARGE_TRADE_E8S: u64 = 1_000_000_000; // Trades of 10 ICP or more fire the LargeTrade stage
+const MAX_WEBHOOK_ATTEMPTS: u32 = 5; // First delivery plus four retries
+const WEBHOOK_RETRY_BASE_NS: u64 = 30_000_000_000; // First retry after 30s, doubling each time
+const MAX_WEBHOOK_ALLOWLIST: usize = 50;
+const ORACLE_MAX_RESPONSE_BYTES: u64 = 16_384; // Oracle answers are a small JSON document
+const ORACLE_CYCLES: u128 = 200_000_000; // Covers a small GET on a 13-node subnet; unused cycles are refunded
+const ORACLE_RETRY_NS: u64 = 600_000_000_000; // Ten minutes between oracle polls
+const MAX_ORACLE_ATTEMPTS: u32 = 6; // After these the market waits for manual resolution
+const MAX_JSON_DEPTH: usize = 32; // Nesting limit when reading oracle responses
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai"; // The IC's Exchange Rate Canister
+const XRC_CYCLES: u128 = 1_000_000_000; // The XRC charges 1B cycles per request
+const XRC_RETRY_NS: u64 = 60_000_000_000; // First retry after a minute, doubling each time
+const MAX_XRC_ATTEMPTS: u32 = 6;
+const MAX_ASSET_SYMBOL_LEN: usize = 16;
+const DEFAULT_LIQUIDITY_MATCH_CAP: u64 = 100 * E8S_PER_ICP; // Most the treasury adds to one market's seed
+const DEFAULT_CLOSE_EXTENSION_NS: u64 = 7 * 86_400_000_000_000; // ExtendMarket keeps trading open another week
+const MAX_CLOSE_EXTENSIONS: u64 = 3; // After this many extensions an untraded market is refunded instead
+const DEFAULT_DISPUTE_WINDOW_NS: u64 = 86_400_000_000_000; // Claims unlock a day after resolution
+const DEFAULT_DISPUTE_BOND: u64 = E8S_PER_ICP; // Posted by the disputer, slashed if the resolution stands
+const DEFAULT_CREATOR_BOND_SLASH_BPS: u64 = 5_000; // Half the creator bond is lost on a bad resolution
+const VOTING_WINDOW_NS: u64 = 3 * 86_400_000_000_000; // Community votes are taken for three days after close
+const AUTO_VOID_GRACE_NS: u64 = 14 * 86_400_000_000_000; // Markets still unresolved two weeks after close are voided
+const RESOLVER_GRACE_NS: u64 = 3 * 86_400_000_000_000; // After close, how long a designated resolver has before the global admin may step in
+const TIMELOCK_DELAY_NS: u64 = 2 * 86_400_000_000_000; // Between scheduling a sensitive admin action and executing it
+const MAX_AUTO_WITHDRAW_DAYS: u64 = 3_650; // Auto-withdraw delays beyond ten years are rejected
+const COMMIT_REVEAL_WINDOW_NS: u64 = 600_000_000_000; // A trade commitment must be revealed within ten minutes
+const MAX_OPEN_COMMITMENTS: usize = 20; // Unrevealed commitments per trader
+const MIN_COMMIT_SALT_LEN: usize = 16; // Short salts would let observers brute-force the trade
+const STATE_CHUNK_BYTES: usize = 1_500_000; // State export slice, well under the 2MB response limit
+const STATE_VERSION: u32 = 1; // Layout of the state saved across upgrades; see STATE_MIGRATIONS
+
+// Approximate cycles pricing for an update call on a 13-node application subnet
+const UPDATE_CALL_BASE_CYCLES: u64 = 5_000_000; // Flat fee per executed update message
+const INGRESS_MESSAGE_BASE_CYCLES: u64 = 1_200_000; // Flat fee per received ingress message
+const CYCLES_PER_10_INSTRUCTIONS: u64 = 4; // Execution cost per 10 instructions
 
 // Data structures for the AMM prediction market
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum MarketStatus {
     Open,
     Resolved,
-    Frozen, // Market is frozen after resolution but before claims are processed
+    Frozen,  // Trading deadline has passed or the resolver froze it early; awaiting resolution
+    Invalid, // Resolved as invalid: holders are refunded the ICP they put in instead of paid out
+    Cancelled, // Withdrawn before resolution: traders and liquidity providers are refunded
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -32,1188 +169,15249 @@ pub struct AmmMarket {
     // AMM reserves using constant product formula (x * y = k)
     pub yes_reserve: u64,        // Reserve of YES tokens
     pub no_reserve: u64,         // Reserve of NO tokens
-    pub icp_liquidity_pool: u64, // Total ICP backing the market
+    pub icp_liquidity_pool: u64, // Total ICP backing the market, in e8s
     pub status: MarketStatus,
     pub winning_outcome: Option<TokenType>,
     pub creator: Principal,
     pub admin: Principal,
-    pub total_fees_collected: u64, // Accumulated trading fees
+    pub total_fees_collected: u64, // Accumulated trading fees, in e8s
     pub creation_time: u64,
+    pub close_time: u64,           // Trading deadline (nanoseconds since epoch)
+    pub total_lp_shares: u64,      // Outstanding liquidity provider shares
+    pub acc_fee_per_share: u128,   // LP fees accrued per share, scaled by FEE_ACC_PRECISION
+    pub creator_fee_bps: u64, // Creator's share of each trading fee, in basis points of the fee
+    pub creator_fees_accrued: u64, // Creator fees not yet claimed
+    pub restricted_regions: Vec<String>, // ISO 3166-1 alpha-2 codes barred from buying
+    pub require_attested_region: bool, // Only users with an attested region may buy
+    pub category: Option<String>, // Lowercased free-form category, e.g. "sports"
+    pub early_exit_haircut: Vec<HaircutTier>, // Fee forfeiture schedule for LPs leaving before close
+    pub resolution_criteria: Option<ResolutionCriteria>, // Machine-readable rules alongside the description
+    pub resolution: Option<ResolutionState>,             // Set once an outcome has been proposed
+    pub close_extensions: u64, // Times the close was pushed back by the ExtendMarket policy
+    pub resolution_mode: ResolutionMode,
+    pub resolver: Option<Principal>, // Resolves instead of the market admin, e.g. an oracle canister
+    pub resolution_source: String,   // Where the outcome will be checked, set at creation
+    pub resolution_evidence: Option<String>, // Why the market resolved the way it did
+    pub metadata: Option<MarketMetadata>, // Display details supplied at creation
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct UserPosition {
-    pub user: Principal,
-    pub market_id: u64,
-    pub yes_tokens: u64,
-    pub no_tokens: u64,
-    pub claimed_reward: bool, // Prevents double claiming
+/// Optional display details for a market, alongside its title and description
+/// The close time and category are `create_market` parameters of their own
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct MarketMetadata {
+    pub image_url: Option<String>, // https only, at most MAX_CRITERIA_FIELD_LEN bytes
+    pub rules: Option<String>,     // Human-readable resolution rules, at most MAX_MARKET_RULES_LEN
+    pub links: Vec<String>,        // Up to MAX_MARKET_LINKS https links to background material
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct TradeResult {
-    pub tokens_received: u64,
-    pub tokens_paid: u64, // In ICP
-    pub fee_paid: u64,    // In ICP
-    pub new_price: f64,   // New price after trade
+/// Who decides a market's outcome, chosen at creation
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum ResolutionMode {
+    #[default]
+    AdminOnly, // The market admin resolves, as before
+    CommunityVote, // Token holders vote after close; the tally resolves the market
 }
 
+/// A token holder's vote on a community-resolved market, weighted by the tokens they held when voting
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct MarketSummary {
-    pub market: AmmMarket,
-    pub yes_price: f64,    // Current YES token price in ICP
-    pub no_price: f64,     // Current NO token price in ICP
-    pub total_volume: u64, // Total ICP volume
-    pub price_impact: f64, // Price impact for a standard trade size
+pub struct ResolutionVote {
+    pub voter: Principal,
+    pub outcome: TokenType,
+    pub weight: u64,
+    pub cast_at: u64,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct RewardClaim {
-    pub user: Principal,
+pub struct VoteTally {
     pub market_id: u64,
-    pub winning_tokens: u64,
-    pub reward_amount: u64, // ICP reward
-    pub claim_time: u64,
+    pub yes_weight: u64,
+    pub no_weight: u64,
+    pub outcome: Option<TokenType>, // None on a tie or without votes; the global admin resolves instead
+    pub tallied_at: u64,
 }
 
-// Error types
-#[derive(CandidType, Serialize, Deserialize, Debug)]
-pub enum PredictionMarketError {
-    MarketNotFound,
-    MarketClosed,
-    MarketResolved,
-    InsufficientDeposit,
-    InsufficientLiquidity,
-    Unauthorized,
-    InvalidAmount,
-    AlreadyClaimed,
-    NoWinningTokens,
-    SlippageExceeded,
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ResolutionPhase {
+    Proposed,   // An outcome awaits finalization; trading is halted
+    Challenged, // The proposed outcome was disputed and needs a new proposal
+    Final,      // The outcome is settled and rewards can be claimed
 }
 
-// State management using thread-local storage
-thread_local! {
-    static MARKETS: RefCell<HashMap<u64, AmmMarket>> = RefCell::new(HashMap::new());
-    static USER_POSITIONS: RefCell<HashMap<(Principal, u64), UserPosition>> = RefCell::new(HashMap::new());
-    static REWARD_CLAIMS: RefCell<Vec<RewardClaim>> = const { RefCell::new(Vec::new()) };
-    static NEXT_MARKET_ID: RefCell<u64> = const { RefCell::new(1) };
-    static ADMIN: RefCell<Option<Principal>> = const { RefCell::new(None) };
-    static USER_BALANCES: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+/// Where a market is in its resolution, shared by every path that can resolve it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolutionState {
+    pub phase: ResolutionPhase,
+    pub outcome: TokenType,
+    pub proposed_by: Principal,
+    pub challenged_by: Option<Principal>,
+    pub updated_at: u64,
+    pub revision: u64, // Bumped on every transition; callers pass the revision they last saw
 }
 
-// =============================================================================
-// INITIALIZATION
-// =============================================================================
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Comparator {
+    Above, // YES if the observed value is strictly greater than the threshold
+    Below, // YES if the observed value is strictly less than the threshold
+}
 
-/// Initialize the canister with the deployer as admin
-#[ic_cdk::init]
-fn init() {
-    let deployer = caller();
-    ADMIN.with(|admin| {
-        *admin.borrow_mut() = Some(deployer);
-    });
+/// Structured rules for deciding a market from a single observed metric
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolutionCriteria {
+    pub source_url: String, // Where the metric is read from, https only
+    pub metric: String,     // e.g. "BTC/USD close price"
+    pub comparator: Comparator,
+    pub threshold: f64,
+    pub timezone: String, // Timezone the observation time is expressed in, e.g. "UTC"
+    pub tiebreak: TokenType, // Outcome when the observed value equals the threshold
 }
 
-// =============================================================================
-// AMM CORE FUNCTIONS
-// =============================================================================
+/// How the value at an oracle's JSON path decides the market
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OracleReading {
+    Outcome, // A boolean, or the string "yes" / "no"
+    Threshold {
+        comparator: Comparator,
+        threshold: f64,
+        tiebreak: TokenType,
+    }, // A number, or a numeric string, compared like ResolutionCriteria
+}
 
-/// Calculate the current price of YES or NO tokens using the constant product formula
-/// Price = opposite_reserve / (target_reserve + opposite_reserve)
-/// This gives us the marginal price for the next infinitesimal trade
-#[ic_cdk::query]
-fn get_token_price(market_id: u64, token_type: TokenType) -> Result<f64, PredictionMarketError> {
-    MARKETS.with(|markets| {
-        let markets_map = markets.borrow();
-        match markets_map.get(&market_id) {
-            Some(market) => {
-                let total_tokens = market.yes_reserve + market.no_reserve;
-                if total_tokens == 0 {
-                    return Ok(0.5); // Equal price when no liquidity
-                }
+/// Where a market's outcome is fetched from once trading closes
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OracleConfig {
+    pub url: String,       // https GET endpoint returning JSON
+    pub json_path: String, // Dot-separated keys and array indices, e.g. "data.0.price"
+    pub reading: OracleReading,
+}
 
-                // Price calculation: P(YES) = NO_reserve / total_tokens
-                // This represents the marginal cost of the next YES token
-                let price = match token_type {
-                    TokenType::Yes => market.no_reserve as f64 / total_tokens as f64,
-                    TokenType::No => market.yes_reserve as f64 / total_tokens as f64,
-                };
-                Ok(price)
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OracleStatus {
+    pub market_id: u64,
+    pub config: OracleConfig,
+    pub attempts: u32,
+    pub next_poll_at: u64,
+    pub last_error: Option<String>, // Why the latest poll did not resolve the market
 }
 
-/// Calculate how many tokens you would receive for a given ICP amount
-/// Uses the constant product formula: x * y = k
-/// When buying YES tokens: new_yes_reserve = yes_reserve + tokens_out
-/// new_no_reserve * new_yes_reserve = k (constant)
-/// Therefore: new_no_reserve = k / new_yes_reserve
-/// ICP_in = no_reserve - new_no_reserve
-fn calculate_tokens_out(
-    yes_reserve: u64,
-    no_reserve: u64,
-    icp_in: u64,
-    buy_yes: bool,
-) -> Result<u64, PredictionMarketError> {
-    if yes_reserve == 0 || no_reserve == 0 {
-        return Err(PredictionMarketError::InsufficientLiquidity);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum XrcAssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
 
-    // Apply trading fee: actual_icp_in = icp_in * (1000 - fee) / 1000
-    let icp_after_fee = (icp_in * (1000 - TRADE_FEE)) / 1000;
+/// An asset as the Exchange Rate Canister names it, e.g. ICP or USD
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct XrcAsset {
+    pub symbol: String,
+    pub class: XrcAssetClass,
+}
 
-    if buy_yes {
-        // Buying YES tokens reduces NO reserve
-        // k = yes_reserve * no_reserve
-        // new_no_reserve = no_reserve - icp_after_fee
-        // new_yes_reserve = k / new_no_reserve
-        // tokens_out = new_yes_reserve - yes_reserve
+/// The rate a price market was resolved from, kept as evidence
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateEvidence {
+    pub rate: f64,
+    pub rate_timestamp: u64, // Seconds, as reported by the XRC
+    pub base_sources: u64,   // Exchanges that returned a base asset rate
+    pub quote_sources: u64,
+    pub fetched_at: u64,
+}
 
-        if icp_after_fee >= no_reserve {
-            return Err(PredictionMarketError::InsufficientLiquidity);
-        }
+/// Resolution of an "asset price above X at close" market from the XRC
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PriceFeed {
+    pub market_id: u64,
+    pub base_asset: XrcAsset,
+    pub quote_asset: XrcAsset,
+    pub attempts: u32,
+    pub next_poll_at: u64,
+    pub last_error: Option<String>,
+    pub evidence: Option<RateEvidence>,
+}
 
-        let k = yes_reserve * no_reserve;
-        let new_no_reserve = no_reserve - icp_after_fee;
-        let new_yes_reserve = k / new_no_reserve;
+/// Share of unclaimed LP fees forfeited when withdrawing within `within_ns` of the close time
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HaircutTier {
+    pub within_ns: u64,
+    pub haircut_bps: u64,
+}
 
-        if new_yes_reserve <= yes_reserve {
-            return Err(PredictionMarketError::InvalidAmount);
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserPosition {
+    pub user: Principal,
+    pub market_id: u64,
+    pub yes_tokens: u64,
+    pub no_tokens: u64,
+    pub claimed_reward: bool, // Prevents double claiming
+}
 
-        Ok(new_yes_reserve - yes_reserve)
-    } else {
-        // Buying NO tokens reduces YES reserve
-        if icp_after_fee >= yes_reserve {
-            return Err(PredictionMarketError::InsufficientLiquidity);
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LiquidityPosition {
+    pub provider: Principal,
+    pub market_id: u64,
+    pub shares: u64,
+    pub share_of_pool: f64,  // Fraction of the market's LP shares held
+    pub claimable_fees: u64, // Trading fees accrued and not yet claimed
+}
 
-        let k = yes_reserve * no_reserve;
-        let new_yes_reserve = yes_reserve - icp_after_fee;
-        let new_no_reserve = k / new_yes_reserve;
+/// Internal LP bookkeeping: `fee_debt` is the part of the accumulator already
+/// credited to this account, so only fees accrued after it are pending
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LpAccount {
+    pub shares: u64,
+    pub fee_debt: u128,
+    pub unclaimed_fees: u64,
+}
 
-        if new_no_reserve <= no_reserve {
-            return Err(PredictionMarketError::InvalidAmount);
-        }
+/// One leg of an OTC swap: either internal ICP balance or outcome tokens of a market
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SwapAsset {
+    Icp {
+        amount: u64,
+    },
+    Tokens {
+        market_id: u64,
+        token_type: TokenType,
+        amount: u64,
+    },
+}
 
-        Ok(new_no_reserve - no_reserve)
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SwapOfferStatus {
+    Open,
+    Filled { taker: Principal, filled_at: u64 },
+    Cancelled,
 }
 
-/// Calculate how much ICP you would receive for selling tokens back to the AMM
-/// When selling YES tokens: new_yes_reserve = yes_reserve - tokens_in
-/// new_no_reserve = k / new_yes_reserve
-/// ICP_out = new_no_reserve - no_reserve
-fn calculate_icp_out(
-    yes_reserve: u64,
-    no_reserve: u64,
-    tokens_in: u64,
-    sell_yes: bool,
-) -> Result<u64, PredictionMarketError> {
-    if yes_reserve == 0 || no_reserve == 0 {
-        return Err(PredictionMarketError::InsufficientLiquidity);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SwapOffer {
+    pub id: u64,
+    pub maker: Principal,
+    pub give: SwapAsset, // Held in escrow by the canister while the offer is open
+    pub want: SwapAsset, // Paid by the taker on acceptance
+    pub status: SwapOfferStatus,
+    pub created_at: u64,
+}
 
-    if sell_yes {
-        // Selling YES tokens increases NO reserve
-        if tokens_in >= yes_reserve {
-            return Err(PredictionMarketError::InvalidAmount);
-        }
+/// Orderings for `list_markets`; ties fall back to market ID
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SortBy {
+    Newest,
+    ClosingSoonest,
+    HighestVolume,  // ICP traded since the market opened
+    MostLiquid,     // ICP in the liquidity pool
+    BiggestMove24h, // Largest YES price change either way over the last 24 hours
+}
 
-        let k = yes_reserve * no_reserve;
-        let new_yes_reserve = yes_reserve - tokens_in;
-        if new_yes_reserve == 0 {
-            return Err(PredictionMarketError::InsufficientLiquidity);
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OrderSide {
+    Buy,  // Spend ICP for tokens once the price drops to the limit
+    Sell, // Sell tokens for ICP once the price rises to the limit
+}
 
-        let new_no_reserve = k / new_yes_reserve;
-        let icp_out = new_no_reserve - no_reserve;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OrderStatus {
+    Open,
+    Filled {
+        filled_at: u64,
+        amount_received: u64,
+    },
+    Cancelled,
+}
 
-        // Apply trading fee to output: icp_after_fee = icp_out * (1000 - fee) / 1000
-        Ok((icp_out * (1000 - TRADE_FEE)) / 1000)
-    } else {
-        // Selling NO tokens increases YES reserve
-        if tokens_in >= no_reserve {
-            return Err(PredictionMarketError::InvalidAmount);
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LimitOrder {
+    pub id: u64,
+    pub owner: Principal,
+    pub market_id: u64,
+    pub token_type: TokenType,
+    pub side: OrderSide,
+    pub limit_price: f64, // Marginal token price that triggers the order
+    pub amount: u64,      // ICP for buy orders, tokens for sell orders (held in escrow)
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
 
-        let k = yes_reserve * no_reserve;
-        let new_no_reserve = no_reserve - tokens_in;
-        if new_no_reserve == 0 {
-            return Err(PredictionMarketError::InsufficientLiquidity);
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TriggerAction {
+    StopLoss,   // Sell once the price falls to the trigger
+    TakeProfit, // Sell once the price rises to the trigger
+}
 
-        let new_yes_reserve = k / new_no_reserve;
-        let icp_out = new_yes_reserve - yes_reserve;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TriggerStatus {
+    Active,
+    Executed { executed_at: u64, icp_received: u64 },
+    Cancelled,
+}
 
-        // Apply trading fee to output
-        Ok((icp_out * (1000 - TRADE_FEE)) / 1000)
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PriceTrigger {
+    pub id: u64,
+    pub owner: Principal,
+    pub market_id: u64,
+    pub token_type: TokenType,
+    pub trigger_price: f64, // Marginal token price at which the whole position is sold
+    pub action: TriggerAction,
+    pub status: TriggerStatus,
+    pub created_at: u64,
 }
 
-// =============================================================================
-// MARKET MANAGEMENT FUNCTIONS
-// =============================================================================
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IdleBalanceStatus {
+    pub balance: u64,
+    pub last_activity: Option<u64>, // Last deposit, withdrawal, trade or claim
+    pub idle_for_ns: u64,
+    pub auto_withdraw_after_days: Option<u64>, // None when the user has not opted in
+    pub auto_withdraw_at: Option<u64>,
+}
 
-/// Create a new prediction market with initial AMM liquidity
-/// Initializes the market with equal reserves of YES and NO tokens
-#[ic_cdk::update]
-fn create_market(
-    title: String,
-    description: String,
-    initial_icp_liquidity: u64,
-) -> Result<u64, PredictionMarketError> {
-    let caller_principal = caller();
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub failures: Vec<String>, // Human-readable description of each violation found
+}
 
-    if initial_icp_liquidity < MIN_DEPOSIT {
-        return Err(PredictionMarketError::InsufficientDeposit);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SelfTestReport {
+    pub passed: bool, // True only if every check passed
+    pub checks: Vec<SelfTestCheck>,
+    pub ran_at: u64,
+}
 
-    // Check if user has sufficient balance
-    let user_balance =
-        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReadScope {
+    Positions,     // Current holdings
+    ProfitAndLoss, // Cost basis and mark-to-market value of holdings
+    Trades,        // Executed trade history
+}
 
-    if user_balance < initial_icp_liquidity {
-        return Err(PredictionMarketError::InsufficientDeposit);
-    }
+/// A bearer token letting a third party read one user's data within the granted scopes
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadToken {
+    pub id: u64,
+    pub owner: Principal,
+    pub label: Option<String>, // e.g. the dashboard it was issued to
+    pub scopes: Vec<ReadScope>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked: bool,
+}
 
-    let market_id = NEXT_MARKET_ID.with(|id| {
-        let current_id = *id.borrow();
-        *id.borrow_mut() = current_id + 1;
-        current_id
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IssuedReadToken {
+    pub token: String, // Secret shown only once, at issuance
+    pub info: ReadToken,
+}
 
-    // Create market with initial AMM reserves
-    // Start with equal reserves to ensure 50/50 pricing
-    // Anyone can create a market, creator becomes the market admin
-    let market = AmmMarket {
-        id: market_id,
-        title,
-        description,
-        yes_reserve: INITIAL_LIQUIDITY,
-        no_reserve: INITIAL_LIQUIDITY,
-        icp_liquidity_pool: initial_icp_liquidity,
-        status: MarketStatus::Open,
-        winning_outcome: None,
-        creator: caller_principal,
-        admin: caller_principal, // Creator becomes the market admin
-        total_fees_collected: 0,
-        creation_time: ic_cdk::api::time(),
-    };
+/// Mark-to-market view of one position
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PositionPnl {
+    pub market_id: u64,
+    pub yes_tokens: u64,
+    pub no_tokens: u64,
+    pub cost_basis: u64,   // ICP paid for the tokens still held
+    pub market_value: u64, // Tokens valued at current AMM prices
+    pub unrealized_pnl: i64,
+}
 
-    // Deduct ICP from creator's balance
-    USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        balances_map.insert(caller_principal, user_balance - initial_icp_liquidity);
-    });
+/// Everything that changes canister state, in the order it happened
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum EventKind {
+    MarketCreated {
+        market_id: u64,
+        creator: Principal,
+    },
+    MarketFrozen {
+        market_id: u64,
+    },
+    MarketExtended {
+        market_id: u64,
+        close_time: u64,
+    },
+    DegeneratePoolSettled {
+        market_id: u64,
+        policy: EdgePolicy,
+        amount: u64,
+    },
+    MarketResolved {
+        market_id: u64,
+        outcome: TokenType,
+    },
+    MarketInvalidated {
+        market_id: u64,
+        refund_pool: u64,
+    },
+    ResolutionDisputed {
+        market_id: u64,
+        disputer: Principal,
+        bond: u64,
+    },
+    DisputeSettled {
+        market_id: u64,
+        verdict: DisputeVerdict,
+        outcome: TokenType,
+    },
+    DisputeWindowClosed {
+        market_id: u64,
+    },
+    DisputeConfigChanged {
+        window_ns: u64,
+        bond: u64,
+    },
+    CreatorBondConfigChanged {
+        amount: u64,
+        slash_bps: u64,
+    },
+    CreatorBondSlashed {
+        market_id: u64,
+        amount: u64,
+        recipient: Option<Principal>, // The disputer who was proven right, else the treasury
+    },
+    CreatorBondReleased {
+        market_id: u64,
+        amount: u64,
+    },
+    ResolutionVoteCast {
+        market_id: u64,
+        voter: Principal,
+        outcome: TokenType,
+        weight: u64,
+    },
+    ResolutionVoteTallied {
+        market_id: u64,
+        outcome: Option<TokenType>,
+        yes_weight: u64,
+        no_weight: u64,
+    },
+    MarketCancelled {
+        market_id: u64,
+        refund_pool: u64,
+    },
+    MarketDeleted {
+        market_id: u64,
+        refunded: u64, // Pool ICP returned to liquidity providers and the treasury
+    },
+    MarketSettingsChanged {
+        market_id: u64,
+        setting: String,
+    },
+    ResolverProposed {
+        market_id: u64,
+        resolver: Principal,
+    },
+    ResolverChanged {
+        market_id: u64,
+        resolver: Option<Principal>, // None once the resolver abandons the market
+    },
+    ResolutionProposed {
+        market_id: u64,
+        outcome: TokenType,
+        proposer: Principal,
+    },
+    ResolutionChallenged {
+        market_id: u64,
+        challenger: Principal,
+    },
+    TradeExecuted {
+        trade_id: u64,
+        market_id: u64,
+        trader: Principal,
+        side: OrderSide,
+        token_type: TokenType,
+    },
+    LiquidityAdded {
+        market_id: u64,
+        provider: Principal,
+        icp_amount: u64,
+        shares: u64,
+    },
+    LiquidityRemoved {
+        market_id: u64,
+        provider: Principal,
+        icp_amount: u64,
+        shares: u64,
+        fees_forfeited: u64,
+    },
+    LiquidityMatched {
+        market_id: u64,
+        icp_amount: u64,
+    },
+    TreasuryLiquiditySettled {
+        market_id: u64,
+        icp_amount: u64,
+        fees: u64,
+    },
+    CompleteSetMinted {
+        market_id: u64,
+        user: Principal,
+        amount: u64,
+    },
+    CompleteSetRedeemed {
+        market_id: u64,
+        user: Principal,
+        amount: u64,
+    },
+    LpFeesClaimed {
+        market_id: u64,
+        provider: Principal,
+        amount: u64,
+    },
+    CreatorFeesClaimed {
+        market_id: u64,
+        creator: Principal,
+        amount: u64,
+    },
+    RewardClaimed {
+        market_id: u64,
+        user: Principal,
+        amount: u64,
+    },
+    RefundClaimed {
+        market_id: u64,
+        user: Principal,
+        amount: u64,
+    },
+    LimitOrderPlaced {
+        order_id: u64,
+        market_id: u64,
+        owner: Principal,
+    },
+    LimitOrderFilled {
+        order_id: u64,
+    },
+    LimitOrderCancelled {
+        order_id: u64,
+    },
+    TriggerCreated {
+        trigger_id: u64,
+        market_id: u64,
+        owner: Principal,
+    },
+    TriggerExecuted {
+        trigger_id: u64,
+    },
+    TriggerCancelled {
+        trigger_id: u64,
+    },
+    SwapOfferCreated {
+        offer_id: u64,
+        maker: Principal,
+    },
+    SwapOfferAccepted {
+        offer_id: u64,
+        taker: Principal,
+    },
+    SwapOfferCancelled {
+        offer_id: u64,
+    },
+    OutcomeTokensTransferred {
+        market_id: u64,
+        token_type: TokenType,
+        from: Principal,
+        to: Principal,
+        amount: u64,
+    },
+    PositionWrapped {
+        receipt_id: u64,
+        market_id: u64,
+        owner: Principal,
+        amount: u64,
+    },
+    PositionUnwrapped {
+        receipt_id: u64,
+        market_id: u64,
+        owner: Principal,
+        amount: u64,
+    },
+    ReceiptTransferred {
+        receipt_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    Deposited {
+        user: Principal,
+        amount: u64,
+    },
+    Withdrawn {
+        user: Principal,
+        amount: u64,
+        automatic: bool, // Swept by the idle-balance safeguard
+    },
+    AutoWithdrawChanged {
+        user: Principal,
+        days: Option<u64>,
+    },
+    RegionAttested {
+        user: Principal,
+        region: Option<String>,
+    },
+    ReadTokenIssued {
+        token_id: u64,
+        owner: Principal,
+    },
+    ReadTokenRevoked {
+        token_id: u64,
+    },
+    AdminChanged {
+        admin: Option<Principal>,
+    },
+    AdminProposed {
+        admin: Principal,
+    },
+    PauseChanged {
+        paused: bool,
+    },
+    QuestionGateChanged {
+        enabled: bool,
+    },
+    CreatorAllowlistModeChanged {
+        enabled: bool,
+    },
+    CreationFeeChanged {
+        amount: u64,
+    },
+    ExposureLimitsChanged {
+        limits: ExposureLimits,
+    },
+    TextLimitsChanged {
+        limits: TextLimits,
+    },
+    CategoryChanged {
+        category: Category,
+    },
+    FeaturedMarketChanged {
+        market_id: u64,
+        until: Option<u64>, // None once the market leaves the list
+    },
+    ShardConfigChanged {
+        config: ShardConfig,
+    },
+    CreatorAllowlistChanged {
+        creator: Principal,
+        allowed: bool,
+    },
+    WebhookAllowlistChanged {
+        hosts: Vec<String>,
+    },
+    MarketOracleChanged {
+        market_id: u64,
+        url: Option<String>,
+    },
+    ResolutionDrafted {
+        market_id: u64,
+        outcome: Option<TokenType>,
+    },
+    CommentPosted {
+        market_id: u64,
+        comment_id: u64,
+        author: Principal,
+    },
+    CommentDeleted {
+        market_id: u64,
+        comment_id: u64,
+        deleted_by: Principal,
+    },
+    ModeratorChanged {
+        moderator: Principal,
+        enabled: bool,
+    },
+    ResolutionDraftReviewed {
+        market_id: u64,
+        status: DraftStatus,
+    },
+    DynamicFeeChanged {
+        config: Option<DynamicFee>,
+    },
+    FeeTiersChanged {
+        tiers: Vec<FeeTier>,
+    },
+    ReferralRegistered {
+        referrer: Principal,
+        referred: Principal,
+    },
+    ReferralRewardsClaimed {
+        referrer: Principal,
+        amount: u64,
+    },
+    TreasuryWithdrawn {
+        amount: u64,
+        to: Principal,
+    },
+    ActionScheduled {
+        action: PendingAction,
+    },
+    ActionExecuted {
+        action_id: u64,
+    },
+    ActionCancelled {
+        action_id: u64,
+    },
+    ActionApproved {
+        action_id: u64,
+        approver: Principal,
+    },
+    AirdropQuorumChanged {
+        quorum: ApprovalQuorum,
+    },
+    TradingLimitsChanged {
+        min_trade_size: u64,
+        min_trade_fee: u64,
+    },
+    FeeHolidayScheduled {
+        holiday_id: u64,
+        scope: FeeHolidayScope,
+        start_time: u64,
+        end_time: u64,
+    },
+    FeeHolidayCancelled {
+        holiday_id: u64,
+    },
+    LiquidityMatchingChanged {
+        match_bps: u64,
+        max_match: u64,
+    },
+    EdgePoliciesChanged {
+        policies: EdgePolicies,
+    },
+    PlatformPhaseChanged {
+        phase: PlatformPhase,
+    },
+    WindDownSettled {
+        claims_paid: u64,
+        orders_cancelled: u64,
+        offers_cancelled: u64,
+    },
+    AirdropCredited {
+        campaign_id: u64,
+        recipients: u64,
+        amount: u64,
+    },
+}
 
-    MARKETS.with(|markets| {
-        markets.borrow_mut().insert(market_id, market);
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: u64, // Starts at 1 and increases by one per event
+    pub timestamp: u64,
+    pub kind: EventKind,
+}
 
-    Ok(market_id)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PlatformPhase {
+    Active,         // Normal operation
+    WindDown,       // No new markets or liquidity; open markets trade until their close time
+    WithdrawalOnly, // Everything is settled; users can only withdraw balances
 }
 
-/// Buy YES tokens using ICP
-/// Implements the constant product AMM formula with slippage protection
-#[ic_cdk::update]
-fn buy_yes_tokens(
-    market_id: u64,
-    icp_amount: u64,
-    min_tokens_out: u64, // Slippage protection
-) -> Result<TradeResult, PredictionMarketError> {
-    execute_buy_trade(market_id, icp_amount, min_tokens_out, TokenType::Yes)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SettlementSummary {
+    pub claims_paid: u64,
+    pub orders_cancelled: u64,
+    pub offers_cancelled: u64,
+    pub lp_fees_paid: u64,
+    pub creator_fees_paid: u64,
+    pub referral_rewards_paid: u64,
 }
 
-/// Buy NO tokens using ICP
-#[ic_cdk::update]
-fn buy_no_tokens(
-    market_id: u64,
-    icp_amount: u64,
-    min_tokens_out: u64, // Slippage protection
-) -> Result<TradeResult, PredictionMarketError> {
-    execute_buy_trade(market_id, icp_amount, min_tokens_out, TokenType::No)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WindDownReport {
+    pub phase: PlatformPhase,
+    pub generated_at: u64,
+    pub open_markets: u64,
+    pub frozen_markets: u64,
+    pub resolved_markets: u64,
+    pub unclaimed_winning_positions: u64,
+    pub unclaimed_refunds: u64, // Holders of invalid or cancelled markets yet to claim a refund
+    pub open_orders: u64,
+    pub open_swap_offers: u64,
+    pub unclaimed_fees: u64, // LP, creator and referral fees not yet paid out
+    pub stranded_pool: u64,  // Pool left in resolved markets with no winning tokens outstanding
+    pub total_user_balances: u64, // ICP owed to users, withdrawable
+    pub treasury_balance: u64,
 }
 
-/// Sell YES tokens back to the AMM for ICP
-#[ic_cdk::update]
-fn sell_yes_tokens(
-    market_id: u64,
-    token_amount: u64,
-    min_icp_out: u64, // Slippage protection
-) -> Result<TradeResult, PredictionMarketError> {
-    execute_sell_trade(market_id, token_amount, min_icp_out, TokenType::Yes)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AirdropCampaign {
+    pub id: u64,
+    pub created_by: Principal,
+    pub recipients: Vec<(Principal, u64)>,
+    pub next_index: u64, // First recipient not yet credited
+    pub total_amount: u64,
+    pub created_at: u64,
 }
 
-/// Sell NO tokens back to the AMM for ICP
-#[ic_cdk::update]
-fn sell_no_tokens(
-    market_id: u64,
-    token_amount: u64,
-    min_icp_out: u64, // Slippage protection
-) -> Result<TradeResult, PredictionMarketError> {
-    execute_sell_trade(market_id, token_amount, min_icp_out, TokenType::No)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AirdropRecord {
+    pub campaign_id: u64,
+    pub recipient: Principal,
+    pub amount: u64,
+    pub credited_at: u64,
 }
 
-// =============================================================================
-// INTERNAL TRADING FUNCTIONS
-// =============================================================================
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AirdropStatus {
+    pub campaign_id: u64,
+    pub processed: u64,
+    pub total_recipients: u64,
+    pub completed: bool,
+}
 
-fn execute_buy_trade(
-    market_id: u64,
-    icp_amount: u64,
-    min_tokens_out: u64,
-    token_type: TokenType,
-) -> Result<TradeResult, PredictionMarketError> {
-    let caller_principal = caller();
+/// Principals who must sign off on an airdrop, and how many of them are needed
+/// The default has no approvers, so no airdrop can run until a quorum is set
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ApprovalQuorum {
+    pub approvers: Vec<Principal>,
+    pub threshold: u64,
+}
 
-    if icp_amount == 0 {
-        return Err(PredictionMarketError::InvalidAmount);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CostOperation {
+    Buy,
+    Sell,
+    ClaimReward,
+    CreateMarket,
+}
 
-    // Check user balance
-    let user_balance =
-        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CostEstimate {
+    pub operation: CostOperation,
+    pub samples: u64,          // Number of executions measured
+    pub avg_instructions: u64, // Average instructions per execution
+    pub approx_cycles: u64,    // Approximate cycles charged per call
+}
 
-    if user_balance < icp_amount {
-        return Err(PredictionMarketError::InsufficientDeposit);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TradeResult {
+    pub tokens_received: u64,
+    pub tokens_paid: u64,               // In ICP
+    pub fee_paid: u64,                  // In ICP
+    pub new_price: f64,                 // New price after trade
+    pub fee_holiday_until: Option<u64>, // Set when a fee holiday waived the fee; the holiday's end time
+    pub market_id: u64,
+    pub token_type: TokenType,
+    pub average_price: f64, // ICP per token across the whole fill, after fees
+    pub price_impact: f64,  // Percentage move of the token's price caused by the trade
+    pub timestamp: u64,     // Execution time, or the time a quote was made
+    pub position: Option<UserPosition>, // The trader's position after the trade; None for quotes
+}
 
-    // Get market and verify it's open
-    let (_market, tokens_out) = MARKETS.with(|markets| {
-        let mut markets_map = markets.borrow_mut();
-        match markets_map.get_mut(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Open) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+/// Trade parameters hidden behind a commitment until `reveal_trade`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevealedTrade {
+    pub market_id: u64,
+    pub side: OrderSide,
+    pub token_type: TokenType,
+    pub amount: u64,  // ICP to spend on a buy, tokens to sell on a sell
+    pub min_out: u64, // Slippage protection: tokens for a buy, ICP for a sell
+}
 
-                // Calculate tokens out using AMM formula
-                let tokens_out = calculate_tokens_out(
-                    market.yes_reserve,
-                    market.no_reserve,
-                    icp_amount,
-                    matches!(token_type, TokenType::Yes),
-                )?;
+/// A hidden trade waiting to be revealed
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TradeCommitment {
+    pub hash: Vec<u8>, // SHA-256 over the trader's principal and the candid-encoded (trade, salt)
+    pub trader: Principal,
+    pub committed_at: u64,
+    pub expires_at: u64,
+}
 
-                // Check slippage protection
-                if tokens_out < min_tokens_out {
-                    return Err(PredictionMarketError::SlippageExceeded);
-                }
+/// One executed AMM trade, as stored in a market's append-only trade log
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TradeRecord {
+    pub id: u64, // Global sequence number across all markets
+    pub market_id: u64,
+    pub trader: Principal,
+    pub side: OrderSide,
+    pub token_type: TokenType,
+    pub icp_amount: u64,   // ICP paid for a buy, received for a sell
+    pub token_amount: u64, // Tokens received for a buy, sold for a sell
+    pub fee: u64,
+    pub price_after: f64, // Price of `token_type` after the trade
+    pub timestamp: u64,
+}
 
-                // Calculate trading fee
-                let fee = (icp_amount * TRADE_FEE) / 1000;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MarketSummary {
+    pub market: AmmMarket,
+    pub yes_price: f64,          // Current YES token price in ICP
+    pub no_price: f64,           // Current NO token price in ICP
+    pub total_volume: u64,       // ICP traded since the market opened, in e8s
+    pub price_impact: f64,       // Price impact for a standard trade size
+    pub volume_24h: u64,         // ICP traded over the last 24 hours
+    pub trade_count_24h: u64,    // Trades executed over the last 24 hours
+    pub unique_traders_24h: u64, // Distinct traders over the last 24 hours
+    pub price_change_24h: f64,   // Change in YES price over the last 24 hours
+    pub commit_reveal: bool,     // Trades must go through commit_trade and reveal_trade
+}
 
-                // Update market reserves based on AMM logic
-                match token_type {
-                    TokenType::Yes => {
-                        market.no_reserve -= (icp_amount * (1000 - TRADE_FEE)) / 1000;
-                        market.yes_reserve += tokens_out;
-                    }
-                    TokenType::No => {
-                        market.yes_reserve -= (icp_amount * (1000 - TRADE_FEE)) / 1000;
-                        market.no_reserve += tokens_out;
-                    }
-                }
+/// A user's stake in a market right after one of their trades
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PositionSnapshot {
+    pub trade_id: u64,
+    pub yes_tokens: u64,
+    pub no_tokens: u64,
+    pub yes_cost_basis: u64, // ICP paid for the YES tokens still held (average cost)
+    pub no_cost_basis: u64,  // ICP paid for the NO tokens still held (average cost)
+    pub yes_price: f64,      // Market YES price after the trade, for chart overlays
+    pub timestamp: u64,
+}
 
-                // Add ICP (minus fee) to liquidity pool
-                market.icp_liquidity_pool += icp_amount - fee;
-                market.total_fees_collected += fee;
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CandleResolution {
+    Hour,
+    Day,
+}
 
-                Ok((market.clone(), tokens_out))
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })?;
+/// YES price candle built from the trades executed in one period
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Candle {
+    pub start: u64, // Period start (nanoseconds since epoch)
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64, // ICP traded during the period
+    pub trades: u64,
+}
 
-    // Update user balance
-    USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        balances_map.insert(caller_principal, user_balance - icp_amount);
-    });
+/// One hour of trading activity in a market's rolling stats ring
+#[derive(Clone, Debug, Default)]
+struct StatsBucket {
+    hour: u64, // Hours since epoch this bucket currently holds
+    volume: u64,
+    trades: u64,
+    opening_yes_price: f64, // YES price before the bucket's first trade
+    traders: BTreeSet<Principal>,
+}
 
-    // Update user position
-    USER_POSITIONS.with(|positions| {
-        let mut positions_map = positions.borrow_mut();
-        let key = (caller_principal, market_id);
-        let position = positions_map.entry(key).or_insert(UserPosition {
-            user: caller_principal,
-            market_id,
-            yes_tokens: 0,
-            no_tokens: 0,
-            claimed_reward: false,
-        });
+/// Incrementally maintained rolling activity for one market
+#[derive(Clone, Debug)]
+struct RollingStats {
+    buckets: Vec<StatsBucket>, // Ring indexed by hour % ROLLING_WINDOW_HOURS
+    last_yes_price: f64,
+    total_volume: u64, // ICP traded since the market opened
+}
 
-        match token_type {
-            TokenType::Yes => position.yes_tokens += tokens_out,
-            TokenType::No => position.no_tokens += tokens_out,
+impl Default for RollingStats {
+    fn default() -> Self {
+        RollingStats {
+            buckets: vec![StatsBucket::default(); ROLLING_WINDOW_HOURS as usize],
+            last_yes_price: 0.5, // Markets open at even odds
+            total_volume: 0,
         }
-    });
+    }
+}
 
-    // Calculate new price for return value
-    let new_price = get_token_price(market_id, token_type)?;
-    let fee_paid = (icp_amount * TRADE_FEE) / 1000;
+/// Payouts a market would make if it resolved to `outcome` at current holdings
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ResolutionSimulation {
+    pub market_id: u64,
+    pub outcome: TokenType,
+    pub prize_pool: u64,           // ICP that would be shared among winners
+    pub total_winning_tokens: u64, // Winning tokens held, wrapped or escrowed across all users
+    pub winner_count: u64,         // Users holding at least one winning token
+    pub caller_winning_tokens: u64,
+    pub caller_payout: u64,
+}
 
-    Ok(TradeResult {
-        tokens_received: tokens_out,
-        tokens_paid: icp_amount,
-        fee_paid,
-        new_price,
-    })
+/// One entry of a bulk quote request
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QuoteRequest {
+    pub market_id: u64,
+    pub token_type: TokenType,
+    pub side: OrderSide,
+    pub amount: u64, // ICP for buys, tokens for sells
 }
 
-fn execute_sell_trade(
-    market_id: u64,
-    token_amount: u64,
-    min_icp_out: u64,
-    token_type: TokenType,
-) -> Result<TradeResult, PredictionMarketError> {
-    let caller_principal = caller();
+/// Compact market entry for list views: no description, region rules or fee accounting
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MarketListing {
+    pub id: u64,
+    pub title: String,
+    pub status: MarketStatus,
+    pub yes_price: f64,
+    pub no_price: f64,
+    pub icp_liquidity_pool: u64,
+    pub close_time: u64,
+    pub category: Option<String>,
+}
 
-    if token_amount == 0 {
-        return Err(PredictionMarketError::InvalidAmount);
-    }
+/// Round trip of one trade size: buy with `size` ICP, then sell the tokens straight back
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PriceBandCheck {
+    pub token_type: TokenType,
+    pub size: u64,
+    pub buy_price: f64,        // ICP paid per token
+    pub round_trip_price: f64, // ICP received per token when selling them back
+    pub allowed_spread: f64,   // Largest relative gap that fees and integer rounding explain
+    pub within_band: bool, // Sell price is not above the buy price and the gap is within the fee band
+}
 
-    // Check if user has enough tokens
-    let user_tokens = USER_POSITIONS.with(|positions| {
-        let positions_map = positions.borrow();
-        if let Some(position) = positions_map.get(&(caller_principal, market_id)) {
-            match token_type {
-                TokenType::Yes => position.yes_tokens,
-                TokenType::No => position.no_tokens,
-            }
-        } else {
-            0
-        }
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LifecycleStage {
+    FirstTrade,
+    NearClose, // NEAR_CLOSE_PERCENT of the trading window has passed
+    Frozen,
+    Disputed, // The proposed resolution was challenged
+    Resolved,
+    LargeTrade, // A single trade moved at least LARGE_TRADE_E8S
+}
 
-    if user_tokens < token_amount {
-        return Err(PredictionMarketError::InvalidAmount);
-    }
+/// Where a creator's lifecycle notifications are delivered
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CallbackTarget {
+    // One-way call with a LifecycleNotification argument
+    Canister {
+        canister_id: Principal,
+        method: String,
+    },
+    // JSON POST, sent once per replica; deduplicate on the Idempotency-Key header
+    Https {
+        url: String,
+    },
+}
 
-    // Get market and calculate ICP out
-    let (_market, icp_out) = MARKETS.with(|markets| {
-        let mut markets_map = markets.borrow_mut();
-        match markets_map.get_mut(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Open) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LifecycleHook {
+    pub target: CallbackTarget,
+    pub stages: Vec<LifecycleStage>,
+    pub near_close_sent: bool,
+}
 
-                let icp_out = calculate_icp_out(
-                    market.yes_reserve,
-                    market.no_reserve,
-                    token_amount,
-                    matches!(token_type, TokenType::Yes),
-                )?;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LifecycleNotification {
+    pub market_id: u64,
+    pub stage: LifecycleStage,
+    pub timestamp: u64,
+    pub trade_id: Option<u64>, // Set for FirstTrade and LargeTrade
+}
 
-                // Check slippage protection
-                if icp_out < min_icp_out {
-                    return Err(PredictionMarketError::SlippageExceeded);
-                }
+/// An HTTPS notification waiting for its next delivery attempt
+#[derive(Clone, Debug)]
+struct WebhookDelivery {
+    url: String,
+    notification: LifecycleNotification,
+    attempt: u32, // Attempts already made
+    next_attempt_at: u64,
+}
 
-                // Calculate trading fee
-                let gross_icp_out = match token_type {
-                    TokenType::Yes => {
-                        let k = market.yes_reserve * market.no_reserve;
-                        let new_yes_reserve = market.yes_reserve - token_amount;
-                        let new_no_reserve = k / new_yes_reserve;
-                        new_no_reserve - market.no_reserve
-                    }
-                    TokenType::No => {
-                        let k = market.yes_reserve * market.no_reserve;
-                        let new_no_reserve = market.no_reserve - token_amount;
-                        let new_yes_reserve = k / new_no_reserve;
-                        new_yes_reserve - market.yes_reserve
-                    }
-                };
+/// Canister health for frontends and monitoring
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CanisterStats {
+    pub cycles_balance: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub market_count: u64,
+    pub position_count: u64,
+    pub claim_count: u64,
+    pub trade_count: u64,
+    pub event_count: u64,
+    pub last_upgrade: Option<u64>, // When the running code was installed or last upgraded
+    pub state_version: u32,        // Layout the state is saved in across upgrades
+}
 
-                let fee = (gross_icp_out * TRADE_FEE) / 1000;
+/// Number of markets in each status
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct MarketStatusCounts {
+    pub open: u64,
+    pub frozen: u64,
+    pub resolved: u64,
+    pub invalid: u64,
+    pub cancelled: u64,
+}
 
-                // Update market reserves
-                match token_type {
-                    TokenType::Yes => {
-                        market.yes_reserve -= token_amount;
-                        market.no_reserve += gross_icp_out;
-                    }
-                    TokenType::No => {
-                        market.no_reserve -= token_amount;
-                        market.yes_reserve += gross_icp_out;
-                    }
-                }
+/// Platform-wide headline numbers
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlatformStats {
+    pub markets: MarketStatusCounts,
+    pub total_volume: u64, // ICP traded across all markets
+    pub total_fees: u64,   // Trading fees charged across all markets
+    pub total_trades: u64,
+    pub total_value_locked: u64, // ICP in the pools of markets still awaiting resolution
+    pub active_users_24h: u64,
+    pub active_users_7d: u64,
+    pub largest_markets: Vec<MarketListing>, // Open markets by pool size, largest first
+}
 
-                // Remove ICP from liquidity pool and add fee
-                market.icp_liquidity_pool = market.icp_liquidity_pool.saturating_sub(icp_out);
-                market.total_fees_collected += fee;
+/// Running trade totals behind the platform stats, rebuilt from the trade log on import
+#[derive(Clone, Debug, Default)]
+struct PlatformTotals {
+    volume: u64,
+    fees: u64,
+    trades: u64,
+}
 
-                Ok((market.clone(), icp_out))
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })?;
+/// Bounds applied to every trade's ICP value and fee
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TradingLimits {
+    pub min_trade_size: u64, // ICP spent on a buy or released by a sell
+    pub min_trade_fee: u64,  // Fee charged when 0.3% of the trade would be less
+}
 
-    // Update user position
-    USER_POSITIONS.with(|positions| {
-        let mut positions_map = positions.borrow_mut();
-        if let Some(position) = positions_map.get_mut(&(caller_principal, market_id)) {
-            match token_type {
-                TokenType::Yes => position.yes_tokens -= token_amount,
-                TokenType::No => position.no_tokens -= token_amount,
-            }
+/// Length bounds for market titles and descriptions, in characters after cleaning
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TextLimits {
+    pub min_title_len: u64,
+    pub max_title_len: u64,
+    pub min_description_len: u64,
+    pub max_description_len: u64,
+}
+
+impl Default for TextLimits {
+    fn default() -> Self {
+        TextLimits {
+            min_title_len: 1,
+            max_title_len: DEFAULT_MAX_TITLE_LEN,
+            min_description_len: 1,
+            max_description_len: DEFAULT_MAX_DESCRIPTION_LEN,
         }
-    });
+    }
+}
 
-    // Add ICP to user balance
-    USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
-        balances_map.insert(caller_principal, current_balance + icp_out);
-    });
+/// A category markets can be filed under, curated by the admin
+/// Markets store the slug, so a rename only changes the display name
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Category {
+    pub slug: String, // Lowercased name at registration; never changes
+    pub name: String,
+    pub archived: bool, // Kept on existing markets but closed to new ones
+    pub created_at: u64,
+}
 
-    // Calculate new price for return value
-    let new_price = get_token_price(market_id, token_type)?;
-    let fee_paid = (icp_out * TRADE_FEE) / (1000 - TRADE_FEE); // Approximate fee
+/// A market pinned to the featured list until `until`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeaturedMarket {
+    pub market_id: u64,
+    pub until: u64,
+    pub featured_by: Principal,
+}
 
-    Ok(TradeResult {
-        tokens_received: icp_out,  // ICP received
-        tokens_paid: token_amount, // Tokens sold
-        fee_paid,
-        new_price,
-    })
+/// A sensitive admin operation that only takes effect through the timelock
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminAction {
+    SetCreationFee {
+        amount: u64,
+    },
+    SetDynamicFee {
+        config: Option<DynamicFee>,
+    },
+    SetFeeTiers {
+        tiers: Vec<FeeTier>,
+    },
+    WithdrawTreasury {
+        amount: u64,
+        to: Principal,
+    },
+    OverrideResolution {
+        market_id: u64, // Must have a challenged proposal when the action executes
+        outcome: TokenType,
+        evidence: String,
+    },
+    OverturnDispute {
+        market_id: u64, // Flips the final outcome; the dispute keeps the window open meanwhile
+    },
+    SetTradingLimits {
+        limits: TradingLimits,
+    },
+    AddFeeHoliday {
+        scope: FeeHolidayScope,
+        start_time: u64,
+        end_time: u64,
+        reimburse_lps: bool,
+    },
+    SetLiquidityMatching {
+        matching: LiquidityMatching,
+    },
+    SetCreatorFeeBps {
+        market_id: u64,
+        creator_fee_bps: u64,
+    },
+    SetDisputeConfig {
+        config: DisputeConfig,
+    },
+    SetCreatorBondConfig {
+        config: CreatorBondConfig,
+    },
+    Airdrop {
+        recipients: Vec<(Principal, u64)>, // Paid from the treasury when the action executes
+    },
+    SetEarlyExitHaircut {
+        market_id: u64,
+        schedule: Vec<HaircutTier>,
+    },
+    SetEdgePolicies {
+        policies: EdgePolicies,
+    },
+    SetExposureLimits {
+        limits: ExposureLimits,
+    },
+    SetAirdropQuorum {
+        quorum: ApprovalQuorum,
+    },
 }
 
-// =============================================================================
-// MARKET RESOLUTION AND REWARDS
-// =============================================================================
+/// An admin action waiting out its delay; anyone can see it before it runs
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingAction {
+    pub id: u64,
+    pub action: AdminAction,
+    pub scheduled_by: Principal,
+    pub scheduled_at: u64,
+    pub executable_at: u64,
+    pub approvals: Vec<Principal>, // Quorum members who signed off; only airdrops need them
+}
 
-/// Resolve a market and set the winning outcome (admin only)
-/// This freezes the market and prepares it for reward claims
-#[ic_cdk::update]
-fn resolve_market(market_id: u64, outcome: TokenType) -> Result<String, PredictionMarketError> {
-    let caller_principal = caller();
+/// Optional caps on how much ICP a buy can put at risk; None leaves a cap off
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ExposureLimits {
+    pub max_trade_icp: Option<u64>,       // ICP spent by a single buy
+    pub max_position_tokens: Option<u64>, // Tokens of one side a user may hold in a market
+    pub max_pool_size: Option<u64>,       // ICP a market's liquidity pool may grow to
+}
 
-    // Check if caller is global admin OR the market creator/admin
-    let is_global_admin = ADMIN.with(|admin| {
-        admin
-            .borrow()
-            .map_or(false, |admin_principal| admin_principal == caller_principal)
-    });
+/// Markets covered by a fee holiday
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FeeHolidayScope {
+    Market(u64),
+    Category(String), // Normalized like market categories
+}
 
-    let is_market_admin = MARKETS.with(|markets| {
-        markets
-            .borrow()
-            .get(&market_id)
-            .map_or(false, |market| market.admin == caller_principal)
-    });
-
-    if !is_global_admin && !is_market_admin {
-        return Err(PredictionMarketError::Unauthorized);
-    }
+/// Admin-scheduled window during which covered markets trade without a fee
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FeeHoliday {
+    pub id: u64,
+    pub scope: FeeHolidayScope,
+    pub start_time: u64,
+    pub end_time: u64,       // Exclusive
+    pub reimburse_lps: bool, // Treasury pays LPs the share of each waived fee they would have earned
+    pub reimbursed: u64,     // Paid to LPs from the treasury so far
+}
 
-    // Update market status and set winning outcome
-    MARKETS.with(|markets| {
-        let mut markets_map = markets.borrow_mut();
-        match markets_map.get_mut(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Open) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+/// Treasury co-funding of creator seed liquidity, so new markets in approved categories start deeper
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LiquidityMatching {
+    pub categories: Vec<String>, // Approved categories, normalized; empty disables matching
+    pub match_bps: u64,          // Treasury ICP per creator ICP; 10_000 matches 1:1
+    pub max_match: u64,          // Cap per market, in e8s
+}
 
-                market.status = MarketStatus::Resolved;
-                market.winning_outcome = Some(outcome.clone());
+/// What happens to a market that reaches a state the normal payout path cannot serve
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum EdgePolicy {
+    Refund,        // Return the pool to the LPs pro-rata to their shares
+    TreasurySweep, // Move the pool to the protocol treasury
+    ExtendMarket,  // Keep trading open for another `extension_ns`
+}
 
-                Ok(format!(
-                    "Market {} resolved with outcome: {:?}. Users can now claim rewards.",
-                    market_id, outcome
-                ))
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })
+/// Explicit handling of degenerate market states
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EdgePolicies {
+    pub untraded_at_close: EdgePolicy, // Trading closes with reserves still equal and no outcome tokens held
+    pub no_winning_supply: EdgePolicy, // Resolved with nobody holding the winning side; cannot extend
+    pub extension_ns: u64,
 }
 
-/// Claim reward for holding winning tokens
-/// Burns the winning tokens and distributes proportional share of ICP liquidity
-#[ic_cdk::update]
-fn claim_reward(market_id: u64) -> Result<RewardClaim, PredictionMarketError> {
-    let caller_principal = caller();
+/// How long a final resolution can be disputed, and the bond a dispute costs
+/// A zero window unlocks claims as soon as a market resolves
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DisputeConfig {
+    pub window_ns: u64,
+    pub bond: u64,
+}
 
-    // Get market and check if it's resolved
-    let (market, winning_token_type) = MARKETS.with(|markets| {
-        let markets_map = markets.borrow();
-        match markets_map.get(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Resolved) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+/// Bond creators lock when opening a market; a zero amount disables it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CreatorBondConfig {
+    pub amount: u64,
+    pub slash_bps: u64, // Share lost when the resolution is overturned or the market voided
+}
 
-                match &market.winning_outcome {
-                    Some(outcome) => Ok((market.clone(), outcome.clone())),
-                    None => Err(PredictionMarketError::MarketNotFound),
-                }
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })?;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CreatorBond {
+    pub market_id: u64,
+    pub creator: Principal,
+    pub amount: u64,
+    pub slash_bps: u64, // Fixed at creation
+    pub slashed: u64,
+    pub released: bool, // The unslashed remainder went back to the creator
+}
 
-    // Get user position and check for previous claims
-    let (user_winning_tokens, already_claimed) = USER_POSITIONS.with(|positions| {
-        let positions_map = positions.borrow();
-        if let Some(position) = positions_map.get(&(caller_principal, market_id)) {
-            if position.claimed_reward {
-                return (0, true);
-            }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DisputeVerdict {
+    Confirmed,  // The resolution stands and the bond goes to the treasury
+    Overturned, // The other outcome wins and the bond is returned
+}
 
-            let winning_tokens = match winning_token_type {
-                TokenType::Yes => position.yes_tokens,
-                TokenType::No => position.no_tokens,
-            };
-            (winning_tokens, false)
-        } else {
-            (0, false)
-        }
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Dispute {
+    pub disputer: Principal,
+    pub bond: u64,
+    pub opened_at: u64,
+    pub verdict: Option<DisputeVerdict>, // None while awaiting the global admin
+}
 
-    if already_claimed {
-        return Err(PredictionMarketError::AlreadyClaimed);
-    }
+/// The period after a final resolution during which claims stay locked
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DisputeWindow {
+    pub market_id: u64,
+    pub closes_at: u64,
+    pub dispute: Option<Dispute>, // At most one per market
+    pub closed: bool,             // Claims are unlocked
+}
 
-    if user_winning_tokens == 0 {
-        return Err(PredictionMarketError::NoWinningTokens);
-    }
+/// Optional fee surcharge that grows with how far a trade moves the YES probability
+/// Deters pushing thin markets around, e.g. right before resolution
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DynamicFee {
+    pub impact_share_bps: u64, // Surcharge per unit of price move: 1_000 adds 10 bps per 100 bps moved
+    pub max_fee_bps: u64,      // Cap on the total fee rate, base fee included
+}
 
-    // Calculate total winning tokens across all users
-    let total_winning_tokens = USER_POSITIONS.with(|positions| {
-        let positions_map = positions.borrow();
-        positions_map
-            .values()
-            .filter(|pos| pos.market_id == market_id)
-            .map(|pos| match winning_token_type {
-                TokenType::Yes => pos.yes_tokens,
-                TokenType::No => pos.no_tokens,
-            })
-            .sum::<u64>()
-    });
+/// Discounted fee rate for traders whose cumulative volume reached `min_volume`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeeTier {
+    pub min_volume: u64, // e8s traded across all markets
+    pub fee_bps: u64,    // Replaces the 30 bps base rate; never higher
+}
 
-    if total_winning_tokens == 0 {
-        return Err(PredictionMarketError::InsufficientLiquidity);
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TraderFeeStatus {
+    pub volume: u64,
+    pub fee_bps: u64,
+    pub next_tier: Option<FeeTier>,
+}
 
-    // Calculate user's share of the ICP liquidity pool
-    // reward = (user_winning_tokens / total_winning_tokens) * total_ICP_pool
-    let reward_amount = (user_winning_tokens as u128 * market.icp_liquidity_pool as u128
-        / total_winning_tokens as u128) as u64;
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LeaderboardPeriod {
+    Day,
+    Week,
+    Month,
+    AllTime,
+}
 
-    // Update user position to mark as claimed
-    USER_POSITIONS.with(|positions| {
-        let mut positions_map = positions.borrow_mut();
-        if let Some(position) = positions_map.get_mut(&(caller_principal, market_id)) {
-            position.claimed_reward = true;
-            // Burn the winning tokens
-            match winning_token_type {
-                TokenType::Yes => position.yes_tokens = 0,
-                TokenType::No => position.no_tokens = 0,
-            }
-        }
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LeaderboardSort {
+    Pnl,
+    WinRate, // Ties broken by the number of settled markets, then PnL
+}
 
-    // Transfer ICP reward to user
-    USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
-        balances_map.insert(caller_principal, current_balance + reward_amount);
-    });
+/// ICP a user put into and took out of one market; `updated_at` dates the last movement
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct MarketPnl {
+    spent: u64,    // Buys and complete-set mints
+    received: u64, // Sells, redemptions, winnings and refunds
+    updated_at: u64,
+}
 
-    // Record the claim
-    let claim = RewardClaim {
-        user: caller_principal,
-        market_id,
-        winning_tokens: user_winning_tokens,
-        reward_amount,
-        claim_time: ic_cdk::api::time(),
-    };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub rank: u64,
+    pub user: Principal,
+    pub realized_pnl: i64, // ICP received minus ICP spent, in e8s
+    pub markets_traded: u64,
+    pub settled_markets: u64,
+    pub wins: u64,     // Settled markets the user got more out of than they put in
+    pub win_rate: f64, // wins / settled_markets, 0 with no settled market
+}
 
-    REWARD_CLAIMS.with(|claims| {
-        claims.borrow_mut().push(claim.clone());
-    });
+/// Forecast accuracy over resolved markets; the forecast is the YES share of a user's
+/// tokens when the market resolved
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserReputation {
+    pub user: Principal,
+    pub markets_scored: u64,
+    pub brier_score: Option<f64>, // Mean squared forecast error, 0 is perfect; None until scored
+}
 
-    // Update market liquidity pool
-    MARKETS.with(|markets| {
-        let mut markets_map = markets.borrow_mut();
-        if let Some(market) = markets_map.get_mut(&market_id) {
-            market.icp_liquidity_pool = market.icp_liquidity_pool.saturating_sub(reward_amount);
-        }
-    });
+/// One side of one market, addressed as its own ICRC-1 token
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutcomeToken {
+    pub market_id: u64,
+    pub token_type: TokenType,
+}
 
-    Ok(claim)
+/// ICRC-1 account; only the default subaccount holds outcome tokens
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
 }
 
-// =============================================================================
-// QUERY FUNCTIONS
-// =============================================================================
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
 
-/// Get all markets with current prices and stats
-#[ic_cdk::query]
-fn get_markets() -> Vec<MarketSummary> {
-    MARKETS.with(|markets| {
-        markets
-            .borrow()
-            .values()
-            .map(|market| {
-                let total_reserves = market.yes_reserve + market.no_reserve;
-                let (yes_price, no_price) = if total_reserves > 0 {
-                    (
-                        market.no_reserve as f64 / total_reserves as f64,
-                        market.yes_reserve as f64 / total_reserves as f64,
-                    )
-                } else {
-                    (0.5, 0.5) // Equal prices when no liquidity
-                };
+/// ICRC-1 transfer errors, as defined by the standard
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
 
-                // Calculate price impact for a standard trade (100 ICP)
-                let standard_trade = 100;
-                let price_impact = if total_reserves > 0 {
-                    let tokens_out = calculate_tokens_out(
-                        market.yes_reserve,
-                        market.no_reserve,
-                        standard_trade,
-                        true,
-                    )
-                    .unwrap_or(0) as f64;
-                    let new_price = if market.yes_reserve + tokens_out as u64 > 0 {
-                        (market.no_reserve - (standard_trade * (1000 - TRADE_FEE)) / 1000) as f64
-                            / (market.yes_reserve + tokens_out as u64 + market.no_reserve
-                                - (standard_trade * (1000 - TRADE_FEE)) / 1000)
-                                as f64
-                    } else {
-                        yes_price
-                    };
-                    ((new_price - yes_price) / yes_price * 100.0).abs()
-                } else {
-                    0.0
-                };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
 
-                MarketSummary {
-                    market: market.clone(),
-                    yes_price,
-                    no_price,
-                    total_volume: market.icp_liquidity_pool,
-                    price_impact,
-                }
-            })
-            .collect()
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OutcomeTransfer {
+    pub id: u64, // ICRC-1 block index
+    pub token: OutcomeToken,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: u64,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64,
 }
 
-/// Get a specific market by ID
-#[ic_cdk::query]
-fn get_market(market_id: u64) -> Option<MarketSummary> {
-    MARKETS.with(|markets| {
-        markets.borrow().get(&market_id).map(|market| {
-            let total_reserves = market.yes_reserve + market.no_reserve;
-            let (yes_price, no_price) = if total_reserves > 0 {
-                (
-                    market.no_reserve as f64 / total_reserves as f64,
-                    market.yes_reserve as f64 / total_reserves as f64,
-                )
-            } else {
-                (0.5, 0.5)
-            };
+/// A sealed block of outcome tokens held by the canister on behalf of an ICRC-7 receipt
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PositionReceipt {
+    pub id: u64,
+    pub owner: Principal,
+    pub market_id: u64,
+    pub token_type: TokenType,
+    pub amount: u64,
+    pub entry_price: f64, // Wrapper's average cost per token, or the market price without a cost basis
+    pub wrapped_at: u64,
+}
 
-            let standard_trade = 100;
-            let price_impact = if total_reserves > 0 {
-                let tokens_out = calculate_tokens_out(
-                    market.yes_reserve,
-                    market.no_reserve,
-                    standard_trade,
-                    true,
-                )
-                .unwrap_or(0) as f64;
-                let new_price = if market.yes_reserve + tokens_out as u64 > 0 {
-                    (market.no_reserve - (standard_trade * (1000 - TRADE_FEE)) / 1000) as f64
-                        / (market.yes_reserve + tokens_out as u64 + market.no_reserve
-                            - (standard_trade * (1000 - TRADE_FEE)) / 1000)
-                            as f64
-                } else {
-                    yes_price
-                };
-                ((new_price - yes_price) / yes_price * 100.0).abs()
-            } else {
-                0.0
-            };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NftTransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
 
-            MarketSummary {
-                market: market.clone(),
-                yes_price,
-                no_price,
-                total_volume: market.icp_liquidity_pool,
-                price_impact,
-            }
-        })
-    })
+/// ICRC-7 transfer errors, as defined by the standard
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NftTransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
 }
 
-/// Get user position for a specific market
-#[ic_cdk::query]
-fn get_user_position(market_id: u64) -> Option<UserPosition> {
-    let caller_principal = caller();
-    USER_POSITIONS.with(|positions| {
-        positions
-            .borrow()
-            .get(&(caller_principal, market_id))
-            .cloned()
-    })
+/// Worker canisters that take new markets once this canister holds `local_market_limit`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ShardConfig {
+    pub workers: Vec<Principal>,
+    pub local_market_limit: Option<u64>, // None keeps every market on this canister
 }
 
-/// Get all user positions
-#[ic_cdk::query]
-fn get_all_user_positions() -> Vec<UserPosition> {
-    let caller_principal = caller();
-    USER_POSITIONS.with(|positions| {
-        positions
-            .borrow()
-            .values()
-            .filter(|pos| pos.user == caller_principal)
-            .cloned()
-            .collect()
-    })
+/// One slice of a candid-encoded state export
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StateChunk {
+    pub index: u64,
+    pub total_chunks: u64,
+    pub state_hash: Vec<u8>, // SHA-256 of the whole export; every chunk of one export carries the same hash
+    pub bytes: Vec<u8>,
 }
 
-/// Get user's ICP balance
-#[ic_cdk::query]
-fn get_user_balance() -> u64 {
-    let caller_principal = caller();
-    USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0))
+/// ICRC-21 consent message request, sent by a wallet before it asks the user to sign a call
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConsentMessageRequest {
+    pub method: String,
+    pub arg: Vec<u8>, // Candid-encoded arguments of the call to describe
+    pub user_preferences: ConsentMessageSpec,
 }
 
-/// Get user's ICP balance by Principal (for admin queries)
-#[ic_cdk::query]
-fn get_balance_of(user: Principal) -> u64 {
-    USER_BALANCES.with(|balances| *balances.borrow().get(&user).unwrap_or(&0))
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConsentMessageSpec {
+    pub metadata: ConsentMessageMetadata,
+    pub device_spec: Option<DisplayMessageType>,
 }
 
-/// Get reward claims for a user
-#[ic_cdk::query]
-fn get_user_claims() -> Vec<RewardClaim> {
-    let caller_principal = caller();
-    REWARD_CLAIMS.with(|claims| {
-        claims
-            .borrow()
-            .iter()
-            .filter(|claim| claim.user == caller_principal)
-            .cloned()
-            .collect()
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsentMessageMetadata {
+    pub language: String,
+    pub utc_offset_minutes: Option<i16>,
 }
 
-/// Calculate quote for buying tokens (without executing the trade)
-#[ic_cdk::query]
-fn get_buy_quote(
-    market_id: u64,
-    icp_amount: u64,
-    token_type: TokenType,
-) -> Result<TradeResult, PredictionMarketError> {
-    MARKETS.with(|markets| {
-        let markets_map = markets.borrow();
-        match markets_map.get(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Open) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum DisplayMessageType {
+    GenericDisplay,
+    LineDisplay {
+        characters_per_line: u16,
+        lines_per_page: u16,
+    },
+}
 
-                let tokens_out = calculate_tokens_out(
-                    market.yes_reserve,
-                    market.no_reserve,
-                    icp_amount,
-                    matches!(token_type, TokenType::Yes),
-                )?;
-
-                let fee_paid = (icp_amount * TRADE_FEE) / 1000;
-
-                // Calculate new price after this hypothetical trade
-                let new_yes_reserve = match token_type {
-                    TokenType::Yes => market.yes_reserve + tokens_out,
-                    TokenType::No => market.yes_reserve - (icp_amount * (1000 - TRADE_FEE)) / 1000,
-                };
-                let new_no_reserve = match token_type {
-                    TokenType::Yes => market.no_reserve - (icp_amount * (1000 - TRADE_FEE)) / 1000,
-                    TokenType::No => market.no_reserve + tokens_out,
-                };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsentMessagePage {
+    pub lines: Vec<String>,
+}
 
-                let total_new_reserves = new_yes_reserve + new_no_reserve;
-                let new_price = if total_new_reserves > 0 {
-                    match token_type {
-                        TokenType::Yes => new_no_reserve as f64 / total_new_reserves as f64,
-                        TokenType::No => new_yes_reserve as f64 / total_new_reserves as f64,
-                    }
-                } else {
-                    0.5
-                };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<ConsentMessagePage> },
+}
 
-                Ok(TradeResult {
-                    tokens_received: tokens_out,
-                    tokens_paid: icp_amount,
-                    fee_paid,
-                    new_price,
-                })
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsentInfo {
+    pub consent_message: ConsentMessage,
+    pub metadata: ConsentMessageMetadata,
 }
 
-/// Calculate quote for selling tokens (without executing the trade)
-#[ic_cdk::query]
-fn get_sell_quote(
-    market_id: u64,
-    token_amount: u64,
-    token_type: TokenType,
-) -> Result<TradeResult, PredictionMarketError> {
-    MARKETS.with(|markets| {
-        let markets_map = markets.borrow();
-        match markets_map.get(&market_id) {
-            Some(market) => {
-                if !matches!(market.status, MarketStatus::Open) {
-                    return Err(PredictionMarketError::MarketClosed);
-                }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsentErrorInfo {
+    pub description: String,
+}
 
-                let icp_out = calculate_icp_out(
-                    market.yes_reserve,
-                    market.no_reserve,
-                    token_amount,
-                    matches!(token_type, TokenType::Yes),
-                )?;
+/// ICRC-21 errors, as defined by the standard
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConsentError {
+    UnsupportedCanisterCall(ConsentErrorInfo),
+    ConsentMessageUnavailable(ConsentErrorInfo),
+    InsufficientPayment(ConsentErrorInfo),
+    GenericError {
+        error_code: Nat,
+        description: String,
+    },
+}
 
-                let fee_paid = (icp_out * TRADE_FEE) / (1000 - TRADE_FEE);
+/// ICRC-10 entry naming a standard the canister implements
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
 
-                // Calculate new price after this hypothetical trade
-                let new_yes_reserve = match token_type {
-                    TokenType::Yes => market.yes_reserve - token_amount,
-                    TokenType::No => {
-                        let k = market.yes_reserve * market.no_reserve;
-                        let new_no_reserve = market.no_reserve - token_amount;
-                        k / new_no_reserve
-                    }
-                };
-                let new_no_reserve = match token_type {
-                    TokenType::Yes => {
-                        let k = market.yes_reserve * market.no_reserve;
-                        let new_yes_reserve = market.yes_reserve - token_amount;
-                        k / new_yes_reserve
-                    }
-                    TokenType::No => market.no_reserve - token_amount,
-                };
+/// Kickbacks earned by a referrer from the trades of the principals they referred
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReferralAccount {
+    pub referred_count: u64,
+    pub total_earned: u64,
+    pub claimable: u64,
+}
 
-                let total_new_reserves = new_yes_reserve + new_no_reserve;
-                let new_price = if total_new_reserves > 0 {
-                    match token_type {
-                        TokenType::Yes => new_no_reserve as f64 / total_new_reserves as f64,
-                        TokenType::No => new_yes_reserve as f64 / total_new_reserves as f64,
-                    }
-                } else {
-                    0.5
-                };
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReferralStats {
+    pub referrer: Option<Principal>, // Who referred the caller, if anyone
+    pub referred_count: u64,
+    pub total_earned: u64,
+    pub claimable: u64,
+}
 
-                Ok(TradeResult {
-                    tokens_received: icp_out,
-                    tokens_paid: token_amount,
-                    fee_paid,
-                    new_price,
-                })
-            }
-            None => Err(PredictionMarketError::MarketNotFound),
-        }
-    })
+/// Review of a market question before creation
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QuestionReview {
+    pub unambiguous: bool,
+    pub has_resolution_source: bool,
+    pub has_deadline: bool,
+    pub suggestions: Vec<String>,
+    pub model_generated: bool, // False when the keyword heuristic answered instead of the LLM
 }
 
-// =============================================================================
-// ADMIN FUNCTIONS
-// =============================================================================
+impl QuestionReview {
+    fn passes(&self) -> bool {
+        self.unambiguous && self.has_resolution_source && self.has_deadline
+    }
+}
 
-#[ic_cdk::update]
-fn set_admin(admin_principal: Principal) -> Result<String, PredictionMarketError> {
-    // Allow anyone to become admin in development mode
-    ADMIN.with(|admin| {
-        *admin.borrow_mut() = Some(admin_principal);
-        Ok("Admin set successfully".to_string())
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DraftStatus {
+    Pending,
+    Confirmed,
+    Rejected,
 }
 
-#[ic_cdk::update]
-fn reset_admin() -> String {
-    ADMIN.with(|admin| {
-        *admin.borrow_mut() = None;
-        "Admin reset - anyone can now become admin".to_string()
-    })
+/// An outcome proposed by the LLM, waiting for the human resolver
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ResolutionDraft {
+    pub market_id: u64,
+    pub outcome: Option<TokenType>, // None when the model found the evidence inconclusive
+    pub reasoning: String,
+    pub evidence: Option<String>,
+    pub requested_by: Principal,
+    pub created_at: u64,
+    pub status: DraftStatus,
+    pub reviewed_at: Option<u64>,
 }
 
-#[ic_cdk::query]
-fn get_admin() -> Option<Principal> {
-    ADMIN.with(|admin| *admin.borrow())
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Comment {
+    pub id: u64,
+    pub market_id: u64,
+    pub author: Principal,
+    pub text: String,
+    pub created_at: u64,
 }
 
-#[ic_cdk::query]
-fn is_admin() -> bool {
-    let caller_principal = caller();
-    ADMIN.with(|admin| {
-        admin
-            .borrow()
-            .map_or(false, |admin_principal| admin_principal == caller_principal)
-    })
+/// `v1_chat` request of the LLM canister; the optional `tools` field is left out
+#[derive(CandidType, Serialize)]
+struct LlmChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
 }
 
-// =============================================================================
-// UTILITY FUNCTIONS
-// =============================================================================
+type LlmResponse = ic_llm::Response;
 
-/// Deposit ICP to user balance (simulation - in production would involve real ICP transfers)
-#[ic_cdk::update]
-fn deposit_icp(amount: u64) -> Result<String, PredictionMarketError> {
-    if amount < MIN_DEPOSIT {
-        return Err(PredictionMarketError::InvalidAmount);
-    }
+/// Platform-wide trading parameters, for frontends and bots
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlatformConfig {
+    pub e8s_per_icp: u64, // All amounts below and across the API are in e8s
+    pub trade_fee_bps: u64,
+    pub protocol_fee_bps: u64, // Treasury's share of each trading fee
+    pub default_creator_fee_bps: u64,
+    pub min_deposit: u64,
+    pub min_trade_size: u64,
+    pub min_trade_fee: u64,
+    pub fee_holidays: Vec<FeeHoliday>, // Scheduled and running, oldest first
+    pub liquidity_matching: LiquidityMatching,
+    pub edge_policies: EdgePolicies,
+    pub dispute: DisputeConfig,
+    pub paused: bool,        // Trading, market creation and claims are halted
+    pub question_gate: bool, // Markets without structured criteria must pass the question review
+    pub dynamic_fee: Option<DynamicFee>,
+    pub fee_tiers: Vec<FeeTier>, // By ascending volume threshold
+    pub creator_bond: CreatorBondConfig,
+    pub creator_allowlist: bool, // Only listed creators and the global admin may create markets
+    pub creation_fee: u64, // Flat fee charged by create_market on top of the seed liquidity and bond
+    pub exposure_limits: ExposureLimits,
+    pub shards: ShardConfig,
+    pub text_limits: TextLimits,
+}
 
-    let caller_principal = caller();
-    USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
-        balances_map.insert(caller_principal, current_balance + amount);
-    });
+/// Criteria for `list_markets`; every field left empty matches all markets
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MarketFilter {
+    pub status: Option<MarketStatus>,
+    pub creator: Option<Principal>,
+    pub category: Option<String>, // Matched case-insensitively
+    pub open_only: bool,
+    pub resolved_only: bool,
+}
 
-    Ok(format!("Successfully deposited {} ICP", amount))
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RefundClaim {
+    pub user: Principal,
+    pub market_id: u64,
+    pub contributed: u64, // Net ICP the user put into the market
+    pub refund_amount: u64,
+    pub claim_time: u64,
 }
 
-// =============================================================================
-// LLM INTEGRATION (KEEPING EXISTING FUNCTIONALITY)
-// =============================================================================
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RewardClaim {
+    pub user: Principal,
+    pub market_id: u64,
+    pub winning_tokens: u64,
+    pub reward_amount: u64, // ICP reward
+    pub claim_time: u64,
+}
 
-// #[ic_cdk::update]
-// async fn prompt(prompt_str: String) -> String {
-//     ic_llm::prompt(Model::Llama3_1_8B, prompt_str).await
-// }
+/// A market `claim_all_rewards` did not pay out, and why
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct SkippedClaim {
+    pub market_id: u64,
+    pub reason: PredictionMarketError,
+}
 
-// #[ic_cdk::update]
-// async fn chat(messages: Vec<ChatMessage>) -> String {
-//     let response = ic_llm::chat(Model::Llama3_1_8B)
-//         .with_messages(messages)
-//         .send()
-//         .await;
+/// Outcome of claiming every resolved market at once
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct ClaimAllResult {
+    pub claims: Vec<RewardClaim>,
+    pub skipped: Vec<SkippedClaim>,
+    pub truncated: bool, // More markets remain; call again to continue
+}
 
-//     response.message.content.unwrap_or_default()
-// }
+/// One page of a list query
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub truncated: bool,          // More results exist beyond this page
+    pub next_cursor: Option<u64>, // Pass back as `cursor` to fetch the next page
+}
 
-/// Generate AI-powered market analysis
-#[ic_cdk::update]
-async fn analyze_market(market_id: u64) -> Result<String, PredictionMarketError> {
-    let market_summary = get_market(market_id).ok_or(PredictionMarketError::MarketNotFound)?;
+impl<T> Page<T> {
+    fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            truncated: self.truncated,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
 
-    let analysis = format!(
-        "📊 AMM Market Analysis\n\n\
-        Market: \"{}\"\n\n\
-        💰 Current Pricing (AMM-based):\n\
-        🟢 YES tokens: {:.1}% probability ({:.4} ICP each)\n\
-        🔴 NO tokens: {:.1}% probability ({:.4} ICP each)\n\n\
-        📈 Market Liquidity:\n\
-        - Total ICP pool: {} ICP\n\
-        - YES token reserve: {} tokens\n\
-        - NO token reserve: {} tokens\n\
-        - Price impact (100 ICP trade): {:.2}%\n\n\
-        🔧 AMM Mechanics:\n\
-        This market uses a constant product formula (x * y = k) where:\n\
-        - x = YES token reserve, y = NO token reserve\n\
-        - k = constant product ({} × {} = {})\n\
-        - Price updates automatically with each trade\n\
-        - Trading fee: {}%\n\n\
-        📊 Market Sentiment:\n\
-        The current token distribution suggests traders are {}.\n\n\
-        ⚠️ Risk Considerations:\n\
-        - Higher price impact indicates lower liquidity\n\
-        - Large trades may face significant slippage\n\
-        - Market status: {:?}",
-        market_summary.market.title,
-        market_summary.yes_price * 100.0,
-        market_summary.yes_price,
-        market_summary.no_price * 100.0,
-        market_summary.no_price,
-        market_summary.market.icp_liquidity_pool,
-        market_summary.market.yes_reserve,
-        market_summary.market.no_reserve,
-        market_summary.price_impact,
-        market_summary.market.yes_reserve,
-        market_summary.market.no_reserve,
-        market_summary.market.yes_reserve * market_summary.market.no_reserve,
-        TRADE_FEE as f64 / 10.0,
-        if market_summary.yes_price > 0.6 {
-            "more confident in a YES outcome based on trading activity"
-        } else if market_summary.no_price > 0.6 {
-            "more confident in a NO outcome based on trading activity"
-        } else {
-            "uncertain, with balanced confidence in both outcomes"
-        },
-        market_summary.market.status
-    );
+// Error types
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub enum PredictionMarketError {
+    MarketNotFound,
+    MarketClosed,
+    MarketResolved,
+    InsufficientDeposit {
+        required: u64,  // e8s the call needs
+        available: u64, // e8s the caller has, or put up
+    },
+    InsufficientLiquidity {
+        max_trade: u64, // Largest amount of the same input the market could take; 0 when none
+    },
+    Unauthorized,
+    InvalidAmount,
+    AlreadyClaimed,
+    NoWinningTokens,
+    SlippageExceeded {
+        min_out: u64,    // Least the caller would accept
+        actual_out: u64, // What the trade would have given at execution
+    },
+    MaxCostExceeded {
+        max_in: u64,    // Most ICP the caller would spend on an exact-output buy
+        actual_in: u64, // ICP the buy would have cost at execution
+    },
+    InvalidCloseTime,
+    NothingToClaim,
+    OfferNotFound,
+    OfferNotOpen,
+    OrderNotFound,
+    OrderNotOpen,
+    TriggerNotFound,
+    TriggerNotActive,
+    InvalidReadToken,
+    CallFailed(String),
+    InvalidResolutionCriteria,
+    ResolutionConflict, // The resolution moved on since the caller read it
+    InvalidResolutionTransition,
+    AirdropNotFound,
+    InvalidRegion,
+    RegionRestricted,
+    PlatformWindingDown,
+    InvalidPlatformPhase,
+    SettlementIncomplete,
+    TradeBelowMinimum,
+    InvalidCallbackTarget,
+    Overflow, // A reserve or balance would exceed u64
+    FeeHolidayNotFound,
+    InvalidFeeHoliday,
+    DisputeWindowOpen, // Claims unlock once the dispute window closes and any dispute is settled
+    DisputeWindowClosed,
+    AlreadyDisputed,
+    DisputeNotFound,
+    VotingClosed,
+    NotEligibleToVote, // Only holders of the market's outcome tokens can vote
+    Paused,            // Trading, market creation and claims are halted by the admin
+    QuestionNeedsWork(Vec<String>), // Suggestions from the question gate
+    DraftNotFound,
+    InvalidReferral, // Self-referrals, anonymous referrers and referral cycles are rejected
+    AlreadyReferred,
+    CommentNotFound,
+    InvalidComment,            // Empty or longer than MAX_COMMENT_LEN
+    MissingResolutionEvidence, // Empty or longer than MAX_RESOLUTION_EVIDENCE_LEN
+    CreatorNotAllowed,         // Creator allowlist mode is on and the caller is not listed
+    TradeSizeLimitExceeded {
+        limit: u64, // ICP per trade, in e8s
+    },
+    PositionLimitExceeded {
+        limit: u64, // Tokens of one side per user per market
+    },
+    PoolSizeLimitExceeded {
+        limit: u64, // ICP in one market's liquidity pool, in e8s
+    },
+    Expired, // The trade's deadline or commitment window passed before it executed
+    CommitRevealRequired, // The market only accepts trades through commit_trade and reveal_trade
+    InvalidCommitment, // Not a SHA-256 hash, or the salt is shorter than MIN_COMMIT_SALT_LEN
+    CommitmentNotFound,
+    RevealTooEarly,    // A commitment can only be revealed in a later round
+    Busy,              // Another call holds the market or user lock; retry once it completes
+    InvalidStateChunk, // Out of order, from another export, or the canister already holds state
+    RoutedWorker,      // This canister is a shard; create markets through its router
+    InvalidInput {
+        field: String,  // e.g. "title" or "metadata.image_url"
+        reason: String, // What the value must look like
+    },
+    MarketAlreadyTraded, // The market's details are final once it has traded
+    ActionNotFound,
+    TimelockPending {
+        executable_at: u64, // When the scheduled action may run
+    },
+    QuorumNotMet {
+        approvals: u64,
+        threshold: u64,
+    },
+    AlreadyApproved,
+}
 
-    Ok(analysis)
+// State management using thread-local storage
+thread_local! {
+    static MARKETS: RefCell<HashMap<u64, AmmMarket>> = RefCell::new(HashMap::new());
+    static USER_POSITIONS: RefCell<HashMap<(Principal, u64), UserPosition>> = RefCell::new(HashMap::new());
+    static REWARD_CLAIMS: RefCell<Vec<RewardClaim>> = const { RefCell::new(Vec::new()) };
+    static NEXT_MARKET_ID: RefCell<u64> = const { RefCell::new(1) };
+    static ADMIN: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static PENDING_ADMIN: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static PAUSED: RefCell<bool> = const { RefCell::new(false) };
+    static QUESTION_GATE: RefCell<bool> = const { RefCell::new(false) }; // Heuristic question review in create_market
+    static RESOLUTION_DRAFTS: RefCell<HashMap<u64, ResolutionDraft>> = RefCell::new(HashMap::new()); // Latest draft per market
+    static COMMENTS: RefCell<HashMap<u64, BTreeMap<u64, Comment>>> = RefCell::new(HashMap::new()); // market_id -> comment_id -> comment
+    static NEXT_COMMENT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static MODERATORS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    static CREATOR_ALLOWLIST_MODE: RefCell<bool> = const { RefCell::new(false) };
+    static ALLOWED_CREATORS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    static CREATION_FEE: RefCell<u64> = const { RefCell::new(0) }; // e8s, paid to the treasury
+    static DYNAMIC_FEE: RefCell<Option<DynamicFee>> = const { RefCell::new(None) };
+    static FEE_TIERS: RefCell<Vec<FeeTier>> = const { RefCell::new(Vec::new()) }; // Ascending min_volume
+    static USER_VOLUME: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new()); // Cumulative e8s traded
+    static REFERRERS: RefCell<HashMap<Principal, Principal>> = RefCell::new(HashMap::new()); // referred -> referrer
+    static REFERRAL_ACCOUNTS: RefCell<HashMap<Principal, ReferralAccount>> = RefCell::new(HashMap::new());
+    static USER_BALANCES: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static LP_ACCOUNTS: RefCell<HashMap<(Principal, u64), LpAccount>> = RefCell::new(HashMap::new());
+    static SWAP_OFFERS: RefCell<HashMap<u64, SwapOffer>> = RefCell::new(HashMap::new());
+    static NEXT_OFFER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static LIMIT_ORDERS: RefCell<HashMap<u64, LimitOrder>> = RefCell::new(HashMap::new());
+    static NEXT_ORDER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static PRICE_TRIGGERS: RefCell<HashMap<u64, PriceTrigger>> = RefCell::new(HashMap::new());
+    static NEXT_TRIGGER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static AIRDROP_CAMPAIGNS: RefCell<HashMap<u64, AirdropCampaign>> = RefCell::new(HashMap::new());
+    static AIRDROP_RECORDS: RefCell<Vec<AirdropRecord>> = const { RefCell::new(Vec::new()) };
+    static NEXT_AIRDROP_ID: RefCell<u64> = const { RefCell::new(1) };
+    static AIRDROP_QUORUM: RefCell<ApprovalQuorum> = const { RefCell::new(ApprovalQuorum { approvers: Vec::new(), threshold: 0 }) };
+    static PLATFORM_PHASE: RefCell<PlatformPhase> = const { RefCell::new(PlatformPhase::Active) };
+    static TREASURY_BALANCE: RefCell<u64> = const { RefCell::new(0) };
+    static USER_REGIONS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+    static TRADE_HISTORY: RefCell<HashMap<u64, Vec<TradeRecord>>> = RefCell::new(HashMap::new()); // market_id -> trades, oldest first
+    static NEXT_TRADE_ID: RefCell<u64> = const { RefCell::new(1) };
+    static USER_TRADE_INDEX: RefCell<HashMap<Principal, Vec<(u64, usize)>>> = RefCell::new(HashMap::new()); // trader -> (market_id, index in TRADE_HISTORY)
+    static POSITION_HISTORY: RefCell<HashMap<(Principal, u64), Vec<PositionSnapshot>>> = RefCell::new(HashMap::new());
+    static NET_CONTRIBUTIONS: RefCell<HashMap<(Principal, u64), u64>> = RefCell::new(HashMap::new()); // (user, market_id) -> ICP put in minus ICP taken out
+    static REALIZED_PNL: RefCell<HashMap<(Principal, u64), MarketPnl>> = RefCell::new(HashMap::new());
+    static FORECASTS: RefCell<HashMap<Principal, BTreeMap<u64, f64>>> = RefCell::new(HashMap::new()); // user -> market_id -> YES probability at resolution
+    static OUTCOME_TRANSFERS: RefCell<Vec<OutcomeTransfer>> = const { RefCell::new(Vec::new()) }; // Index is the block index
+    static POSITION_RECEIPTS: RefCell<BTreeMap<u64, PositionReceipt>> = const { RefCell::new(BTreeMap::new()) }; // ICRC-7 token id -> receipt
+    static NEXT_RECEIPT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static DISPUTE_CONFIG: RefCell<DisputeConfig> = const { RefCell::new(DisputeConfig {
+        window_ns: DEFAULT_DISPUTE_WINDOW_NS,
+        bond: DEFAULT_DISPUTE_BOND,
+    }) };
+    static DISPUTE_WINDOWS: RefCell<HashMap<u64, DisputeWindow>> = RefCell::new(HashMap::new());
+    static CREATOR_BOND_CONFIG: RefCell<CreatorBondConfig> = const { RefCell::new(CreatorBondConfig {
+        amount: 0,
+        slash_bps: DEFAULT_CREATOR_BOND_SLASH_BPS,
+    }) };
+    static CREATOR_BONDS: RefCell<BTreeMap<u64, CreatorBond>> = const { RefCell::new(BTreeMap::new()) };
+    static RESOLUTION_VOTES: RefCell<HashMap<u64, BTreeMap<Principal, ResolutionVote>>> = RefCell::new(HashMap::new());
+    static VOTE_TALLIES: RefCell<HashMap<u64, VoteTally>> = RefCell::new(HashMap::new());
+    static HOURLY_CANDLES: RefCell<HashMap<u64, BTreeMap<u64, Candle>>> = RefCell::new(HashMap::new()); // market_id -> start -> candle
+    static DAILY_CANDLES: RefCell<HashMap<u64, BTreeMap<u64, Candle>>> = RefCell::new(HashMap::new());
+    static PRICE_OBSERVATIONS: RefCell<HashMap<u64, Vec<(u64, f64)>>> = RefCell::new(HashMap::new()); // market_id -> (timestamp, YES price), oldest first
+    static ROLLING_STATS: RefCell<HashMap<u64, RollingStats>> = RefCell::new(HashMap::new());
+    static PLATFORM_TOTALS: RefCell<PlatformTotals> = RefCell::new(PlatformTotals::default());
+    static LAST_TRADED_AT: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static BALANCE_ACTIVITY: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static AUTO_WITHDRAW_DAYS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static READ_TOKENS: RefCell<HashMap<String, ReadToken>> = RefCell::new(HashMap::new()); // secret -> token
+    static NEXT_READ_TOKEN_ID: RefCell<u64> = const { RefCell::new(1) };
+    static TRADING_LIMITS: RefCell<TradingLimits> = const { RefCell::new(TradingLimits {
+        min_trade_size: DEFAULT_MIN_TRADE_SIZE,
+        min_trade_fee: DEFAULT_MIN_TRADE_FEE,
+    }) };
+    static EXPOSURE_LIMITS: RefCell<ExposureLimits> = const { RefCell::new(ExposureLimits {
+        max_trade_icp: None,
+        max_position_tokens: None,
+        max_pool_size: None,
+    }) };
+    static TEXT_LIMITS: RefCell<TextLimits> = const { RefCell::new(TextLimits {
+        min_title_len: 1,
+        max_title_len: DEFAULT_MAX_TITLE_LEN,
+        min_description_len: 1,
+        max_description_len: DEFAULT_MAX_DESCRIPTION_LEN,
+    }) };
+    static CATEGORIES: RefCell<BTreeMap<String, Category>> = const { RefCell::new(BTreeMap::new()) }; // slug -> category
+    static FEATURED_MARKETS: RefCell<Vec<FeaturedMarket>> = const { RefCell::new(Vec::new()) }; // In display order
+    static PENDING_RESOLVERS: RefCell<BTreeMap<u64, Principal>> = const { RefCell::new(BTreeMap::new()) }; // market_id -> proposed resolver
+    static ABANDONED_MARKETS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) }; // Markets whose resolver stepped down
+    static PENDING_ACTIONS: RefCell<BTreeMap<u64, PendingAction>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_ACTION_ID: RefCell<u64> = const { RefCell::new(1) };
+    static COMMIT_REVEAL_MARKETS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+    static TRADE_COMMITMENTS: RefCell<HashMap<Vec<u8>, TradeCommitment>> = RefCell::new(HashMap::new()); // hash -> commitment
+    static OPERATION_LOCKS: RefCell<BTreeSet<LockKey>> = const { RefCell::new(BTreeSet::new()) }; // Held only while a call runs, never persisted
+    static STATE_IMPORT: RefCell<Option<StateImport>> = const { RefCell::new(None) };
+    static SHARD_CONFIG: RefCell<ShardConfig> = const { RefCell::new(ShardConfig {
+        workers: Vec::new(),
+        local_market_limit: None,
+    }) };
+    static MARKET_SHARDS: RefCell<BTreeMap<u64, Principal>> = const { RefCell::new(BTreeMap::new()) }; // market_id -> worker holding it
+    static ROUTER: RefCell<Option<Principal>> = const { RefCell::new(None) }; // Set on workers: the only canister that may create markets here
+    static FEE_HOLIDAYS: RefCell<BTreeMap<u64, FeeHoliday>> = const { RefCell::new(BTreeMap::new()) };
+    static LIQUIDITY_MATCHING: RefCell<LiquidityMatching> = const { RefCell::new(LiquidityMatching {
+        categories: Vec::new(),
+        match_bps: 10_000,
+        max_match: DEFAULT_LIQUIDITY_MATCH_CAP,
+    }) };
+    static EDGE_POLICIES: RefCell<EdgePolicies> = const { RefCell::new(EdgePolicies {
+        untraded_at_close: EdgePolicy::Refund,
+        no_winning_supply: EdgePolicy::Refund,
+        extension_ns: DEFAULT_CLOSE_EXTENSION_NS,
+    }) };
+    static TREASURY_LP_ACCOUNTS: RefCell<HashMap<u64, LpAccount>> = RefCell::new(HashMap::new()); // market_id -> matched position
+    static LIFECYCLE_HOOKS: RefCell<HashMap<u64, LifecycleHook>> = RefCell::new(HashMap::new());
+    static LIFECYCLE_OUTBOX: RefCell<Vec<(CallbackTarget, LifecycleNotification)>> = const { RefCell::new(Vec::new()) };
+    static WEBHOOK_RETRIES: RefCell<Vec<WebhookDelivery>> = const { RefCell::new(Vec::new()) };
+    static WEBHOOK_SECRETS: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new()); // market_id -> HMAC key
+    static WEBHOOK_ALLOWLIST: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) }; // Empty allows every host
+    static MARKET_ORACLES: RefCell<BTreeMap<u64, OracleStatus>> = const { RefCell::new(BTreeMap::new()) };
+    static PRICE_FEEDS: RefCell<BTreeMap<u64, PriceFeed>> = const { RefCell::new(BTreeMap::new()) };
+    static LAST_UPGRADE: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+    static CERTIFIED_DOCUMENTS: RefCell<BTreeMap<String, CertifiedDocument>> = const { RefCell::new(BTreeMap::new()) };
+    static COST_PROFILE: RefCell<HashMap<CostOperation, (u64, u64)>> = RefCell::new(HashMap::new()); // (samples, total instructions)
 }
 
 // =============================================================================
-// LEGACY FUNCTIONS (FOR COMPATIBILITY)
+// INITIALIZATION
 // =============================================================================
 
-thread_local! {
-    static COUNTER: RefCell<u64> = const { RefCell::new(0) };
+/// Initialize the canister with the deployer as admin
+#[ic_cdk::init]
+fn init() {
+    let deployer = caller();
+    ADMIN.with(|admin| {
+        *admin.borrow_mut() = Some(deployer);
+    });
+    LAST_UPGRADE.with(|last| *last.borrow_mut() = Some(ic_cdk::api::time()));
 }
 
-#[ic_cdk::query]
-fn greet(name: String) -> String {
-    format!("Hello, {}! Welcome to the AMM Prediction Market!", name)
+/// Save the state, tagged with its layout version, for the next code to restore
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let encoded = candid::encode_one(capture_state())
+        .unwrap_or_else(|error| ic_cdk::trap(&format!("Failed to encode state: {}", error)));
+    ic_cdk::storage::stable_save((STATE_VERSION, encoded))
+        .unwrap_or_else(|error| ic_cdk::trap(&format!("Failed to save state: {}", error)));
 }
 
-#[ic_cdk::update]
-fn increment() -> u64 {
-    COUNTER.with(|counter| {
-        let val = *counter.borrow() + 1;
-        *counter.borrow_mut() = val;
-        val
-    })
+/// Restore the saved state, migrating older layouts first
+/// A trap here rolls the upgrade back, leaving the previous code and state running
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let now = ic_cdk::api::time();
+    // Releases before the first STATE_VERSION kept nothing in stable memory
+    if ic_cdk::api::stable::stable_size() > 0 {
+        let (version, encoded): (u32, Vec<u8>) = ic_cdk::storage::stable_restore()
+            .unwrap_or_else(|error| ic_cdk::trap(&format!("Failed to read state: {}", error)));
+        let snapshot = migrate_state(version, encoded).unwrap_or_else(|error| ic_cdk::trap(&error));
+        restore_state(snapshot, now);
+    }
+    LAST_UPGRADE.with(|last| *last.borrow_mut() = Some(now));
+    schedule_global_timer();
 }
 
-#[ic_cdk::query]
-fn get_count() -> u64 {
-    COUNTER.with(|counter| *counter.borrow())
+/// Drop ingress update calls from the anonymous principal before they reach consensus
+/// Every update endpoint also carries the `reject_anonymous` guard, since calls from
+/// other canisters skip this hook
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if caller() != Principal::anonymous() {
+        ic_cdk::api::call::accept_message();
+    }
 }
 
-#[ic_cdk::update]
-fn set_count(value: u64) -> u64 {
-    COUNTER.with(|counter| {
-        *counter.borrow_mut() = value;
-        value
-    })
+/// Anonymous callers could hold balances and positions nobody controls
+fn reject_anonymous() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        Err("The anonymous principal cannot call update methods".to_string())
+    } else {
+        Ok(())
+    }
 }
 
 // =============================================================================
-// UNIT TESTS FOR AMM PREDICTION MARKET
+// OPERATION LOCKS
 // =============================================================================
+// A call that awaits another canister lets other messages run in between. Trades and
+// claims take a lock on the market and the user they touch, so a second call on either
+// fails with Busy instead of interleaving. Locks are released when the guard drops: on
+// return, and on a trap after an await, where ic-cdk drops the call's locals during
+// cleanup. A trap before the first await rolls the lock back with the rest of the state.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LockKey {
+    Market(u64),
+    User(Principal),
+}
 
-    // Helper function to create a test principal
-    fn test_principal(n: u8) -> Principal {
-        Principal::from_slice(&[n; 29])
-    }
+/// Holds its keys until dropped
+struct OperationGuard {
+    keys: Vec<LockKey>,
+}
 
-    // Helper function to reset state between tests
-    fn reset_state() {
-        MARKETS.with(|m| m.borrow_mut().clear());
-        USER_POSITIONS.with(|p| p.borrow_mut().clear());
-        USER_BALANCES.with(|b| b.borrow_mut().clear());
-        REWARD_CLAIMS.with(|c| c.borrow_mut().clear());
-        NEXT_MARKET_ID.with(|id| *id.borrow_mut() = 1);
-        ADMIN.with(|a| *a.borrow_mut() = None);
+impl OperationGuard {
+    /// Take every key or none of them
+    fn acquire(keys: Vec<LockKey>) -> Result<Self, PredictionMarketError> {
+        OPERATION_LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            if keys.iter().any(|key| locks.contains(key)) {
+                return Err(PredictionMarketError::Busy);
+            }
+            locks.extend(keys.iter().cloned());
+            Ok(OperationGuard { keys })
+        })
+    }
+
+    /// Lock a market and a user for a trade or claim
+    fn market_and_user(market_id: u64, user: Principal) -> Result<Self, PredictionMarketError> {
+        Self::acquire(vec![LockKey::Market(market_id), LockKey::User(user)])
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        OPERATION_LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            for key in &self.keys {
+                locks.remove(key);
+            }
+        });
+    }
+}
+
+// =============================================================================
+// AMM CORE FUNCTIONS
+// =============================================================================
+
+/// Calculate the current price of YES or NO tokens using the constant product formula
+/// Price = opposite_reserve / (target_reserve + opposite_reserve)
+/// This gives us the marginal price for the next infinitesimal trade
+#[ic_cdk::query]
+fn get_token_price(market_id: u64, token_type: TokenType) -> Result<f64, PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        match markets_map.get(&market_id) {
+            Some(market) => {
+                // Price calculation: P(YES) = NO_reserve / total_tokens
+                // This represents the marginal cost of the next YES token
+                let (yes_price, no_price) = market_prices(market);
+                Ok(match token_type {
+                    TokenType::Yes => yes_price,
+                    TokenType::No => no_price,
+                })
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })
+}
+
+/// Calculate how many tokens you would receive for a given ICP amount
+/// Uses the constant product formula: x * y = k
+/// When buying YES tokens: new_yes_reserve = yes_reserve + tokens_out
+/// new_no_reserve * new_yes_reserve = k (constant)
+/// Therefore: new_no_reserve = k / new_yes_reserve
+/// ICP_in = no_reserve - new_no_reserve
+fn calculate_tokens_out(
+    yes_reserve: u64,
+    no_reserve: u64,
+    icp_in: u64,
+    buy_yes: bool,
+) -> Result<u64, PredictionMarketError> {
+    // Apply trading fee: actual_icp_in = icp_in - max(0.3% of icp_in, flat fee floor)
+    calculate_tokens_out_after_fee(yes_reserve, no_reserve, icp_in, trade_fee(icp_in), buy_yes)
+}
+
+/// `calculate_tokens_out` with the fee already decided, e.g. waived by a fee holiday
+fn calculate_tokens_out_after_fee(
+    yes_reserve: u64,
+    no_reserve: u64,
+    icp_in: u64,
+    fee: u64,
+    buy_yes: bool,
+) -> Result<u64, PredictionMarketError> {
+    if yes_reserve == 0 || no_reserve == 0 {
+        return Err(PredictionMarketError::InsufficientLiquidity { max_trade: 0 });
+    }
+    check_min_trade_size(icp_in)?;
+
+    let icp_after_fee = icp_in.saturating_sub(fee);
+
+    if buy_yes {
+        // Buying YES tokens reduces NO reserve
+        // k = yes_reserve * no_reserve
+        // new_no_reserve = no_reserve - icp_after_fee
+        // new_yes_reserve = k / new_no_reserve
+        // tokens_out = new_yes_reserve - yes_reserve
+
+        if icp_after_fee >= no_reserve {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: no_reserve - 1,
+            });
+        }
+
+        let new_no_reserve = no_reserve - icp_after_fee;
+        let new_yes_reserve = constant_product_reserve(yes_reserve, no_reserve, new_no_reserve)?;
+
+        if new_yes_reserve <= yes_reserve {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+
+        Ok(new_yes_reserve - yes_reserve)
+    } else {
+        // Buying NO tokens reduces YES reserve
+        if icp_after_fee >= yes_reserve {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: yes_reserve - 1,
+            });
+        }
+
+        let new_yes_reserve = yes_reserve - icp_after_fee;
+        let new_no_reserve = constant_product_reserve(yes_reserve, no_reserve, new_yes_reserve)?;
+
+        if new_no_reserve <= no_reserve {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+
+        Ok(new_no_reserve - no_reserve)
+    }
+}
+
+/// Calculate how much ICP the AMM releases for tokens sold back to it, before the trading fee
+/// When selling YES tokens: new_yes_reserve = yes_reserve - tokens_in
+/// new_no_reserve = k / new_yes_reserve
+/// ICP_out = new_no_reserve - no_reserve
+fn calculate_icp_out(
+    yes_reserve: u64,
+    no_reserve: u64,
+    tokens_in: u64,
+    sell_yes: bool,
+) -> Result<u64, PredictionMarketError> {
+    if yes_reserve == 0 || no_reserve == 0 {
+        return Err(PredictionMarketError::InsufficientLiquidity { max_trade: 0 });
+    }
+
+    if sell_yes {
+        // Selling YES tokens increases NO reserve
+        if tokens_in >= yes_reserve {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+
+        let new_yes_reserve = yes_reserve - tokens_in;
+        if new_yes_reserve == 0 {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: yes_reserve - 1,
+            });
+        }
+
+        let new_no_reserve = constant_product_reserve(yes_reserve, no_reserve, new_yes_reserve)?;
+        Ok(new_no_reserve - no_reserve)
+    } else {
+        // Selling NO tokens increases YES reserve
+        if tokens_in >= no_reserve {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+
+        let new_no_reserve = no_reserve - tokens_in;
+        if new_no_reserve == 0 {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: no_reserve - 1,
+            });
+        }
+
+        let new_yes_reserve = constant_product_reserve(yes_reserve, no_reserve, new_no_reserve)?;
+        Ok(new_yes_reserve - yes_reserve)
+    }
+}
+
+/// Smallest ICP amount whose buy yields at least `tokens_wanted`, the inverse of `calculate_tokens_out`
+/// `fee_of` gives the fee charged on an amount, so fee holidays carry over
+fn calculate_icp_in(
+    yes_reserve: u64,
+    no_reserve: u64,
+    tokens_wanted: u64,
+    buy_yes: bool,
+    fee_of: impl Fn(u64) -> u64,
+) -> Result<u64, PredictionMarketError> {
+    if yes_reserve == 0 || no_reserve == 0 {
+        return Err(PredictionMarketError::InsufficientLiquidity { max_trade: 0 });
+    }
+    if tokens_wanted == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    // Buying YES moves ICP into the NO side and takes tokens out of the YES side
+    let (reserve_in, reserve_out) = if buy_yes {
+        (no_reserve, yes_reserve)
+    } else {
+        (yes_reserve, no_reserve)
+    };
+    // floor(k / (reserve_in - a)) >= reserve_out + tokens_wanted  <=>  a >= reserve_in - floor(k / target)
+    let target_out = add_or_overflow(reserve_out, tokens_wanted)?;
+    let k = yes_reserve as u128 * no_reserve as u128;
+    let max_reserve_in = (k / target_out as u128) as u64; // Below reserve_in, since target_out > reserve_out
+    if max_reserve_in == 0 {
+        return Err(PredictionMarketError::InsufficientLiquidity {
+            max_trade: (k.min(u64::MAX as u128) as u64).saturating_sub(reserve_out),
+        });
+    }
+    let icp_after_fee = reserve_in - max_reserve_in;
+
+    // Least x with x - fee(x) >= icp_after_fee; the fee is monotone, so iterating from below finds it
+    let mut icp_in = icp_after_fee;
+    loop {
+        let next = add_or_overflow(icp_after_fee, fee_of(icp_in))?;
+        if next == icp_in {
+            break;
+        }
+        icp_in = next;
+    }
+    let min_trade_size = TRADING_LIMITS.with(|limits| limits.borrow().min_trade_size);
+    Ok(icp_in.max(min_trade_size))
+}
+
+/// Largest amount in `0..=upper` accepted by `fits`, which must hold up to some amount and fail past it
+/// Returns 0 when nothing fits
+fn largest_fitting(upper: u64, fits: impl Fn(u64) -> bool) -> u64 {
+    let (mut low, mut high) = (0, upper);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// Most tokens of one side a market can buy back without paying out more ICP than its pool holds
+fn max_tokens_sellable(market: &AmmMarket, sell_yes: bool) -> u64 {
+    let reserve = if sell_yes {
+        market.yes_reserve
+    } else {
+        market.no_reserve
+    };
+    largest_fitting(reserve.saturating_sub(1), |tokens| {
+        calculate_icp_out(market.yes_reserve, market.no_reserve, tokens, sell_yes)
+            .is_ok_and(|icp_out| icp_out <= market.icp_liquidity_pool)
+    })
+}
+
+/// Reserve on the other side that keeps yes * no = k once one side becomes `new_reserve`
+/// k is formed in 128 bits, so it cannot overflow; only a result beyond u64 is an error
+fn constant_product_reserve(
+    yes_reserve: u64,
+    no_reserve: u64,
+    new_reserve: u64,
+) -> Result<u64, PredictionMarketError> {
+    let k = yes_reserve as u128 * no_reserve as u128;
+    u64::try_from(k / new_reserve as u128).map_err(|_| PredictionMarketError::Overflow)
+}
+
+fn add_or_overflow(value: u64, amount: u64) -> Result<u64, PredictionMarketError> {
+    value
+        .checked_add(amount)
+        .ok_or(PredictionMarketError::Overflow)
+}
+
+/// Trading fee on an ICP amount: 0.3% rounded up, but never below the flat fee floor
+fn trade_fee(icp_amount: u64) -> u64 {
+    fee_at_rate(icp_amount, TRADE_FEE * 10)
+}
+
+/// Fee at `rate_bps` of an ICP amount, rounded up, but never below the flat fee floor
+fn fee_at_rate(icp_amount: u64, rate_bps: u64) -> u64 {
+    let min_trade_fee = TRADING_LIMITS.with(|limits| limits.borrow().min_trade_fee);
+    ((icp_amount as u128 * rate_bps as u128).div_ceil(10_000) as u64).max(min_trade_fee)
+}
+
+/// Check that buying with `size` ICP and selling the tokens back loses no more than the fees
+/// A sell price above the buy price would be a negative spread, i.e. free ICP for a round trip
+fn check_price_band(
+    yes_reserve: u64,
+    no_reserve: u64,
+    size: u64,
+    token_type: TokenType,
+) -> Result<PriceBandCheck, PredictionMarketError> {
+    let buy_yes = matches!(token_type, TokenType::Yes);
+    let tokens = calculate_tokens_out(yes_reserve, no_reserve, size, buy_yes)?;
+    let buy_fee = trade_fee(size);
+    let (yes_after, no_after) = if buy_yes {
+        (
+            add_or_overflow(yes_reserve, tokens)?,
+            no_reserve - (size - buy_fee),
+        )
+    } else {
+        (
+            yes_reserve - (size - buy_fee),
+            add_or_overflow(no_reserve, tokens)?,
+        )
+    };
+    let gross_back = calculate_icp_out(yes_after, no_after, tokens, buy_yes)?;
+    let sell_fee = trade_fee(gross_back);
+    let icp_back = gross_back.saturating_sub(sell_fee);
+
+    // Flooring k / reserve loses up to (opposite reserve after the buy / reserve) + 1 ICP
+    let rounding = if buy_yes {
+        no_after.div_ceil(yes_reserve) + 1
+    } else {
+        yes_after.div_ceil(no_reserve) + 1
+    };
+    let allowed_loss = buy_fee + sell_fee + rounding;
+
+    Ok(PriceBandCheck {
+        token_type,
+        size,
+        buy_price: size as f64 / tokens as f64,
+        round_trip_price: icp_back as f64 / tokens as f64,
+        allowed_spread: allowed_loss as f64 / size as f64,
+        within_band: icp_back <= size && size - icp_back <= allowed_loss,
+    })
+}
+
+fn check_min_trade_size(icp_amount: u64) -> Result<(), PredictionMarketError> {
+    let min_trade_size = TRADING_LIMITS.with(|limits| limits.borrow().min_trade_size);
+    if icp_amount < min_trade_size {
+        return Err(PredictionMarketError::TradeBelowMinimum);
+    }
+    Ok(())
+}
+
+/// Slippage protection: a fill must give at least what the caller asked for
+fn check_min_out(min_out: u64, actual_out: u64) -> Result<(), PredictionMarketError> {
+    if actual_out < min_out {
+        return Err(PredictionMarketError::SlippageExceeded {
+            min_out,
+            actual_out,
+        });
+    }
+    Ok(())
+}
+
+// =============================================================================
+// MARKET MANAGEMENT FUNCTIONS
+// =============================================================================
+
+/// Create a new prediction market with initial AMM liquidity
+/// Initializes the market with equal reserves of YES and NO tokens
+/// Once this canister holds its share of markets, new ones are created on a worker shard
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[allow(clippy::too_many_arguments)] // Candid arguments; trailing options keep older clients compatible
+async fn create_market(
+    title: String,
+    description: String,
+    initial_icp_liquidity: u64, // e8s
+    close_time: u64,
+    category: Option<String>,
+    resolution_criteria: Option<ResolutionCriteria>,
+    resolution_mode: Option<ResolutionMode>, // AdminOnly when omitted
+    resolver: Option<Principal>,             // Delegate resolution to a neutral party
+    resolution_source: Option<String>,       // Defaults to the criteria's source URL
+    metadata: Option<MarketMetadata>,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_routed_worker()?;
+    let new_market = NewMarket {
+        title,
+        description,
+        initial_icp_liquidity,
+        close_time,
+        category,
+        resolution_criteria,
+        resolution_mode,
+        resolver,
+        resolution_source,
+        metadata,
+    };
+    let now = ic_cdk::api::time();
+    let market_id = match shard_for_new_market() {
+        Some(worker) => create_market_on_shard(caller(), worker, new_market, now).await?,
+        None => process_create_market(caller(), new_market, now)?,
+    };
+    schedule_global_timer();
+    record_instructions(CostOperation::CreateMarket);
+    Ok(market_id)
+}
+
+/// Arguments of `create_market`, shared with the typed market constructors and sent to shards
+#[derive(CandidType, Deserialize, Clone)]
+struct NewMarket {
+    title: String,
+    description: String,
+    initial_icp_liquidity: u64,
+    close_time: u64,
+    category: Option<String>,
+    resolution_criteria: Option<ResolutionCriteria>,
+    resolution_mode: Option<ResolutionMode>,
+    resolver: Option<Principal>,
+    resolution_source: Option<String>,
+    metadata: Option<MarketMetadata>,
+}
+
+fn process_create_market(
+    caller_principal: Principal,
+    new_market: NewMarket,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let NewMarket {
+        title,
+        description,
+        initial_icp_liquidity,
+        close_time,
+        category,
+        resolution_criteria,
+        resolution_mode,
+        resolver,
+        resolution_source,
+        metadata,
+    } = new_market;
+
+    ensure_platform_active()?;
+    ensure_not_paused()?;
+    ensure_creator_allowed(caller_principal)?;
+    let (title, description) = clean_market_text(&title, &description)?;
+    let category = registered_category(category)?;
+
+    if initial_icp_liquidity < MIN_DEPOSIT {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: MIN_DEPOSIT,
+            available: initial_icp_liquidity,
+        });
+    }
+
+    if close_time <= now {
+        return Err(PredictionMarketError::InvalidCloseTime);
+    }
+
+    if let Some(criteria) = &resolution_criteria {
+        validate_resolution_criteria(criteria)?;
+    }
+    ensure_question_gate(&title, &description, resolution_criteria.is_some())?;
+    let resolution_source = resolution_source
+        .or_else(|| {
+            resolution_criteria
+                .as_ref()
+                .map(|criteria| criteria.source_url.clone())
+        })
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    if resolution_source.len() > MAX_CRITERIA_FIELD_LEN {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    // A vote and a designated resolver would compete for the same outcome
+    if resolver.is_some() && resolution_mode == Some(ResolutionMode::CommunityVote) {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    let metadata = metadata.map(normalize_metadata).transpose()?;
+
+    // Check if user has sufficient balance
+    let user_balance =
+        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+
+    let bond = CREATOR_BOND_CONFIG.with(|config| config.borrow().clone());
+    let creation_fee = CREATION_FEE.with(|fee| *fee.borrow());
+    let total_cost = initial_icp_liquidity
+        .saturating_add(bond.amount)
+        .saturating_add(creation_fee);
+    if user_balance < total_cost {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: total_cost,
+            available: user_balance,
+        });
+    }
+
+    let market_id = NEXT_MARKET_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+
+    // Create market with initial AMM reserves
+    // Both reserves start at the seed in e8s: 50/50 pricing, and depth that grows with the seed
+    // Anyone can create a market, creator becomes the market admin
+    let mut market = AmmMarket {
+        id: market_id,
+        title,
+        description,
+        yes_reserve: initial_icp_liquidity,
+        no_reserve: initial_icp_liquidity,
+        icp_liquidity_pool: initial_icp_liquidity,
+        status: MarketStatus::Open,
+        winning_outcome: None,
+        creator: caller_principal,
+        admin: caller_principal, // Creator becomes the market admin
+        total_fees_collected: 0,
+        creation_time: now,
+        close_time,
+        total_lp_shares: initial_icp_liquidity, // Seed liquidity mints shares 1:1
+        acc_fee_per_share: 0,
+        creator_fee_bps: DEFAULT_CREATOR_FEE_BPS,
+        creator_fees_accrued: 0,
+        restricted_regions: Vec::new(),
+        require_attested_region: false,
+        category,
+        early_exit_haircut: default_exit_haircut(),
+        resolution_criteria,
+        resolution: None,
+        close_extensions: 0,
+        resolution_mode: resolution_mode.unwrap_or_default(),
+        resolver,
+        resolution_source,
+        resolution_evidence: None,
+        metadata,
+    };
+
+    let matched = apply_liquidity_match(&mut market);
+
+    // Deduct ICP from creator's balance
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        balances_map.insert(caller_principal, user_balance - total_cost);
+    });
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += creation_fee);
+    if bond.amount > 0 {
+        CREATOR_BONDS.with(|bonds| {
+            bonds.borrow_mut().insert(
+                market_id,
+                CreatorBond {
+                    market_id,
+                    creator: caller_principal,
+                    amount: bond.amount,
+                    slash_bps: bond.slash_bps,
+                    slashed: 0,
+                    released: false,
+                },
+            )
+        });
+    }
+
+    MARKETS.with(|markets| {
+        markets.borrow_mut().insert(market_id, market);
+    });
+
+    LP_ACCOUNTS.with(|accounts| {
+        accounts.borrow_mut().insert(
+            (caller_principal, market_id),
+            LpAccount {
+                shares: initial_icp_liquidity,
+                ..Default::default()
+            },
+        );
+    });
+
+    emit_event(
+        now,
+        EventKind::MarketCreated {
+            market_id,
+            creator: caller_principal,
+        },
+    );
+    if matched > 0 {
+        emit_event(
+            now,
+            EventKind::LiquidityMatched {
+                market_id,
+                icp_amount: matched,
+            },
+        );
+    }
+
+    Ok(market_id)
+}
+
+/// Fix the title, description or close time of a market nobody has traded yet (creator only)
+/// Fields left empty keep their value; once the first trade executes the details are final
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn update_market(
+    market_id: u64,
+    new_title: Option<String>,
+    new_description: Option<String>,
+    new_close_time: Option<u64>,
+) -> Result<String, PredictionMarketError> {
+    process_update_market(
+        caller(),
+        market_id,
+        new_title,
+        new_description,
+        new_close_time,
+        ic_cdk::api::time(),
+    )?;
+    schedule_global_timer();
+    Ok(format!("Market {} updated", market_id))
+}
+
+fn process_update_market(
+    caller_principal: Principal,
+    market_id: u64,
+    new_title: Option<String>,
+    new_description: Option<String>,
+    new_close_time: Option<u64>,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    if market.creator != caller_principal {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if market.status != MarketStatus::Open {
+        return Err(PredictionMarketError::MarketClosed);
+    }
+    if has_traded(market_id) {
+        return Err(PredictionMarketError::MarketAlreadyTraded);
+    }
+    let (title, description) = clean_market_text(
+        new_title.as_deref().unwrap_or(&market.title),
+        new_description.as_deref().unwrap_or(&market.description),
+    )?;
+    ensure_question_gate(&title, &description, market.resolution_criteria.is_some())?;
+    let close_time = new_close_time.unwrap_or(market.close_time);
+    if close_time <= now {
+        return Err(PredictionMarketError::InvalidCloseTime);
+    }
+
+    MARKETS.with(|markets| {
+        if let Some(market) = markets.borrow_mut().get_mut(&market_id) {
+            market.title = title;
+            market.description = description;
+            market.close_time = close_time;
+        }
+    });
+    emit_event(
+        now,
+        EventKind::MarketSettingsChanged {
+            market_id,
+            setting: "details".to_string(),
+        },
+    );
+    Ok(())
+}
+
+/// Whether any trade has executed on a market; seed liquidity is not a trade
+fn has_traded(market_id: u64) -> bool {
+    TRADE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .get(&market_id)
+            .is_some_and(|trades| !trades.is_empty())
+    })
+}
+
+/// Buy or sell `token_type` tokens against the AMM
+/// `amount` is ICP to spend on a buy and tokens to sell on a sell; `min_out` is the slippage
+/// protection, tokens for a buy and ICP for a sell
+/// `max_slippage_bps` bounds the fill relative to the spot price at execution; the stricter
+/// of it and `min_out` applies
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn trade(
+    market_id: u64,
+    side: OrderSide,
+    token_type: TokenType,
+    amount: u64,
+    min_out: u64,
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+    max_slippage_bps: Option<u64>, // e.g. 100 for at most 1% worse than the spot price
+) -> Result<TradeResult, PredictionMarketError> {
+    check_trade_deadline(deadline, ic_cdk::api::time())?;
+    ensure_direct_trading(market_id)?;
+    let caller_principal = caller();
+    let _guard = OperationGuard::market_and_user(market_id, caller_principal)?;
+    let min_out = match max_slippage_bps {
+        Some(bps) => min_out.max(slippage_floor(market_id, &side, &token_type, amount, bps)?),
+        None => min_out,
+    };
+    let result = execute_trade(
+        caller_principal,
+        market_id,
+        side,
+        token_type,
+        amount,
+        min_out,
+    )?;
+    match_limit_orders(market_id);
+    Ok(result)
+}
+
+/// Buy YES tokens using ICP, same as `trade` with a YES buy
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn buy_yes_tokens(
+    market_id: u64,
+    icp_amount: u64,       // e8s
+    min_tokens_out: u64,   // Slippage protection
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+) -> Result<TradeResult, PredictionMarketError> {
+    trade(
+        market_id,
+        OrderSide::Buy,
+        TokenType::Yes,
+        icp_amount,
+        min_tokens_out,
+        deadline,
+        None,
+    )
+}
+
+/// Buy NO tokens using ICP, same as `trade` with a NO buy
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn buy_no_tokens(
+    market_id: u64,
+    icp_amount: u64,       // e8s
+    min_tokens_out: u64,   // Slippage protection
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+) -> Result<TradeResult, PredictionMarketError> {
+    trade(
+        market_id,
+        OrderSide::Buy,
+        TokenType::No,
+        icp_amount,
+        min_tokens_out,
+        deadline,
+        None,
+    )
+}
+
+/// Buy exactly `tokens_wanted` tokens (or the nearest amount above), spending at most `max_icp_in`
+/// The required ICP is worked out from current reserves, as in `get_buy_quote_exact_out`
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn buy_exact_tokens(
+    market_id: u64,
+    tokens_wanted: u64,
+    token_type: TokenType,
+    max_icp_in: u64,       // Slippage protection, in e8s
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+) -> Result<TradeResult, PredictionMarketError> {
+    let now = ic_cdk::api::time();
+    check_trade_deadline(deadline, now)?;
+    ensure_direct_trading(market_id)?;
+    let caller_principal = caller();
+    let _guard = OperationGuard::market_and_user(market_id, caller_principal)?;
+    let icp_amount = required_icp_in(market_id, tokens_wanted, &token_type, now)?;
+    if icp_amount > max_icp_in {
+        return Err(PredictionMarketError::MaxCostExceeded {
+            max_in: max_icp_in,
+            actual_in: icp_amount,
+        });
+    }
+    let result = execute_buy_trade(
+        caller_principal,
+        market_id,
+        icp_amount,
+        tokens_wanted,
+        token_type,
+    )?;
+    match_limit_orders(market_id);
+    Ok(result)
+}
+
+/// Sell YES tokens back to the AMM for ICP, same as `trade` with a YES sell
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn sell_yes_tokens(
+    market_id: u64,
+    token_amount: u64,
+    min_icp_out: u64,      // Slippage protection
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+) -> Result<TradeResult, PredictionMarketError> {
+    trade(
+        market_id,
+        OrderSide::Sell,
+        TokenType::Yes,
+        token_amount,
+        min_icp_out,
+        deadline,
+        None,
+    )
+}
+
+/// Sell NO tokens back to the AMM for ICP, same as `trade` with a NO sell
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn sell_no_tokens(
+    market_id: u64,
+    token_amount: u64,
+    min_icp_out: u64,      // Slippage protection
+    deadline: Option<u64>, // Nanoseconds; the trade is rejected once it has passed
+) -> Result<TradeResult, PredictionMarketError> {
+    trade(
+        market_id,
+        OrderSide::Sell,
+        TokenType::No,
+        token_amount,
+        min_icp_out,
+        deadline,
+        None,
+    )
+}
+
+// =============================================================================
+// INTERNAL TRADING FUNCTIONS
+// =============================================================================
+
+/// Run a buy or a sell for `trader`; `amount` and `min_out` are read as in `trade`
+fn execute_trade(
+    trader: Principal,
+    market_id: u64,
+    side: OrderSide,
+    token_type: TokenType,
+    amount: u64,
+    min_out: u64,
+) -> Result<TradeResult, PredictionMarketError> {
+    match side {
+        OrderSide::Buy => execute_buy_trade(trader, market_id, amount, min_out, token_type),
+        OrderSide::Sell => execute_sell_trade(trader, market_id, amount, min_out, token_type),
+    }
+}
+
+fn position_of(user: Principal, market_id: u64) -> Option<UserPosition> {
+    USER_POSITIONS.with(|positions| positions.borrow().get(&(user, market_id)).cloned())
+}
+
+/// Slow wallets can submit a signed trade long after it was priced; min-out alone
+/// would still let it fill at a stale price
+fn check_trade_deadline(deadline: Option<u64>, now: u64) -> Result<(), PredictionMarketError> {
+    match deadline {
+        Some(deadline) if now > deadline => Err(PredictionMarketError::Expired),
+        _ => Ok(()),
+    }
+}
+
+/// Least a trade of `amount` may return to stay within `max_slippage_bps` of the spot price:
+/// tokens for a buy, ICP for a sell. Fees count toward the slippage
+fn slippage_floor(
+    market_id: u64,
+    side: &OrderSide,
+    token_type: &TokenType,
+    amount: u64,
+    max_slippage_bps: u64,
+) -> Result<u64, PredictionMarketError> {
+    if max_slippage_bps > 10_000 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    let spot_price = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| price_after(market.yes_reserve, market.no_reserve, token_type))
+            .ok_or(PredictionMarketError::MarketNotFound)
+    })?;
+    let at_spot = match side {
+        OrderSide::Buy if spot_price > 0.0 => amount as f64 / spot_price,
+        OrderSide::Buy => return Ok(0),
+        OrderSide::Sell => amount as f64 * spot_price,
+    };
+    Ok((at_spot * (10_000 - max_slippage_bps) as f64 / 10_000.0) as u64)
+}
+
+fn execute_buy_trade(
+    caller_principal: Principal,
+    market_id: u64,
+    icp_amount: u64,
+    min_tokens_out: u64,
+    token_type: TokenType,
+) -> Result<TradeResult, PredictionMarketError> {
+    ensure_not_paused()?;
+    if icp_amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    // Check user balance
+    let user_balance =
+        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+
+    if user_balance < icp_amount {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: icp_amount,
+            available: user_balance,
+        });
+    }
+    let limits = EXPOSURE_LIMITS.with(|limits| limits.borrow().clone());
+    check_trade_size_limit(&limits, icp_amount)?;
+    let held_tokens = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .map_or(0, |position| match token_type {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            })
+    });
+
+    // Get market and verify it's open
+    let now = ic_cdk::api::time();
+    let (tokens_out, fee_paid, fee_holiday_until, price_before) = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        match markets_map.get_mut(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+                check_region_allowed(market, caller_principal)?;
+                let price_before = price_after(market.yes_reserve, market.no_reserve, &token_type);
+
+                // Calculate tokens out using AMM formula
+                let impact_bps = buy_impact_bps(market, icp_amount, &token_type);
+                let (fee, holiday) =
+                    market_trade_fee(market, icp_amount, impact_bps, Some(caller_principal), now);
+                let tokens_out = calculate_tokens_out_after_fee(
+                    market.yes_reserve,
+                    market.no_reserve,
+                    icp_amount,
+                    fee,
+                    matches!(token_type, TokenType::Yes),
+                )?;
+                debug_assert!(
+                    check_price_band(
+                        market.yes_reserve,
+                        market.no_reserve,
+                        icp_amount,
+                        token_type.clone()
+                    )
+                    .map_or(true, |check| check.within_band),
+                    "negative or oversized spread on market {}",
+                    market_id
+                );
+
+                check_min_out(min_tokens_out, tokens_out)?;
+
+                let icp_after_fee = icp_amount - fee;
+
+                // Compute every new value before touching the market, so an overflow changes nothing
+                let (yes_reserve, no_reserve) = match token_type {
+                    TokenType::Yes => (
+                        add_or_overflow(market.yes_reserve, tokens_out)?,
+                        market.no_reserve - icp_after_fee,
+                    ),
+                    TokenType::No => (
+                        market.yes_reserve - icp_after_fee,
+                        add_or_overflow(market.no_reserve, tokens_out)?,
+                    ),
+                };
+                let icp_liquidity_pool = add_or_overflow(market.icp_liquidity_pool, icp_after_fee)?;
+                let total_fees_collected = add_or_overflow(market.total_fees_collected, fee)?;
+                check_exposure_limits(
+                    &limits,
+                    held_tokens.saturating_add(tokens_out),
+                    icp_liquidity_pool,
+                )?;
+
+                // Update market reserves based on AMM logic
+                market.yes_reserve = yes_reserve;
+                market.no_reserve = no_reserve;
+
+                // Add ICP (minus fee) to liquidity pool, fee is split between treasury, creator and LPs
+                market.icp_liquidity_pool = icp_liquidity_pool;
+                market.total_fees_collected = total_fees_collected;
+                distribute_trade_fee(market, fee, caller_principal);
+                reimburse_waived_fee(market, holiday.as_ref(), icp_amount);
+
+                Ok((
+                    tokens_out,
+                    fee,
+                    holiday.map(|holiday| holiday.end_time),
+                    price_before,
+                ))
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })?;
+
+    // Update user balance
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        balances_map.insert(caller_principal, user_balance - icp_amount);
+    });
+
+    // Update user position
+    USER_POSITIONS.with(|positions| {
+        let mut positions_map = positions.borrow_mut();
+        let key = (caller_principal, market_id);
+        let position = positions_map.entry(key).or_insert(UserPosition {
+            user: caller_principal,
+            market_id,
+            yes_tokens: 0,
+            no_tokens: 0,
+            claimed_reward: false,
+        });
+
+        match token_type {
+            TokenType::Yes => position.yes_tokens += tokens_out,
+            TokenType::No => position.no_tokens += tokens_out,
+        }
+    });
+
+    // Calculate new price for return value
+    let new_price = get_token_price(market_id, token_type.clone())?;
+    record_trade(TradeRecord {
+        id: next_trade_id(),
+        market_id,
+        trader: caller_principal,
+        side: OrderSide::Buy,
+        token_type: token_type.clone(),
+        icp_amount,
+        token_amount: tokens_out,
+        fee: fee_paid,
+        price_after: new_price,
+        timestamp: now,
+    });
+    touch_balance_activity(caller_principal, now);
+    record_instructions(CostOperation::Buy);
+
+    Ok(TradeResult {
+        tokens_received: tokens_out,
+        tokens_paid: icp_amount,
+        fee_paid,
+        new_price,
+        fee_holiday_until,
+        market_id,
+        token_type,
+        average_price: average_price(icp_amount, tokens_out),
+        price_impact: price_impact_pct(price_before, new_price),
+        timestamp: now,
+        position: position_of(caller_principal, market_id),
+    })
+}
+
+fn execute_sell_trade(
+    caller_principal: Principal,
+    market_id: u64,
+    token_amount: u64,
+    min_icp_out: u64,
+    token_type: TokenType,
+) -> Result<TradeResult, PredictionMarketError> {
+    ensure_not_paused()?;
+    if token_amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    // Check if user has enough tokens
+    let user_tokens = USER_POSITIONS.with(|positions| {
+        let positions_map = positions.borrow();
+        if let Some(position) = positions_map.get(&(caller_principal, market_id)) {
+            match token_type {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            }
+        } else {
+            0
+        }
+    });
+
+    if user_tokens < token_amount {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    // Get market and calculate ICP out
+    let now = ic_cdk::api::time();
+    let (icp_out, fee_paid, fee_holiday_until, price_before) = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        match markets_map.get_mut(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+                let price_before = price_after(market.yes_reserve, market.no_reserve, &token_type);
+
+                let gross_icp_out = calculate_icp_out(
+                    market.yes_reserve,
+                    market.no_reserve,
+                    token_amount,
+                    matches!(token_type, TokenType::Yes),
+                )?;
+                check_min_trade_size(gross_icp_out)?;
+                // A sell may empty the pool but never pay out ICP it does not hold
+                if gross_icp_out > market.icp_liquidity_pool {
+                    return Err(PredictionMarketError::InsufficientLiquidity {
+                        max_trade: max_tokens_sellable(
+                            market,
+                            matches!(token_type, TokenType::Yes),
+                        ),
+                    });
+                }
+
+                // Calculate trading fee
+                let impact_bps = sell_impact_bps(market, token_amount, gross_icp_out, &token_type);
+                let (fee, holiday) = market_trade_fee(
+                    market,
+                    gross_icp_out,
+                    impact_bps,
+                    Some(caller_principal),
+                    now,
+                );
+                let icp_out = gross_icp_out.saturating_sub(fee);
+
+                check_min_out(min_icp_out, icp_out)?;
+
+                // Compute every new value before touching the market, so an overflow changes nothing
+                let (yes_reserve, no_reserve) = match token_type {
+                    TokenType::Yes => (
+                        market.yes_reserve - token_amount,
+                        add_or_overflow(market.no_reserve, gross_icp_out)?,
+                    ),
+                    TokenType::No => (
+                        add_or_overflow(market.yes_reserve, gross_icp_out)?,
+                        market.no_reserve - token_amount,
+                    ),
+                };
+                let total_fees_collected = add_or_overflow(market.total_fees_collected, fee)?;
+
+                // Update market reserves
+                market.yes_reserve = yes_reserve;
+                market.no_reserve = no_reserve;
+
+                // Remove ICP and the fee from the liquidity pool, fee is split between treasury, creator and LPs
+                market.icp_liquidity_pool -= gross_icp_out;
+                market.total_fees_collected = total_fees_collected;
+                distribute_trade_fee(market, fee, caller_principal);
+                reimburse_waived_fee(market, holiday.as_ref(), gross_icp_out);
+
+                Ok((
+                    icp_out,
+                    fee,
+                    holiday.map(|holiday| holiday.end_time),
+                    price_before,
+                ))
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })?;
+
+    // Update user position
+    USER_POSITIONS.with(|positions| {
+        let mut positions_map = positions.borrow_mut();
+        if let Some(position) = positions_map.get_mut(&(caller_principal, market_id)) {
+            match token_type {
+                TokenType::Yes => position.yes_tokens -= token_amount,
+                TokenType::No => position.no_tokens -= token_amount,
+            }
+        }
+    });
+
+    // Add ICP to user balance
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + icp_out);
+    });
+
+    // Calculate new price for return value
+    let new_price = get_token_price(market_id, token_type.clone())?;
+    record_trade(TradeRecord {
+        id: next_trade_id(),
+        market_id,
+        trader: caller_principal,
+        side: OrderSide::Sell,
+        token_type: token_type.clone(),
+        icp_amount: icp_out,
+        token_amount,
+        fee: fee_paid,
+        price_after: new_price,
+        timestamp: now,
+    });
+    touch_balance_activity(caller_principal, now);
+    record_instructions(CostOperation::Sell);
+
+    Ok(TradeResult {
+        tokens_received: icp_out,  // ICP received
+        tokens_paid: token_amount, // Tokens sold
+        fee_paid,
+        new_price,
+        fee_holiday_until,
+        market_id,
+        token_type,
+        average_price: average_price(icp_out, token_amount),
+        price_impact: price_impact_pct(price_before, new_price),
+        timestamp: now,
+        position: position_of(caller_principal, market_id),
+    })
+}
+
+// =============================================================================
+// COMMIT-REVEAL TRADING
+// =============================================================================
+// Front-running-sensitive markets hide each trade until it executes. The trader first
+// commits to a hash of the trade, then reveals it in a later round; the AMM executes
+// reveals in the order they arrive, so a trade is public only once it has filled.
+
+/// Require commit-reveal trading on a market (global admin or market admin)
+/// Open limit orders and triggers on the market keep working until cancelled
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_commit_reveal(market_id: u64, enabled: bool) -> Result<String, PredictionMarketError> {
+    let message = process_set_commit_reveal(caller(), market_id, enabled)?;
+    emit_market_settings_changed(market_id, "commit_reveal", ic_cdk::api::time());
+    Ok(message)
+}
+
+fn process_set_commit_reveal(
+    caller_principal: Principal,
+    market_id: u64,
+    enabled: bool,
+) -> Result<String, PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let markets = markets.borrow();
+        let market = markets
+            .get(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !is_global_admin(caller_principal) && market.admin != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        Ok(())
+    })?;
+    COMMIT_REVEAL_MARKETS.with(|flagged| {
+        let mut flagged = flagged.borrow_mut();
+        if enabled {
+            flagged.insert(market_id);
+        } else {
+            flagged.remove(&market_id);
+        }
+    });
+    Ok(format!(
+        "Commit-reveal trading {} for market {}",
+        if enabled {
+            "required"
+        } else {
+            "no longer required"
+        },
+        market_id
+    ))
+}
+
+/// Commit to a hidden trade; reveal it with `reveal_trade` in a later round
+/// `hash` is SHA-256 over the caller's principal bytes followed by the candid encoding of
+/// `(RevealedTrade, salt)`, with a salt of at least 16 random bytes
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn commit_trade(hash: Vec<u8>) -> Result<TradeCommitment, PredictionMarketError> {
+    process_commit_trade(caller(), hash, ic_cdk::api::time())
+}
+
+fn process_commit_trade(
+    caller_principal: Principal,
+    hash: Vec<u8>,
+    now: u64,
+) -> Result<TradeCommitment, PredictionMarketError> {
+    ensure_not_paused()?;
+    if hash.len() != 32 {
+        return Err(PredictionMarketError::InvalidCommitment);
+    }
+    TRADE_COMMITMENTS.with(|commitments| {
+        let mut commitments = commitments.borrow_mut();
+        commitments.retain(|_, commitment| commitment.expires_at >= now);
+        let open = commitments
+            .values()
+            .filter(|commitment| commitment.trader == caller_principal)
+            .count();
+        if open >= MAX_OPEN_COMMITMENTS || commitments.contains_key(&hash) {
+            return Err(PredictionMarketError::InvalidCommitment);
+        }
+        let commitment = TradeCommitment {
+            hash: hash.clone(),
+            trader: caller_principal,
+            committed_at: now,
+            expires_at: now + COMMIT_REVEAL_WINDOW_NS,
+        };
+        commitments.insert(hash, commitment.clone());
+        Ok(commitment)
+    })
+}
+
+/// Reveal and execute a committed trade; the commitment is used up even if the trade fails
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn reveal_trade(trade: RevealedTrade, salt: Vec<u8>) -> Result<TradeResult, PredictionMarketError> {
+    let market_id = trade.market_id;
+    let caller_principal = caller();
+    let _guard = OperationGuard::market_and_user(market_id, caller_principal)?;
+    let result = process_reveal_trade(caller_principal, trade, salt, ic_cdk::api::time())?;
+    match_limit_orders(market_id);
+    Ok(result)
+}
+
+fn process_reveal_trade(
+    caller_principal: Principal,
+    trade: RevealedTrade,
+    salt: Vec<u8>,
+    now: u64,
+) -> Result<TradeResult, PredictionMarketError> {
+    if salt.len() < MIN_COMMIT_SALT_LEN {
+        return Err(PredictionMarketError::InvalidCommitment);
+    }
+    let hash = trade_commitment_hash(caller_principal, &trade, &salt)?;
+    TRADE_COMMITMENTS.with(|commitments| {
+        let mut commitments = commitments.borrow_mut();
+        let commitment = commitments
+            .get(&hash)
+            .filter(|commitment| commitment.trader == caller_principal)
+            .ok_or(PredictionMarketError::CommitmentNotFound)?;
+        // Messages in the same round share a timestamp
+        if now <= commitment.committed_at {
+            return Err(PredictionMarketError::RevealTooEarly);
+        }
+        let expired = now > commitment.expires_at;
+        commitments.remove(&hash);
+        if expired {
+            return Err(PredictionMarketError::Expired);
+        }
+        Ok(())
+    })?;
+
+    execute_trade(
+        caller_principal,
+        trade.market_id,
+        trade.side,
+        trade.token_type,
+        trade.amount,
+        trade.min_out,
+    )
+}
+
+fn trade_commitment_hash(
+    trader: Principal,
+    trade: &RevealedTrade,
+    salt: &Vec<u8>,
+) -> Result<Vec<u8>, PredictionMarketError> {
+    let encoded = candid::encode_args((trade, salt))
+        .map_err(|error| PredictionMarketError::CallFailed(error.to_string()))?;
+    let mut hasher = Sha256::new();
+    hasher.update(trader.as_slice());
+    hasher.update(&encoded);
+    Ok(hasher.finalize().to_vec())
+}
+
+fn requires_commit_reveal(market_id: u64) -> bool {
+    COMMIT_REVEAL_MARKETS.with(|flagged| flagged.borrow().contains(&market_id))
+}
+
+/// Direct trades and resting orders would disclose trades on a commit-reveal market
+fn ensure_direct_trading(market_id: u64) -> Result<(), PredictionMarketError> {
+    if requires_commit_reveal(market_id) {
+        return Err(PredictionMarketError::CommitRevealRequired);
+    }
+    Ok(())
+}
+
+// =============================================================================
+// COMPLETE SETS
+// =============================================================================
+
+/// Turn `icp_amount` e8s into `icp_amount` YES and `icp_amount` NO tokens, fee-free
+/// One of the two always wins, so a pair is worth one unit whatever the outcome; when the AMM
+/// prices YES + NO away from 1, minting or redeeming and trading the legs closes the gap
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn mint_complete_set(
+    market_id: u64,
+    icp_amount: u64,
+) -> Result<UserPosition, PredictionMarketError> {
+    process_mint_complete_set(caller(), market_id, icp_amount, ic_cdk::api::time())
+}
+
+fn process_mint_complete_set(
+    caller_principal: Principal,
+    market_id: u64,
+    icp_amount: u64,
+    now: u64,
+) -> Result<UserPosition, PredictionMarketError> {
+    ensure_platform_active()?;
+    ensure_not_paused()?;
+    if icp_amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let user_balance =
+        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+    if user_balance < icp_amount {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: icp_amount,
+            available: user_balance,
+        });
+    }
+
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !is_trading_open(market, now) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        check_region_allowed(market, caller_principal)?;
+        // The ICP backs the payout of whichever side wins
+        market.icp_liquidity_pool = add_or_overflow(market.icp_liquidity_pool, icp_amount)?;
+        Ok(())
+    })?;
+
+    USER_BALANCES.with(|balances| {
+        balances
+            .borrow_mut()
+            .insert(caller_principal, user_balance - icp_amount);
+    });
+    let position = USER_POSITIONS.with(|positions| {
+        let mut positions_map = positions.borrow_mut();
+        let position = positions_map
+            .entry((caller_principal, market_id))
+            .or_insert(UserPosition {
+                user: caller_principal,
+                market_id,
+                yes_tokens: 0,
+                no_tokens: 0,
+                claimed_reward: false,
+            });
+        position.yes_tokens += icp_amount;
+        position.no_tokens += icp_amount;
+        position.clone()
+    });
+    adjust_contribution(caller_principal, market_id, icp_amount, 0);
+    record_pnl(caller_principal, market_id, icp_amount, 0, now);
+
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::CompleteSetMinted {
+            market_id,
+            user: caller_principal,
+            amount: icp_amount,
+        },
+    );
+    Ok(position)
+}
+
+/// Burn `amount` YES and `amount` NO tokens for `amount` e8s, fee-free
+/// Available until the market is settled, including after trading closes
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn redeem_complete_set(market_id: u64, amount: u64) -> Result<UserPosition, PredictionMarketError> {
+    process_redeem_complete_set(caller(), market_id, amount, ic_cdk::api::time())
+}
+
+fn process_redeem_complete_set(
+    caller_principal: Principal,
+    market_id: u64,
+    amount: u64,
+    now: u64,
+) -> Result<UserPosition, PredictionMarketError> {
+    ensure_not_paused()?;
+    if amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let held = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .map_or(0, |position| position.yes_tokens.min(position.no_tokens))
+    });
+    if held < amount {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if is_settled(&market.status) {
+            return Err(PredictionMarketError::MarketResolved);
+        }
+        if market.icp_liquidity_pool < amount {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: market.icp_liquidity_pool,
+            });
+        }
+        market.icp_liquidity_pool -= amount;
+        Ok(())
+    })?;
+
+    let position = USER_POSITIONS.with(|positions| {
+        let mut positions_map = positions.borrow_mut();
+        let position = positions_map
+            .get_mut(&(caller_principal, market_id))
+            .expect("position checked above");
+        position.yes_tokens -= amount;
+        position.no_tokens -= amount;
+        position.clone()
+    });
+    adjust_contribution(caller_principal, market_id, 0, amount);
+    record_pnl(caller_principal, market_id, 0, amount, now);
+    USER_BALANCES.with(|balances| {
+        *balances.borrow_mut().entry(caller_principal).or_insert(0) += amount;
+    });
+
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::CompleteSetRedeemed {
+            market_id,
+            user: caller_principal,
+            amount,
+        },
+    );
+    Ok(position)
+}
+
+// =============================================================================
+// EVENT LOG
+// =============================================================================
+
+/// Append an event to the audit trail
+/// Events that touch a market also refresh its certified document
+fn emit_event(now: u64, kind: EventKind) {
+    let market_id = kind.market_id();
+    let lifecycle_stages = lifecycle_stages_of(&kind);
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let id = events.len() as u64 + 1;
+        events.push(Event {
+            id,
+            timestamp: now,
+            kind,
+        });
+    });
+    if let Some(market_id) = market_id {
+        certify_market(market_id, now);
+    }
+    for (market_id, stage, trade_id) in lifecycle_stages {
+        queue_lifecycle_notification(market_id, stage, trade_id, now);
+    }
+}
+
+impl EventKind {
+    fn market_id(&self) -> Option<u64> {
+        match self {
+            EventKind::MarketCreated { market_id, .. }
+            | EventKind::MarketFrozen { market_id }
+            | EventKind::MarketExtended { market_id, .. }
+            | EventKind::DegeneratePoolSettled { market_id, .. }
+            | EventKind::MarketResolved { market_id, .. }
+            | EventKind::MarketInvalidated { market_id, .. }
+            | EventKind::MarketCancelled { market_id, .. }
+            | EventKind::MarketDeleted { market_id, .. }
+            | EventKind::ResolutionDisputed { market_id, .. }
+            | EventKind::DisputeSettled { market_id, .. }
+            | EventKind::CreatorBondSlashed { market_id, .. }
+            | EventKind::CreatorBondReleased { market_id, .. }
+            | EventKind::DisputeWindowClosed { market_id }
+            | EventKind::ResolutionVoteCast { market_id, .. }
+            | EventKind::ResolutionVoteTallied { market_id, .. }
+            | EventKind::MarketSettingsChanged { market_id, .. }
+            | EventKind::ResolverProposed { market_id, .. }
+            | EventKind::ResolverChanged { market_id, .. }
+            | EventKind::ResolutionProposed { market_id, .. }
+            | EventKind::ResolutionChallenged { market_id, .. }
+            | EventKind::ResolutionDrafted { market_id, .. }
+            | EventKind::MarketOracleChanged { market_id, .. }
+            | EventKind::CommentPosted { market_id, .. }
+            | EventKind::CommentDeleted { market_id, .. }
+            | EventKind::ResolutionDraftReviewed { market_id, .. }
+            | EventKind::TradeExecuted { market_id, .. }
+            | EventKind::OutcomeTokensTransferred { market_id, .. }
+            | EventKind::PositionWrapped { market_id, .. }
+            | EventKind::PositionUnwrapped { market_id, .. }
+            | EventKind::LiquidityAdded { market_id, .. }
+            | EventKind::LiquidityRemoved { market_id, .. }
+            | EventKind::LiquidityMatched { market_id, .. }
+            | EventKind::TreasuryLiquiditySettled { market_id, .. }
+            | EventKind::CompleteSetMinted { market_id, .. }
+            | EventKind::CompleteSetRedeemed { market_id, .. }
+            | EventKind::LpFeesClaimed { market_id, .. }
+            | EventKind::CreatorFeesClaimed { market_id, .. }
+            | EventKind::RewardClaimed { market_id, .. }
+            | EventKind::RefundClaimed { market_id, .. }
+            | EventKind::LimitOrderPlaced { market_id, .. }
+            | EventKind::TriggerCreated { market_id, .. } => Some(*market_id),
+            _ => None,
+        }
+    }
+}
+
+/// Get events with ID >= `from_id`, oldest first
+/// `limit` is capped at MAX_LIST_RESULTS; `next_cursor` is the `from_id` of the following page
+#[ic_cdk::query]
+fn get_events(from_id: u64, limit: u64) -> Page<Event> {
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        let start = from_id.saturating_sub(1) as usize;
+        let tail = events.get(start..).unwrap_or_default();
+        let limit = (limit as usize).min(MAX_LIST_RESULTS);
+        let next_cursor = tail.get(limit).map(|event| event.id);
+        Page {
+            items: tail.iter().take(limit).cloned().collect(),
+            truncated: next_cursor.is_some(),
+            next_cursor,
+        }
+    })
+}
+
+// =============================================================================
+// TRADE HISTORY
+// =============================================================================
+// Trades and events stay in this canister for its lifetime; there are no archive canisters,
+// so get_trade_history, get_my_trades and get_events always page over the complete log
+
+fn next_trade_id() -> u64 {
+    NEXT_TRADE_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    })
+}
+
+/// Append an executed trade to its market's log, index it by trader,
+/// snapshot the trader's position and fold it into the rolling stats, platform totals, candles,
+/// price observations and the trader's cumulative volume
+fn record_trade(record: TradeRecord) {
+    let event = EventKind::TradeExecuted {
+        trade_id: record.id,
+        market_id: record.market_id,
+        trader: record.trader,
+        side: record.side.clone(),
+        token_type: record.token_type.clone(),
+    };
+    let timestamp = record.timestamp;
+    update_rolling_stats(&record);
+    update_platform_totals(&record);
+    update_candles(&record);
+    PRICE_OBSERVATIONS.with(|observations| {
+        observations
+            .borrow_mut()
+            .entry(record.market_id)
+            .or_default()
+            .push((record.timestamp, yes_price_after(&record)))
+    });
+    record_position_snapshot(&record);
+    let (trader, market_id) = (record.trader, record.market_id);
+    USER_VOLUME.with(|volume| {
+        let mut volume = volume.borrow_mut();
+        let traded = volume.entry(trader).or_insert(0);
+        *traded = traded.saturating_add(record.icp_amount);
+    });
+    match record.side {
+        OrderSide::Buy => {
+            adjust_contribution(trader, market_id, record.icp_amount, 0);
+            record_pnl(trader, market_id, record.icp_amount, 0, timestamp);
+        }
+        OrderSide::Sell => {
+            adjust_contribution(trader, market_id, 0, record.icp_amount);
+            record_pnl(trader, market_id, 0, record.icp_amount, timestamp);
+        }
+    }
+    let index = TRADE_HISTORY.with(|history| {
+        let mut history_map = history.borrow_mut();
+        let trades = history_map.entry(market_id).or_default();
+        trades.push(record);
+        trades.len() - 1
+    });
+    USER_TRADE_INDEX.with(|trade_index| {
+        trade_index
+            .borrow_mut()
+            .entry(trader)
+            .or_default()
+            .push((market_id, index))
+    });
+    emit_event(timestamp, event);
+}
+
+/// Get the caller's executed trades across all markets, most recent first
+/// `limit` is capped at MAX_LIST_RESULTS; `next_cursor` is the offset of the following page
+#[ic_cdk::query]
+fn get_my_trades(offset: u64, limit: u64) -> Page<TradeRecord> {
+    trades_of(caller(), offset, limit)
+}
+
+fn trades_of(trader: Principal, offset: u64, limit: u64) -> Page<TradeRecord> {
+    let entries = USER_TRADE_INDEX.with(|trade_index| {
+        trade_index
+            .borrow()
+            .get(&trader)
+            .map(|entries| entries.iter().rev().copied().collect())
+            .unwrap_or_default()
+    });
+    let page = paginate_offset(entries, offset, limit);
+    TRADE_HISTORY.with(|history| {
+        let history_map = history.borrow();
+        page.map(|(market_id, index)| history_map[&market_id][index].clone())
+    })
+}
+
+/// Carry a side's cost basis across a trade: buys add the ICP paid,
+/// sells release the sold fraction at average cost
+fn next_cost_basis(cost_basis: u64, held_after: u64, record: &TradeRecord) -> u64 {
+    match record.side {
+        OrderSide::Buy => cost_basis + record.icp_amount,
+        OrderSide::Sell => scale_by(cost_basis, held_after, held_after + record.token_amount),
+    }
+}
+
+/// Snapshot the trader's position after a trade (USER_POSITIONS already reflects it)
+fn record_position_snapshot(record: &TradeRecord) {
+    let key = (record.trader, record.market_id);
+    let (yes_tokens, no_tokens) = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&key)
+            .map_or((0, 0), |position| (position.yes_tokens, position.no_tokens))
+    });
+
+    POSITION_HISTORY.with(|history| {
+        let mut history_map = history.borrow_mut();
+        let snapshots = history_map.entry(key).or_default();
+        let (mut yes_cost_basis, mut no_cost_basis) = snapshots
+            .last()
+            .map_or((0, 0), |last| (last.yes_cost_basis, last.no_cost_basis));
+        match record.token_type {
+            TokenType::Yes => yes_cost_basis = next_cost_basis(yes_cost_basis, yes_tokens, record),
+            TokenType::No => no_cost_basis = next_cost_basis(no_cost_basis, no_tokens, record),
+        }
+        snapshots.push(PositionSnapshot {
+            trade_id: record.id,
+            yes_tokens,
+            no_tokens,
+            yes_cost_basis,
+            no_cost_basis,
+            yes_price: yes_price_after(record),
+            timestamp: record.timestamp,
+        });
+    });
+}
+
+/// Get how the caller's position in a market evolved, one snapshot per trade, oldest first
+#[ic_cdk::query]
+fn get_position_history(market_id: u64) -> Vec<PositionSnapshot> {
+    let caller_principal = caller();
+    POSITION_HISTORY.with(|history| {
+        history
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+/// YES price implied by a trade's resulting price, since YES and NO prices sum to one
+fn yes_price_after(record: &TradeRecord) -> f64 {
+    match record.token_type {
+        TokenType::Yes => record.price_after,
+        TokenType::No => 1.0 - record.price_after,
+    }
+}
+
+/// Add a trade to its market's hourly ring, recycling the slot if it holds an older hour
+fn update_rolling_stats(record: &TradeRecord) {
+    let hour = record.timestamp / NANOS_PER_HOUR;
+    ROLLING_STATS.with(|stats| {
+        let mut stats_map = stats.borrow_mut();
+        let market_stats = stats_map.entry(record.market_id).or_default();
+        let previous_yes_price = market_stats.last_yes_price;
+        let bucket = &mut market_stats.buckets[(hour % ROLLING_WINDOW_HOURS) as usize];
+        if bucket.hour != hour || bucket.trades == 0 {
+            *bucket = StatsBucket {
+                hour,
+                volume: 0,
+                trades: 0,
+                opening_yes_price: previous_yes_price,
+                traders: BTreeSet::new(),
+            };
+        }
+        bucket.volume += record.icp_amount;
+        bucket.trades += 1;
+        bucket.traders.insert(record.trader);
+        market_stats.last_yes_price = yes_price_after(record);
+        market_stats.total_volume = market_stats.total_volume.saturating_add(record.icp_amount);
+    });
+}
+
+fn candle_period(resolution: CandleResolution) -> u64 {
+    match resolution {
+        CandleResolution::Hour => NANOS_PER_HOUR,
+        CandleResolution::Day => NANOS_PER_DAY,
+    }
+}
+
+/// Fold a trade into its market's hourly candle and the daily rollup
+fn update_candles(record: &TradeRecord) {
+    let price = yes_price_after(record);
+    for (resolution, candles) in [
+        (CandleResolution::Hour, &HOURLY_CANDLES),
+        (CandleResolution::Day, &DAILY_CANDLES),
+    ] {
+        let period = candle_period(resolution);
+        let start = record.timestamp / period * period;
+        candles.with(|candles| {
+            let mut candles_map = candles.borrow_mut();
+            let candle = candles_map
+                .entry(record.market_id)
+                .or_default()
+                .entry(start)
+                .or_insert(Candle {
+                    start,
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    volume: 0,
+                    trades: 0,
+                });
+            candle.high = candle.high.max(price);
+            candle.low = candle.low.min(price);
+            candle.close = price;
+            candle.volume += record.icp_amount;
+            candle.trades += 1;
+        });
+    }
+}
+
+/// Get YES price candles starting in [from, to), oldest first
+/// Periods without trades have no candle. At most MAX_LIST_RESULTS candles are returned;
+/// pass `next_cursor` as `from` for more
+#[ic_cdk::query]
+fn get_price_history(
+    market_id: u64,
+    resolution: CandleResolution,
+    from: u64,
+    to: u64,
+) -> Page<Candle> {
+    let candles = match resolution {
+        CandleResolution::Hour => &HOURLY_CANDLES,
+        CandleResolution::Day => &DAILY_CANDLES,
+    };
+    candles.with(|candles| {
+        let keyed = candles
+            .borrow()
+            .get(&market_id)
+            .map(|series| {
+                series
+                    .range(from..to.max(from))
+                    .map(|(start, candle)| (*start, candle.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        paginate(keyed, None)
+    })
+}
+
+/// Time-weighted average YES probability over the `window_ns` ending now
+/// The window is clipped to the market's lifetime; markets open at 0.5
+#[ic_cdk::query]
+fn get_twap(market_id: u64, window_ns: u64) -> Result<f64, PredictionMarketError> {
+    if window_ns == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    let creation_time = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| market.creation_time)
+            .ok_or(PredictionMarketError::MarketNotFound)
+    })?;
+    let now = ic_cdk::api::time();
+    let start = now.saturating_sub(window_ns).max(creation_time);
+    Ok(PRICE_OBSERVATIONS.with(|observations| {
+        let observations = observations.borrow();
+        let series = observations.get(&market_id).map_or(&[][..], Vec::as_slice);
+        time_weighted_average(series, 0.5, start, now)
+    }))
+}
+
+/// Average of a step function over [start, end): each observation holds until the next one,
+/// and `initial` holds before the first
+fn time_weighted_average(observations: &[(u64, f64)], initial: f64, start: u64, end: u64) -> f64 {
+    if end <= start {
+        return observations
+            .iter()
+            .take_while(|(timestamp, _)| *timestamp <= end)
+            .last()
+            .map_or(initial, |(_, price)| *price);
+    }
+
+    let mut price = initial;
+    let mut cursor = start;
+    let mut weighted = 0.0;
+    for (timestamp, observed) in observations {
+        if *timestamp >= end {
+            break;
+        }
+        if *timestamp > cursor {
+            weighted += price * (*timestamp - cursor) as f64;
+            cursor = *timestamp;
+        }
+        price = *observed;
+    }
+    weighted += price * (end - cursor) as f64;
+    weighted / (end - start) as f64
+}
+
+/// (volume, trade count, YES price change) over the 24 hours ending at `now`
+fn rolling_stats_24h(market_id: u64, now: u64) -> (u64, u64, f64) {
+    let current_hour = now / NANOS_PER_HOUR;
+    let window_start = current_hour.saturating_sub(ROLLING_WINDOW_HOURS - 1);
+    ROLLING_STATS.with(|stats| {
+        let stats_map = stats.borrow();
+        let Some(market_stats) = stats_map.get(&market_id) else {
+            return (0, 0, 0.0);
+        };
+        let live: Vec<&StatsBucket> = market_stats
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.trades > 0)
+            .filter(|bucket| bucket.hour >= window_start && bucket.hour <= current_hour)
+            .collect();
+        let volume = live.iter().map(|bucket| bucket.volume).sum();
+        let trades = live.iter().map(|bucket| bucket.trades).sum();
+        let price_change = live
+            .iter()
+            .min_by_key(|bucket| bucket.hour)
+            .map_or(0.0, |oldest| {
+                market_stats.last_yes_price - oldest.opening_yes_price
+            });
+        (volume, trades, price_change)
+    })
+}
+
+/// Distinct principals that traded a market over the last 24 hours
+fn unique_traders_24h(market_id: u64, now: u64) -> u64 {
+    let current_hour = now / NANOS_PER_HOUR;
+    let window_start = current_hour.saturating_sub(ROLLING_WINDOW_HOURS - 1);
+    ROLLING_STATS.with(|stats| {
+        let stats_map = stats.borrow();
+        let Some(market_stats) = stats_map.get(&market_id) else {
+            return 0;
+        };
+        market_stats
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.trades > 0)
+            .filter(|bucket| bucket.hour >= window_start && bucket.hour <= current_hour)
+            .flat_map(|bucket| bucket.traders.iter())
+            .collect::<BTreeSet<_>>()
+            .len() as u64
+    })
+}
+
+/// ICP traded in a market since it opened
+fn total_traded_volume(market_id: u64) -> u64 {
+    ROLLING_STATS.with(|stats| {
+        stats
+            .borrow()
+            .get(&market_id)
+            .map_or(0, |market_stats| market_stats.total_volume)
+    })
+}
+
+/// Get a market's executed trades, most recent first
+/// `limit` is capped at MAX_LIST_RESULTS; `next_cursor` is the offset of the following page
+#[ic_cdk::query]
+fn get_trade_history(market_id: u64, offset: u64, limit: u64) -> Page<TradeRecord> {
+    TRADE_HISTORY.with(|history| {
+        let trades = history
+            .borrow()
+            .get(&market_id)
+            .map(|trades| trades.iter().rev().cloned().collect())
+            .unwrap_or_default();
+        paginate_offset(trades, offset, limit)
+    })
+}
+
+// =============================================================================
+// LEADERBOARD
+// =============================================================================
+
+/// Rank traders by realized PnL (default) or by win rate over settled markets
+/// A period covers the markets a trader moved ICP in or out of during it;
+/// `limit` is capped at MAX_LIST_RESULTS
+#[ic_cdk::query]
+fn get_leaderboard(
+    period: LeaderboardPeriod,
+    limit: u64,
+    sort_by: Option<LeaderboardSort>,
+) -> Vec<LeaderboardEntry> {
+    build_leaderboard(
+        period,
+        limit,
+        sort_by.unwrap_or(LeaderboardSort::Pnl),
+        ic_cdk::api::time(),
+    )
+}
+
+fn build_leaderboard(
+    period: LeaderboardPeriod,
+    limit: u64,
+    sort_by: LeaderboardSort,
+    now: u64,
+) -> Vec<LeaderboardEntry> {
+    let since = match period {
+        LeaderboardPeriod::Day => now.saturating_sub(NANOS_PER_DAY),
+        LeaderboardPeriod::Week => now.saturating_sub(7 * NANOS_PER_DAY),
+        LeaderboardPeriod::Month => now.saturating_sub(30 * NANOS_PER_DAY),
+        LeaderboardPeriod::AllTime => 0,
+    };
+    let mut totals: HashMap<Principal, LeaderboardEntry> = HashMap::new();
+    REALIZED_PNL.with(|ledger| {
+        for ((user, market_id), pnl) in ledger.borrow().iter() {
+            if pnl.updated_at < since {
+                continue;
+            }
+            let entry = totals.entry(*user).or_insert_with(|| LeaderboardEntry {
+                rank: 0,
+                user: *user,
+                realized_pnl: 0,
+                markets_traded: 0,
+                settled_markets: 0,
+                wins: 0,
+                win_rate: 0.0,
+            });
+            entry.realized_pnl = entry
+                .realized_pnl
+                .saturating_add(pnl.received as i64 - pnl.spent as i64);
+            entry.markets_traded += 1;
+            let settled = MARKETS.with(|markets| {
+                markets
+                    .borrow()
+                    .get(market_id)
+                    .is_some_and(|market| is_settled(&market.status))
+            });
+            if settled {
+                entry.settled_markets += 1;
+                if pnl.received > pnl.spent {
+                    entry.wins += 1;
+                }
+            }
+        }
+    });
+
+    let mut entries: Vec<LeaderboardEntry> = totals
+        .into_values()
+        .map(|mut entry| {
+            if entry.settled_markets > 0 {
+                entry.win_rate = entry.wins as f64 / entry.settled_markets as f64;
+            }
+            entry
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        let order = match sort_by {
+            LeaderboardSort::Pnl => b.realized_pnl.cmp(&a.realized_pnl),
+            LeaderboardSort::WinRate => b
+                .win_rate
+                .total_cmp(&a.win_rate)
+                .then(b.settled_markets.cmp(&a.settled_markets))
+                .then(b.realized_pnl.cmp(&a.realized_pnl)),
+        };
+        order.then(a.user.cmp(&b.user))
+    });
+    entries.truncate((limit as usize).min(MAX_LIST_RESULTS));
+    for (index, entry) in entries.iter_mut().enumerate() {
+        entry.rank = index as u64 + 1;
+    }
+    entries
+}
+
+/// Book ICP a user put into (`spent`) or took out of (`received`) a market
+fn record_pnl(user: Principal, market_id: u64, spent: u64, received: u64, now: u64) {
+    REALIZED_PNL.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        let pnl = ledger.entry((user, market_id)).or_default();
+        pnl.spent = pnl.spent.saturating_add(spent);
+        pnl.received = pnl.received.saturating_add(received);
+        pnl.updated_at = now;
+    });
+}
+
+// =============================================================================
+// FORECASTER REPUTATION
+// =============================================================================
+
+/// Get a user's Brier score over the markets they held a position in at resolution
+/// Scores follow the final outcome, so an overturned dispute rescores the market
+#[ic_cdk::query]
+fn get_user_reputation(user: Principal) -> UserReputation {
+    let scores: Vec<f64> = FORECASTS.with(|forecasts| {
+        forecasts
+            .borrow()
+            .get(&user)
+            .map(|markets| {
+                markets
+                    .iter()
+                    .filter_map(|(market_id, yes_probability)| {
+                        let outcome = MARKETS.with(|markets| {
+                            markets.borrow().get(market_id)?.winning_outcome.clone()
+                        })?;
+                        let happened = if outcome == TokenType::Yes { 1.0 } else { 0.0 };
+                        Some((yes_probability - happened).powi(2))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    UserReputation {
+        user,
+        markets_scored: scores.len() as u64,
+        brier_score: (!scores.is_empty()).then(|| scores.iter().sum::<f64>() / scores.len() as f64),
+    }
+}
+
+/// Snapshot every holder's implied YES probability as the market resolves
+fn record_forecasts(market_id: u64) {
+    let holdings: Vec<(Principal, f64)> = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .values()
+            .filter(|position| position.market_id == market_id)
+            .filter_map(|position| {
+                let held = position.yes_tokens + position.no_tokens;
+                (held > 0).then(|| (position.user, position.yes_tokens as f64 / held as f64))
+            })
+            .collect()
+    });
+    FORECASTS.with(|forecasts| {
+        let mut forecasts = forecasts.borrow_mut();
+        for (user, yes_probability) in holdings {
+            forecasts
+                .entry(user)
+                .or_default()
+                .insert(market_id, yes_probability);
+        }
+    });
+}
+
+// =============================================================================
+// SCOPED READ TOKENS
+// =============================================================================
+
+/// Issue a read token for the caller's data, e.g. for a portfolio tracker
+/// The secret is returned once; the holder passes it to the `read_*` queries
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn issue_read_token(
+    scopes: Vec<ReadScope>,
+    expires_at: Option<u64>,
+    label: Option<String>,
+) -> Result<IssuedReadToken, PredictionMarketError> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+
+    if scopes.is_empty() || expires_at.is_some_and(|expiry| expiry <= now) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| PredictionMarketError::CallFailed(message))?;
+    let token: String = random_bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let token_id = NEXT_READ_TOKEN_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+    let info = ReadToken {
+        id: token_id,
+        owner: caller_principal,
+        label,
+        scopes,
+        created_at: now,
+        expires_at,
+        revoked: false,
+    };
+    READ_TOKENS.with(|tokens| tokens.borrow_mut().insert(token.clone(), info.clone()));
+    emit_event(
+        now,
+        EventKind::ReadTokenIssued {
+            token_id,
+            owner: caller_principal,
+        },
+    );
+
+    Ok(IssuedReadToken { token, info })
+}
+
+/// Revoke one of the caller's read tokens
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn revoke_read_token(token_id: u64) -> Result<ReadToken, PredictionMarketError> {
+    let caller_principal = caller();
+    let token = READ_TOKENS.with(|tokens| {
+        let mut tokens_map = tokens.borrow_mut();
+        let token = tokens_map
+            .values_mut()
+            .find(|token| token.id == token_id && token.owner == caller_principal)
+            .ok_or(PredictionMarketError::InvalidReadToken)?;
+        token.revoked = true;
+        Ok(token.clone())
+    })?;
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::ReadTokenRevoked { token_id },
+    );
+    Ok(token)
+}
+
+/// List the read tokens the caller has issued (without their secrets)
+#[ic_cdk::query]
+fn get_my_read_tokens() -> Vec<ReadToken> {
+    let caller_principal = caller();
+    let mut issued: Vec<ReadToken> = READ_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .values()
+            .filter(|token| token.owner == caller_principal)
+            .cloned()
+            .collect()
+    });
+    issued.sort_by_key(|token| token.id);
+    issued
+}
+
+/// Resolve a token to the user whose data it may read under `scope`
+fn authorize_read_token(
+    token: &str,
+    scope: ReadScope,
+    now: u64,
+) -> Result<Principal, PredictionMarketError> {
+    READ_TOKENS.with(|tokens| {
+        let tokens_map = tokens.borrow();
+        let info = tokens_map
+            .get(token)
+            .filter(|info| !info.revoked && info.expires_at.is_none_or(|expiry| now < expiry))
+            .ok_or(PredictionMarketError::InvalidReadToken)?;
+        if !info.scopes.contains(&scope) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        Ok(info.owner)
+    })
+}
+
+/// Read the token owner's positions
+#[ic_cdk::query]
+fn read_positions(
+    token: String,
+    cursor: Option<u64>,
+) -> Result<Page<UserPosition>, PredictionMarketError> {
+    let owner = authorize_read_token(&token, ReadScope::Positions, ic_cdk::api::time())?;
+    Ok(positions_of(owner, cursor))
+}
+
+/// Read the token owner's mark-to-market P&L per position
+#[ic_cdk::query]
+fn read_pnl(
+    token: String,
+    cursor: Option<u64>,
+) -> Result<Page<PositionPnl>, PredictionMarketError> {
+    let owner = authorize_read_token(&token, ReadScope::ProfitAndLoss, ic_cdk::api::time())?;
+    Ok(positions_of(owner, cursor).map(|position| build_position_pnl(&position)))
+}
+
+/// Read the token owner's trade history, most recent first
+#[ic_cdk::query]
+fn read_trades(
+    token: String,
+    offset: u64,
+    limit: u64,
+) -> Result<Page<TradeRecord>, PredictionMarketError> {
+    let owner = authorize_read_token(&token, ReadScope::Trades, ic_cdk::api::time())?;
+    Ok(trades_of(owner, offset, limit))
+}
+
+fn build_position_pnl(position: &UserPosition) -> PositionPnl {
+    let (yes_price, no_price) = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&position.market_id)
+            .map_or((0.0, 0.0), market_prices)
+    });
+    let cost_basis = POSITION_HISTORY.with(|history| {
+        history
+            .borrow()
+            .get(&(position.user, position.market_id))
+            .and_then(|snapshots| snapshots.last())
+            .map_or(0, |last| last.yes_cost_basis + last.no_cost_basis)
+    });
+    let market_value =
+        (position.yes_tokens as f64 * yes_price + position.no_tokens as f64 * no_price) as u64;
+    PositionPnl {
+        market_id: position.market_id,
+        yes_tokens: position.yes_tokens,
+        no_tokens: position.no_tokens,
+        cost_basis,
+        market_value,
+        unrealized_pnl: market_value as i64 - cost_basis as i64,
+    }
+}
+
+// =============================================================================
+// LIMIT ORDERS
+// =============================================================================
+
+/// The asset a limit order holds in escrow
+fn limit_order_escrow(order: &LimitOrder) -> SwapAsset {
+    match order.side {
+        OrderSide::Buy => SwapAsset::Icp {
+            amount: order.amount,
+        },
+        OrderSide::Sell => SwapAsset::Tokens {
+            market_id: order.market_id,
+            token_type: order.token_type.clone(),
+            amount: order.amount,
+        },
+    }
+}
+
+/// Least the order's fill must return so its average price honours the limit:
+/// tokens for a buy (ICP / limit), ICP for a sell (tokens * limit). Fees count against it
+fn limit_order_min_out(order: &LimitOrder) -> u64 {
+    match order.side {
+        OrderSide::Buy => (order.amount as f64 / order.limit_price).floor() as u64,
+        OrderSide::Sell => (order.amount as f64 * order.limit_price).ceil() as u64,
+    }
+}
+
+/// A buy order fires at or below its limit, a sell order at or above it
+fn limit_order_triggered(side: &OrderSide, limit_price: f64, current_price: f64) -> bool {
+    match side {
+        OrderSide::Buy => current_price <= limit_price,
+        OrderSide::Sell => current_price >= limit_price,
+    }
+}
+
+/// Place a resting limit order against the AMM
+/// The order's ICP (buy) or tokens (sell) are escrowed until it fills or is cancelled
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn place_limit_order(
+    market_id: u64,
+    token_type: TokenType,
+    side: OrderSide,
+    limit_price: f64,
+    amount: u64,
+) -> Result<LimitOrder, PredictionMarketError> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+
+    ensure_platform_active()?;
+
+    if amount == 0 || !(limit_price > 0.0 && limit_price < 1.0) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    ensure_direct_trading(market_id)?;
+
+    let order_id = NEXT_ORDER_ID.with(|id| *id.borrow());
+    let order = LimitOrder {
+        id: order_id,
+        owner: caller_principal,
+        market_id,
+        token_type,
+        side,
+        limit_price,
+        amount,
+        status: OrderStatus::Open,
+        created_at: now,
+    };
+
+    let escrow = limit_order_escrow(&order);
+    ensure_trading_open(market_id, now)?;
+    debit_swap_asset(caller_principal, &escrow)?;
+
+    NEXT_ORDER_ID.with(|id| *id.borrow_mut() = order_id + 1);
+    LIMIT_ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order.clone()));
+    emit_event(
+        now,
+        EventKind::LimitOrderPlaced {
+            order_id,
+            market_id,
+            owner: caller_principal,
+        },
+    );
+
+    // The price may already be through the limit
+    match_limit_orders(market_id);
+
+    Ok(LIMIT_ORDERS.with(|orders| orders.borrow()[&order_id].clone()))
+}
+
+/// Cancel an open limit order and release its escrow
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_order(order_id: u64) -> Result<LimitOrder, PredictionMarketError> {
+    let caller_principal = caller();
+
+    let order = LIMIT_ORDERS.with(|orders| {
+        let mut orders_map = orders.borrow_mut();
+        let order = orders_map
+            .get_mut(&order_id)
+            .ok_or(PredictionMarketError::OrderNotFound)?;
+        if order.owner != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if order.status != OrderStatus::Open {
+            return Err(PredictionMarketError::OrderNotOpen);
+        }
+        order.status = OrderStatus::Cancelled;
+        Ok(order.clone())
+    })?;
+
+    credit_swap_asset(order.owner, &limit_order_escrow(&order));
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::LimitOrderCancelled { order_id },
+    );
+
+    Ok(order)
+}
+
+/// Get the caller's open limit orders, optionally for a single market
+#[ic_cdk::query]
+fn get_open_orders(market_id: Option<u64>, cursor: Option<u64>) -> Page<LimitOrder> {
+    let caller_principal = caller();
+    LIMIT_ORDERS.with(|orders| {
+        let keyed = orders
+            .borrow()
+            .values()
+            .filter(|order| order.owner == caller_principal && order.status == OrderStatus::Open)
+            .filter(|order| market_id.is_none_or(|id| order.market_id == id))
+            .map(|order| (order.id, order.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+/// Execute every open order on a market whose limit the current AMM price has crossed
+/// Orders are visited oldest first and the price is re-read after each fill
+fn match_limit_orders(market_id: u64) {
+    let mut candidates: Vec<LimitOrder> = LIMIT_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .values()
+            .filter(|order| order.market_id == market_id && order.status == OrderStatus::Open)
+            .cloned()
+            .collect()
+    });
+    candidates.sort_by_key(|order| order.id);
+
+    for order in candidates {
+        let current_price = match get_token_price(market_id, order.token_type.clone()) {
+            Ok(price) => price,
+            Err(_) => return,
+        };
+        if !limit_order_triggered(&order.side, order.limit_price, current_price) {
+            continue;
+        }
+
+        // Release the escrow to the owner and trade on their behalf
+        let escrow = limit_order_escrow(&order);
+        credit_swap_asset(order.owner, &escrow);
+        // A fill that would average past the limit fails and the order keeps resting
+        let result = execute_trade(
+            order.owner,
+            market_id,
+            order.side.clone(),
+            order.token_type.clone(),
+            order.amount,
+            limit_order_min_out(&order),
+        );
+
+        match result {
+            Ok(trade) => {
+                let now = ic_cdk::api::time();
+                LIMIT_ORDERS.with(|orders| {
+                    if let Some(stored) = orders.borrow_mut().get_mut(&order.id) {
+                        stored.status = OrderStatus::Filled {
+                            filled_at: now,
+                            amount_received: trade.tokens_received,
+                        };
+                    }
+                });
+                emit_event(now, EventKind::LimitOrderFilled { order_id: order.id });
+            }
+            // The trade could not execute (e.g. not enough liquidity): keep the order resting
+            Err(_) => {
+                let _ = debit_swap_asset(order.owner, &escrow);
+            }
+        }
+    }
+}
+
+// =============================================================================
+// STOP-LOSS AND TAKE-PROFIT TRIGGERS
+// =============================================================================
+
+/// A stop-loss fires at or below its trigger, a take-profit at or above it
+fn price_trigger_fired(action: &TriggerAction, trigger_price: f64, current_price: f64) -> bool {
+    match action {
+        TriggerAction::StopLoss => current_price <= trigger_price,
+        TriggerAction::TakeProfit => current_price >= trigger_price,
+    }
+}
+
+/// Least ICP selling `held` tokens must return when a trigger fires
+/// A take-profit sells at an average of at least its trigger price; a stop-loss accepts up to
+/// STOP_LOSS_MAX_SLIPPAGE_BPS below it, so a sudden drop cannot dump the position at any price
+fn trigger_min_out(trigger: &PriceTrigger, held: u64) -> u64 {
+    let floor_price = match trigger.action {
+        TriggerAction::TakeProfit => trigger.trigger_price,
+        TriggerAction::StopLoss => {
+            trigger.trigger_price * (10_000 - STOP_LOSS_MAX_SLIPPAGE_BPS) as f64 / 10_000.0
+        }
+    };
+    (held as f64 * floor_price).ceil() as u64
+}
+
+/// Whether the periodic trigger check needs to keep running
+fn has_active_triggers() -> bool {
+    PRICE_TRIGGERS.with(|triggers| {
+        triggers
+            .borrow()
+            .values()
+            .any(|trigger| trigger.status == TriggerStatus::Active)
+    })
+}
+
+/// Register a conditional order that sells the caller's whole position
+/// once the AMM price crosses the trigger price
+/// Nothing is escrowed: the position held at execution time is what gets sold
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn create_trigger(
+    market_id: u64,
+    token_type: TokenType,
+    trigger_price: f64,
+    action: TriggerAction,
+) -> Result<PriceTrigger, PredictionMarketError> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+
+    ensure_platform_active()?;
+
+    if !(trigger_price > 0.0 && trigger_price < 1.0) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    ensure_trading_open(market_id, now)?;
+    ensure_direct_trading(market_id)?;
+
+    let held = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .map_or(0, |position| match token_type {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            })
+    });
+    if held == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let trigger_id = NEXT_TRIGGER_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+    let trigger = PriceTrigger {
+        id: trigger_id,
+        owner: caller_principal,
+        market_id,
+        token_type,
+        trigger_price,
+        action,
+        status: TriggerStatus::Active,
+        created_at: now,
+    };
+    PRICE_TRIGGERS.with(|triggers| triggers.borrow_mut().insert(trigger_id, trigger.clone()));
+    emit_event(
+        now,
+        EventKind::TriggerCreated {
+            trigger_id,
+            market_id,
+            owner: caller_principal,
+        },
+    );
+
+    schedule_global_timer();
+
+    Ok(trigger)
+}
+
+/// Cancel an active trigger
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_trigger(trigger_id: u64) -> Result<PriceTrigger, PredictionMarketError> {
+    let caller_principal = caller();
+
+    let trigger = PRICE_TRIGGERS.with(|triggers| {
+        let mut triggers_map = triggers.borrow_mut();
+        let trigger = triggers_map
+            .get_mut(&trigger_id)
+            .ok_or(PredictionMarketError::TriggerNotFound)?;
+        if trigger.owner != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if trigger.status != TriggerStatus::Active {
+            return Err(PredictionMarketError::TriggerNotActive);
+        }
+        trigger.status = TriggerStatus::Cancelled;
+        Ok(trigger.clone())
+    })?;
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::TriggerCancelled { trigger_id },
+    );
+    Ok(trigger)
+}
+
+/// Get the caller's triggers in any status, optionally for a single market
+#[ic_cdk::query]
+fn get_my_triggers(market_id: Option<u64>, cursor: Option<u64>) -> Page<PriceTrigger> {
+    let caller_principal = caller();
+    PRICE_TRIGGERS.with(|triggers| {
+        let keyed = triggers
+            .borrow()
+            .values()
+            .filter(|trigger| trigger.owner == caller_principal)
+            .filter(|trigger| market_id.is_none_or(|id| trigger.market_id == id))
+            .map(|trigger| (trigger.id, trigger.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+/// Execute every active trigger whose price condition holds
+/// Triggers are visited oldest first and the price is re-read after each sale.
+/// Triggers on markets that stopped trading, or whose owner no longer holds tokens, are cancelled
+fn evaluate_price_triggers(now: u64) {
+    let mut candidates: Vec<PriceTrigger> = PRICE_TRIGGERS.with(|triggers| {
+        triggers
+            .borrow()
+            .values()
+            .filter(|trigger| trigger.status == TriggerStatus::Active)
+            .cloned()
+            .collect()
+    });
+    candidates.sort_by_key(|trigger| trigger.id);
+
+    for trigger in candidates {
+        let set_status = |status: TriggerStatus| {
+            let kind = match status {
+                TriggerStatus::Executed { .. } => EventKind::TriggerExecuted {
+                    trigger_id: trigger.id,
+                },
+                _ => EventKind::TriggerCancelled {
+                    trigger_id: trigger.id,
+                },
+            };
+            PRICE_TRIGGERS.with(|triggers| {
+                if let Some(stored) = triggers.borrow_mut().get_mut(&trigger.id) {
+                    stored.status = status;
+                }
+            });
+            emit_event(now, kind);
+        };
+
+        if ensure_trading_open(trigger.market_id, now).is_err() {
+            set_status(TriggerStatus::Cancelled);
+            continue;
+        }
+
+        let current_price = match get_token_price(trigger.market_id, trigger.token_type.clone()) {
+            Ok(price) => price,
+            Err(_) => continue,
+        };
+        if !price_trigger_fired(&trigger.action, trigger.trigger_price, current_price) {
+            continue;
+        }
+
+        let held = USER_POSITIONS.with(|positions| {
+            positions
+                .borrow()
+                .get(&(trigger.owner, trigger.market_id))
+                .map_or(0, |position| match trigger.token_type {
+                    TokenType::Yes => position.yes_tokens,
+                    TokenType::No => position.no_tokens,
+                })
+        });
+        if held == 0 {
+            set_status(TriggerStatus::Cancelled);
+            continue;
+        }
+
+        // A failed sale (e.g. not enough liquidity, or a price past the floor) leaves the
+        // trigger active for the next check
+        if let Ok(trade) = execute_sell_trade(
+            trigger.owner,
+            trigger.market_id,
+            held,
+            trigger_min_out(&trigger, held),
+            trigger.token_type.clone(),
+        ) {
+            set_status(TriggerStatus::Executed {
+                executed_at: now,
+                icp_received: trade.tokens_received,
+            });
+            match_limit_orders(trigger.market_id);
+        }
+    }
+}
+
+// =============================================================================
+// TRADING DEADLINE AND AUTOMATIC CLOSE
+// =============================================================================
+
+/// A market accepts trades only while it is open and before its close time
+fn is_trading_open(market: &AmmMarket, now: u64) -> bool {
+    matches!(market.status, MarketStatus::Open) && now < market.close_time
+}
+
+/// The market has reached a final status: resolved, invalidated or cancelled
+fn is_settled(status: &MarketStatus) -> bool {
+    matches!(
+        status,
+        MarketStatus::Resolved | MarketStatus::Invalid | MarketStatus::Cancelled
+    )
+}
+
+/// Fail unless the market exists and is currently accepting trades
+fn ensure_trading_open(market_id: u64, now: u64) -> Result<(), PredictionMarketError> {
+    MARKETS.with(|markets| match markets.borrow().get(&market_id) {
+        Some(market) if is_trading_open(market, now) => Ok(()),
+        Some(_) => Err(PredictionMarketError::MarketClosed),
+        None => Err(PredictionMarketError::MarketNotFound),
+    })
+}
+
+/// Freeze every open market whose close time has passed
+/// Returns the IDs of the markets that were frozen
+fn freeze_expired_markets(now: u64) -> Vec<u64> {
+    let policies = EDGE_POLICIES.with(|policies| policies.borrow().clone());
+    let mut events = Vec::new();
+    let frozen: Vec<u64> = MARKETS.with(|markets| {
+        markets
+            .borrow_mut()
+            .values_mut()
+            .filter(|market| {
+                matches!(market.status, MarketStatus::Open) && market.close_time <= now
+            })
+            .filter_map(|market| {
+                if !is_untraded(market) {
+                    market.status = MarketStatus::Frozen;
+                    return Some(market.id);
+                }
+                // Nobody took a side, so there is nothing to resolve
+                let policy = match policies.untraded_at_close.clone() {
+                    EdgePolicy::ExtendMarket if market.close_extensions < MAX_CLOSE_EXTENSIONS => {
+                        market.close_time = now.saturating_add(policies.extension_ns);
+                        market.close_extensions += 1;
+                        events.push(EventKind::MarketExtended {
+                            market_id: market.id,
+                            close_time: market.close_time,
+                        });
+                        return None;
+                    }
+                    EdgePolicy::ExtendMarket => EdgePolicy::Refund,
+                    policy => policy,
+                };
+                market.status = MarketStatus::Frozen;
+                let amount = settle_degenerate_pool(market, &policy);
+                events.push(EventKind::DegeneratePoolSettled {
+                    market_id: market.id,
+                    policy,
+                    amount,
+                });
+                Some(market.id)
+            })
+            .collect()
+    });
+    for market_id in &frozen {
+        emit_event(
+            now,
+            EventKind::MarketFrozen {
+                market_id: *market_id,
+            },
+        );
+    }
+    for kind in events {
+        emit_event(now, kind);
+    }
+    frozen
+}
+
+/// Stop trading before the close time, e.g. once the outcome is already known
+/// (designated resolver, else market admin; or global admin)
+/// The market then follows the deadline path: resolution, oracle polls and auto-void
+/// still run from the original close time
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn freeze_market(market_id: u64) -> Result<String, PredictionMarketError> {
+    process_freeze_market(caller(), market_id, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(format!("Market {} frozen, awaiting resolution", market_id))
+}
+
+fn process_freeze_market(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let actor_is_global_admin = is_global_admin(actor);
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !actor_is_global_admin && !is_market_resolver(market, actor) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if !is_trading_open(market, now) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        market.status = MarketStatus::Frozen;
+        Ok(())
+    })?;
+    emit_event(now, EventKind::MarketFrozen { market_id });
+    Ok(())
+}
+
+/// Reserves still at 50/50 and no outcome tokens held by anyone
+fn is_untraded(market: &AmmMarket) -> bool {
+    market.yes_reserve == market.no_reserve
+        && USER_POSITIONS.with(|positions| {
+            !positions.borrow().values().any(|position| {
+                position.market_id == market.id
+                    && (position.yes_tokens > 0 || position.no_tokens > 0)
+            })
+        })
+}
+
+/// Empty a market's pool under a Refund or TreasurySweep policy; returns the amount moved
+/// Refunds follow LP shares, with the treasury's matched shares and rounding dust going to the treasury
+fn settle_degenerate_pool(market: &mut AmmMarket, policy: &EdgePolicy) -> u64 {
+    let pool = std::mem::take(&mut market.icp_liquidity_pool);
+    let refunded = match policy {
+        EdgePolicy::Refund => refund_lp_shares(market, pool),
+        _ => 0,
+    };
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += pool - refunded);
+    pool
+}
+
+/// Credit `amount` to a market's liquidity providers by share; returns what was paid
+/// The treasury's matched shares and rounding dust are left for the caller to sweep
+fn refund_lp_shares(market: &AmmMarket, amount: u64) -> u64 {
+    if market.total_lp_shares == 0 {
+        return 0;
+    }
+    let refunds: Vec<(Principal, u64)> = LP_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .filter(|((_, market_id), _)| *market_id == market.id)
+            .map(|((provider, _), account)| {
+                (
+                    *provider,
+                    scale_by(amount, account.shares, market.total_lp_shares),
+                )
+            })
+            .collect()
+    });
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        refunds
+            .into_iter()
+            .map(|(provider, refund)| {
+                *balances_map.entry(provider).or_insert(0) += refund;
+                refund
+            })
+            .sum()
+    })
+}
+
+/// Earliest close time among markets that are still open, if any
+fn next_market_close_time() -> Option<u64> {
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .filter(|market| matches!(market.status, MarketStatus::Open))
+            .map(|market| market.close_time)
+            .min()
+    })
+}
+
+/// Arm the canister global timer for the next piece of scheduled work
+/// The IC keeps a single global timer per canister, so it points at the earliest close time,
+/// the next price trigger check while any trigger is active, the next idle-balance sweep,
+/// the next near-close notification, the next oracle or price feed poll, or right away while lifecycle
+/// callbacks are queued
+fn schedule_global_timer() {
+    let now = ic_cdk::api::time();
+    let next_trigger_check =
+        has_active_triggers().then(|| now.saturating_add(TRIGGER_CHECK_INTERVAL_NS));
+    let pending_callbacks = has_queued_lifecycle_callbacks().then_some(now);
+    let next_wakeup = [
+        next_market_close_time(),
+        next_trigger_check,
+        next_auto_withdraw_time(),
+        next_near_close_time(),
+        next_dispute_window_close(),
+        next_vote_tally_time(),
+        next_webhook_retry_time(),
+        next_oracle_poll_time(),
+        next_price_feed_poll_time(),
+        next_auto_void_time(),
+        pending_callbacks,
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    ic_cdk::api::set_global_timer(next_wakeup.unwrap_or(0));
+}
+
+/// Global timer handler: freezes markets that reached their deadline, polls their oracles and
+/// price feeds, fires price triggers,
+/// sweeps idle balances, tallies community votes, closes dispute windows, voids abandoned markets,
+/// delivers lifecycle callbacks and webhook retries, and re-arms the timer
+#[export_name = "canister_global_timer"]
+extern "C" fn canister_global_timer() {
+    ic_cdk::setup();
+    let now = ic_cdk::api::time();
+    freeze_expired_markets(now);
+    poll_market_oracles(now);
+    poll_price_feeds(now);
+    evaluate_price_triggers(now);
+    sweep_idle_balances(now);
+    notify_near_close(now);
+    tally_resolution_votes(now, ic_cdk::id());
+    close_dispute_windows(now);
+    void_stale_markets(now);
+    deliver_lifecycle_callbacks(now);
+    schedule_global_timer();
+}
+
+/// Fire the global timer right after the current message, e.g. to deliver queued callbacks
+fn wake_global_timer() {
+    // The system API only exists inside a canister; unit tests leave the queue alone
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::set_global_timer(ic_cdk::api::time());
+}
+
+// =============================================================================
+// CREATOR LIFECYCLE CALLBACKS
+// =============================================================================
+
+/// Register where a market's lifecycle notifications go, or remove it with `None` (creator only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_lifecycle_hook(
+    market_id: u64,
+    target: Option<CallbackTarget>,
+    stages: Vec<LifecycleStage>,
+) -> Result<String, PredictionMarketError> {
+    register_lifecycle_hook(caller(), market_id, target, stages)?;
+    schedule_global_timer();
+    Ok(format!(
+        "Lifecycle callbacks updated for market {}",
+        market_id
+    ))
+}
+
+/// Get a market's lifecycle hook; only its creator can see it
+#[ic_cdk::query]
+fn get_lifecycle_hook(market_id: u64) -> Option<LifecycleHook> {
+    let caller_principal = caller();
+    let is_creator = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .is_some_and(|market| market.creator == caller_principal)
+    });
+    if !is_creator {
+        return None;
+    }
+    LIFECYCLE_HOOKS.with(|hooks| hooks.borrow().get(&market_id).cloned())
+}
+
+fn register_lifecycle_hook(
+    caller_principal: Principal,
+    market_id: u64,
+    target: Option<CallbackTarget>,
+    stages: Vec<LifecycleStage>,
+) -> Result<(), PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    if market.creator != caller_principal {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+
+    let Some(target) = target else {
+        LIFECYCLE_HOOKS.with(|hooks| hooks.borrow_mut().remove(&market_id));
+        WEBHOOK_SECRETS.with(|secrets| secrets.borrow_mut().remove(&market_id));
+        return Ok(());
+    };
+    let valid_target = match &target {
+        CallbackTarget::Canister { method, .. } => {
+            !method.is_empty() && method.len() <= MAX_CALLBACK_METHOD_LEN
+        }
+        CallbackTarget::Https { url } => {
+            url.len() <= MAX_CRITERIA_FIELD_LEN && is_https_url(url) && is_webhook_host_allowed(url)
+        }
+    };
+    if !valid_target || stages.is_empty() {
+        return Err(PredictionMarketError::InvalidCallbackTarget);
+    }
+
+    // Keep the NearClose flag so re-registering does not notify twice
+    let near_close_sent = LIFECYCLE_HOOKS.with(|hooks| {
+        hooks
+            .borrow()
+            .get(&market_id)
+            .is_some_and(|hook| hook.near_close_sent)
+    });
+    LIFECYCLE_HOOKS.with(|hooks| {
+        hooks.borrow_mut().insert(
+            market_id,
+            LifecycleHook {
+                target,
+                stages,
+                near_close_sent,
+            },
+        )
+    });
+    Ok(())
+}
+
+/// Lifecycle stages an event marks, with the trade behind trade stages
+fn lifecycle_stages_of(kind: &EventKind) -> Vec<(u64, LifecycleStage, Option<u64>)> {
+    match kind {
+        EventKind::TradeExecuted {
+            market_id,
+            trade_id,
+            ..
+        } => {
+            // record_trade stores the trade before emitting its event
+            let (trade_count, icp_amount) = TRADE_HISTORY.with(|history| {
+                history.borrow().get(market_id).map_or((0, 0), |trades| {
+                    (
+                        trades.len(),
+                        trades.last().map_or(0, |trade| trade.icp_amount),
+                    )
+                })
+            });
+            let mut stages = Vec::new();
+            if trade_count == 1 {
+                stages.push((*market_id, LifecycleStage::FirstTrade, Some(*trade_id)));
+            }
+            if icp_amount >= LARGE_TRADE_E8S {
+                stages.push((*market_id, LifecycleStage::LargeTrade, Some(*trade_id)));
+            }
+            stages
+        }
+        EventKind::MarketFrozen { market_id } => {
+            vec![(*market_id, LifecycleStage::Frozen, None)]
+        }
+        EventKind::ResolutionChallenged { market_id, .. }
+        | EventKind::ResolutionDisputed { market_id, .. } => {
+            vec![(*market_id, LifecycleStage::Disputed, None)]
+        }
+        EventKind::MarketResolved { market_id, .. }
+        | EventKind::MarketInvalidated { market_id, .. } => {
+            vec![(*market_id, LifecycleStage::Resolved, None)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn queue_lifecycle_notification(
+    market_id: u64,
+    stage: LifecycleStage,
+    trade_id: Option<u64>,
+    now: u64,
+) {
+    let Some(hook) = LIFECYCLE_HOOKS.with(|hooks| hooks.borrow().get(&market_id).cloned()) else {
+        return;
+    };
+    if !hook.stages.contains(&stage) {
+        return;
+    }
+    LIFECYCLE_OUTBOX.with(|outbox| {
+        outbox.borrow_mut().push((
+            hook.target,
+            LifecycleNotification {
+                market_id,
+                stage,
+                timestamp: now,
+                trade_id,
+            },
+        ))
+    });
+    wake_global_timer();
+}
+
+fn has_queued_lifecycle_callbacks() -> bool {
+    LIFECYCLE_OUTBOX.with(|outbox| !outbox.borrow().is_empty())
+}
+
+fn near_close_time(market: &AmmMarket) -> u64 {
+    let window = market.close_time.saturating_sub(market.creation_time);
+    market.creation_time + scale_by(window, NEAR_CLOSE_PERCENT, 100)
+}
+
+/// Open markets whose creator still waits for a NearClose notification
+fn pending_near_close() -> Vec<(u64, u64)> {
+    LIFECYCLE_HOOKS.with(|hooks| {
+        let hooks = hooks.borrow();
+        MARKETS.with(|markets| {
+            hooks
+                .iter()
+                .filter(|(_, hook)| {
+                    !hook.near_close_sent && hook.stages.contains(&LifecycleStage::NearClose)
+                })
+                .filter_map(|(market_id, _)| markets.borrow().get(market_id).cloned())
+                .filter(|market| market.status == MarketStatus::Open)
+                .map(|market| (market.id, near_close_time(&market)))
+                .collect()
+        })
+    })
+}
+
+fn next_near_close_time() -> Option<u64> {
+    pending_near_close().into_iter().map(|(_, at)| at).min()
+}
+
+fn notify_near_close(now: u64) {
+    for (market_id, _) in pending_near_close()
+        .into_iter()
+        .filter(|(_, at)| *at <= now)
+    {
+        LIFECYCLE_HOOKS.with(|hooks| {
+            if let Some(hook) = hooks.borrow_mut().get_mut(&market_id) {
+                hook.near_close_sent = true;
+            }
+        });
+        queue_lifecycle_notification(market_id, LifecycleStage::NearClose, None, now);
+    }
+}
+
+/// Send every queued notification and every webhook retry that is due
+/// Canister callbacks are best effort; failed HTTPS deliveries are retried with backoff
+fn deliver_lifecycle_callbacks(now: u64) {
+    let outbox = LIFECYCLE_OUTBOX.with(|outbox| std::mem::take(&mut *outbox.borrow_mut()));
+    for (target, notification) in outbox {
+        match target {
+            CallbackTarget::Canister {
+                canister_id,
+                method,
+            } => {
+                // One-way call, so a slow or failing receiver cannot hold up the timer
+                let _ = ic_cdk::notify(canister_id, &method, (notification,));
+            }
+            CallbackTarget::Https { url } => {
+                ic_cdk::spawn(post_lifecycle_webhook(WebhookDelivery {
+                    url,
+                    notification,
+                    attempt: 0,
+                    next_attempt_at: now,
+                }));
+            }
+        }
+    }
+    for delivery in take_due_webhook_retries(now) {
+        ic_cdk::spawn(post_lifecycle_webhook(delivery));
+    }
+}
+
+fn lifecycle_notification_json(notification: &LifecycleNotification) -> Json {
+    Json::Object(vec![
+        ("market_id", Json::Int(notification.market_id)),
+        ("stage", Json::Str(format!("{:?}", notification.stage))),
+        ("timestamp", Json::Int(notification.timestamp)),
+        (
+            "trade_id",
+            notification.trade_id.map_or(Json::Null, Json::Int),
+        ),
+    ])
+}
+
+async fn post_lifecycle_webhook(delivery: WebhookDelivery) {
+    // The allowlist may have tightened since the hook was registered
+    if !is_webhook_host_allowed(&delivery.url) {
+        return;
+    }
+    let notification = &delivery.notification;
+    let body = lifecycle_notification_json(notification)
+        .encode()
+        .into_bytes();
+    let idempotency_key = match notification.trade_id {
+        Some(trade_id) => format!(
+            "market-{}-{:?}-{}",
+            notification.market_id, notification.stage, trade_id
+        ),
+        None => format!("market-{}-{:?}", notification.market_id, notification.stage),
+    };
+    let mut headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: idempotency_key,
+        },
+    ];
+    if let Some(secret) =
+        WEBHOOK_SECRETS.with(|secrets| secrets.borrow().get(&notification.market_id).cloned())
+    {
+        headers.push(HttpHeader {
+            name: "X-Webhook-Signature".to_string(),
+            value: format!("sha256={}", hex(&hmac_sha256(secret.as_bytes(), &body))),
+        });
+    }
+    let request = CanisterHttpRequestArgument {
+        url: delivery.url.clone(),
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            vec![],
+        )),
+    };
+    let delivered = match http_outcall(request, WEBHOOK_CYCLES).await {
+        Ok((response,)) => {
+            u64::try_from(&response.status.0).is_ok_and(|status| (200..300).contains(&status))
+        }
+        Err(_) => false,
+    };
+    // Creators who miss every attempt can fall back to get_events
+    if !delivered && queue_webhook_retry(delivery, ic_cdk::api::time()) {
+        schedule_global_timer();
+    }
+}
+
+/// Queue the next attempt of a failed delivery; false once the attempts are used up
+fn queue_webhook_retry(mut delivery: WebhookDelivery, now: u64) -> bool {
+    delivery.attempt += 1;
+    if delivery.attempt >= MAX_WEBHOOK_ATTEMPTS {
+        return false;
+    }
+    delivery.next_attempt_at = now.saturating_add(WEBHOOK_RETRY_BASE_NS << (delivery.attempt - 1));
+    WEBHOOK_RETRIES.with(|retries| retries.borrow_mut().push(delivery));
+    true
+}
+
+fn take_due_webhook_retries(now: u64) -> Vec<WebhookDelivery> {
+    WEBHOOK_RETRIES.with(|retries| {
+        let (due, waiting) = std::mem::take(&mut *retries.borrow_mut())
+            .into_iter()
+            .partition(|delivery| delivery.next_attempt_at <= now);
+        *retries.borrow_mut() = waiting;
+        due
+    })
+}
+
+fn next_webhook_retry_time() -> Option<u64> {
+    WEBHOOK_RETRIES.with(|retries| {
+        retries
+            .borrow()
+            .iter()
+            .map(|delivery| delivery.next_attempt_at)
+            .min()
+    })
+}
+
+/// Generate a new HMAC key for a market's webhook (creator only); returned once
+/// Receivers verify `X-Webhook-Signature: sha256=<hex HMAC-SHA256 of the body>`
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn rotate_webhook_secret(market_id: u64) -> Result<String, PredictionMarketError> {
+    let caller_principal = caller();
+    let is_creator = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| market.creator == caller_principal)
+            .ok_or(PredictionMarketError::MarketNotFound)
+    })?;
+    if !is_creator {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| PredictionMarketError::CallFailed(message))?;
+    let secret = hex(&random_bytes);
+    WEBHOOK_SECRETS.with(|secrets| secrets.borrow_mut().insert(market_id, secret.clone()));
+    Ok(secret)
+}
+
+/// Restrict webhooks to these hosts (admin only); an empty list allows every HTTPS host
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_webhook_allowlist(hosts: Vec<String>) -> Result<String, PredictionMarketError> {
+    process_set_webhook_allowlist(caller(), hosts, ic_cdk::api::time())
+}
+
+fn process_set_webhook_allowlist(
+    caller_principal: Principal,
+    hosts: Vec<String>,
+    now: u64,
+) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let hosts: Vec<String> = hosts
+        .iter()
+        .map(|host| host.trim().to_lowercase())
+        .collect();
+    let valid = hosts.len() <= MAX_WEBHOOK_ALLOWLIST
+        && hosts.iter().all(|host| {
+            !host.is_empty()
+                && !host.contains(['/', ':', '@'])
+                && !host.contains(char::is_whitespace)
+        });
+    if !valid {
+        return Err(PredictionMarketError::InvalidCallbackTarget);
+    }
+    WEBHOOK_ALLOWLIST.with(|allowlist| *allowlist.borrow_mut() = hosts.clone());
+    let count = hosts.len();
+    emit_event(now, EventKind::WebhookAllowlistChanged { hosts });
+    Ok(format!("{} webhook hosts allowed", count))
+}
+
+#[ic_cdk::query]
+fn get_webhook_allowlist() -> Vec<String> {
+    WEBHOOK_ALLOWLIST.with(|allowlist| allowlist.borrow().clone())
+}
+
+fn is_webhook_host_allowed(url: &str) -> bool {
+    let host = url
+        .strip_prefix("https://")
+        .and_then(|rest| rest.split('/').next())
+        .and_then(|authority| authority.rsplit('@').next())
+        .and_then(|authority| authority.split(':').next())
+        .unwrap_or_default()
+        .to_lowercase();
+    WEBHOOK_ALLOWLIST.with(|allowlist| {
+        let allowlist = allowlist.borrow();
+        allowlist.is_empty() || allowlist.contains(&host)
+    })
+}
+
+/// HMAC-SHA256 (RFC 2104) over the sha2 digest
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|key_byte| key_byte ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Keep only the status of a webhook response so every replica agrees on it
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> OutcallResponse {
+    OutcallResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// =============================================================================
+// LIQUIDITY PROVISION
+// =============================================================================
+
+/// Number of LP shares minted for depositing `icp_amount` into a pool
+/// The first provider gets shares 1:1, later providers get shares proportional to the pool
+fn calculate_lp_shares(icp_amount: u64, pool: u64, total_shares: u64) -> u64 {
+    if pool == 0 || total_shares == 0 {
+        return icp_amount;
+    }
+    (icp_amount as u128 * total_shares as u128 / pool as u128) as u64
+}
+
+/// Scale a value by `numerator / denominator` using 128-bit intermediates
+fn scale_by(value: u64, numerator: u64, denominator: u64) -> u64 {
+    (value as u128 * numerator as u128 / denominator as u128) as u64
+}
+
+/// Add ICP liquidity to an open market in exchange for LP shares
+/// Reserves are deepened proportionally so the current price does not move
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn add_liquidity(market_id: u64, icp_amount: u64) -> Result<u64, PredictionMarketError> {
+    process_add_liquidity(caller(), market_id, icp_amount, ic_cdk::api::time())
+}
+
+fn process_add_liquidity(
+    caller_principal: Principal,
+    market_id: u64,
+    icp_amount: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_platform_active()?;
+    ensure_not_paused()?;
+
+    if icp_amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let user_balance =
+        USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0));
+
+    if user_balance < icp_amount {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: icp_amount,
+            available: user_balance,
+        });
+    }
+
+    let (shares, acc_fee_per_share) = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        match markets_map.get_mut(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+                if market.icp_liquidity_pool == 0 {
+                    return Err(PredictionMarketError::InsufficientLiquidity { max_trade: 0 });
+                }
+
+                // Priced against LP capital, the same base withdrawals pay out of
+                let shares =
+                    calculate_lp_shares(icp_amount, lp_capital(market), market.total_lp_shares);
+                if shares == 0 {
+                    return Err(PredictionMarketError::InvalidAmount);
+                }
+
+                let new_pool = market.icp_liquidity_pool + icp_amount;
+                market.yes_reserve =
+                    scale_by(market.yes_reserve, new_pool, market.icp_liquidity_pool);
+                market.no_reserve =
+                    scale_by(market.no_reserve, new_pool, market.icp_liquidity_pool);
+                market.icp_liquidity_pool = new_pool;
+                market.total_lp_shares += shares;
+
+                Ok((shares, market.acc_fee_per_share))
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })?;
+
+    USER_BALANCES.with(|balances| {
+        balances
+            .borrow_mut()
+            .insert(caller_principal, user_balance - icp_amount);
+    });
+
+    LP_ACCOUNTS.with(|accounts| {
+        let mut accounts_map = accounts.borrow_mut();
+        let account = accounts_map
+            .entry((caller_principal, market_id))
+            .or_default();
+        settle_lp_fees(account, acc_fee_per_share);
+        account.shares += shares;
+        account.fee_debt = accrued_fees(account.shares, acc_fee_per_share);
+    });
+
+    emit_event(
+        now,
+        EventKind::LiquidityAdded {
+            market_id,
+            provider: caller_principal,
+            icp_amount,
+            shares,
+        },
+    );
+
+    Ok(shares)
+}
+
+/// Burn LP shares and withdraw the matching portion of the LP-owned ICP in the pool
+/// Traders' net ICP stays behind to back their outcome tokens
+/// Reserves shrink proportionally; the last outstanding shares cannot be withdrawn
+/// so the market always keeps liquidity to trade and pay out against
+/// Withdrawing before close forfeits part of the withdrawn shares' unclaimed fees
+/// to the remaining LPs, per the market's `early_exit_haircut` schedule
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn remove_liquidity(market_id: u64, shares: u64) -> Result<u64, PredictionMarketError> {
+    process_remove_liquidity(caller(), market_id, shares, ic_cdk::api::time())
+}
+
+fn process_remove_liquidity(
+    caller_principal: Principal,
+    market_id: u64,
+    shares: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_paused()?;
+    if shares == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let owned_shares = LP_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .map_or(0, |account| account.shares)
+    });
+
+    if owned_shares < shares {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let (icp_out, acc_fee_per_share, haircut_bps) = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        match markets_map.get_mut(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+                let icp_out = withdraw_pool_share(market, shares)?;
+
+                Ok((
+                    icp_out,
+                    market.acc_fee_per_share,
+                    exit_haircut_bps(market, now),
+                ))
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })?;
+
+    let forfeited = LP_ACCOUNTS.with(|accounts| {
+        let mut accounts_map = accounts.borrow_mut();
+        let Some(account) = accounts_map.get_mut(&(caller_principal, market_id)) else {
+            return 0;
+        };
+        settle_lp_fees(account, acc_fee_per_share);
+        // Only the fees attributable to the withdrawn shares are haircut
+        let withdrawn_fees = scale_by(account.unclaimed_fees, shares, owned_shares);
+        let forfeited = scale_by(withdrawn_fees, haircut_bps, 10_000);
+        account.unclaimed_fees -= forfeited;
+        account.shares -= shares;
+        forfeited
+    });
+
+    // Forfeited fees go to the remaining shares; the leaver's own debt is set afterwards
+    // so they do not earn back part of what they gave up
+    let acc_fee_per_share = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .expect("market checked above");
+        accrue_lp_fees(market, forfeited);
+        market.acc_fee_per_share
+    });
+    LP_ACCOUNTS.with(|accounts| {
+        if let Some(account) = accounts
+            .borrow_mut()
+            .get_mut(&(caller_principal, market_id))
+        {
+            account.fee_debt = accrued_fees(account.shares, acc_fee_per_share);
+        }
+    });
+
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + icp_out);
+    });
+
+    emit_event(
+        now,
+        EventKind::LiquidityRemoved {
+            market_id,
+            provider: caller_principal,
+            icp_amount: icp_out,
+            shares,
+            fees_forfeited: forfeited,
+        },
+    );
+
+    Ok(icp_out)
+}
+
+/// Part of the pool liquidity providers own; the rest is traders' net ICP, which backs
+/// their outcome tokens and is only paid out to winners or as refunds
+fn lp_capital(market: &AmmMarket) -> u64 {
+    market
+        .icp_liquidity_pool
+        .saturating_sub(total_contributions(market.id))
+}
+
+/// (ICP out, pool, YES reserve, NO reserve) after `shares` leave the pool,
+/// or None if they are all the shares or would empty a reserve
+/// Shares only ever pay out of `capital`, so trader collateral stays in the pool
+fn pool_after_withdrawal(
+    market: &AmmMarket,
+    shares: u64,
+    capital: u64,
+) -> Option<(u64, u64, u64, u64)> {
+    if shares >= market.total_lp_shares {
+        return None;
+    }
+    let icp_out = scale_by(capital, shares, market.total_lp_shares);
+    let new_pool = market.icp_liquidity_pool - icp_out;
+    let new_yes_reserve = scale_by(market.yes_reserve, new_pool, market.icp_liquidity_pool);
+    let new_no_reserve = scale_by(market.no_reserve, new_pool, market.icp_liquidity_pool);
+    if new_yes_reserve == 0 || new_no_reserve == 0 {
+        return None;
+    }
+    Some((icp_out, new_pool, new_yes_reserve, new_no_reserve))
+}
+
+/// Take `shares`' portion of the LP capital out of an open market, shrinking reserves proportionally
+/// The last outstanding shares stay, so the market keeps liquidity to trade against
+fn withdraw_pool_share(market: &mut AmmMarket, shares: u64) -> Result<u64, PredictionMarketError> {
+    let capital = lp_capital(market);
+    let Some((icp_out, new_pool, new_yes_reserve, new_no_reserve)) =
+        pool_after_withdrawal(market, shares, capital)
+    else {
+        let max_trade = largest_fitting(market.total_lp_shares.saturating_sub(1), |shares| {
+            pool_after_withdrawal(market, shares, capital).is_some()
+        });
+        return Err(PredictionMarketError::InsufficientLiquidity { max_trade });
+    };
+
+    market.yes_reserve = new_yes_reserve;
+    market.no_reserve = new_no_reserve;
+    market.icp_liquidity_pool = new_pool;
+    market.total_lp_shares -= shares;
+    Ok(icp_out)
+}
+
+/// Schedule applied to new markets: half of unclaimed fees in the final day, a fifth in the final week
+fn default_exit_haircut() -> Vec<HaircutTier> {
+    vec![
+        HaircutTier {
+            within_ns: NANOS_PER_DAY,
+            haircut_bps: 5_000,
+        },
+        HaircutTier {
+            within_ns: 7 * NANOS_PER_DAY,
+            haircut_bps: 2_000,
+        },
+    ]
+}
+
+/// Haircut for an LP leaving at `now`: the steepest tier whose window has been reached
+fn exit_haircut_bps(market: &AmmMarket, now: u64) -> u64 {
+    let remaining = market.close_time.saturating_sub(now);
+    market
+        .early_exit_haircut
+        .iter()
+        .filter(|tier| remaining <= tier.within_ns)
+        .map(|tier| tier.haircut_bps)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Schedule a replacement for a market's early-exit haircut schedule (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_early_exit_haircut(
+    market_id: u64,
+    schedule: Vec<HaircutTier>,
+) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetEarlyExitHaircut {
+            market_id,
+            schedule,
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn validate_haircut_schedule(schedule: &[HaircutTier]) -> Result<(), PredictionMarketError> {
+    if schedule
+        .iter()
+        .any(|tier| tier.haircut_bps > MAX_EXIT_HAIRCUT_BPS)
+    {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn process_set_early_exit_haircut(
+    caller_principal: Principal,
+    market_id: u64,
+    schedule: Vec<HaircutTier>,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_haircut_schedule(&schedule)?;
+
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        market.early_exit_haircut = schedule;
+        Ok(())
+    })?;
+    emit_market_settings_changed(market_id, "early_exit_haircut", now);
+    Ok(())
+}
+
+fn emit_market_settings_changed(market_id: u64, setting: &str, now: u64) {
+    emit_event(
+        now,
+        EventKind::MarketSettingsChanged {
+            market_id,
+            setting: setting.to_string(),
+        },
+    );
+}
+
+/// Get the caller's liquidity position in a market
+#[ic_cdk::query]
+fn get_liquidity_position(market_id: u64) -> Option<LiquidityPosition> {
+    let caller_principal = caller();
+    let mut account = LP_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .cloned()
+    })?;
+    let (total_shares, acc_fee_per_share) = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| (market.total_lp_shares, market.acc_fee_per_share))
+    })?;
+    settle_lp_fees(&mut account, acc_fee_per_share);
+
+    Some(LiquidityPosition {
+        provider: caller_principal,
+        market_id,
+        shares: account.shares,
+        share_of_pool: if total_shares > 0 {
+            account.shares as f64 / total_shares as f64
+        } else {
+            0.0
+        },
+        claimable_fees: account.unclaimed_fees,
+    })
+}
+
+// =============================================================================
+// LP FEE DISTRIBUTION
+// =============================================================================
+
+/// Split a trading fee between the protocol treasury, the market creator and the liquidity providers
+/// The trader's referrer, if any, gets a kickback out of the treasury's cut
+fn distribute_trade_fee(market: &mut AmmMarket, fee: u64, trader: Principal) {
+    let protocol_cut = fee * PROTOCOL_FEE_BPS / 10_000;
+    let creator_cut = fee * market.creator_fee_bps / 10_000;
+    let kickback = credit_referral_kickback(trader, protocol_cut);
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += protocol_cut - kickback);
+    market.creator_fees_accrued += creator_cut;
+    accrue_lp_fees(market, fee - protocol_cut - creator_cut);
+}
+
+/// Spread a trading fee across all outstanding LP shares of a market
+/// Runs in O(1) regardless of the number of providers
+fn accrue_lp_fees(market: &mut AmmMarket, fee: u64) {
+    if market.total_lp_shares == 0 || fee == 0 {
+        return;
+    }
+    market.acc_fee_per_share += fee as u128 * FEE_ACC_PRECISION / market.total_lp_shares as u128;
+}
+
+/// Fees owed to `shares` under the given accumulator value
+fn accrued_fees(shares: u64, acc_fee_per_share: u128) -> u128 {
+    shares as u128 * acc_fee_per_share / FEE_ACC_PRECISION
+}
+
+/// Move everything accrued since the last settlement into `unclaimed_fees`
+/// Must be called before an account's share count changes
+fn settle_lp_fees(account: &mut LpAccount, acc_fee_per_share: u128) {
+    let accrued = accrued_fees(account.shares, acc_fee_per_share);
+    account.unclaimed_fees += accrued.saturating_sub(account.fee_debt) as u64;
+    account.fee_debt = accrued;
+}
+
+/// Claim the caller's share of trading fees earned as a liquidity provider
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_lp_fees(market_id: u64) -> Result<u64, PredictionMarketError> {
+    process_claim_lp_fees(caller(), market_id, ic_cdk::api::time())
+}
+
+fn process_claim_lp_fees(
+    caller_principal: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_paused()?;
+
+    let acc_fee_per_share = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| market.acc_fee_per_share)
+            .ok_or(PredictionMarketError::MarketNotFound)
+    })?;
+
+    let amount = LP_ACCOUNTS.with(|accounts| {
+        let mut accounts_map = accounts.borrow_mut();
+        let account = accounts_map
+            .get_mut(&(caller_principal, market_id))
+            .ok_or(PredictionMarketError::NothingToClaim)?;
+        settle_lp_fees(account, acc_fee_per_share);
+        if account.unclaimed_fees == 0 {
+            return Err(PredictionMarketError::NothingToClaim);
+        }
+        Ok(std::mem::take(&mut account.unclaimed_fees))
+    })?;
+
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + amount);
+    });
+
+    emit_event(
+        now,
+        EventKind::LpFeesClaimed {
+            market_id,
+            provider: caller_principal,
+            amount,
+        },
+    );
+
+    Ok(amount)
+}
+
+// =============================================================================
+// CREATOR FEES
+// =============================================================================
+
+/// Claim the creator's accrued share of trading fees on a market
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_creator_fees(market_id: u64) -> Result<u64, PredictionMarketError> {
+    process_claim_creator_fees(caller(), market_id, ic_cdk::api::time())
+}
+
+fn process_claim_creator_fees(
+    caller_principal: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_paused()?;
+
+    let amount = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if market.creator != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if market.creator_fees_accrued == 0 {
+            return Err(PredictionMarketError::NothingToClaim);
+        }
+        Ok(std::mem::take(&mut market.creator_fees_accrued))
+    })?;
+
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + amount);
+    });
+
+    emit_event(
+        now,
+        EventKind::CreatorFeesClaimed {
+            market_id,
+            creator: caller_principal,
+            amount,
+        },
+    );
+
+    Ok(amount)
+}
+
+/// Schedule a change to the creator's share of trading fees on a market (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_creator_fee_bps(
+    market_id: u64,
+    creator_fee_bps: u64,
+) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetCreatorFeeBps {
+            market_id,
+            creator_fee_bps,
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_creator_fee_bps(
+    caller_principal: Principal,
+    market_id: u64,
+    creator_fee_bps: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if creator_fee_bps > MAX_CREATOR_FEE_BPS {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        market.creator_fee_bps = creator_fee_bps;
+        Ok(())
+    })?;
+    emit_market_settings_changed(market_id, "creator_fee_bps", now);
+    Ok(())
+}
+
+// =============================================================================
+// REGION COMPLIANCE
+// =============================================================================
+
+/// Normalize a region code to upper-case ISO 3166-1 alpha-2
+fn normalize_region(region: &str) -> Result<String, PredictionMarketError> {
+    let code = region.trim().to_ascii_uppercase();
+    if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(PredictionMarketError::InvalidRegion);
+    }
+    Ok(code)
+}
+
+/// Check a user's attested region against a market's restrictions
+/// Only new exposure is blocked; restricted users can still sell out of existing positions
+fn check_region_allowed(market: &AmmMarket, user: Principal) -> Result<(), PredictionMarketError> {
+    if market.restricted_regions.is_empty() && !market.require_attested_region {
+        return Ok(());
+    }
+    match USER_REGIONS.with(|regions| regions.borrow().get(&user).cloned()) {
+        Some(region) if market.restricted_regions.contains(&region) => {
+            Err(PredictionMarketError::RegionRestricted)
+        }
+        Some(_) => Ok(()),
+        None if market.require_attested_region => Err(PredictionMarketError::RegionRestricted),
+        None => Ok(()),
+    }
+}
+
+/// Record the region a user has been verified in, or clear it (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn attest_user_region(
+    user: Principal,
+    region: Option<String>,
+) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+
+    let (region, message) = match region {
+        Some(region) => {
+            let code = normalize_region(&region)?;
+            USER_REGIONS.with(|regions| regions.borrow_mut().insert(user, code.clone()));
+            let message = format!("Region of {} attested as {}", user, code);
+            (Some(code), message)
+        }
+        None => {
+            USER_REGIONS.with(|regions| regions.borrow_mut().remove(&user));
+            (None, format!("Region attestation of {} cleared", user))
+        }
+    };
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::RegionAttested { user, region },
+    );
+    Ok(message)
+}
+
+/// Configure which regions may buy into a market (global admin or market admin)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_market_region_restrictions(
+    market_id: u64,
+    restricted_regions: Vec<String>,
+    require_attested_region: bool,
+) -> Result<String, PredictionMarketError> {
+    let caller_principal = caller();
+
+    let mut codes = restricted_regions
+        .iter()
+        .map(|region| normalize_region(region))
+        .collect::<Result<Vec<_>, _>>()?;
+    codes.sort();
+    codes.dedup();
+
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !is_global_admin(caller_principal) && market.admin != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        market.restricted_regions = codes;
+        market.require_attested_region = require_attested_region;
+        Ok(())
+    })?;
+    emit_market_settings_changed(market_id, "region_restrictions", ic_cdk::api::time());
+    Ok(format!(
+        "Region restrictions updated for market {}",
+        market_id
+    ))
+}
+
+/// Get the caller's attested region, if any
+#[ic_cdk::query]
+fn get_my_region() -> Option<String> {
+    let caller_principal = caller();
+    USER_REGIONS.with(|regions| regions.borrow().get(&caller_principal).cloned())
+}
+
+// =============================================================================
+// OTC SWAP ESCROW
+// =============================================================================
+
+/// Token legs can only move while their market is still trading
+fn validate_swap_asset(asset: &SwapAsset, now: u64) -> Result<(), PredictionMarketError> {
+    match asset {
+        SwapAsset::Icp { amount } => {
+            if *amount == 0 {
+                return Err(PredictionMarketError::InvalidAmount);
+            }
+            Ok(())
+        }
+        SwapAsset::Tokens {
+            market_id, amount, ..
+        } => {
+            if *amount == 0 {
+                return Err(PredictionMarketError::InvalidAmount);
+            }
+            ensure_trading_open(*market_id, now)
+        }
+    }
+}
+
+/// Take an asset away from a principal, failing without side effects if they do not hold it
+fn debit_swap_asset(owner: Principal, asset: &SwapAsset) -> Result<(), PredictionMarketError> {
+    match asset {
+        SwapAsset::Icp { amount } => USER_BALANCES.with(|balances| {
+            let mut balances_map = balances.borrow_mut();
+            let current_balance = *balances_map.get(&owner).unwrap_or(&0);
+            if current_balance < *amount {
+                return Err(PredictionMarketError::InsufficientDeposit {
+                    required: *amount,
+                    available: current_balance,
+                });
+            }
+            balances_map.insert(owner, current_balance - amount);
+            Ok(())
+        }),
+        SwapAsset::Tokens {
+            market_id,
+            token_type,
+            amount,
+        } => USER_POSITIONS.with(|positions| {
+            let mut positions_map = positions.borrow_mut();
+            let position = positions_map
+                .get_mut(&(owner, *market_id))
+                .ok_or(PredictionMarketError::InvalidAmount)?;
+            let held = match token_type {
+                TokenType::Yes => &mut position.yes_tokens,
+                TokenType::No => &mut position.no_tokens,
+            };
+            if *held < *amount {
+                return Err(PredictionMarketError::InvalidAmount);
+            }
+            *held -= amount;
+            Ok(())
+        }),
+    }
+}
+
+/// Give an asset to a principal
+fn credit_swap_asset(owner: Principal, asset: &SwapAsset) {
+    match asset {
+        SwapAsset::Icp { amount } => USER_BALANCES.with(|balances| {
+            let mut balances_map = balances.borrow_mut();
+            let current_balance = *balances_map.get(&owner).unwrap_or(&0);
+            balances_map.insert(owner, current_balance + amount);
+        }),
+        SwapAsset::Tokens {
+            market_id,
+            token_type,
+            amount,
+        } => USER_POSITIONS.with(|positions| {
+            let mut positions_map = positions.borrow_mut();
+            let position = positions_map
+                .entry((owner, *market_id))
+                .or_insert(UserPosition {
+                    user: owner,
+                    market_id: *market_id,
+                    yes_tokens: 0,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                });
+            match token_type {
+                TokenType::Yes => position.yes_tokens += amount,
+                TokenType::No => position.no_tokens += amount,
+            }
+        }),
+    }
+}
+
+/// Offer `give` in exchange for `want`; the offered asset is moved into escrow immediately
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn create_swap_offer(give: SwapAsset, want: SwapAsset) -> Result<u64, PredictionMarketError> {
+    process_create_swap_offer(caller(), give, want, ic_cdk::api::time())
+}
+
+/// Put `amount` tokens up for sale at `ask_price` e8s for the whole block, outside the AMM
+/// The tokens sit in escrow until a buyer accepts or the seller cancels with `cancel_swap_offer`
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn create_sell_offer(
+    market_id: u64,
+    token_type: TokenType,
+    amount: u64,
+    ask_price: u64,
+) -> Result<u64, PredictionMarketError> {
+    process_create_swap_offer(
+        caller(),
+        SwapAsset::Tokens {
+            market_id,
+            token_type,
+            amount,
+        },
+        SwapAsset::Icp { amount: ask_price },
+        ic_cdk::api::time(),
+    )
+}
+
+fn process_create_swap_offer(
+    caller_principal: Principal,
+    give: SwapAsset,
+    want: SwapAsset,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_platform_active()?;
+    ensure_not_paused()?;
+    validate_swap_asset(&give, now)?;
+    validate_swap_asset(&want, now)?;
+    debit_swap_asset(caller_principal, &give)?;
+
+    let offer_id = NEXT_OFFER_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+
+    SWAP_OFFERS.with(|offers| {
+        offers.borrow_mut().insert(
+            offer_id,
+            SwapOffer {
+                id: offer_id,
+                maker: caller_principal,
+                give,
+                want,
+                status: SwapOfferStatus::Open,
+                created_at: now,
+            },
+        );
+    });
+    emit_event(
+        now,
+        EventKind::SwapOfferCreated {
+            offer_id,
+            maker: caller_principal,
+        },
+    );
+
+    Ok(offer_id)
+}
+
+/// Accept an open offer: the caller pays `want` to the maker and receives the escrowed `give`
+/// Both legs settle in the same message, so either both happen or neither does
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn accept_swap_offer(offer_id: u64) -> Result<SwapOffer, PredictionMarketError> {
+    process_accept_swap_offer(caller(), offer_id, ic_cdk::api::time())
+}
+
+/// Buy the tokens of a sell offer at its ask price
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn accept_offer(offer_id: u64) -> Result<SwapOffer, PredictionMarketError> {
+    process_accept_swap_offer(caller(), offer_id, ic_cdk::api::time())
+}
+
+fn process_accept_swap_offer(
+    caller_principal: Principal,
+    offer_id: u64,
+    now: u64,
+) -> Result<SwapOffer, PredictionMarketError> {
+    ensure_not_paused()?;
+    let offer = SWAP_OFFERS
+        .with(|offers| offers.borrow().get(&offer_id).cloned())
+        .ok_or(PredictionMarketError::OfferNotFound)?;
+
+    if offer.status != SwapOfferStatus::Open {
+        return Err(PredictionMarketError::OfferNotOpen);
+    }
+    if offer.maker == caller_principal {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+
+    validate_swap_asset(&offer.give, now)?;
+    validate_swap_asset(&offer.want, now)?;
+    debit_swap_asset(caller_principal, &offer.want)?;
+    credit_swap_asset(offer.maker, &offer.want);
+    credit_swap_asset(caller_principal, &offer.give);
+    record_swap_pnl(&offer, caller_principal, now);
+
+    let filled = SWAP_OFFERS.with(|offers| {
+        let mut offers_map = offers.borrow_mut();
+        let stored = offers_map
+            .get_mut(&offer_id)
+            .expect("offer existence checked above");
+        stored.status = SwapOfferStatus::Filled {
+            taker: caller_principal,
+            filled_at: now,
+        };
+        stored.clone()
+    });
+    emit_event(
+        now,
+        EventKind::SwapOfferAccepted {
+            offer_id,
+            taker: caller_principal,
+        },
+    );
+    Ok(filled)
+}
+
+/// Cancel an open offer and return the escrowed asset to its maker
+/// Works even after the underlying market has closed so escrow is never stranded
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_swap_offer(offer_id: u64) -> Result<SwapOffer, PredictionMarketError> {
+    process_cancel_swap_offer(caller(), offer_id, ic_cdk::api::time())
+}
+
+fn process_cancel_swap_offer(
+    caller_principal: Principal,
+    offer_id: u64,
+    now: u64,
+) -> Result<SwapOffer, PredictionMarketError> {
+    let offer = SWAP_OFFERS.with(|offers| {
+        let mut offers_map = offers.borrow_mut();
+        let offer = offers_map
+            .get_mut(&offer_id)
+            .ok_or(PredictionMarketError::OfferNotFound)?;
+        if offer.maker != caller_principal {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if offer.status != SwapOfferStatus::Open {
+            return Err(PredictionMarketError::OfferNotOpen);
+        }
+        offer.status = SwapOfferStatus::Cancelled;
+        Ok(offer.clone())
+    })?;
+
+    credit_swap_asset(offer.maker, &offer.give);
+    emit_event(now, EventKind::SwapOfferCancelled { offer_id });
+
+    Ok(offer)
+}
+
+/// Book a tokens-for-ICP swap in both parties' realized PnL, like an AMM trade
+fn record_swap_pnl(offer: &SwapOffer, taker: Principal, now: u64) {
+    match (&offer.give, &offer.want) {
+        (SwapAsset::Tokens { market_id, .. }, SwapAsset::Icp { amount }) => {
+            record_pnl(offer.maker, *market_id, 0, *amount, now);
+            record_pnl(taker, *market_id, *amount, 0, now);
+        }
+        (SwapAsset::Icp { amount }, SwapAsset::Tokens { market_id, .. }) => {
+            record_pnl(offer.maker, *market_id, *amount, 0, now);
+            record_pnl(taker, *market_id, 0, *amount, now);
+        }
+        _ => {}
+    }
+}
+
+/// List open swap offers, optionally restricted to those involving a market
+#[ic_cdk::query]
+fn get_open_swap_offers(market_id: Option<u64>, cursor: Option<u64>) -> Page<SwapOffer> {
+    let involves = |asset: &SwapAsset, id: u64| matches!(asset, SwapAsset::Tokens { market_id, .. } if *market_id == id);
+    SWAP_OFFERS.with(|offers| {
+        let keyed = offers
+            .borrow()
+            .values()
+            .filter(|offer| offer.status == SwapOfferStatus::Open)
+            .filter(|offer| {
+                market_id.is_none_or(|id| involves(&offer.give, id) || involves(&offer.want, id))
+            })
+            .map(|offer| (offer.id, offer.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+// =============================================================================
+// ICRC-1 OUTCOME TOKENS
+// =============================================================================
+// Every (market, side) pair is exposed as an ICRC-1 token. Balances are read from and
+// written to USER_POSITIONS directly, so there is no second ledger to reconcile.
+// Transfers move tokens only: refund rights on invalid or cancelled markets stay with
+// the principal who paid ICP in.
+
+/// ICRC-1 metadata of an outcome token
+#[ic_cdk::query]
+fn outcome_token_metadata(
+    token: OutcomeToken,
+) -> Result<Vec<(String, MetadataValue)>, PredictionMarketError> {
+    let side = match token.token_type {
+        TokenType::Yes => "YES",
+        TokenType::No => "NO",
+    };
+    let title = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&token.market_id)
+            .map(|market| market.title.clone())
+            .ok_or(PredictionMarketError::MarketNotFound)
+    })?;
+    Ok(vec![
+        (
+            "icrc1:name".to_string(),
+            MetadataValue::Text(format!("{} ({})", title, side)),
+        ),
+        (
+            "icrc1:symbol".to_string(),
+            MetadataValue::Text(format!("M{}-{}", token.market_id, side)),
+        ),
+        (
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(8u8)),
+        ),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(0u8))),
+    ])
+}
+
+/// ICRC-1 `icrc1_balance_of` for an outcome token
+#[ic_cdk::query]
+fn outcome_token_balance_of(token: OutcomeToken, account: Account) -> Nat {
+    if !is_default_subaccount(&account.subaccount) {
+        return Nat::from(0u8);
+    }
+    Nat::from(outcome_balance(&token, account.owner))
+}
+
+/// ICRC-1 `icrc1_total_supply`: every token of that side held by a user or sealed in a receipt
+#[ic_cdk::query]
+fn outcome_token_total_supply(token: OutcomeToken) -> Nat {
+    Nat::from(winning_supply(token.market_id, &token.token_type))
+}
+
+/// ICRC-1 `icrc1_fee`: outcome token transfers are free
+#[ic_cdk::query]
+fn outcome_token_fee(_token: OutcomeToken) -> Nat {
+    Nat::from(0u8)
+}
+
+/// ICRC-1 `icrc1_transfer` for an outcome token; returns the block index
+/// Like OTC swaps, tokens can only move while their market is trading
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn outcome_token_transfer(token: OutcomeToken, arg: TransferArg) -> Result<Nat, TransferError> {
+    process_outcome_transfer(caller(), token, arg, ic_cdk::api::time())
+}
+
+fn process_outcome_transfer(
+    caller_principal: Principal,
+    token: OutcomeToken,
+    arg: TransferArg,
+    now: u64,
+) -> Result<Nat, TransferError> {
+    if ensure_not_paused().is_err() {
+        return Err(TransferError::TemporarilyUnavailable);
+    }
+    if !is_default_subaccount(&arg.from_subaccount) || !is_default_subaccount(&arg.to.subaccount) {
+        return Err(generic_transfer_error(
+            1,
+            "Only default subaccounts hold outcome tokens",
+        ));
+    }
+    if arg
+        .fee
+        .as_ref()
+        .is_some_and(|fee| u64::try_from(&fee.0) != Ok(0))
+    {
+        return Err(TransferError::BadFee {
+            expected_fee: Nat::from(0u8),
+        });
+    }
+    if let Some(created_at_time) = arg.created_at_time {
+        if created_at_time + OUTCOME_TX_WINDOW_NS + OUTCOME_TX_DRIFT_NS < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at_time > now + OUTCOME_TX_DRIFT_NS {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    let amount = u64::try_from(&arg.amount.0)
+        .map_err(|_| generic_transfer_error(2, "Amount exceeds the token supply"))?;
+    if let Err(e) = ensure_trading_open(token.market_id, now) {
+        return Err(generic_transfer_error(3, &format!("{:?}", e)));
+    }
+
+    let to = arg.to.owner;
+    let duplicate_of = arg.created_at_time.and_then(|created_at_time| {
+        OUTCOME_TRANSFERS.with(|transfers| {
+            transfers
+                .borrow()
+                .iter()
+                .rev()
+                .take_while(|transfer| transfer.timestamp + OUTCOME_TX_WINDOW_NS >= now)
+                .find(|transfer| {
+                    transfer.created_at_time == Some(created_at_time)
+                        && transfer.token == token
+                        && transfer.from == caller_principal
+                        && transfer.to == to
+                        && transfer.amount == amount
+                        && transfer.memo == arg.memo
+                })
+                .map(|transfer| transfer.id)
+        })
+    });
+    if let Some(duplicate_of) = duplicate_of {
+        return Err(TransferError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        });
+    }
+
+    let asset = SwapAsset::Tokens {
+        market_id: token.market_id,
+        token_type: token.token_type.clone(),
+        amount,
+    };
+    if debit_swap_asset(caller_principal, &asset).is_err() {
+        return Err(TransferError::InsufficientFunds {
+            balance: Nat::from(outcome_balance(&token, caller_principal)),
+        });
+    }
+    credit_swap_asset(to, &asset);
+
+    let id = OUTCOME_TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        let id = transfers.len() as u64;
+        transfers.push(OutcomeTransfer {
+            id,
+            token: token.clone(),
+            from: caller_principal,
+            to,
+            amount,
+            memo: arg.memo,
+            created_at_time: arg.created_at_time,
+            timestamp: now,
+        });
+        id
+    });
+    emit_event(
+        now,
+        EventKind::OutcomeTokensTransferred {
+            market_id: token.market_id,
+            token_type: token.token_type,
+            from: caller_principal,
+            to,
+            amount,
+        },
+    );
+    Ok(Nat::from(id))
+}
+
+fn outcome_balance(token: &OutcomeToken, owner: Principal) -> u64 {
+    USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(owner, token.market_id))
+            .map_or(0, |position| match token.token_type {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            })
+    })
+}
+
+/// ICRC-1 treats a missing subaccount and 32 zero bytes as the same default account
+fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    subaccount
+        .as_ref()
+        .is_none_or(|bytes| bytes.len() == 32 && bytes.iter().all(|byte| *byte == 0))
+}
+
+fn generic_transfer_error(error_code: u8, message: &str) -> TransferError {
+    TransferError::GenericError {
+        error_code: Nat::from(error_code),
+        message: message.to_string(),
+    }
+}
+
+// =============================================================================
+// ICRC-7 POSITION RECEIPTS
+// =============================================================================
+// Wrapping seals outcome tokens into a transferable ICRC-7 receipt. The tokens keep
+// counting toward the market's supply while sealed. Wrapping, unwrapping and receipt
+// transfers stop with trading, so votes cannot be recycled through receipts; receipts
+// still held then are redeemed by the holder's reward claim.
+
+/// Seal `amount` of the caller's tokens into a receipt; returns the receipt's token id
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn wrap_position(
+    market_id: u64,
+    token_type: TokenType,
+    amount: u64,
+) -> Result<u64, PredictionMarketError> {
+    process_wrap_position(caller(), market_id, token_type, amount, ic_cdk::api::time())
+}
+
+fn process_wrap_position(
+    caller_principal: Principal,
+    market_id: u64,
+    token_type: TokenType,
+    amount: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_paused()?;
+    if amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    ensure_trading_open(market_id, now)?;
+
+    let token = OutcomeToken {
+        market_id,
+        token_type: token_type.clone(),
+    };
+    let entry_price = match average_entry_price(caller_principal, &token) {
+        Some(price) => price,
+        None => get_token_price(market_id, token_type.clone())?,
+    };
+    debit_swap_asset(
+        caller_principal,
+        &SwapAsset::Tokens {
+            market_id,
+            token_type: token_type.clone(),
+            amount,
+        },
+    )?;
+
+    let id = NEXT_RECEIPT_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    POSITION_RECEIPTS.with(|receipts| {
+        receipts.borrow_mut().insert(
+            id,
+            PositionReceipt {
+                id,
+                owner: caller_principal,
+                market_id,
+                token_type,
+                amount,
+                entry_price,
+                wrapped_at: now,
+            },
+        )
+    });
+    emit_event(
+        now,
+        EventKind::PositionWrapped {
+            receipt_id: id,
+            market_id,
+            owner: caller_principal,
+            amount,
+        },
+    );
+    Ok(id)
+}
+
+/// Burn a receipt the caller owns and return its tokens to the caller's position
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn unwrap_position(receipt_id: u64) -> Result<UserPosition, PredictionMarketError> {
+    process_unwrap_position(caller(), receipt_id, ic_cdk::api::time())
+}
+
+fn process_unwrap_position(
+    caller_principal: Principal,
+    receipt_id: u64,
+    now: u64,
+) -> Result<UserPosition, PredictionMarketError> {
+    let receipt = POSITION_RECEIPTS
+        .with(|receipts| receipts.borrow().get(&receipt_id).cloned())
+        .ok_or(PredictionMarketError::InvalidAmount)?;
+    if receipt.owner != caller_principal {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    ensure_not_paused()?;
+    ensure_trading_open(receipt.market_id, now)?;
+    Ok(release_receipt(&receipt, now))
+}
+
+/// Burn a receipt and credit its tokens to the receipt owner's position
+fn release_receipt(receipt: &PositionReceipt, now: u64) -> UserPosition {
+    POSITION_RECEIPTS.with(|receipts| receipts.borrow_mut().remove(&receipt.id));
+    credit_swap_asset(
+        receipt.owner,
+        &SwapAsset::Tokens {
+            market_id: receipt.market_id,
+            token_type: receipt.token_type.clone(),
+            amount: receipt.amount,
+        },
+    );
+    emit_event(
+        now,
+        EventKind::PositionUnwrapped {
+            receipt_id: receipt.id,
+            market_id: receipt.market_id,
+            owner: receipt.owner,
+            amount: receipt.amount,
+        },
+    );
+    USER_POSITIONS.with(|positions| positions.borrow()[&(receipt.owner, receipt.market_id)].clone())
+}
+
+/// Release every receipt `owner` holds on a market, once trading there has stopped
+fn release_receipts_of(owner: Principal, market_id: u64, now: u64) {
+    let held: Vec<PositionReceipt> = POSITION_RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .values()
+            .filter(|receipt| receipt.owner == owner && receipt.market_id == market_id)
+            .cloned()
+            .collect()
+    });
+    for receipt in held {
+        release_receipt(&receipt, now);
+    }
+}
+
+/// Get the receipts a principal owns
+#[ic_cdk::query]
+fn get_position_receipts(owner: Principal) -> Vec<PositionReceipt> {
+    POSITION_RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .values()
+            .filter(|receipt| receipt.owner == owner)
+            .cloned()
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        (
+            "icrc7:symbol".to_string(),
+            MetadataValue::Text("PMRCPT".to_string()),
+        ),
+        (
+            "icrc7:name".to_string(),
+            MetadataValue::Text("Prediction Market Position Receipts".to_string()),
+        ),
+        (
+            "icrc7:description".to_string(),
+            MetadataValue::Text(
+                "Sealed outcome token positions, redeemable with unwrap_position".to_string(),
+            ),
+        ),
+        (
+            "icrc7:total_supply".to_string(),
+            MetadataValue::Nat(icrc7_total_supply()),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(POSITION_RECEIPTS.with(|receipts| receipts.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, MetadataValue)>>> {
+    token_ids
+        .iter()
+        .map(|token_id| {
+            let receipt = receipt_by_nat(token_id)?;
+            let side = match receipt.token_type {
+                TokenType::Yes => "YES",
+                TokenType::No => "NO",
+            };
+            Some(vec![
+                (
+                    "icrc7:name".to_string(),
+                    MetadataValue::Text(format!("Market {} {} position", receipt.market_id, side)),
+                ),
+                (
+                    "market_id".to_string(),
+                    MetadataValue::Nat(Nat::from(receipt.market_id)),
+                ),
+                ("side".to_string(), MetadataValue::Text(side.to_string())),
+                (
+                    "amount".to_string(),
+                    MetadataValue::Nat(Nat::from(receipt.amount)),
+                ),
+                (
+                    "entry_price".to_string(),
+                    MetadataValue::Text(format!("{:.8}", receipt.entry_price)),
+                ),
+            ])
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .map(|token_id| {
+            receipt_by_nat(token_id).map(|receipt| Account {
+                owner: receipt.owner,
+                subaccount: None,
+            })
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .iter()
+        .map(|account| {
+            if !is_default_subaccount(&account.subaccount) {
+                return Nat::from(0u8);
+            }
+            Nat::from(receipt_ids_of(account.owner).len())
+        })
+        .collect()
+}
+
+/// Receipt ids in ascending order, starting after `prev`; `take` is capped at MAX_LIST_RESULTS
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let ids = POSITION_RECEIPTS.with(|receipts| receipts.borrow().keys().copied().collect());
+    page_receipt_ids(ids, prev, take)
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    if !is_default_subaccount(&account.subaccount) {
+        return Vec::new();
+    }
+    page_receipt_ids(receipt_ids_of(account.owner), prev, take)
+}
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn icrc7_transfer(args: Vec<NftTransferArg>) -> Vec<Option<Result<Nat, NftTransferError>>> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    args.into_iter()
+        .map(|arg| Some(process_receipt_transfer(caller_principal, arg, now)))
+        .collect()
+}
+
+fn process_receipt_transfer(
+    caller_principal: Principal,
+    arg: NftTransferArg,
+    now: u64,
+) -> Result<Nat, NftTransferError> {
+    if !is_default_subaccount(&arg.from_subaccount) || !is_default_subaccount(&arg.to.subaccount) {
+        return Err(NftTransferError::InvalidRecipient);
+    }
+    if arg.to.owner == Principal::anonymous() {
+        return Err(NftTransferError::InvalidRecipient);
+    }
+    let receipt = receipt_by_nat(&arg.token_id).ok_or(NftTransferError::NonExistingTokenId)?;
+    if receipt.owner != caller_principal {
+        return Err(NftTransferError::Unauthorized);
+    }
+    if let Err(e) = ensure_not_paused().and_then(|_| ensure_trading_open(receipt.market_id, now)) {
+        return Err(NftTransferError::GenericError {
+            error_code: Nat::from(1u8),
+            message: format!("{:?}", e),
+        });
+    }
+    POSITION_RECEIPTS.with(|receipts| {
+        if let Some(receipt) = receipts.borrow_mut().get_mut(&receipt.id) {
+            receipt.owner = arg.to.owner;
+        }
+    });
+    emit_event(
+        now,
+        EventKind::ReceiptTransferred {
+            receipt_id: receipt.id,
+            from: caller_principal,
+            to: arg.to.owner,
+        },
+    );
+    Ok(arg.token_id)
+}
+
+fn receipt_by_nat(token_id: &Nat) -> Option<PositionReceipt> {
+    let id = u64::try_from(&token_id.0).ok()?;
+    POSITION_RECEIPTS.with(|receipts| receipts.borrow().get(&id).cloned())
+}
+
+fn receipt_ids_of(owner: Principal) -> Vec<u64> {
+    POSITION_RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .values()
+            .filter(|receipt| receipt.owner == owner)
+            .map(|receipt| receipt.id)
+            .collect()
+    })
+}
+
+fn page_receipt_ids(ids: Vec<u64>, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let after = prev.and_then(|prev| u64::try_from(&prev.0).ok());
+    let take = take
+        .and_then(|take| usize::try_from(&take.0).ok())
+        .unwrap_or(MAX_LIST_RESULTS)
+        .min(MAX_LIST_RESULTS);
+    ids.into_iter()
+        .filter(|id| after.is_none_or(|after| *id > after))
+        .take(take)
+        .map(Nat::from)
+        .collect()
+}
+
+/// Tokens sealed in receipts for one side of a market
+fn wrapped_supply(market_id: u64, token_type: &TokenType) -> u64 {
+    POSITION_RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .values()
+            .filter(|receipt| receipt.market_id == market_id && receipt.token_type == *token_type)
+            .map(|receipt| receipt.amount)
+            .sum()
+    })
+}
+
+/// Average cost per token of a side, from the holder's position history
+fn average_entry_price(owner: Principal, token: &OutcomeToken) -> Option<f64> {
+    let (held, cost_basis) = POSITION_HISTORY.with(|history| {
+        history
+            .borrow()
+            .get(&(owner, token.market_id))
+            .and_then(|snapshots| snapshots.last())
+            .map(|last| match token.token_type {
+                TokenType::Yes => (last.yes_tokens, last.yes_cost_basis),
+                TokenType::No => (last.no_tokens, last.no_cost_basis),
+            })
+    })?;
+    (held > 0 && cost_basis > 0).then(|| cost_basis as f64 / held as f64)
+}
+
+// =============================================================================
+// ICRC-21 CONSENT MESSAGES
+// =============================================================================
+// Wallets ask for a readable description of a call before the user signs it. Trades are
+// described against a quote from current reserves, so the estimate is what the call would
+// get if it executed now; the minimum in the arguments is what it is guaranteed.
+
+/// ICRC-10 `icrc10_supported_standards`
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-21".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-21/ICRC-21.md".to_string(),
+        },
+    ]
+}
+
+/// ICRC-21 consent message for the trade and claim endpoints
+/// Messages are only written in English; other languages fall back to it
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn icrc21_canister_call_consent_message(
+    request: ConsentMessageRequest,
+) -> Result<ConsentInfo, ConsentError> {
+    build_consent_message(request, ic_cdk::api::time())
+}
+
+fn build_consent_message(
+    request: ConsentMessageRequest,
+    now: u64,
+) -> Result<ConsentInfo, ConsentError> {
+    let text = describe_call(&request.method, &request.arg, now)?;
+    let consent_message = match request.user_preferences.device_spec {
+        Some(DisplayMessageType::LineDisplay {
+            characters_per_line,
+            lines_per_page,
+        }) => ConsentMessage::LineDisplayMessage {
+            pages: paginate_consent_text(&text, characters_per_line, lines_per_page)?,
+        },
+        _ => ConsentMessage::GenericDisplayMessage(text),
+    };
+    Ok(ConsentInfo {
+        consent_message,
+        metadata: ConsentMessageMetadata {
+            language: "en".to_string(),
+            utc_offset_minutes: request.user_preferences.metadata.utc_offset_minutes,
+        },
+    })
+}
+
+fn describe_buy(
+    market_id: u64,
+    token_type: TokenType,
+    icp_amount: u64,
+    min_tokens_out: u64,
+    now: u64,
+) -> String {
+    let expected = compute_buy_quote(market_id, icp_amount, token_type.clone(), now)
+        .ok()
+        .map(|quote| quote.tokens_received);
+    format!(
+        "Buy {} {} tokens on market #{} for {}, {}",
+        approx_token_amount(expected, min_tokens_out),
+        side_label(&token_type),
+        market_id,
+        format_e8s(icp_amount),
+        slippage_clause(expected, min_tokens_out),
+    )
+}
+
+fn describe_sell(
+    market_id: u64,
+    token_type: TokenType,
+    token_amount: u64,
+    min_icp_out: u64,
+    now: u64,
+) -> String {
+    let expected = compute_sell_quote(market_id, token_amount, token_type.clone(), now)
+        .ok()
+        .map(|quote| quote.tokens_received);
+    let proceeds = match expected {
+        Some(icp_out) => format!("~{}", format_e8s(icp_out)),
+        None => format!("at least {}", format_e8s(min_icp_out)),
+    };
+    format!(
+        "Sell {} {} tokens on market #{} for {}, {}",
+        format_token_amount(token_amount),
+        side_label(&token_type),
+        market_id,
+        proceeds,
+        slippage_clause(expected, min_icp_out),
+    )
+}
+
+fn describe_call(method: &str, arg: &[u8], now: u64) -> Result<String, ConsentError> {
+    match method {
+        "trade" => {
+            let (market_id, side, token_type, amount, min_out, _deadline, max_slippage_bps) =
+                candid::decode_args::<(
+                    u64,
+                    OrderSide,
+                    TokenType,
+                    u64,
+                    u64,
+                    Option<u64>,
+                    Option<u64>,
+                )>(arg)
+                .map_err(invalid_consent_arg)?;
+            let min_out = match max_slippage_bps {
+                Some(bps) => slippage_floor(market_id, &side, &token_type, amount, bps)
+                    .map_err(|error| {
+                        ConsentError::UnsupportedCanisterCall(ConsentErrorInfo {
+                            description: format!("The trade cannot be priced: {:?}", error),
+                        })
+                    })?
+                    .max(min_out),
+                None => min_out,
+            };
+            Ok(match side {
+                OrderSide::Buy => describe_buy(market_id, token_type, amount, min_out, now),
+                OrderSide::Sell => describe_sell(market_id, token_type, amount, min_out, now),
+            })
+        }
+        "buy_yes_tokens" | "buy_no_tokens" => {
+            let (market_id, icp_amount, min_tokens_out, _deadline) =
+                candid::decode_args::<(u64, u64, u64, Option<u64>)>(arg)
+                    .map_err(invalid_consent_arg)?;
+            let token_type = if method == "buy_yes_tokens" {
+                TokenType::Yes
+            } else {
+                TokenType::No
+            };
+            Ok(describe_buy(
+                market_id,
+                token_type,
+                icp_amount,
+                min_tokens_out,
+                now,
+            ))
+        }
+        "buy_exact_tokens" => {
+            let (market_id, tokens_wanted, token_type, max_icp_in, _deadline) =
+                candid::decode_args::<(u64, u64, TokenType, u64, Option<u64>)>(arg)
+                    .map_err(invalid_consent_arg)?;
+            Ok(format!(
+                "Buy {} {} tokens on market #{}, paying at most {}",
+                format_token_amount(tokens_wanted),
+                side_label(&token_type),
+                market_id,
+                format_e8s(max_icp_in),
+            ))
+        }
+        "sell_yes_tokens" | "sell_no_tokens" => {
+            let (market_id, token_amount, min_icp_out, _deadline) =
+                candid::decode_args::<(u64, u64, u64, Option<u64>)>(arg)
+                    .map_err(invalid_consent_arg)?;
+            let token_type = if method == "sell_yes_tokens" {
+                TokenType::Yes
+            } else {
+                TokenType::No
+            };
+            Ok(describe_sell(
+                market_id,
+                token_type,
+                token_amount,
+                min_icp_out,
+                now,
+            ))
+        }
+        "claim_reward" => {
+            let (market_id,) = candid::decode_args::<(u64,)>(arg).map_err(invalid_consent_arg)?;
+            Ok(format!(
+                "Claim your winnings on market #{} and burn your winning tokens",
+                market_id
+            ))
+        }
+        "claim_refund" => {
+            let (market_id,) = candid::decode_args::<(u64,)>(arg).map_err(invalid_consent_arg)?;
+            Ok(format!(
+                "Claim your refund on market #{} and burn your outcome tokens",
+                market_id
+            ))
+        }
+        "claim_all_rewards" => Ok(
+            "Claim your winnings on every resolved market you hold winning tokens in".to_string(),
+        ),
+        _ => Err(ConsentError::UnsupportedCanisterCall(ConsentErrorInfo {
+            description: format!("No consent message for method {}", method),
+        })),
+    }
+}
+
+fn invalid_consent_arg(error: candid::Error) -> ConsentError {
+    ConsentError::UnsupportedCanisterCall(ConsentErrorInfo {
+        description: format!("Arguments do not match the method: {}", error),
+    })
+}
+
+fn side_label(token_type: &TokenType) -> &'static str {
+    match token_type {
+        TokenType::Yes => "YES",
+        TokenType::No => "NO",
+    }
+}
+
+/// The quoted amount when the market can be quoted, otherwise the guaranteed minimum
+fn approx_token_amount(expected: Option<u64>, minimum: u64) -> String {
+    match expected {
+        Some(amount) => format!("~{}", format_token_amount(amount)),
+        None => format!("at least {}", format_token_amount(minimum)),
+    }
+}
+
+/// How far below the quote the call's minimum lets the trade fill, e.g. "max slippage 2%"
+fn slippage_clause(expected: Option<u64>, minimum: u64) -> String {
+    match expected {
+        _ if minimum == 0 => "no slippage limit".to_string(),
+        Some(quoted) if quoted > minimum => {
+            let bps = scale_by(quoted - minimum, 10_000, quoted);
+            format!("max slippage {}", format_bps(bps))
+        }
+        Some(_) => "max slippage 0%".to_string(),
+        None => "the market cannot be quoted right now".to_string(),
+    }
+}
+
+/// Render basis points as a percentage with trailing zeros trimmed, e.g. 250 -> "2.5%"
+fn format_bps(bps: u64) -> String {
+    let whole = bps / 100;
+    let fraction = bps % 100;
+    if fraction == 0 {
+        return format!("{}%", whole);
+    }
+    let digits = format!("{:02}", fraction);
+    format!("{}.{}%", whole, digits.trim_end_matches('0'))
+}
+
+/// Word-wrap a message into pages for wallets with a fixed line display
+fn paginate_consent_text(
+    text: &str,
+    characters_per_line: u16,
+    lines_per_page: u16,
+) -> Result<Vec<ConsentMessagePage>, ConsentError> {
+    if characters_per_line == 0 || lines_per_page == 0 {
+        return Err(ConsentError::ConsentMessageUnavailable(ConsentErrorInfo {
+            description: "The line display has no room for text".to_string(),
+        }));
+    }
+    let width = characters_per_line as usize;
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        // Words longer than a line are hard-split
+        while word.len() > width {
+            if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+            lines.push(word.drain(..width).collect());
+        }
+        let word: String = word.into_iter().collect();
+        if word.is_empty() {
+            continue;
+        }
+        if current.is_empty() {
+            current = word;
+        } else if current.chars().count() + 1 + word.chars().count() <= width {
+            current.push(' ');
+            current.push_str(&word);
+        } else {
+            lines.push(std::mem::replace(&mut current, word));
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    Ok(lines
+        .chunks(lines_per_page as usize)
+        .map(|chunk| ConsentMessagePage {
+            lines: chunk.to_vec(),
+        })
+        .collect())
+}
+
+// =============================================================================
+// RESOLUTION CRITERIA
+// =============================================================================
+
+/// Reject criteria that an oracle could not evaluate unambiguously
+fn validate_resolution_criteria(
+    criteria: &ResolutionCriteria,
+) -> Result<(), PredictionMarketError> {
+    let text_fields = [&criteria.source_url, &criteria.metric, &criteria.timezone];
+    if text_fields
+        .iter()
+        .any(|field| field.trim().is_empty() || field.len() > MAX_CRITERIA_FIELD_LEN)
+    {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    if !is_https_url(&criteria.source_url) {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    if !criteria.threshold.is_finite() {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    Ok(())
+}
+
+/// Trim metadata text, drop empty fields and check lengths and URLs
+fn normalize_metadata(metadata: MarketMetadata) -> Result<MarketMetadata, PredictionMarketError> {
+    let trimmed = |text: String| Some(text.trim().to_string()).filter(|text| !text.is_empty());
+    let image_url = metadata.image_url.and_then(trimmed);
+    let rules = metadata.rules.and_then(trimmed);
+    let links: Vec<String> = metadata.links.into_iter().filter_map(trimmed).collect();
+    let valid_url = |url: &String| url.len() <= MAX_CRITERIA_FIELD_LEN && is_https_url(url);
+    let url_reason = format!(
+        "must be an https URL of at most {} bytes",
+        MAX_CRITERIA_FIELD_LEN
+    );
+    if !image_url.iter().all(valid_url) {
+        return Err(invalid_input("metadata.image_url", url_reason));
+    }
+    if rules
+        .as_ref()
+        .is_some_and(|rules| rules.chars().count() > MAX_MARKET_RULES_LEN)
+    {
+        return Err(invalid_input(
+            "metadata.rules",
+            format!("must be at most {} characters", MAX_MARKET_RULES_LEN),
+        ));
+    }
+    if links.len() > MAX_MARKET_LINKS {
+        return Err(invalid_input(
+            "metadata.links",
+            format!("must hold at most {} links", MAX_MARKET_LINKS),
+        ));
+    }
+    if !links.iter().all(valid_url) {
+        return Err(invalid_input("metadata.links", url_reason));
+    }
+    Ok(MarketMetadata {
+        image_url,
+        rules,
+        links,
+    })
+}
+
+/// An https URL with a host and no whitespace
+fn is_https_url(url: &str) -> bool {
+    let host = url
+        .strip_prefix("https://")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+    !host.is_empty() && !url.chars().any(char::is_whitespace)
+}
+
+/// Decide the outcome an observed metric value implies under the criteria
+fn evaluate_resolution_criteria(criteria: &ResolutionCriteria, observed: f64) -> TokenType {
+    threshold_outcome(
+        &criteria.comparator,
+        criteria.threshold,
+        &criteria.tiebreak,
+        observed,
+    )
+}
+
+fn threshold_outcome(
+    comparator: &Comparator,
+    threshold: f64,
+    tiebreak: &TokenType,
+    observed: f64,
+) -> TokenType {
+    if observed == threshold {
+        return tiebreak.clone();
+    }
+    let yes = match comparator {
+        Comparator::Above => observed > threshold,
+        Comparator::Below => observed < threshold,
+    };
+    if yes {
+        TokenType::Yes
+    } else {
+        TokenType::No
+    }
+}
+
+// =============================================================================
+// ORACLE AUTO-RESOLUTION
+// =============================================================================
+
+/// Resolve the market from an HTTPS source once trading closes, or remove it with `None`
+/// Set by whoever may resolve the market, and only while it is still trading
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_market_oracle(
+    market_id: u64,
+    config: Option<OracleConfig>,
+) -> Result<String, PredictionMarketError> {
+    let message = process_set_market_oracle(caller(), market_id, config, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(message)
+}
+
+fn process_set_market_oracle(
+    caller_principal: Principal,
+    market_id: u64,
+    config: Option<OracleConfig>,
+    now: u64,
+) -> Result<String, PredictionMarketError> {
+    let close_time = MARKETS.with(|markets| {
+        let markets = markets.borrow();
+        let market = markets
+            .get(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !is_global_admin(caller_principal) && !is_market_resolver(market, caller_principal) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if !is_trading_open(market, now) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        Ok(market.close_time)
+    })?;
+
+    let Some(config) = config else {
+        MARKET_ORACLES.with(|oracles| oracles.borrow_mut().remove(&market_id));
+        emit_event(
+            now,
+            EventKind::MarketOracleChanged {
+                market_id,
+                url: None,
+            },
+        );
+        return Ok(format!("Oracle removed from market {}", market_id));
+    };
+    validate_oracle_config(&config)?;
+    let url = config.url.clone();
+    MARKET_ORACLES.with(|oracles| {
+        oracles.borrow_mut().insert(
+            market_id,
+            OracleStatus {
+                market_id,
+                config,
+                attempts: 0,
+                next_poll_at: close_time,
+                last_error: None,
+            },
+        )
+    });
+    emit_event(
+        now,
+        EventKind::MarketOracleChanged {
+            market_id,
+            url: Some(url),
+        },
+    );
+    Ok(format!(
+        "Market {} resolves from its oracle after close",
+        market_id
+    ))
+}
+
+/// Oracle sources share the outbound allowlist with webhooks
+fn validate_oracle_config(config: &OracleConfig) -> Result<(), PredictionMarketError> {
+    let valid_reading = match &config.reading {
+        OracleReading::Outcome => true,
+        OracleReading::Threshold { threshold, .. } => threshold.is_finite(),
+    };
+    let valid = config.url.len() <= MAX_CRITERIA_FIELD_LEN
+        && is_https_url(&config.url)
+        && is_webhook_host_allowed(&config.url)
+        && !config.json_path.trim().is_empty()
+        && config.json_path.len() <= MAX_CRITERIA_FIELD_LEN
+        && valid_reading;
+    if valid {
+        Ok(())
+    } else {
+        Err(PredictionMarketError::InvalidResolutionCriteria)
+    }
+}
+
+#[ic_cdk::query]
+fn get_market_oracle(market_id: u64) -> Option<OracleStatus> {
+    MARKET_ORACLES.with(|oracles| oracles.borrow().get(&market_id).cloned())
+}
+
+/// Frozen by its deadline and not yet proposed or resolved by anyone
+fn awaits_oracle(market_id: u64) -> bool {
+    MARKETS.with(|markets| {
+        markets.borrow().get(&market_id).is_some_and(|market| {
+            market.status == MarketStatus::Frozen && market.resolution.is_none()
+        })
+    })
+}
+
+fn poll_market_oracles(now: u64) {
+    for (market_id, config) in take_due_oracle_polls(now) {
+        ic_cdk::spawn(poll_market_oracle(market_id, config));
+    }
+}
+
+/// Oracles due for a poll; the attempt is recorded up front so a slow outcall is not repeated
+fn take_due_oracle_polls(now: u64) -> Vec<(u64, OracleConfig)> {
+    MARKET_ORACLES.with(|oracles| {
+        oracles
+            .borrow_mut()
+            .values_mut()
+            .filter(|oracle| {
+                oracle.next_poll_at <= now
+                    && oracle.attempts < MAX_ORACLE_ATTEMPTS
+                    && awaits_oracle(oracle.market_id)
+            })
+            .map(|oracle| {
+                oracle.attempts += 1;
+                oracle.next_poll_at = now.saturating_add(ORACLE_RETRY_NS);
+                (oracle.market_id, oracle.config.clone())
+            })
+            .collect()
+    })
+}
+
+fn next_oracle_poll_time() -> Option<u64> {
+    MARKET_ORACLES.with(|oracles| {
+        oracles
+            .borrow()
+            .values()
+            .filter(|oracle| {
+                oracle.attempts < MAX_ORACLE_ATTEMPTS && awaits_oracle(oracle.market_id)
+            })
+            .map(|oracle| oracle.next_poll_at)
+            .min()
+    })
+}
+
+async fn poll_market_oracle(market_id: u64, config: OracleConfig) {
+    let request = CanisterHttpRequestArgument {
+        url: config.url.clone(),
+        max_response_bytes: Some(ORACLE_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "Accept".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_oracle_response".to_string(),
+            vec![],
+        )),
+    };
+    let body = match http_outcall(request, ORACLE_CYCLES).await {
+        Ok((response,)) if u64::try_from(&response.status.0).is_ok_and(|s| s == 200) => {
+            Ok(response.body)
+        }
+        Ok((response,)) => Err(format!("Oracle answered with status {}", response.status)),
+        Err((_, message)) => Err(message),
+    };
+    let _ = apply_oracle_response(market_id, &config, body, ic_cdk::id(), ic_cdk::api::time());
+    schedule_global_timer();
+}
+
+/// Drop headers so every replica sees the same response; the body carries the reading
+#[ic_cdk::query]
+fn transform_oracle_response(args: TransformArgs) -> OutcallResponse {
+    OutcallResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: args.response.body,
+    }
+}
+
+/// Resolve from a fetched oracle body, or record why the market is still unresolved
+fn apply_oracle_response(
+    market_id: u64,
+    config: &OracleConfig,
+    body: Result<Vec<u8>, String>,
+    oracle: Principal,
+    now: u64,
+) -> Result<TokenType, String> {
+    let result = body
+        .and_then(|body| read_oracle_outcome(config, &body))
+        .and_then(|(outcome, reading)| {
+            // Polls only start while no resolution exists, so anyone who got there first wins
+            transition_resolution(
+                market_id,
+                0,
+                ResolutionTransition::Oracle(outcome.clone()),
+                oracle,
+                now,
+            )
+            .map_err(|error| format!("{:?}", error))?;
+            record_resolution_evidence(
+                market_id,
+                format!("{} = {} from {}", config.json_path, reading, config.url),
+            );
+            Ok(outcome)
+        });
+    MARKET_ORACLES.with(|oracles| {
+        if let Some(status) = oracles.borrow_mut().get_mut(&market_id) {
+            status.last_error = result.as_ref().err().cloned();
+        }
+    });
+    result
+}
+
+/// The outcome an oracle response implies, with the value it was read from
+fn read_oracle_outcome(config: &OracleConfig, body: &[u8]) -> Result<(TokenType, String), String> {
+    let text = std::str::from_utf8(body).map_err(|_| "Response is not UTF-8".to_string())?;
+    let document = ParsedJson::parse(text).ok_or("Response is not valid JSON".to_string())?;
+    let value = document
+        .lookup(&config.json_path)
+        .ok_or_else(|| format!("Nothing found at {}", config.json_path))?;
+    let unexpected = || format!("Unexpected value at {}", config.json_path);
+    let reading = match value {
+        ParsedJson::Bool(flag) => flag.to_string(),
+        ParsedJson::Number(number) => number.to_string(),
+        ParsedJson::Str(text) => text.clone(),
+        _ => return Err(unexpected()),
+    };
+    let outcome = match (&config.reading, value) {
+        (OracleReading::Outcome, ParsedJson::Bool(true)) => Ok(TokenType::Yes),
+        (OracleReading::Outcome, ParsedJson::Bool(false)) => Ok(TokenType::No),
+        (OracleReading::Outcome, ParsedJson::Str(answer)) => {
+            match answer.trim().to_lowercase().as_str() {
+                "yes" => Ok(TokenType::Yes),
+                "no" => Ok(TokenType::No),
+                _ => Err(unexpected()),
+            }
+        }
+        (
+            OracleReading::Threshold {
+                comparator,
+                threshold,
+                tiebreak,
+            },
+            ParsedJson::Number(_) | ParsedJson::Str(_),
+        ) => {
+            // Many price APIs quote numbers as strings
+            let observed = match value {
+                ParsedJson::Number(number) => *number,
+                ParsedJson::Str(number) => {
+                    number.trim().parse::<f64>().map_err(|_| unexpected())?
+                }
+                _ => unreachable!("matched a number or a string"),
+            };
+            if !observed.is_finite() {
+                return Err(unexpected());
+            }
+            Ok(threshold_outcome(
+                comparator, *threshold, tiebreak, observed,
+            ))
+        }
+        _ => Err(unexpected()),
+    }?;
+    Ok((outcome, reading))
+}
+
+/// JSON read from an oracle response; parsed by hand, like `Json` is rendered
+#[derive(Debug, PartialEq)]
+enum ParsedJson {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<ParsedJson>),
+    Object(Vec<(String, ParsedJson)>),
+}
+
+type JsonChars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+impl ParsedJson {
+    fn parse(text: &str) -> Option<ParsedJson> {
+        let mut chars = text.chars().peekable();
+        let value = Self::parse_value(&mut chars, 0)?;
+        Self::skip_whitespace(&mut chars);
+        chars.peek().is_none().then_some(value)
+    }
+
+    /// Follow dot-separated object keys and array indices
+    fn lookup(&self, path: &str) -> Option<&ParsedJson> {
+        path.split('.').try_fold(self, |value, key| match value {
+            ParsedJson::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, field)| field),
+            ParsedJson::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+    }
+
+    fn skip_whitespace(chars: &mut JsonChars) {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn parse_value(chars: &mut JsonChars, depth: usize) -> Option<ParsedJson> {
+        if depth > MAX_JSON_DEPTH {
+            return None;
+        }
+        Self::skip_whitespace(chars);
+        let literal = |chars: &mut JsonChars, word: &str, value: ParsedJson| {
+            word.chars()
+                .all(|c| chars.next() == Some(c))
+                .then_some(value)
+        };
+        match *chars.peek()? {
+            '{' => {
+                chars.next();
+                let mut fields = Vec::new();
+                Self::skip_whitespace(chars);
+                if chars.next_if_eq(&'}').is_some() {
+                    return Some(ParsedJson::Object(fields));
+                }
+                loop {
+                    Self::skip_whitespace(chars);
+                    let key = Self::parse_string(chars)?;
+                    Self::skip_whitespace(chars);
+                    chars.next_if_eq(&':')?;
+                    fields.push((key, Self::parse_value(chars, depth + 1)?));
+                    Self::skip_whitespace(chars);
+                    match chars.next()? {
+                        ',' => continue,
+                        '}' => return Some(ParsedJson::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            '[' => {
+                chars.next();
+                let mut items = Vec::new();
+                Self::skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    return Some(ParsedJson::Array(items));
+                }
+                loop {
+                    items.push(Self::parse_value(chars, depth + 1)?);
+                    Self::skip_whitespace(chars);
+                    match chars.next()? {
+                        ',' => continue,
+                        ']' => return Some(ParsedJson::Array(items)),
+                        _ => return None,
+                    }
+                }
+            }
+            '"' => Self::parse_string(chars).map(ParsedJson::Str),
+            't' => literal(chars, "true", ParsedJson::Bool(true)),
+            'f' => literal(chars, "false", ParsedJson::Bool(false)),
+            'n' => literal(chars, "null", ParsedJson::Null),
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    number.push(c);
+                }
+                number.parse().ok().map(ParsedJson::Number)
+            }
+        }
+    }
+
+    fn parse_string(chars: &mut JsonChars) -> Option<String> {
+        chars.next_if_eq(&'"')?;
+        let mut out = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => match chars.next()? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let code: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&code, 16).ok()?;
+                        // Surrogate pairs are not needed for the keys and values oracles return
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c @ ('"' | '\\' | '/') => out.push(c),
+                    _ => return None,
+                },
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+// =============================================================================
+// EXCHANGE RATE PRICE MARKETS
+// =============================================================================
+
+/// Request and response types of the Exchange Rate Canister's `get_exchange_rate`
+#[derive(CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: Option<u64>, // Seconds; the XRC uses the start of that minute
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+    base_asset_num_received_rates: u64,
+    base_asset_num_queried_sources: u64,
+    quote_asset_num_received_rates: u64,
+    quote_asset_num_queried_sources: u64,
+    standard_deviation: u64,
+    forex_timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ExchangeRate {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: u64,
+    rate: u64, // Scaled by 10^metadata.decimals
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct XrcOtherError {
+    code: u32,
+    description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(XrcOtherError),
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetExchangeRateResult {
+    Ok(ExchangeRate),
+    Err(ExchangeRateError),
+}
+
+/// Create a market on whether `base_asset` trades above or below `threshold` in `quote_asset`
+/// at the trading deadline; it resolves itself from the Exchange Rate Canister
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[allow(clippy::too_many_arguments)] // Candid arguments
+fn create_price_market(
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    comparator: Comparator,
+    threshold: f64,
+    initial_icp_liquidity: u64, // e8s
+    close_time: u64,
+    category: Option<String>,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_routed_worker()?;
+    let market_id = process_create_price_market(
+        caller(),
+        base_asset,
+        quote_asset,
+        comparator,
+        threshold,
+        initial_icp_liquidity,
+        close_time,
+        category,
+        ic_cdk::api::time(),
+    )?;
+    schedule_global_timer();
+    record_instructions(CostOperation::CreateMarket);
+    Ok(market_id)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_create_price_market(
+    caller_principal: Principal,
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    comparator: Comparator,
+    threshold: f64,
+    initial_icp_liquidity: u64,
+    close_time: u64,
+    category: Option<String>,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let valid_symbol = |asset: &XrcAsset| {
+        !asset.symbol.is_empty()
+            && asset.symbol.len() <= MAX_ASSET_SYMBOL_LEN
+            && asset.symbol.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    if !valid_symbol(&base_asset) || !valid_symbol(&quote_asset) || base_asset == quote_asset {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    let base_asset = XrcAsset {
+        symbol: base_asset.symbol.to_uppercase(),
+        ..base_asset
+    };
+    let quote_asset = XrcAsset {
+        symbol: quote_asset.symbol.to_uppercase(),
+        ..quote_asset
+    };
+
+    let pair = format!("{}/{}", base_asset.symbol, quote_asset.symbol);
+    let direction = match comparator {
+        Comparator::Above => "above",
+        Comparator::Below => "below",
+    };
+    let source_url = format!(
+        "https://dashboard.internetcomputer.org/canister/{}",
+        XRC_CANISTER_ID
+    );
+    let criteria = ResolutionCriteria {
+        source_url: source_url.clone(),
+        metric: format!("{} rate from the Exchange Rate Canister", pair),
+        comparator,
+        threshold,
+        timezone: "UTC".to_string(),
+        tiebreak: TokenType::No,
+    };
+    let market_id = process_create_market(
+        caller_principal,
+        NewMarket {
+            title: format!(
+                "Will {} be {} {} at the trading deadline?",
+                pair, direction, threshold
+            ),
+            description: format!(
+                "Resolves YES if the Exchange Rate Canister reports {} strictly {} {} \
+                for the minute of the trading deadline (UTC), and NO otherwise.",
+                pair, direction, threshold
+            ),
+            initial_icp_liquidity,
+            close_time,
+            category,
+            resolution_criteria: Some(criteria),
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: Some(source_url),
+            metadata: None,
+        },
+        now,
+    )?;
+    PRICE_FEEDS.with(|feeds| {
+        feeds.borrow_mut().insert(
+            market_id,
+            PriceFeed {
+                market_id,
+                base_asset,
+                quote_asset,
+                attempts: 0,
+                next_poll_at: close_time,
+                last_error: None,
+                evidence: None,
+            },
+        )
+    });
+    Ok(market_id)
+}
+
+#[ic_cdk::query]
+fn get_price_feed(market_id: u64) -> Option<PriceFeed> {
+    PRICE_FEEDS.with(|feeds| feeds.borrow().get(&market_id).cloned())
+}
+
+fn poll_price_feeds(now: u64) {
+    for (market_id, request) in take_due_price_feeds(now) {
+        ic_cdk::spawn(fetch_exchange_rate(market_id, request));
+    }
+}
+
+/// Feeds due for a poll, with the attempt and its backoff recorded up front
+fn take_due_price_feeds(now: u64) -> Vec<(u64, GetExchangeRateRequest)> {
+    let close_times: HashMap<u64, u64> = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .map(|market| (market.id, market.close_time))
+            .collect()
+    });
+    PRICE_FEEDS.with(|feeds| {
+        feeds
+            .borrow_mut()
+            .values_mut()
+            .filter(|feed| {
+                feed.next_poll_at <= now
+                    && feed.attempts < MAX_XRC_ATTEMPTS
+                    && awaits_oracle(feed.market_id)
+            })
+            .map(|feed| {
+                feed.attempts += 1;
+                feed.next_poll_at = now.saturating_add(XRC_RETRY_NS << (feed.attempts - 1));
+                let close_time = close_times.get(&feed.market_id).copied().unwrap_or(now);
+                let request = GetExchangeRateRequest {
+                    base_asset: feed.base_asset.clone(),
+                    quote_asset: feed.quote_asset.clone(),
+                    timestamp: Some(close_time / 1_000_000_000),
+                };
+                (feed.market_id, request)
+            })
+            .collect()
+    })
+}
+
+fn next_price_feed_poll_time() -> Option<u64> {
+    PRICE_FEEDS.with(|feeds| {
+        feeds
+            .borrow()
+            .values()
+            .filter(|feed| feed.attempts < MAX_XRC_ATTEMPTS && awaits_oracle(feed.market_id))
+            .map(|feed| feed.next_poll_at)
+            .min()
+    })
+}
+
+async fn fetch_exchange_rate(market_id: u64, request: GetExchangeRateRequest) {
+    let xrc = Principal::from_text(XRC_CANISTER_ID).expect("valid XRC canister id");
+    let result = match ic_cdk::api::call::call_with_payment128::<_, (GetExchangeRateResult,)>(
+        xrc,
+        "get_exchange_rate",
+        (request,),
+        XRC_CYCLES,
+    )
+    .await
+    {
+        Ok((GetExchangeRateResult::Ok(rate),)) => Ok(rate),
+        Ok((GetExchangeRateResult::Err(error),)) => Err(format!("{:?}", error)),
+        Err((_, message)) => Err(message),
+    };
+    let _ = apply_exchange_rate(market_id, result, ic_cdk::id(), ic_cdk::api::time());
+    schedule_global_timer();
+}
+
+/// Resolve a price market from the fetched rate, or record why it is still unresolved
+fn apply_exchange_rate(
+    market_id: u64,
+    result: Result<ExchangeRate, String>,
+    oracle: Principal,
+    now: u64,
+) -> Result<TokenType, String> {
+    let evidence = result.as_ref().ok().map(|rate| RateEvidence {
+        rate: rate.rate as f64 / 10f64.powi(rate.metadata.decimals as i32),
+        rate_timestamp: rate.timestamp,
+        base_sources: rate.metadata.base_asset_num_received_rates,
+        quote_sources: rate.metadata.quote_asset_num_received_rates,
+        fetched_at: now,
+    });
+    let outcome = result.and_then(|_| {
+        let observed = evidence.as_ref().map_or(f64::NAN, |evidence| evidence.rate);
+        let criteria = MARKETS
+            .with(|markets| {
+                markets
+                    .borrow()
+                    .get(&market_id)
+                    .and_then(|market| market.resolution_criteria.clone())
+            })
+            .ok_or_else(|| "Market has no resolution criteria".to_string())?;
+        let outcome = evaluate_resolution_criteria(&criteria, observed);
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Oracle(outcome.clone()),
+            oracle,
+            now,
+        )
+        .map_err(|error| format!("{:?}", error))?;
+        record_resolution_evidence(
+            market_id,
+            format!(
+                "{} = {} from the Exchange Rate Canister",
+                criteria.metric, observed
+            ),
+        );
+        Ok(outcome)
+    });
+    PRICE_FEEDS.with(|feeds| {
+        if let Some(feed) = feeds.borrow_mut().get_mut(&market_id) {
+            feed.last_error = outcome.as_ref().err().cloned();
+            if evidence.is_some() {
+                feed.evidence = evidence;
+            }
+        }
+    });
+    outcome
+}
+
+// =============================================================================
+// MARKET RESOLUTION AND REWARDS
+// =============================================================================
+
+/// Resolve a market and set the winning outcome (designated resolver, else market admin)
+/// The global admin only stands in for a designated resolver that abandoned the market or missed RESOLVER_GRACE_NS
+/// Works on open markets and on markets frozen by their trading deadline
+/// Goes straight to the final phase; a challenged proposal can only be overridden by the
+/// global admin through a timelocked `AdminAction::OverrideResolution`
+/// `evidence` (a link, quote or observed value) is stored with the market for users to verify
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn resolve_market(
+    market_id: u64,
+    outcome: TokenType,
+    evidence: String,
+) -> Result<String, PredictionMarketError> {
+    process_resolve_market(
+        caller(),
+        market_id,
+        outcome.clone(),
+        evidence,
+        ic_cdk::api::time(),
+    )?;
+    schedule_global_timer();
+
+    Ok(format!(
+        "Market {} resolved with outcome: {:?}. Users can now claim rewards.",
+        market_id, outcome
+    ))
+}
+
+fn process_resolve_market(
+    actor: Principal,
+    market_id: u64,
+    outcome: TokenType,
+    evidence: String,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let evidence = evidence.trim().to_string();
+    if evidence.is_empty() || evidence.len() > MAX_RESOLUTION_EVIDENCE_LEN {
+        return Err(PredictionMarketError::MissingResolutionEvidence);
+    }
+    transition_resolution(
+        market_id,
+        current_resolution_revision(market_id),
+        ResolutionTransition::Resolve(outcome),
+        actor,
+        now,
+    )?;
+    record_resolution_evidence(market_id, evidence);
+    Ok(())
+}
+
+/// Settle a challenged market on the global admin's outcome; run by `execute_action`
+fn process_override_resolution(
+    actor: Principal,
+    market_id: u64,
+    outcome: TokenType,
+    evidence: String,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    transition_resolution(
+        market_id,
+        current_resolution_revision(market_id),
+        ResolutionTransition::Override(outcome),
+        actor,
+        now,
+    )?;
+    record_resolution_evidence(market_id, evidence);
+    Ok(())
+}
+
+/// Keep what a resolution was based on; automated paths describe their own reading
+fn record_resolution_evidence(market_id: u64, evidence: String) {
+    let evidence: String = evidence.chars().take(MAX_RESOLUTION_EVIDENCE_LEN).collect();
+    MARKETS.with(|markets| {
+        if let Some(market) = markets.borrow_mut().get_mut(&market_id) {
+            market.resolution_evidence = Some(evidence);
+        }
+    });
+}
+
+/// Propose an outcome for a market and halt trading until it is finalized (admin only)
+/// `expected_revision` is the `resolution.revision` the caller last saw, 0 if none
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn propose_resolution(
+    market_id: u64,
+    outcome: TokenType,
+    expected_revision: u64,
+) -> Result<ResolutionState, PredictionMarketError> {
+    transition_resolution(
+        market_id,
+        expected_revision,
+        ResolutionTransition::Propose(outcome),
+        caller(),
+        ic_cdk::api::time(),
+    )
+}
+
+/// Dispute the proposed outcome so that a new one has to be proposed (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn challenge_resolution(
+    market_id: u64,
+    expected_revision: u64,
+) -> Result<ResolutionState, PredictionMarketError> {
+    transition_resolution(
+        market_id,
+        expected_revision,
+        ResolutionTransition::Challenge,
+        caller(),
+        ic_cdk::api::time(),
+    )
+}
+
+/// Settle the proposed outcome so users can claim rewards (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn finalize_resolution(
+    market_id: u64,
+    expected_revision: u64,
+) -> Result<ResolutionState, PredictionMarketError> {
+    let state = transition_resolution(
+        market_id,
+        expected_revision,
+        ResolutionTransition::Finalize,
+        caller(),
+        ic_cdk::api::time(),
+    )?;
+    schedule_global_timer();
+    Ok(state)
+}
+
+/// A requested move in the resolution state machine
+enum ResolutionTransition {
+    Propose(TokenType),
+    Challenge,
+    Finalize,
+    Resolve(TokenType),  // Propose and finalize in one step
+    Override(TokenType), // Replace a challenged proposal; only through the timelock
+    Tally(TokenType),    // A community vote's result; needs no admin
+    Oracle(TokenType),   // A configured oracle's reading; needs no admin
+}
+
+fn current_resolution_revision(market_id: u64) -> u64 {
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .and_then(|market| market.resolution.as_ref())
+            .map_or(0, |state| state.revision)
+    })
+}
+
+/// Apply a resolution transition if the market is still at `expected_revision`
+/// Every resolution path goes through here, so `status`, `winning_outcome` and
+/// `resolution` change together; a path that read the state before an await
+/// fails with ResolutionConflict instead of overwriting a newer transition
+fn transition_resolution(
+    market_id: u64,
+    expected_revision: u64,
+    transition: ResolutionTransition,
+    actor: Principal,
+    now: u64,
+) -> Result<ResolutionState, PredictionMarketError> {
+    let actor_is_global_admin = is_global_admin(actor);
+    let needs_no_admin = matches!(
+        transition,
+        ResolutionTransition::Tally(_) | ResolutionTransition::Oracle(_)
+    );
+
+    let state = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+
+        let admin_may_act = actor_is_global_admin && admin_may_resolve(market, &transition, now);
+        if !needs_no_admin && !admin_may_act && !is_market_resolver(market, actor) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if is_settled(&market.status) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+
+        let current = market.resolution.clone();
+        if current.as_ref().map_or(0, |state| state.revision) != expected_revision {
+            return Err(PredictionMarketError::ResolutionConflict);
+        }
+
+        let fresh = |phase: ResolutionPhase, outcome: TokenType| ResolutionState {
+            phase,
+            outcome,
+            proposed_by: actor,
+            challenged_by: None,
+            updated_at: now,
+            revision: expected_revision + 1,
+        };
+        let phase = current.as_ref().map(|state| state.phase.clone());
+        let next = match (transition, phase) {
+            (ResolutionTransition::Propose(outcome), None | Some(ResolutionPhase::Challenged)) => {
+                fresh(ResolutionPhase::Proposed, outcome)
+            }
+            (ResolutionTransition::Challenge, Some(ResolutionPhase::Proposed)) => ResolutionState {
+                phase: ResolutionPhase::Challenged,
+                challenged_by: Some(actor),
+                updated_at: now,
+                revision: expected_revision + 1,
+                ..current.expect("phase read from the current state")
+            },
+            (ResolutionTransition::Finalize, Some(ResolutionPhase::Proposed)) => ResolutionState {
+                phase: ResolutionPhase::Final,
+                updated_at: now,
+                revision: expected_revision + 1,
+                ..current.expect("phase read from the current state")
+            },
+            (ResolutionTransition::Resolve(_), Some(ResolutionPhase::Challenged)) => {
+                return Err(PredictionMarketError::Unauthorized);
+            }
+            (ResolutionTransition::Override(outcome), Some(ResolutionPhase::Challenged))
+                if actor_is_global_admin =>
+            {
+                fresh(ResolutionPhase::Final, outcome)
+            }
+            (
+                ResolutionTransition::Resolve(outcome)
+                | ResolutionTransition::Tally(outcome)
+                | ResolutionTransition::Oracle(outcome),
+                _,
+            ) => fresh(ResolutionPhase::Final, outcome),
+            _ => return Err(PredictionMarketError::InvalidResolutionTransition),
+        };
+
+        let mut settled = None;
+        if next.phase == ResolutionPhase::Final {
+            market.status = MarketStatus::Resolved;
+            market.winning_outcome = Some(next.outcome.clone());
+            // An unclaimable pool is only settled once the outcome can no longer be disputed
+            if !open_dispute_window(market_id, now) {
+                settled = settle_unclaimable_pool(market);
+            }
+        } else {
+            market.status = MarketStatus::Frozen;
+        }
+        market.resolution = Some(next.clone());
+        Ok((next, settled))
+    })?;
+    let (state, settled) = state;
+    if state.phase == ResolutionPhase::Final {
+        record_forecasts(market_id);
+        // With a dispute window the bond is held until it closes
+        if !DISPUTE_WINDOWS.with(|windows| windows.borrow().contains_key(&market_id)) {
+            release_creator_bond(market_id, now);
+        }
+    }
+
+    let kind = match state.phase {
+        ResolutionPhase::Proposed => EventKind::ResolutionProposed {
+            market_id,
+            outcome: state.outcome.clone(),
+            proposer: actor,
+        },
+        ResolutionPhase::Challenged => EventKind::ResolutionChallenged {
+            market_id,
+            challenger: actor,
+        },
+        ResolutionPhase::Final => EventKind::MarketResolved {
+            market_id,
+            outcome: state.outcome.clone(),
+        },
+    };
+    emit_event(now, kind);
+    if let Some(settled) = settled {
+        emit_event(now, settled);
+    }
+
+    Ok(state)
+}
+
+/// Nobody can claim the pool of a resolved market, so it goes wherever the policy says
+fn settle_unclaimable_pool(market: &mut AmmMarket) -> Option<EventKind> {
+    let outcome = market.winning_outcome.clone()?;
+    if winning_supply(market.id, &outcome) > 0 {
+        return None;
+    }
+    let policy = EDGE_POLICIES.with(|policies| policies.borrow().no_winning_supply.clone());
+    let amount = settle_degenerate_pool(market, &policy);
+    Some(EventKind::DegeneratePoolSettled {
+        market_id: market.id,
+        policy,
+        amount,
+    })
+}
+
+/// Whether the global admin may take a resolution step on the market
+/// A designated resolver holds the outcome until they abandon the market or sit on it
+/// RESOLVER_GRACE_NS past close; disputes stay with the global admin either way
+fn admin_may_resolve(market: &AmmMarket, transition: &ResolutionTransition, now: u64) -> bool {
+    let disputed = matches!(transition, ResolutionTransition::Challenge)
+        || market
+            .resolution
+            .as_ref()
+            .is_some_and(|state| state.phase == ResolutionPhase::Challenged);
+    market.resolver.is_none()
+        || disputed
+        || now >= market.close_time.saturating_add(RESOLVER_GRACE_NS)
+}
+
+/// Offer the resolver role for a market to a neutral party (market admin or global admin)
+/// Nothing changes until they call `accept_market_resolver`; a new proposal replaces the pending one
+/// Once a resolver has accepted, only the global admin may propose a replacement
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_market_resolver(
+    market_id: u64,
+    resolver: Principal,
+) -> Result<String, PredictionMarketError> {
+    process_set_market_resolver(caller(), market_id, resolver, ic_cdk::api::time())?;
+    Ok(format!(
+        "Resolver for market {} proposed to {}; it takes effect once they accept",
+        market_id, resolver
+    ))
+}
+
+fn process_set_market_resolver(
+    actor: Principal,
+    market_id: u64,
+    resolver: Principal,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    let has_resolver = market.resolver.is_some();
+    if !is_global_admin(actor) && (market.admin != actor || has_resolver) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if is_settled(&market.status) {
+        return Err(PredictionMarketError::MarketClosed);
+    }
+    // A vote and a designated resolver would compete for the same outcome
+    if market.resolution_mode == ResolutionMode::CommunityVote {
+        return Err(PredictionMarketError::InvalidResolutionCriteria);
+    }
+    if resolver == market.creator || resolver == Principal::anonymous() {
+        return Err(invalid_input(
+            "resolver",
+            "must be someone other than the market creator".to_string(),
+        ));
+    }
+
+    PENDING_RESOLVERS.with(|pending| pending.borrow_mut().insert(market_id, resolver));
+    emit_event(
+        now,
+        EventKind::ResolverProposed {
+            market_id,
+            resolver,
+        },
+    );
+    Ok(())
+}
+
+/// Take over the resolver role proposed to the caller for a market
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn accept_market_resolver(market_id: u64) -> Result<String, PredictionMarketError> {
+    process_accept_market_resolver(caller(), market_id, ic_cdk::api::time())?;
+    Ok(format!("You now resolve market {}", market_id))
+}
+
+fn process_accept_market_resolver(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let proposed =
+        PENDING_RESOLVERS.with(|pending| pending.borrow().get(&market_id).copied() == Some(actor));
+    if !proposed {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    MARKETS.with(|markets| {
+        let mut markets = markets.borrow_mut();
+        let market = markets
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if is_settled(&market.status) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        market.resolver = Some(actor);
+        Ok(())
+    })?;
+    PENDING_RESOLVERS.with(|pending| pending.borrow_mut().remove(&market_id));
+    ABANDONED_MARKETS.with(|abandoned| abandoned.borrow_mut().remove(&market_id));
+    emit_event(
+        now,
+        EventKind::ResolverChanged {
+            market_id,
+            resolver: Some(actor),
+        },
+    );
+    Ok(())
+}
+
+/// Step down as a market's resolver; from then on only the global admin can resolve it
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn abandon_market_resolver(market_id: u64) -> Result<String, PredictionMarketError> {
+    process_abandon_market_resolver(caller(), market_id, ic_cdk::api::time())?;
+    Ok(format!(
+        "You no longer resolve market {}; the platform admin takes over",
+        market_id
+    ))
+}
+
+fn process_abandon_market_resolver(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let mut markets = markets.borrow_mut();
+        let market = markets
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if market.resolver != Some(actor) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if is_settled(&market.status) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        market.resolver = None;
+        Ok(())
+    })?;
+    ABANDONED_MARKETS.with(|abandoned| abandoned.borrow_mut().insert(market_id));
+    emit_event(
+        now,
+        EventKind::ResolverChanged {
+            market_id,
+            resolver: None,
+        },
+    );
+    Ok(())
+}
+
+/// Get the principal a market's resolver handover is waiting on, if any
+#[ic_cdk::query]
+fn get_pending_resolver(market_id: u64) -> Option<Principal> {
+    PENDING_RESOLVERS.with(|pending| pending.borrow().get(&market_id).copied())
+}
+
+/// Whether `actor` may resolve the market short of being the global admin
+/// A designated resolver replaces the market admin; a community vote leaves the global admin as fallback,
+/// and so does a resolver abandoning the market
+fn is_market_resolver(market: &AmmMarket, actor: Principal) -> bool {
+    if ABANDONED_MARKETS.with(|abandoned| abandoned.borrow().contains(&market.id)) {
+        return false;
+    }
+    match (market.resolver, &market.resolution_mode) {
+        (Some(resolver), _) => resolver == actor,
+        (None, ResolutionMode::AdminOnly) => market.admin == actor,
+        (None, ResolutionMode::CommunityVote) => false,
+    }
+}
+
+/// Outcome tokens of `outcome` sitting in open swap offers and sell limit orders
+/// They go back to their owners on cancel, so they still count toward the supply
+fn escrowed_supply(market_id: u64, outcome: &TokenType) -> u64 {
+    let tokens_of = |asset: &SwapAsset| match asset {
+        SwapAsset::Tokens {
+            market_id: asset_market,
+            token_type,
+            amount,
+        } if *asset_market == market_id && token_type == outcome => *amount,
+        _ => 0,
+    };
+    let in_offers: u64 = SWAP_OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|offer| offer.status == SwapOfferStatus::Open)
+            .map(|offer| tokens_of(&offer.give))
+            .sum()
+    });
+    let in_orders: u64 = LIMIT_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .values()
+            .filter(|order| order.status == OrderStatus::Open)
+            .map(|order| tokens_of(&limit_order_escrow(order)))
+            .sum()
+    });
+    in_offers + in_orders
+}
+
+/// Outcome tokens of `outcome` across all positions, wrapped receipts and escrows in a market
+fn winning_supply(market_id: u64, outcome: &TokenType) -> u64 {
+    let held: u64 = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .values()
+            .filter(|position| position.market_id == market_id)
+            .map(|position| match outcome {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            })
+            .sum()
+    });
+    held + wrapped_supply(market_id, outcome) + escrowed_supply(market_id, outcome)
+}
+
+/// Resolve a market by applying its resolution criteria to an observed metric value
+/// Same authorization as `resolve_market`; used by operators and the oracle path
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn resolve_market_from_observation(
+    market_id: u64,
+    observed_value: f64,
+) -> Result<String, PredictionMarketError> {
+    let criteria = MARKETS
+        .with(|markets| {
+            markets
+                .borrow()
+                .get(&market_id)
+                .map(|market| market.resolution_criteria.clone())
+                .ok_or(PredictionMarketError::MarketNotFound)
+        })?
+        .ok_or(PredictionMarketError::InvalidResolutionCriteria)?;
+
+    resolve_market(
+        market_id,
+        evaluate_resolution_criteria(&criteria, observed_value),
+        format!(
+            "Observed {} for {} from {}",
+            observed_value, criteria.metric, criteria.source_url
+        ),
+    )
+}
+
+/// Claim reward for holding winning tokens
+/// Burns the winning tokens and distributes proportional share of ICP liquidity
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_reward(market_id: u64) -> Result<RewardClaim, PredictionMarketError> {
+    let caller_principal = caller();
+    let _guard = OperationGuard::market_and_user(market_id, caller_principal)?;
+    let claim = process_reward_claim(caller_principal, market_id, ic_cdk::api::time())?;
+    record_instructions(CostOperation::ClaimReward);
+    Ok(claim)
+}
+
+/// Claim rewards in every resolved market where the caller has an unclaimed position
+/// Markets are processed in ID order, at most MAX_LIST_RESULTS per call
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_all_rewards() -> ClaimAllResult {
+    let result = process_claim_all_rewards(caller(), ic_cdk::api::time());
+    record_instructions(CostOperation::ClaimReward);
+    result
+}
+
+fn process_claim_all_rewards(caller_principal: Principal, now: u64) -> ClaimAllResult {
+    let mut market_ids: Vec<u64> = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .values()
+            .filter(|position| position.user == caller_principal && !position.claimed_reward)
+            .map(|position| position.market_id)
+            .filter(|market_id| {
+                MARKETS.with(|markets| {
+                    markets
+                        .borrow()
+                        .get(market_id)
+                        .is_some_and(|market| market.status == MarketStatus::Resolved)
+                })
+            })
+            .collect()
+    });
+    market_ids.sort_unstable();
+    let truncated = market_ids.len() > MAX_LIST_RESULTS;
+    market_ids.truncate(MAX_LIST_RESULTS);
+
+    let mut claims = Vec::new();
+    let mut skipped = Vec::new();
+    for market_id in market_ids {
+        match process_reward_claim(caller_principal, market_id, now) {
+            Ok(claim) => claims.push(claim),
+            Err(reason) => skipped.push(SkippedClaim { market_id, reason }),
+        }
+    }
+    ClaimAllResult {
+        claims,
+        skipped,
+        truncated,
+    }
+}
+
+fn process_reward_claim(
+    caller_principal: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<RewardClaim, PredictionMarketError> {
+    ensure_not_paused()?;
+    // Get market and check if it's resolved
+    let (market, winning_token_type) = MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        match markets_map.get(&market_id) {
+            Some(market) => {
+                if !matches!(market.status, MarketStatus::Resolved) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+
+                match &market.winning_outcome {
+                    Some(outcome) => Ok((market.clone(), outcome.clone())),
+                    None => Err(PredictionMarketError::MarketNotFound),
+                }
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })?;
+    ensure_claims_unlocked(market_id, now)?;
+    // Receipts can no longer be unwrapped, so claiming redeems them
+    release_receipts_of(caller_principal, market_id, now);
+
+    // Get user position and check for previous claims
+    let (user_winning_tokens, already_claimed) = USER_POSITIONS.with(|positions| {
+        let positions_map = positions.borrow();
+        if let Some(position) = positions_map.get(&(caller_principal, market_id)) {
+            if position.claimed_reward {
+                return (0, true);
+            }
+
+            let winning_tokens = match winning_token_type {
+                TokenType::Yes => position.yes_tokens,
+                TokenType::No => position.no_tokens,
+            };
+            (winning_tokens, false)
+        } else {
+            (0, false)
+        }
+    });
+
+    if already_claimed {
+        return Err(PredictionMarketError::AlreadyClaimed);
+    }
+
+    if user_winning_tokens == 0 {
+        return Err(PredictionMarketError::NoWinningTokens);
+    }
+
+    // Calculate total winning tokens across all users
+    // Never zero here: the caller holds some, and a market resolved without any was settled by policy
+    let total_winning_tokens = winning_supply(market_id, &winning_token_type);
+
+    // Calculate user's share of the ICP liquidity pool
+    // reward = (user_winning_tokens / total_winning_tokens) * total_ICP_pool
+    let reward_amount = (user_winning_tokens as u128 * market.icp_liquidity_pool as u128
+        / total_winning_tokens as u128) as u64;
+
+    // Update user position to mark as claimed
+    USER_POSITIONS.with(|positions| {
+        let mut positions_map = positions.borrow_mut();
+        if let Some(position) = positions_map.get_mut(&(caller_principal, market_id)) {
+            position.claimed_reward = true;
+            // Burn the winning tokens
+            match winning_token_type {
+                TokenType::Yes => position.yes_tokens = 0,
+                TokenType::No => position.no_tokens = 0,
+            }
+        }
+    });
+
+    // Transfer ICP reward to user
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + reward_amount);
+    });
+
+    // Record the claim
+    let claim = RewardClaim {
+        user: caller_principal,
+        market_id,
+        winning_tokens: user_winning_tokens,
+        reward_amount,
+        claim_time: now,
+    };
+
+    REWARD_CLAIMS.with(|claims| {
+        claims.borrow_mut().push(claim.clone());
+    });
+    record_pnl(caller_principal, market_id, 0, reward_amount, now);
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::RewardClaimed {
+            market_id,
+            user: caller_principal,
+            amount: reward_amount,
+        },
+    );
+
+    // Update market liquidity pool
+    MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        if let Some(market) = markets_map.get_mut(&market_id) {
+            market.icp_liquidity_pool = market.icp_liquidity_pool.saturating_sub(reward_amount);
+        }
+    });
+
+    Ok(claim)
+}
+
+/// What-if analysis: what the caller and all winners would receive if the market resolved now
+/// Pass `hypothetical_outcome` to simulate one outcome, or `None` for both
+#[ic_cdk::query]
+fn simulate_resolution(
+    market_id: u64,
+    hypothetical_outcome: Option<TokenType>,
+) -> Result<Vec<ResolutionSimulation>, PredictionMarketError> {
+    let outcomes = match hypothetical_outcome {
+        Some(outcome) => vec![outcome],
+        None => vec![TokenType::Yes, TokenType::No],
+    };
+    outcomes
+        .into_iter()
+        .map(|outcome| build_resolution_simulation(caller(), market_id, outcome))
+        .collect()
+}
+
+/// Apply the claim formula (winning tokens / total winning tokens * pool) without touching state
+fn build_resolution_simulation(
+    user: Principal,
+    market_id: u64,
+    outcome: TokenType,
+) -> Result<ResolutionSimulation, PredictionMarketError> {
+    let prize_pool = MARKETS.with(|markets| match markets.borrow().get(&market_id) {
+        Some(market) if is_settled(&market.status) => Err(PredictionMarketError::MarketResolved),
+        Some(market) => Ok(market.icp_liquidity_pool),
+        None => Err(PredictionMarketError::MarketNotFound),
+    })?;
+
+    // Holdings as a claim would see them: positions plus the receipts it redeems
+    let mut holdings: BTreeMap<Principal, u64> = BTreeMap::new();
+    USER_POSITIONS.with(|positions| {
+        for position in positions.borrow().values() {
+            if position.market_id == market_id {
+                let tokens = match outcome {
+                    TokenType::Yes => position.yes_tokens,
+                    TokenType::No => position.no_tokens,
+                };
+                *holdings.entry(position.user).or_default() += tokens;
+            }
+        }
+    });
+    POSITION_RECEIPTS.with(|receipts| {
+        for receipt in receipts.borrow().values() {
+            if receipt.market_id == market_id && receipt.token_type == outcome {
+                *holdings.entry(receipt.owner).or_default() += receipt.amount;
+            }
+        }
+    });
+    let winner_count = holdings.values().filter(|tokens| **tokens > 0).count() as u64;
+    let caller_winning_tokens = holdings.get(&user).copied().unwrap_or(0);
+    // Same denominator as `process_reward_claim`, escrowed tokens included
+    let total_winning_tokens = winning_supply(market_id, &outcome);
+
+    let caller_payout = if total_winning_tokens > 0 {
+        (caller_winning_tokens as u128 * prize_pool as u128 / total_winning_tokens as u128) as u64
+    } else {
+        0
+    };
+
+    Ok(ResolutionSimulation {
+        market_id,
+        outcome,
+        prize_pool,
+        total_winning_tokens,
+        winner_count,
+        caller_winning_tokens,
+        caller_payout,
+    })
+}
+
+// =============================================================================
+// DISPUTE WINDOW
+// =============================================================================
+
+/// Schedule the dispute window and bond for markets resolved from then on (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_dispute_config(window_ns: u64, bond: u64) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetDisputeConfig {
+            config: DisputeConfig { window_ns, bond },
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn validate_dispute_config(config: &DisputeConfig) -> Result<(), PredictionMarketError> {
+    if config.window_ns > 0 && config.bond == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn process_set_dispute_config(
+    caller_principal: Principal,
+    config: DisputeConfig,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_dispute_config(&config)?;
+    let DisputeConfig { window_ns, bond } = config;
+    DISPUTE_CONFIG.with(|current| *current.borrow_mut() = config);
+    emit_event(now, EventKind::DisputeConfigChanged { window_ns, bond });
+    Ok(())
+}
+
+/// Get a resolved market's dispute window and dispute, if any
+#[ic_cdk::query]
+fn get_dispute_window(market_id: u64) -> Option<DisputeWindow> {
+    DISPUTE_WINDOWS.with(|windows| windows.borrow().get(&market_id).cloned())
+}
+
+/// Dispute a market's resolution before its window closes, posting the dispute bond
+/// Claims stay locked until the global admin confirms or overturns the resolution
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn dispute_resolution(market_id: u64) -> Result<DisputeWindow, PredictionMarketError> {
+    process_dispute(caller(), market_id, ic_cdk::api::time())
+}
+
+fn process_dispute(
+    caller_principal: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<DisputeWindow, PredictionMarketError> {
+    let window = DISPUTE_WINDOWS
+        .with(|windows| windows.borrow().get(&market_id).cloned())
+        .filter(|window| !window.closed && now < window.closes_at)
+        .ok_or(PredictionMarketError::DisputeWindowClosed)?;
+    if window.dispute.is_some() {
+        return Err(PredictionMarketError::AlreadyDisputed);
+    }
+
+    let bond = DISPUTE_CONFIG.with(|config| config.borrow().bond);
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let balance = balances_map.entry(caller_principal).or_insert(0);
+        if *balance < bond {
+            return Err(PredictionMarketError::InsufficientDeposit {
+                required: bond,
+                available: *balance,
+            });
+        }
+        *balance -= bond;
+        Ok(())
+    })?;
+
+    let window = DISPUTE_WINDOWS.with(|windows| {
+        let mut windows_map = windows.borrow_mut();
+        let window = windows_map
+            .get_mut(&market_id)
+            .expect("window checked above");
+        window.dispute = Some(Dispute {
+            disputer: caller_principal,
+            bond,
+            opened_at: now,
+            verdict: None,
+        });
+        window.clone()
+    });
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::ResolutionDisputed {
+            market_id,
+            disputer: caller_principal,
+            bond,
+        },
+    );
+    Ok(window)
+}
+
+/// Confirm or overturn a disputed resolution (global admin only)
+/// Confirming slashes the bond to the treasury right away; overturning returns it and flips
+/// the outcome, so it is scheduled as `AdminAction::OverturnDispute` and waits out the timelock
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn settle_dispute(market_id: u64, overturn: bool) -> Result<String, PredictionMarketError> {
+    let now = ic_cdk::api::time();
+    if overturn {
+        let pending =
+            process_schedule_action(caller(), AdminAction::OverturnDispute { market_id }, now)?;
+        return Ok(scheduled_message(&pending));
+    }
+    process_dispute_settlement(caller(), market_id, false, now)?;
+    schedule_global_timer();
+    Ok(format!("Resolution of market {} confirmed", market_id))
+}
+
+/// The open dispute on a market, if its window has one awaiting a verdict
+fn pending_dispute(market_id: u64) -> Option<Dispute> {
+    DISPUTE_WINDOWS
+        .with(|windows| windows.borrow().get(&market_id).cloned())
+        .and_then(|window| window.dispute)
+        .filter(|dispute| dispute.verdict.is_none())
+}
+
+fn process_dispute_settlement(
+    actor: Principal,
+    market_id: u64,
+    overturn: bool,
+    now: u64,
+) -> Result<DisputeWindow, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let dispute = pending_dispute(market_id).ok_or(PredictionMarketError::DisputeNotFound)?;
+
+    let outcome = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        let resolution = market
+            .resolution
+            .as_mut()
+            .ok_or(PredictionMarketError::DisputeNotFound)?;
+        if overturn {
+            resolution.outcome = match resolution.outcome {
+                TokenType::Yes => TokenType::No,
+                TokenType::No => TokenType::Yes,
+            };
+            resolution.updated_at = now;
+            resolution.revision += 1;
+            market.winning_outcome = Some(resolution.outcome.clone());
+        }
+        Ok(resolution.outcome.clone())
+    })?;
+
+    let verdict = if overturn {
+        USER_BALANCES.with(|balances| {
+            *balances.borrow_mut().entry(dispute.disputer).or_insert(0) += dispute.bond;
+        });
+        slash_creator_bond(market_id, Some(dispute.disputer), now);
+        DisputeVerdict::Overturned
+    } else {
+        TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += dispute.bond);
+        DisputeVerdict::Confirmed
+    };
+    DISPUTE_WINDOWS.with(|windows| {
+        if let Some(dispute) = windows
+            .borrow_mut()
+            .get_mut(&market_id)
+            .and_then(|window| window.dispute.as_mut())
+        {
+            dispute.verdict = Some(verdict.clone());
+        }
+    });
+    emit_event(
+        now,
+        EventKind::DisputeSettled {
+            market_id,
+            verdict,
+            outcome,
+        },
+    );
+
+    // A window that ran out while the dispute was pending closes right away
+    close_dispute_windows(now);
+    Ok(DISPUTE_WINDOWS.with(|windows| windows.borrow()[&market_id].clone()))
+}
+
+/// Start the dispute window of a market that just resolved; false if disputes are disabled
+fn open_dispute_window(market_id: u64, now: u64) -> bool {
+    let window_ns = DISPUTE_CONFIG.with(|config| config.borrow().window_ns);
+    if window_ns == 0 {
+        return false;
+    }
+    DISPUTE_WINDOWS.with(|windows| {
+        windows.borrow_mut().insert(
+            market_id,
+            DisputeWindow {
+                market_id,
+                closes_at: now.saturating_add(window_ns),
+                dispute: None,
+                closed: false,
+            },
+        )
+    });
+    true
+}
+
+/// A window holds claims back until it has run out and its dispute, if any, is settled
+fn is_window_closable(window: &DisputeWindow, now: u64) -> bool {
+    !window.closed
+        && now >= window.closes_at
+        && window
+            .dispute
+            .as_ref()
+            .is_none_or(|dispute| dispute.verdict.is_some())
+}
+
+/// Unlock claims on every window that can close, settling pools nobody can claim
+fn close_dispute_windows(now: u64) -> Vec<u64> {
+    let mut closed: Vec<u64> = DISPUTE_WINDOWS.with(|windows| {
+        windows
+            .borrow_mut()
+            .values_mut()
+            .filter(|window| is_window_closable(window, now))
+            .map(|window| {
+                window.closed = true;
+                window.market_id
+            })
+            .collect()
+    });
+    closed.sort_unstable();
+
+    for &market_id in &closed {
+        let settled = MARKETS.with(|markets| {
+            markets
+                .borrow_mut()
+                .get_mut(&market_id)
+                .and_then(settle_unclaimable_pool)
+        });
+        emit_event(now, EventKind::DisputeWindowClosed { market_id });
+        if let Some(settled) = settled {
+            emit_event(now, settled);
+        }
+        release_creator_bond(market_id, now);
+    }
+    closed
+}
+
+fn ensure_claims_unlocked(market_id: u64, now: u64) -> Result<(), PredictionMarketError> {
+    let locked = DISPUTE_WINDOWS.with(|windows| {
+        windows
+            .borrow()
+            .get(&market_id)
+            .is_some_and(|window| !window.closed && !is_window_closable(window, now))
+    });
+    if locked {
+        return Err(PredictionMarketError::DisputeWindowOpen);
+    }
+    Ok(())
+}
+
+/// Earliest time an undisputed dispute window runs out, if any
+fn next_dispute_window_close() -> Option<u64> {
+    DISPUTE_WINDOWS.with(|windows| {
+        windows
+            .borrow()
+            .values()
+            .filter(|window| !window.closed && window.dispute.is_none())
+            .map(|window| window.closes_at)
+            .min()
+    })
+}
+
+// =============================================================================
+// COMMUNITY VOTE RESOLUTION
+// =============================================================================
+
+/// Vote on the outcome of a community-resolved market once its trading has closed
+/// The vote weighs the caller's YES and NO tokens; voting again replaces the earlier vote
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cast_resolution_vote(
+    market_id: u64,
+    outcome: TokenType,
+) -> Result<ResolutionVote, PredictionMarketError> {
+    process_resolution_vote(caller(), market_id, outcome, ic_cdk::api::time())
+}
+
+fn process_resolution_vote(
+    caller_principal: Principal,
+    market_id: u64,
+    outcome: TokenType,
+    now: u64,
+) -> Result<ResolutionVote, PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    if market.resolution_mode != ResolutionMode::CommunityVote {
+        return Err(PredictionMarketError::InvalidResolutionTransition);
+    }
+    let tallied = VOTE_TALLIES.with(|tallies| tallies.borrow().contains_key(&market_id));
+    if now < market.close_time
+        || now >= voting_ends_at(&market)
+        || tallied
+        || is_settled(&market.status)
+    {
+        return Err(PredictionMarketError::VotingClosed);
+    }
+
+    let weight = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .map_or(0, |position| position.yes_tokens + position.no_tokens)
+    });
+    if weight == 0 {
+        return Err(PredictionMarketError::NotEligibleToVote);
+    }
+
+    let vote = ResolutionVote {
+        voter: caller_principal,
+        outcome: outcome.clone(),
+        weight,
+        cast_at: now,
+    };
+    RESOLUTION_VOTES.with(|votes| {
+        votes
+            .borrow_mut()
+            .entry(market_id)
+            .or_default()
+            .insert(caller_principal, vote.clone())
+    });
+    emit_event(
+        now,
+        EventKind::ResolutionVoteCast {
+            market_id,
+            voter: caller_principal,
+            outcome,
+            weight,
+        },
+    );
+    Ok(vote)
+}
+
+/// Get the votes cast on a market, ordered by voter
+#[ic_cdk::query]
+fn get_resolution_votes(market_id: u64) -> Vec<ResolutionVote> {
+    RESOLUTION_VOTES.with(|votes| {
+        votes
+            .borrow()
+            .get(&market_id)
+            .map(|votes| votes.values().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Get the result of a market's community vote once it has been tallied
+#[ic_cdk::query]
+fn get_vote_tally(market_id: u64) -> Option<VoteTally> {
+    VOTE_TALLIES.with(|tallies| tallies.borrow().get(&market_id).cloned())
+}
+
+fn voting_ends_at(market: &AmmMarket) -> u64 {
+    market.close_time.saturating_add(VOTING_WINDOW_NS)
+}
+
+/// Community-resolved markets whose voting window has ended without a tally
+fn markets_awaiting_tally() -> Vec<AmmMarket> {
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .filter(|market| {
+                market.resolution_mode == ResolutionMode::CommunityVote
+                    && !is_settled(&market.status)
+                    && !VOTE_TALLIES.with(|tallies| tallies.borrow().contains_key(&market.id))
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+/// Tally every vote whose window has ended; a strict majority of weight resolves the market
+/// `tallier` is recorded as the proposer of the resulting resolution
+fn tally_resolution_votes(now: u64, tallier: Principal) -> Vec<u64> {
+    let mut due: Vec<u64> = markets_awaiting_tally()
+        .into_iter()
+        .filter(|market| now >= voting_ends_at(market))
+        .map(|market| market.id)
+        .collect();
+    due.sort_unstable();
+
+    for &market_id in &due {
+        let (yes_weight, no_weight) = RESOLUTION_VOTES.with(|votes| {
+            votes.borrow().get(&market_id).map_or((0, 0), |votes| {
+                votes
+                    .values()
+                    .fold((0u64, 0u64), |(yes, no), vote| match vote.outcome {
+                        TokenType::Yes => (yes.saturating_add(vote.weight), no),
+                        TokenType::No => (yes, no.saturating_add(vote.weight)),
+                    })
+            })
+        });
+        let majority = match yes_weight.cmp(&no_weight) {
+            std::cmp::Ordering::Greater => Some(TokenType::Yes),
+            std::cmp::Ordering::Less => Some(TokenType::No),
+            std::cmp::Ordering::Equal => None,
+        };
+        // Recorded before resolving so the vote cannot be tallied twice
+        VOTE_TALLIES.with(|tallies| {
+            tallies.borrow_mut().insert(
+                market_id,
+                VoteTally {
+                    market_id,
+                    yes_weight,
+                    no_weight,
+                    outcome: majority.clone(),
+                    tallied_at: now,
+                },
+            )
+        });
+        emit_event(
+            now,
+            EventKind::ResolutionVoteTallied {
+                market_id,
+                outcome: majority.clone(),
+                yes_weight,
+                no_weight,
+            },
+        );
+        if let Some(outcome) = majority {
+            let tallied = transition_resolution(
+                market_id,
+                current_resolution_revision(market_id),
+                ResolutionTransition::Tally(outcome),
+                tallier,
+                now,
+            );
+            if tallied.is_ok() {
+                record_resolution_evidence(
+                    market_id,
+                    format!(
+                        "Community vote: {} YES / {} NO token weight",
+                        yes_weight, no_weight
+                    ),
+                );
+            }
+        }
+    }
+    due
+}
+
+/// Earliest end of a voting window still waiting to be tallied, if any
+fn next_vote_tally_time() -> Option<u64> {
+    markets_awaiting_tally().iter().map(voting_ends_at).min()
+}
+
+// =============================================================================
+// CREATOR BONDS
+// =============================================================================
+
+/// Schedule the bond creators lock for markets created from then on (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_creator_bond_config(amount: u64, slash_bps: u64) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetCreatorBondConfig {
+            config: CreatorBondConfig { amount, slash_bps },
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_creator_bond_config(
+    caller_principal: Principal,
+    amount: u64,
+    slash_bps: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if slash_bps > 10_000 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    CREATOR_BOND_CONFIG
+        .with(|config| *config.borrow_mut() = CreatorBondConfig { amount, slash_bps });
+    emit_event(
+        now,
+        EventKind::CreatorBondConfigChanged { amount, slash_bps },
+    );
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_creator_bond(market_id: u64) -> Option<CreatorBond> {
+    CREATOR_BONDS.with(|bonds| bonds.borrow().get(&market_id).cloned())
+}
+
+/// Take the bond's slash share, once, to the vindicated disputer or else the treasury
+fn slash_creator_bond(market_id: u64, recipient: Option<Principal>, now: u64) -> u64 {
+    let amount = CREATOR_BONDS.with(|bonds| {
+        let mut bonds = bonds.borrow_mut();
+        match bonds.get_mut(&market_id) {
+            Some(bond) if !bond.released && bond.slashed == 0 => {
+                bond.slashed = scale_by(bond.amount, bond.slash_bps, 10_000);
+                bond.slashed
+            }
+            _ => 0,
+        }
+    });
+    if amount == 0 {
+        return 0;
+    }
+    match recipient {
+        Some(recipient) => USER_BALANCES.with(|balances| {
+            *balances.borrow_mut().entry(recipient).or_insert(0) += amount;
+        }),
+        None => TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += amount),
+    }
+    emit_event(
+        now,
+        EventKind::CreatorBondSlashed {
+            market_id,
+            amount,
+            recipient,
+        },
+    );
+    amount
+}
+
+/// Return what is left of the bond to the creator once the market is settled for good
+fn release_creator_bond(market_id: u64, now: u64) -> u64 {
+    let refund = CREATOR_BONDS.with(|bonds| {
+        let mut bonds = bonds.borrow_mut();
+        let bond = bonds.get_mut(&market_id).filter(|bond| !bond.released)?;
+        bond.released = true;
+        Some((bond.creator, bond.amount - bond.slashed))
+    });
+    let Some((creator, amount)) = refund else {
+        return 0;
+    };
+    USER_BALANCES.with(|balances| {
+        *balances.borrow_mut().entry(creator).or_insert(0) += amount;
+    });
+    emit_event(now, EventKind::CreatorBondReleased { market_id, amount });
+    amount
+}
+
+// =============================================================================
+// INVALID AND CANCELLED MARKETS
+// =============================================================================
+
+/// Resolve a market as invalid when its question turns out ambiguous (admin only)
+/// Nobody wins: holders claim back the ICP they put in, pro-rata if the pool falls short,
+/// and whatever the pool holds beyond that is returned to liquidity providers
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn resolve_market_invalid(market_id: u64) -> Result<String, PredictionMarketError> {
+    let refund_pool = process_invalidation(market_id, caller(), ic_cdk::api::time())?;
+    Ok(format!(
+        "Market {} resolved as invalid. Holders can now claim refunds from {}.",
+        market_id,
+        format_e8s(refund_pool)
+    ))
+}
+
+fn process_invalidation(
+    market_id: u64,
+    actor: Principal,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let actor_is_global_admin = is_global_admin(actor);
+    let contributed = total_contributions(market_id);
+
+    let refund_pool = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !actor_is_global_admin && !is_market_resolver(market, actor) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if is_settled(&market.status) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        Ok(open_refunds(market, contributed, MarketStatus::Invalid))
+    })?;
+
+    emit_event(
+        now,
+        EventKind::MarketInvalidated {
+            market_id,
+            refund_pool,
+        },
+    );
+    // An ambiguous question is the creator's failure
+    slash_creator_bond(market_id, None, now);
+    release_creator_bond(market_id, now);
+    Ok(refund_pool)
+}
+
+/// Void every market still unresolved AUTO_VOID_GRACE_NS after its close time
+/// so trader funds are not stuck when the resolver disappears; returns the voided IDs
+fn void_stale_markets(now: u64) -> Vec<u64> {
+    let stale: Vec<u64> = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .filter(|market| {
+                !is_settled(&market.status)
+                    && now >= market.close_time.saturating_add(AUTO_VOID_GRACE_NS)
+            })
+            .map(|market| market.id)
+            .collect()
+    });
+    for &market_id in &stale {
+        let contributed = total_contributions(market_id);
+        let refund_pool = MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map
+                .get_mut(&market_id)
+                .expect("stale market read above");
+            open_refunds(market, contributed, MarketStatus::Invalid)
+        });
+        emit_event(
+            now,
+            EventKind::MarketInvalidated {
+                market_id,
+                refund_pool,
+            },
+        );
+        slash_creator_bond(market_id, None, now);
+        release_creator_bond(market_id, now);
+    }
+    stale
+}
+
+/// When the next unresolved market runs out of grace, if any
+fn next_auto_void_time() -> Option<u64> {
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .filter(|market| !is_settled(&market.status))
+            .map(|market| market.close_time.saturating_add(AUTO_VOID_GRACE_NS))
+            .min()
+    })
+}
+
+/// Cancel an open market, e.g. a duplicate or malformed question (creator or global admin)
+/// Trading stops; traders claim back their net ICP and the seed liquidity returns to its providers
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_market(market_id: u64) -> Result<String, PredictionMarketError> {
+    let refund_pool = process_cancellation(market_id, caller(), ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(format!(
+        "Market {} cancelled. Traders can now claim refunds from {}.",
+        market_id,
+        format_e8s(refund_pool)
+    ))
+}
+
+fn process_cancellation(
+    market_id: u64,
+    actor: Principal,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let actor_is_global_admin = is_global_admin(actor);
+    let contributed = total_contributions(market_id);
+
+    let refund_pool = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !actor_is_global_admin && market.creator != actor {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        if market.status != MarketStatus::Open {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        Ok(open_refunds(market, contributed, MarketStatus::Cancelled))
+    })?;
+    release_creator_bond(market_id, now);
+
+    emit_event(
+        now,
+        EventKind::MarketCancelled {
+            market_id,
+            refund_pool,
+        },
+    );
+    Ok(refund_pool)
+}
+
+/// Remove a market nobody has traded or minted sets in, e.g. spam or a test (creator or moderator)
+/// The pool goes back to its liquidity providers, resting orders are released and the bond returned
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn delete_market(market_id: u64) -> Result<String, PredictionMarketError> {
+    let refunded = process_delete_market(caller(), market_id, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(format!(
+        "Market {} deleted. {} returned to its liquidity providers.",
+        market_id,
+        format_e8s(refunded)
+    ))
+}
+
+fn process_delete_market(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    if market.creator != actor && !is_moderator(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if market.status != MarketStatus::Open {
+        return Err(PredictionMarketError::MarketClosed);
+    }
+    if has_traded(market_id) || total_contributions(market_id) > 0 {
+        return Err(PredictionMarketError::MarketAlreadyTraded);
+    }
+
+    let open_orders: Vec<LimitOrder> = LIMIT_ORDERS.with(|orders| {
+        let mut orders = orders.borrow_mut();
+        let order_ids: Vec<u64> = orders
+            .values()
+            .filter(|order| order.market_id == market_id)
+            .map(|order| order.id)
+            .collect();
+        order_ids
+            .into_iter()
+            .filter_map(|order_id| orders.remove(&order_id))
+            .filter(|order| order.status == OrderStatus::Open)
+            .collect()
+    });
+    for order in open_orders {
+        credit_swap_asset(order.owner, &limit_order_escrow(&order));
+        emit_event(now, EventKind::LimitOrderCancelled { order_id: order.id });
+    }
+    release_creator_bond(market_id, now);
+
+    // Like a cancellation with nothing owed to traders: the whole pool is LP surplus
+    let refunded = market.icp_liquidity_pool;
+    let paid_to_providers = refund_lp_shares(&market, refunded);
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += refunded - paid_to_providers);
+
+    MARKETS.with(|markets| markets.borrow_mut().remove(&market_id));
+    LP_ACCOUNTS.with(|accounts| accounts.borrow_mut().retain(|(_, id), _| *id != market_id));
+    TREASURY_LP_ACCOUNTS.with(|accounts| accounts.borrow_mut().remove(&market_id));
+    USER_POSITIONS.with(|positions| positions.borrow_mut().retain(|(_, id), _| *id != market_id));
+    PRICE_TRIGGERS.with(|triggers| {
+        triggers
+            .borrow_mut()
+            .retain(|_, trigger| trigger.market_id != market_id)
+    });
+    COMMIT_REVEAL_MARKETS.with(|markets| markets.borrow_mut().remove(&market_id));
+    COMMENTS.with(|comments| comments.borrow_mut().remove(&market_id));
+    CREATOR_BONDS.with(|bonds| bonds.borrow_mut().remove(&market_id));
+    FEATURED_MARKETS.with(|featured| {
+        featured
+            .borrow_mut()
+            .retain(|entry| entry.market_id != market_id)
+    });
+    PENDING_RESOLVERS.with(|pending| pending.borrow_mut().remove(&market_id));
+    ABANDONED_MARKETS.with(|abandoned| abandoned.borrow_mut().remove(&market_id));
+    MARKET_ORACLES.with(|oracles| oracles.borrow_mut().remove(&market_id));
+    PRICE_FEEDS.with(|feeds| feeds.borrow_mut().remove(&market_id));
+    LIFECYCLE_HOOKS.with(|hooks| hooks.borrow_mut().remove(&market_id));
+    WEBHOOK_SECRETS.with(|secrets| secrets.borrow_mut().remove(&market_id));
+    DISPUTE_WINDOWS.with(|windows| windows.borrow_mut().remove(&market_id));
+    RESOLUTION_VOTES.with(|votes| votes.borrow_mut().remove(&market_id));
+    VOTE_TALLIES.with(|tallies| tallies.borrow_mut().remove(&market_id));
+    RESOLUTION_DRAFTS.with(|drafts| drafts.borrow_mut().remove(&market_id));
+    TRADE_HISTORY.with(|history| history.borrow_mut().remove(&market_id));
+    HOURLY_CANDLES.with(|candles| candles.borrow_mut().remove(&market_id));
+    DAILY_CANDLES.with(|candles| candles.borrow_mut().remove(&market_id));
+    PRICE_OBSERVATIONS.with(|observations| observations.borrow_mut().remove(&market_id));
+    ROLLING_STATS.with(|stats| stats.borrow_mut().remove(&market_id));
+    FEE_HOLIDAYS.with(|holidays| {
+        holidays
+            .borrow_mut()
+            .retain(|_, holiday| holiday.scope != FeeHolidayScope::Market(market_id))
+    });
+
+    // Emitted after the removal, so the market's certified document is withdrawn
+    emit_event(
+        now,
+        EventKind::MarketDeleted {
+            market_id,
+            refunded,
+        },
+    );
+    Ok(refunded)
+}
+
+/// Move a market into a refund status and set aside what its holders put in
+/// The rest of the pool, the seed liquidity included, goes back to liquidity providers by share
+/// Returns the refund pool, which only falls short of `contributed` if the pool does
+fn open_refunds(market: &mut AmmMarket, contributed: u64, status: MarketStatus) -> u64 {
+    let refund_pool = market.icp_liquidity_pool.min(contributed);
+    let surplus = market.icp_liquidity_pool - refund_pool;
+    let refunded = refund_lp_shares(market, surplus);
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += surplus - refunded);
+    market.icp_liquidity_pool = refund_pool;
+    market.status = status;
+    market.winning_outcome = None;
+    refund_pool
+}
+
+/// Invalid and cancelled markets pay refunds instead of rewards
+fn pays_refunds(status: &MarketStatus) -> bool {
+    matches!(status, MarketStatus::Invalid | MarketStatus::Cancelled)
+}
+
+/// Claim the refund from an invalid or cancelled market and burn the caller's outcome tokens
+/// Every holder gets the same fraction of what they put in
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_refund(market_id: u64) -> Result<RefundClaim, PredictionMarketError> {
+    let caller_principal = caller();
+    let _guard = OperationGuard::market_and_user(market_id, caller_principal)?;
+    let claim = process_refund_claim(caller_principal, market_id, ic_cdk::api::time())?;
+    record_instructions(CostOperation::ClaimReward);
+    Ok(claim)
+}
+
+fn process_refund_claim(
+    caller_principal: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<RefundClaim, PredictionMarketError> {
+    ensure_not_paused()?;
+    let pool = MARKETS.with(|markets| match markets.borrow().get(&market_id) {
+        Some(market) if pays_refunds(&market.status) => Ok(market.icp_liquidity_pool),
+        Some(_) => Err(PredictionMarketError::MarketClosed),
+        None => Err(PredictionMarketError::MarketNotFound),
+    })?;
+    let contributed = NET_CONTRIBUTIONS.with(|contributions| {
+        contributions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .copied()
+            .unwrap_or(0)
+    });
+    if contributed == 0 {
+        return Err(PredictionMarketError::NothingToClaim);
+    }
+
+    // The pool and the outstanding contributions shrink together, so the ratio holds for later claims
+    let refund_amount = scale_by(pool, contributed, total_contributions(market_id));
+    MARKETS.with(|markets| {
+        if let Some(market) = markets.borrow_mut().get_mut(&market_id) {
+            market.icp_liquidity_pool -= refund_amount;
+        }
+    });
+    NET_CONTRIBUTIONS.with(|contributions| {
+        contributions
+            .borrow_mut()
+            .remove(&(caller_principal, market_id))
+    });
+    USER_POSITIONS.with(|positions| {
+        if let Some(position) = positions
+            .borrow_mut()
+            .get_mut(&(caller_principal, market_id))
+        {
+            position.yes_tokens = 0;
+            position.no_tokens = 0;
+            position.claimed_reward = true;
+        }
+    });
+    USER_BALANCES.with(|balances| {
+        *balances.borrow_mut().entry(caller_principal).or_insert(0) += refund_amount;
+    });
+    record_pnl(caller_principal, market_id, 0, refund_amount, now);
+
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::RefundClaimed {
+            market_id,
+            user: caller_principal,
+            amount: refund_amount,
+        },
+    );
+    Ok(RefundClaim {
+        user: caller_principal,
+        market_id,
+        contributed,
+        refund_amount,
+        claim_time: now,
+    })
+}
+
+/// Track the net ICP a user has put into a market; never goes below zero
+fn adjust_contribution(user: Principal, market_id: u64, added: u64, removed: u64) {
+    NET_CONTRIBUTIONS.with(|contributions| {
+        let mut contributions_map = contributions.borrow_mut();
+        let contributed = contributions_map.entry((user, market_id)).or_insert(0);
+        *contributed = contributed.saturating_add(added).saturating_sub(removed);
+        if *contributed == 0 {
+            contributions_map.remove(&(user, market_id));
+        }
+    });
+}
+
+/// Net ICP still owed back across every holder of a market
+fn total_contributions(market_id: u64) -> u64 {
+    NET_CONTRIBUTIONS.with(|contributions| {
+        contributions
+            .borrow()
+            .iter()
+            .filter(|((_, id), _)| *id == market_id)
+            .map(|(_, contributed)| *contributed)
+            .sum()
+    })
+}
+
+/// Refunds not yet claimed in invalid or cancelled markets, as (user, market_id)
+fn pending_refunds() -> Vec<(Principal, u64)> {
+    NET_CONTRIBUTIONS.with(|contributions| {
+        contributions
+            .borrow()
+            .keys()
+            .filter(|(_, market_id)| {
+                MARKETS.with(|markets| {
+                    markets
+                        .borrow()
+                        .get(market_id)
+                        .is_some_and(|market| pays_refunds(&market.status))
+                })
+            })
+            .copied()
+            .collect()
+    })
+}
+
+// =============================================================================
+// MARKET COMMENTS
+// =============================================================================
+
+/// Post a comment on a market, e.g. to share evidence; open to any signed-in principal
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn post_comment(market_id: u64, text: String) -> Result<Comment, PredictionMarketError> {
+    process_post_comment(caller(), market_id, text, ic_cdk::api::time())
+}
+
+fn process_post_comment(
+    caller_principal: Principal,
+    market_id: u64,
+    text: String,
+    now: u64,
+) -> Result<Comment, PredictionMarketError> {
+    if caller_principal == Principal::anonymous() {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let text = text.trim().to_string();
+    if text.is_empty() || text.chars().count() > MAX_COMMENT_LEN {
+        return Err(PredictionMarketError::InvalidComment);
+    }
+    if MARKETS.with(|markets| !markets.borrow().contains_key(&market_id)) {
+        return Err(PredictionMarketError::MarketNotFound);
+    }
+
+    let comment_id = NEXT_COMMENT_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+    let comment = Comment {
+        id: comment_id,
+        market_id,
+        author: caller_principal,
+        text,
+        created_at: now,
+    };
+    COMMENTS.with(|comments| {
+        comments
+            .borrow_mut()
+            .entry(market_id)
+            .or_default()
+            .insert(comment_id, comment.clone())
+    });
+    emit_event(
+        now,
+        EventKind::CommentPosted {
+            market_id,
+            comment_id,
+            author: caller_principal,
+        },
+    );
+    Ok(comment)
+}
+
+/// Delete a comment; allowed for its author, moderators and the admin
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn delete_comment(market_id: u64, comment_id: u64) -> Result<(), PredictionMarketError> {
+    process_delete_comment(caller(), market_id, comment_id, ic_cdk::api::time())
+}
+
+fn process_delete_comment(
+    caller_principal: Principal,
+    market_id: u64,
+    comment_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        let thread = comments
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::CommentNotFound)?;
+        let author = thread
+            .get(&comment_id)
+            .ok_or(PredictionMarketError::CommentNotFound)?
+            .author;
+        if author != caller_principal && !is_moderator(caller_principal) {
+            return Err(PredictionMarketError::Unauthorized);
+        }
+        thread.remove(&comment_id);
+        Ok(())
+    })?;
+    emit_event(
+        now,
+        EventKind::CommentDeleted {
+            market_id,
+            comment_id,
+            deleted_by: caller_principal,
+        },
+    );
+    Ok(())
+}
+
+/// Get a market's comments, oldest first
+/// Pass the previous page's `next_cursor` to continue; pages are capped at MAX_LIST_RESULTS
+#[ic_cdk::query]
+fn get_comments(market_id: u64, cursor: Option<u64>) -> Page<Comment> {
+    let keyed = COMMENTS.with(|comments| {
+        comments
+            .borrow()
+            .get(&market_id)
+            .map(|thread| {
+                thread
+                    .iter()
+                    .map(|(id, comment)| (*id, comment.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    paginate(keyed, cursor)
+}
+
+/// Grant or revoke the moderator role (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_moderator(moderator: Principal, enabled: bool) -> Result<String, PredictionMarketError> {
+    process_set_moderator(caller(), moderator, enabled, ic_cdk::api::time())?;
+    Ok(format!(
+        "{} is {} a moderator",
+        moderator,
+        if enabled { "now" } else { "no longer" }
+    ))
+}
+
+fn process_set_moderator(
+    caller_principal: Principal,
+    moderator: Principal,
+    enabled: bool,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    MODERATORS.with(|moderators| {
+        let mut moderators = moderators.borrow_mut();
+        moderators.retain(|existing| *existing != moderator);
+        if enabled {
+            moderators.push(moderator);
+        }
+    });
+    emit_event(now, EventKind::ModeratorChanged { moderator, enabled });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_moderators() -> Vec<Principal> {
+    MODERATORS.with(|moderators| moderators.borrow().clone())
+}
+
+/// Moderators and the global admin can remove any comment
+fn is_moderator(principal: Principal) -> bool {
+    is_global_admin(principal)
+        || MODERATORS.with(|moderators| moderators.borrow().contains(&principal))
+}
+
+// =============================================================================
+// MARKET SHARDING
+// =============================================================================
+// The main canister is a router: past `local_market_limit` markets it moves the
+// creator's funds to the least-loaded worker and has the worker create the market under
+// an ID the router reserved, so IDs stay unique across shards. The router keeps the
+// registry; clients trade on the canister `get_market_shard` names, and the composite
+// queries below read across every shard. Workers run the same code with `ROUTER` set.
+
+/// Configure the worker shards and how many markets stay on this canister (admin only)
+/// Workers should share this canister's bond and fee settings, since the creator's funds
+/// are forwarded at this canister's rates
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_shard_config(config: ShardConfig) -> Result<String, PredictionMarketError> {
+    process_set_shard_config(caller(), config, ic_cdk::api::time())
+}
+
+fn process_set_shard_config(
+    actor: Principal,
+    mut config: ShardConfig,
+    now: u64,
+) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let mut seen = Vec::new();
+    config.workers.retain(|worker| {
+        let first = !seen.contains(worker);
+        seen.push(*worker);
+        first
+    });
+    if config.local_market_limit.is_some() && config.workers.is_empty() {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    // A shard that already holds markets must stay reachable
+    let in_use = MARKET_SHARDS.with(|shards| {
+        shards
+            .borrow()
+            .values()
+            .any(|worker| !config.workers.contains(worker))
+    });
+    if in_use {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    SHARD_CONFIG.with(|current| *current.borrow_mut() = config.clone());
+    emit_event(now, EventKind::ShardConfigChanged { config });
+    Ok("Shard configuration updated".to_string())
+}
+
+/// Make this canister a worker that only creates markets for `router` (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_router(router: Option<Principal>) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    ROUTER.with(|current| *current.borrow_mut() = router);
+    Ok(match router {
+        Some(router) => format!("Markets are now created through router {}", router),
+        None => "Markets are created directly on this canister".to_string(),
+    })
+}
+
+/// The worker canister holding a market, or None when it lives on this canister
+#[ic_cdk::query]
+fn get_market_shard(market_id: u64) -> Option<Principal> {
+    MARKET_SHARDS.with(|shards| shards.borrow().get(&market_id).copied())
+}
+
+/// Get a market from whichever shard holds it
+#[ic_cdk::query(composite = true)]
+async fn get_market_any_shard(
+    market_id: u64,
+) -> Result<Option<MarketSummary>, PredictionMarketError> {
+    match get_market_shard(market_id) {
+        Some(worker) => {
+            let (summary,): (Option<MarketSummary>,) =
+                ic_cdk::call(worker, "get_market", (market_id,))
+                    .await
+                    .map_err(|(_, message)| PredictionMarketError::CallFailed(message))?;
+            Ok(summary)
+        }
+        None => Ok(get_market(market_id)),
+    }
+}
+
+/// Get markets from this canister and every worker, ordered by ID
+/// Pages work like `get_markets`: pass `next_cursor` back as `cursor`
+#[ic_cdk::query(composite = true)]
+async fn get_markets_all_shards(
+    cursor: Option<u64>,
+) -> Result<Page<MarketSummary>, PredictionMarketError> {
+    let mut pages = vec![get_markets(cursor)];
+    let workers = SHARD_CONFIG.with(|config| config.borrow().workers.clone());
+    for worker in workers {
+        let (page,): (Page<MarketSummary>,) = ic_cdk::call(worker, "get_markets", (cursor,))
+            .await
+            .map_err(|(_, message)| PredictionMarketError::CallFailed(message))?;
+        pages.push(page);
+    }
+    Ok(merge_shard_pages(pages, cursor))
+}
+
+/// Merge per-shard pages into one page ordered by ID
+/// A truncated shard has only returned IDs below its `next_cursor`, so nothing at or past
+/// the lowest such cursor can be listed yet without skipping that shard's markets
+fn merge_shard_pages(pages: Vec<Page<MarketSummary>>, cursor: Option<u64>) -> Page<MarketSummary> {
+    let cutoff = pages.iter().filter_map(|page| page.next_cursor).min();
+    let keyed = pages
+        .into_iter()
+        .flat_map(|page| page.items)
+        .filter(|summary| cutoff.is_none_or(|cutoff| summary.market.id < cutoff))
+        .map(|summary| (summary.market.id, summary))
+        .collect();
+    let mut page = paginate(keyed, cursor);
+    if page.next_cursor.is_none() && cutoff.is_some() {
+        page.next_cursor = cutoff;
+        page.truncated = true;
+    }
+    page
+}
+
+/// The worker a new market goes to, once this canister holds its share
+fn shard_for_new_market() -> Option<Principal> {
+    let config = SHARD_CONFIG.with(|config| config.borrow().clone());
+    let limit = config.local_market_limit?;
+    let local_markets = MARKETS.with(|markets| markets.borrow().len() as u64);
+    if local_markets < limit {
+        return None;
+    }
+    // Least-loaded worker, the first listed on a tie
+    MARKET_SHARDS.with(|shards| {
+        let shards = shards.borrow();
+        config
+            .workers
+            .iter()
+            .copied()
+            .min_by_key(|worker| shards.values().filter(|holder| *holder == worker).count())
+    })
+}
+
+/// Workers only take markets from their router
+fn ensure_not_routed_worker() -> Result<(), PredictionMarketError> {
+    if ROUTER.with(|router| router.borrow().is_some()) {
+        return Err(PredictionMarketError::RoutedWorker);
+    }
+    Ok(())
+}
+
+/// Debit the creator, reserve an ID and register it, then have the worker create the market
+/// Everything is undone if the worker call fails or the worker rejects the market
+async fn create_market_on_shard(
+    creator: Principal,
+    worker: Principal,
+    new_market: NewMarket,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    let (market_id, funding) = reserve_sharded_market(creator, worker, &new_market, now)?;
+    let result: Result<(Result<u64, PredictionMarketError>,), _> = ic_cdk::call(
+        worker,
+        "create_routed_market",
+        (creator, market_id, funding, new_market),
+    )
+    .await;
+    let created = match result {
+        Ok((created,)) => created,
+        Err((_, message)) => Err(PredictionMarketError::CallFailed(message)),
+    };
+    if let Err(error) = created {
+        release_sharded_market(creator, market_id, funding);
+        return Err(error);
+    }
+    Ok(market_id)
+}
+
+fn reserve_sharded_market(
+    creator: Principal,
+    worker: Principal,
+    new_market: &NewMarket,
+    now: u64,
+) -> Result<(u64, u64), PredictionMarketError> {
+    ensure_platform_active()?;
+    ensure_not_paused()?;
+    ensure_creator_allowed(creator)?;
+    registered_category(new_market.category.clone())?;
+    if new_market.initial_icp_liquidity < MIN_DEPOSIT {
+        return Err(PredictionMarketError::InsufficientDeposit {
+            required: MIN_DEPOSIT,
+            available: new_market.initial_icp_liquidity,
+        });
+    }
+    if new_market.close_time <= now {
+        return Err(PredictionMarketError::InvalidCloseTime);
+    }
+    let bond = CREATOR_BOND_CONFIG.with(|config| config.borrow().amount);
+    let funding = new_market
+        .initial_icp_liquidity
+        .saturating_add(bond)
+        .saturating_add(CREATION_FEE.with(|fee| *fee.borrow()));
+    USER_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.entry(creator).or_insert(0);
+        if *balance < funding {
+            return Err(PredictionMarketError::InsufficientDeposit {
+                required: funding,
+                available: *balance,
+            });
+        }
+        *balance -= funding;
+        Ok(())
+    })?;
+    let market_id = NEXT_MARKET_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+    MARKET_SHARDS.with(|shards| shards.borrow_mut().insert(market_id, worker));
+    Ok((market_id, funding))
+}
+
+fn release_sharded_market(creator: Principal, market_id: u64, funding: u64) {
+    MARKET_SHARDS.with(|shards| shards.borrow_mut().remove(&market_id));
+    USER_BALANCES.with(|balances| {
+        *balances.borrow_mut().entry(creator).or_insert(0) += funding;
+    });
+}
+
+/// Create a market forwarded by this worker's router under the ID the router reserved
+/// `funding` is the creator's ICP the router debited; whatever the market does not use
+/// stays in the creator's balance here
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn create_routed_market(
+    creator: Principal,
+    market_id: u64,
+    funding: u64,
+    new_market: NewMarket,
+) -> Result<u64, PredictionMarketError> {
+    let market_id = process_create_routed_market(
+        caller(),
+        creator,
+        market_id,
+        funding,
+        new_market,
+        ic_cdk::api::time(),
+    )?;
+    schedule_global_timer();
+    Ok(market_id)
+}
+
+fn process_create_routed_market(
+    caller_principal: Principal,
+    creator: Principal,
+    market_id: u64,
+    funding: u64,
+    new_market: NewMarket,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    if ROUTER.with(|router| *router.borrow()) != Some(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    USER_BALANCES.with(|balances| *balances.borrow_mut().entry(creator).or_insert(0) += funding);
+    // Worker IDs come from the router, so the local counter only matters for this call
+    let next_local_id = NEXT_MARKET_ID.with(|id| id.replace(market_id));
+    let created = process_create_market(creator, new_market, now);
+    NEXT_MARKET_ID.with(|id| *id.borrow_mut() = next_local_id);
+    if created.is_err() {
+        USER_BALANCES.with(|balances| {
+            if let Some(balance) = balances.borrow_mut().get_mut(&creator) {
+                *balance -= funding;
+            }
+        });
+    }
+    created
+}
+
+// =============================================================================
+// CREATOR ALLOWLIST AND CREATION FEE
+// =============================================================================
+
+/// Restrict market creation to listed creators, e.g. during early operation (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_creator_allowlist_mode(enabled: bool) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    CREATOR_ALLOWLIST_MODE.with(|mode| *mode.borrow_mut() = enabled);
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::CreatorAllowlistModeChanged { enabled },
+    );
+    Ok(format!(
+        "Creator allowlist {}",
+        if enabled { "enabled" } else { "disabled" }
+    ))
+}
+
+/// Schedule a new flat fee `create_market` sends to the treasury; 0 disables it (admin only)
+/// Frontends read it from `get_config` to show the full cost of creating a market
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_creation_fee(amount: u64) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetCreationFee { amount },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_creation_fee(
+    caller_principal: Principal,
+    amount: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    CREATION_FEE.with(|fee| *fee.borrow_mut() = amount);
+    emit_event(now, EventKind::CreationFeeChanged { amount });
+    Ok(())
+}
+
+/// Allow a principal to create markets while allowlist mode is on (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn add_creator(creator: Principal) -> Result<String, PredictionMarketError> {
+    process_set_creator(caller(), creator, true, ic_cdk::api::time())?;
+    Ok(format!("{} may create markets", creator))
+}
+
+/// Take a principal off the creator allowlist; its existing markets are unaffected (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn remove_creator(creator: Principal) -> Result<String, PredictionMarketError> {
+    process_set_creator(caller(), creator, false, ic_cdk::api::time())?;
+    Ok(format!("{} removed from the creator allowlist", creator))
+}
+
+fn process_set_creator(
+    caller_principal: Principal,
+    creator: Principal,
+    allowed: bool,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    ALLOWED_CREATORS.with(|creators| {
+        let mut creators = creators.borrow_mut();
+        creators.retain(|existing| *existing != creator);
+        if allowed {
+            creators.push(creator);
+        }
+    });
+    emit_event(now, EventKind::CreatorAllowlistChanged { creator, allowed });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_creators() -> Vec<Principal> {
+    ALLOWED_CREATORS.with(|creators| creators.borrow().clone())
+}
+
+fn ensure_creator_allowed(creator: Principal) -> Result<(), PredictionMarketError> {
+    let allowed = !CREATOR_ALLOWLIST_MODE.with(|mode| *mode.borrow())
+        || is_global_admin(creator)
+        || ALLOWED_CREATORS.with(|creators| creators.borrow().contains(&creator));
+    if allowed {
+        Ok(())
+    } else {
+        Err(PredictionMarketError::CreatorNotAllowed)
+    }
+}
+
+// =============================================================================
+// MARKET TEXT VALIDATION
+// =============================================================================
+
+/// Set the length bounds for market titles and descriptions (admin only)
+/// Existing markets keep their text; the bounds apply to markets created afterwards
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_text_limits(limits: TextLimits) -> Result<String, PredictionMarketError> {
+    process_set_text_limits(caller(), limits, ic_cdk::api::time())
+}
+
+fn process_set_text_limits(
+    actor: Principal,
+    limits: TextLimits,
+    now: u64,
+) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if limits.min_title_len == 0 || limits.min_title_len > limits.max_title_len {
+        return Err(invalid_input(
+            "min_title_len",
+            "must be between 1 and max_title_len".to_string(),
+        ));
+    }
+    if limits.min_description_len == 0 || limits.min_description_len > limits.max_description_len {
+        return Err(invalid_input(
+            "min_description_len",
+            "must be between 1 and max_description_len".to_string(),
+        ));
+    }
+
+    TEXT_LIMITS.with(|current| *current.borrow_mut() = limits.clone());
+    emit_event(now, EventKind::TextLimitsChanged { limits });
+    Ok("Text limits updated".to_string())
+}
+
+/// Cleaned title and description, or the first field that breaks the configured limits
+/// Titles lose every control character; descriptions keep their line breaks
+fn clean_market_text(
+    title: &str,
+    description: &str,
+) -> Result<(String, String), PredictionMarketError> {
+    let limits = TEXT_LIMITS.with(|limits| limits.borrow().clone());
+    let title = clean_text(
+        "title",
+        title,
+        |c| c.is_control(),
+        limits.min_title_len,
+        limits.max_title_len,
+    )?;
+    let description = clean_text(
+        "description",
+        description,
+        |c| c.is_control() && c != '\n',
+        limits.min_description_len,
+        limits.max_description_len,
+    )?;
+    Ok((title, description))
+}
+
+fn clean_text(
+    field: &str,
+    text: &str,
+    strip: impl Fn(char) -> bool,
+    min_len: u64,
+    max_len: u64,
+) -> Result<String, PredictionMarketError> {
+    let cleaned: String = text.chars().filter(|c| !strip(*c)).collect();
+    let cleaned = cleaned.trim().to_string();
+    if cleaned.is_empty() {
+        return Err(invalid_input(field, "must not be empty".to_string()));
+    }
+    let len = cleaned.chars().count() as u64;
+    if len < min_len || len > max_len {
+        return Err(invalid_input(
+            field,
+            format!("must be {} to {} characters", min_len, max_len),
+        ));
+    }
+    Ok(cleaned)
+}
+
+fn invalid_input(field: &str, reason: String) -> PredictionMarketError {
+    PredictionMarketError::InvalidInput {
+        field: field.to_string(),
+        reason,
+    }
+}
+
+// =============================================================================
+// CATEGORY REGISTRY
+// =============================================================================
+
+/// Register a category markets can be created under (admin only)
+/// Until the first category is added, markets may name any category
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn add_category(name: String) -> Result<Category, PredictionMarketError> {
+    process_add_category(caller(), name, ic_cdk::api::time())
+}
+
+fn process_add_category(
+    actor: Principal,
+    name: String,
+    now: u64,
+) -> Result<Category, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let name = clean_category_name(&name)?;
+    let slug = name.to_lowercase();
+    if CATEGORIES.with(|categories| categories.borrow().contains_key(&slug)) {
+        return Err(invalid_input("name", "category already exists".to_string()));
+    }
+
+    let category = Category {
+        slug: slug.clone(),
+        name,
+        archived: false,
+        created_at: now,
+    };
+    CATEGORIES.with(|categories| categories.borrow_mut().insert(slug, category.clone()));
+    emit_event(
+        now,
+        EventKind::CategoryChanged {
+            category: category.clone(),
+        },
+    );
+    Ok(category)
+}
+
+/// Change a category's display name (admin only)
+/// The slug stays put, so markets, fee holidays and matching rules filed under it are unaffected
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn rename_category(slug: String, name: String) -> Result<Category, PredictionMarketError> {
+    process_rename_category(caller(), slug, name, ic_cdk::api::time())
+}
+
+fn process_rename_category(
+    actor: Principal,
+    slug: String,
+    name: String,
+    now: u64,
+) -> Result<Category, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let slug = slug.trim().to_lowercase();
+    let name = clean_category_name(&name)?;
+    // Two entries that differ only in case would be indistinguishable in listings
+    let clashes = CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .values()
+            .any(|other| other.slug != slug && other.name.to_lowercase() == name.to_lowercase())
+    });
+    if clashes {
+        return Err(invalid_input("name", "category already exists".to_string()));
+    }
+
+    let category = CATEGORIES.with(|categories| {
+        let mut categories = categories.borrow_mut();
+        let category = categories
+            .get_mut(&slug)
+            .ok_or_else(|| invalid_input("slug", "is not a registered category".to_string()))?;
+        category.name = name;
+        Ok(category.clone())
+    })?;
+    emit_event(
+        now,
+        EventKind::CategoryChanged {
+            category: category.clone(),
+        },
+    );
+    Ok(category)
+}
+
+/// Close a category to new markets (admin only)
+/// Markets already filed under it keep the category and stay listed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn archive_category(slug: String) -> Result<Category, PredictionMarketError> {
+    process_archive_category(caller(), slug, ic_cdk::api::time())
+}
+
+fn process_archive_category(
+    actor: Principal,
+    slug: String,
+    now: u64,
+) -> Result<Category, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let slug = slug.trim().to_lowercase();
+    let category = CATEGORIES.with(|categories| {
+        let mut categories = categories.borrow_mut();
+        let category = categories
+            .get_mut(&slug)
+            .ok_or_else(|| invalid_input("slug", "is not a registered category".to_string()))?;
+        category.archived = true;
+        Ok(category.clone())
+    })?;
+    emit_event(
+        now,
+        EventKind::CategoryChanged {
+            category: category.clone(),
+        },
+    );
+    Ok(category)
+}
+
+/// Every registered category, archived ones included, by slug
+#[ic_cdk::query]
+fn list_categories() -> Vec<Category> {
+    CATEGORIES.with(|categories| categories.borrow().values().cloned().collect())
+}
+
+fn clean_category_name(name: &str) -> Result<String, PredictionMarketError> {
+    clean_text("name", name, |c| c.is_control(), 1, MAX_CATEGORY_NAME_LEN)
+}
+
+/// The slug a new market is filed under, or why the category is not accepted
+/// With an empty registry any category is taken as given, as before the registry existed
+fn registered_category(category: Option<String>) -> Result<Option<String>, PredictionMarketError> {
+    let Some(slug) = normalize_category(category) else {
+        return Ok(None);
+    };
+    CATEGORIES.with(|categories| {
+        let categories = categories.borrow();
+        if categories.is_empty() {
+            return Ok(Some(slug));
+        }
+        match categories.get(&slug) {
+            Some(entry) if entry.archived => Err(invalid_input(
+                "category",
+                "category is archived".to_string(),
+            )),
+            Some(_) => Ok(Some(slug)),
+            None => Err(invalid_input(
+                "category",
+                "is not a registered category".to_string(),
+            )),
+        }
+    })
+}
+
+// =============================================================================
+// FEATURED MARKETS
+// =============================================================================
+
+/// Pin a market to the featured list until `until` (moderators only)
+/// A market already on the list keeps its place and takes the new expiry; new ones go last
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn feature_market(market_id: u64, until: u64) -> Result<(), PredictionMarketError> {
+    process_feature_market(caller(), market_id, until, ic_cdk::api::time())
+}
+
+fn process_feature_market(
+    actor: Principal,
+    market_id: u64,
+    until: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_moderator(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if until <= now {
+        return Err(invalid_input("until", "must be in the future".to_string()));
+    }
+    if !MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+        return Err(PredictionMarketError::MarketNotFound);
+    }
+
+    FEATURED_MARKETS.with(|featured| {
+        let mut featured = featured.borrow_mut();
+        featured.retain(|entry| entry.until > now);
+        if let Some(entry) = featured
+            .iter_mut()
+            .find(|entry| entry.market_id == market_id)
+        {
+            entry.until = until;
+            entry.featured_by = actor;
+            return Ok(());
+        }
+        if featured.len() >= MAX_FEATURED_MARKETS {
+            return Err(invalid_input(
+                "market_id",
+                format!("at most {} markets can be featured", MAX_FEATURED_MARKETS),
+            ));
+        }
+        featured.push(FeaturedMarket {
+            market_id,
+            until,
+            featured_by: actor,
+        });
+        Ok(())
+    })?;
+    emit_event(
+        now,
+        EventKind::FeaturedMarketChanged {
+            market_id,
+            until: Some(until),
+        },
+    );
+    Ok(())
+}
+
+/// Take a market off the featured list before it expires (moderators only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn unfeature_market(market_id: u64) -> Result<(), PredictionMarketError> {
+    process_unfeature_market(caller(), market_id, ic_cdk::api::time())
+}
+
+fn process_unfeature_market(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_moderator(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let removed = FEATURED_MARKETS.with(|featured| {
+        let mut featured = featured.borrow_mut();
+        let before = featured.len();
+        featured.retain(|entry| entry.market_id != market_id);
+        featured.len() < before
+    });
+    if !removed {
+        return Err(PredictionMarketError::MarketNotFound);
+    }
+    emit_event(
+        now,
+        EventKind::FeaturedMarketChanged {
+            market_id,
+            until: None,
+        },
+    );
+    Ok(())
+}
+
+/// Summaries of the markets currently featured, in the order moderators pinned them
+#[ic_cdk::query]
+fn get_featured_markets() -> Vec<MarketSummary> {
+    featured_markets(ic_cdk::api::time())
+}
+
+fn featured_markets(now: u64) -> Vec<MarketSummary> {
+    let featured = FEATURED_MARKETS.with(|featured| featured.borrow().clone());
+    MARKETS.with(|markets| {
+        let markets = markets.borrow();
+        featured
+            .iter()
+            .filter(|entry| entry.until > now)
+            .filter_map(|entry| markets.get(&entry.market_id))
+            .map(|market| build_market_summary(market, now))
+            .collect()
+    })
+}
+
+// =============================================================================
+// QUERY FUNCTIONS
+// =============================================================================
+
+/// Compute prices and stats for a market
+fn build_market_summary(market: &AmmMarket, now: u64) -> MarketSummary {
+    let (yes_price, no_price) = market_prices(market);
+
+    // Calculate price impact for a standard trade (1 ICP)
+    let standard_trade = E8S_PER_ICP;
+    let price_impact =
+        match calculate_tokens_out(market.yes_reserve, market.no_reserve, standard_trade, true) {
+            Ok(tokens_out) => {
+                let icp_after_fee = standard_trade - trade_fee(standard_trade);
+                let new_yes_reserve = market.yes_reserve as f64 + tokens_out as f64;
+                let new_no_reserve = (market.no_reserve - icp_after_fee) as f64;
+                let new_price = new_no_reserve / (new_yes_reserve + new_no_reserve);
+                ((new_price - yes_price) / yes_price * 100.0).abs()
+            }
+            Err(_) => 0.0,
+        };
+
+    let (volume_24h, trade_count_24h, price_change_24h) = rolling_stats_24h(market.id, now);
+
+    MarketSummary {
+        market: market.clone(),
+        yes_price,
+        no_price,
+        total_volume: total_traded_volume(market.id),
+        price_impact,
+        volume_24h,
+        trade_count_24h,
+        unique_traders_24h: unique_traders_24h(market.id, now),
+        price_change_24h,
+        commit_reveal: requires_commit_reveal(market.id),
+    }
+}
+
+/// Current (YES, NO) marginal prices of a market
+/// Summed as f64 so two large reserves cannot overflow
+fn market_prices(market: &AmmMarket) -> (f64, f64) {
+    let total_reserves = market.yes_reserve as f64 + market.no_reserve as f64;
+    if total_reserves > 0.0 {
+        (
+            market.no_reserve as f64 / total_reserves,
+            market.yes_reserve as f64 / total_reserves,
+        )
+    } else {
+        (0.5, 0.5) // Equal prices when no liquidity
+    }
+}
+
+fn build_market_listing(market: &AmmMarket) -> MarketListing {
+    let (yes_price, no_price) = market_prices(market);
+    MarketListing {
+        id: market.id,
+        title: market.title.clone(),
+        status: market.status.clone(),
+        yes_price,
+        no_price,
+        icp_liquidity_pool: market.icp_liquidity_pool,
+        close_time: market.close_time,
+        category: market.category.clone(),
+    }
+}
+
+/// Trim and lowercase a category, treating a blank one as no category
+fn normalize_category(category: Option<String>) -> Option<String> {
+    category
+        .map(|category| category.trim().to_lowercase())
+        .filter(|category| !category.is_empty())
+}
+
+fn market_matches_filter(market: &AmmMarket, filter: &MarketFilter) -> bool {
+    let category = normalize_category(filter.category.clone());
+    filter
+        .status
+        .as_ref()
+        .is_none_or(|status| market.status == *status)
+        && filter
+            .creator
+            .is_none_or(|creator| market.creator == creator)
+        && category.is_none_or(|category| market.category.as_ref() == Some(&category))
+        && (!filter.open_only || market.status == MarketStatus::Open)
+        && (!filter.resolved_only || market.status == MarketStatus::Resolved)
+}
+
+/// Get markets with current prices and stats, ordered by ID
+/// At most MAX_LIST_RESULTS markets are returned; follow `next_cursor` for more
+#[ic_cdk::query]
+fn get_markets(cursor: Option<u64>) -> Page<MarketSummary> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        let keyed = markets_map
+            .values()
+            .map(|market| (market.id, market))
+            .collect();
+        let now = ic_cdk::api::time();
+        paginate(keyed, cursor).map(|market| build_market_summary(market, now))
+    })
+}
+
+/// Get a window of lightweight market listings ordered by ID
+/// `limit` is capped at MAX_LIST_RESULTS; `next_cursor` is the offset of the following page
+#[ic_cdk::query]
+fn get_markets_paginated(offset: u64, limit: u64) -> Page<MarketListing> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        let mut ids: Vec<u64> = markets_map.keys().copied().collect();
+        ids.sort_unstable();
+        paginate_offset(ids, offset, limit).map(|id| build_market_listing(&markets_map[&id]))
+    })
+}
+
+/// List markets matching a filter as lightweight listings, ordered by ID unless `sort` is set
+/// At most MAX_LIST_RESULTS markets are returned; `offset` counts listings into the chosen
+/// order, and `next_cursor` is the offset of the following page whether sorted or not
+#[ic_cdk::query]
+fn list_markets(
+    filter: MarketFilter,
+    offset: Option<u64>,
+    sort: Option<SortBy>,
+) -> Page<MarketListing> {
+    compute_market_listing(filter, offset, sort, ic_cdk::api::time())
+}
+
+fn compute_market_listing(
+    filter: MarketFilter,
+    offset: Option<u64>,
+    sort: Option<SortBy>,
+    now: u64,
+) -> Page<MarketListing> {
+    MARKETS.with(|markets| {
+        let markets = markets.borrow();
+        let matching = markets
+            .values()
+            .filter(|market| market_matches_filter(market, &filter));
+        // Sort keys come from the maintained rolling stats, read once per market;
+        // without a sort every key ties and the order falls back to market ID
+        let mut keyed: Vec<(i128, f64, &AmmMarket)> = matching
+            .map(|market| match sort {
+                None => (0, 0.0, market),
+                Some(SortBy::Newest) => (-(market.creation_time as i128), 0.0, market),
+                Some(SortBy::ClosingSoonest) => (market.close_time as i128, 0.0, market),
+                Some(SortBy::HighestVolume) => {
+                    (-(total_traded_volume(market.id) as i128), 0.0, market)
+                }
+                Some(SortBy::MostLiquid) => (-(market.icp_liquidity_pool as i128), 0.0, market),
+                Some(SortBy::BiggestMove24h) => {
+                    (0, -rolling_stats_24h(market.id, now).2.abs(), market)
+                }
+            })
+            .collect();
+        keyed.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then(a.1.total_cmp(&b.1))
+                .then(a.2.id.cmp(&b.2.id))
+        });
+        let ranked = keyed
+            .into_iter()
+            .map(|(_, _, market)| build_market_listing(market))
+            .collect();
+        paginate_offset(ranked, offset.unwrap_or(0), MAX_LIST_RESULTS as u64)
+    })
+}
+
+/// Find markets whose title or description contains every word of `query` (case-insensitive)
+/// At most MAX_LIST_RESULTS markets are returned; follow `next_cursor` for more
+#[ic_cdk::query]
+fn search_markets(query: String, cursor: Option<u64>) -> Page<MarketListing> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    MARKETS.with(|markets| {
+        let keyed = markets
+            .borrow()
+            .values()
+            .filter(|market| market_matches_search(market, &terms))
+            .map(|market| (market.id, build_market_listing(market)))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+fn market_matches_search(market: &AmmMarket, terms: &[String]) -> bool {
+    let title = market.title.to_lowercase();
+    let description = market.description.to_lowercase();
+    terms
+        .iter()
+        .all(|term| title.contains(term.as_str()) || description.contains(term.as_str()))
+}
+
+/// Total number of markets, in any status
+#[ic_cdk::query]
+fn get_market_count() -> u64 {
+    MARKETS.with(|markets| markets.borrow().len() as u64)
+}
+
+/// Get a specific market by ID
+#[ic_cdk::query]
+fn get_market(market_id: u64) -> Option<MarketSummary> {
+    let now = ic_cdk::api::time();
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| build_market_summary(market, now))
+    })
+}
+
+/// Sort items by key and return those at or after `cursor`, capped at MAX_LIST_RESULTS
+fn paginate<T>(mut keyed: Vec<(u64, T)>, cursor: Option<u64>) -> Page<T> {
+    keyed.retain(|(key, _)| cursor.is_none_or(|start| *key >= start));
+    keyed.sort_by_key(|(key, _)| *key);
+    let next_cursor = keyed.get(MAX_LIST_RESULTS).map(|(key, _)| *key);
+    keyed.truncate(MAX_LIST_RESULTS);
+    Page {
+        items: keyed.into_iter().map(|(_, item)| item).collect(),
+        truncated: next_cursor.is_some(),
+        next_cursor,
+    }
+}
+
+/// Return up to `limit` (capped at MAX_LIST_RESULTS) items starting at `offset`
+fn paginate_offset<T>(items: Vec<T>, offset: u64, limit: u64) -> Page<T> {
+    let limit = (limit as usize).min(MAX_LIST_RESULTS);
+    let start = (offset as usize).min(items.len());
+    let end = start.saturating_add(limit).min(items.len());
+    let next_cursor = (end < items.len()).then_some(end as u64);
+    Page {
+        items: items.into_iter().skip(start).take(end - start).collect(),
+        truncated: next_cursor.is_some(),
+        next_cursor,
+    }
+}
+
+/// Get user position for a specific market
+#[ic_cdk::query]
+fn get_user_position(market_id: u64) -> Option<UserPosition> {
+    let caller_principal = caller();
+    USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .get(&(caller_principal, market_id))
+            .cloned()
+    })
+}
+
+/// Get all user positions, ordered by market ID
+#[ic_cdk::query]
+fn get_all_user_positions(cursor: Option<u64>) -> Page<UserPosition> {
+    positions_of(caller(), cursor)
+}
+
+fn positions_of(user: Principal, cursor: Option<u64>) -> Page<UserPosition> {
+    USER_POSITIONS.with(|positions| {
+        let keyed = positions
+            .borrow()
+            .values()
+            .filter(|pos| pos.user == user)
+            .map(|pos| (pos.market_id, pos.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+/// Get user's ICP balance
+#[ic_cdk::query]
+fn get_user_balance() -> u64 {
+    let caller_principal = caller();
+    USER_BALANCES.with(|balances| *balances.borrow().get(&caller_principal).unwrap_or(&0))
+}
+
+/// Get user's ICP balance by Principal (for admin queries)
+#[ic_cdk::query]
+fn get_balance_of(user: Principal) -> u64 {
+    USER_BALANCES.with(|balances| *balances.borrow().get(&user).unwrap_or(&0))
+}
+
+/// Get reward claims for a user
+#[ic_cdk::query]
+fn get_user_claims(cursor: Option<u64>) -> Page<RewardClaim> {
+    let caller_principal = caller();
+    REWARD_CLAIMS.with(|claims| {
+        let keyed = claims
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, claim)| claim.user == caller_principal)
+            .map(|(index, claim)| (index as u64, claim.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    })
+}
+
+/// Calculate quote for buying tokens (without executing the trade)
+#[ic_cdk::query]
+fn get_buy_quote(
+    market_id: u64,
+    icp_amount: u64,
+    token_type: TokenType,
+) -> Result<TradeResult, PredictionMarketError> {
+    compute_buy_quote(market_id, icp_amount, token_type, ic_cdk::api::time())
+}
+
+fn compute_buy_quote(
+    market_id: u64,
+    icp_amount: u64,
+    token_type: TokenType,
+    now: u64,
+) -> Result<TradeResult, PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        match markets_map.get(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+
+                let impact_bps = buy_impact_bps(market, icp_amount, &token_type);
+                let (fee_paid, holiday) =
+                    market_trade_fee(market, icp_amount, impact_bps, None, now);
+                let tokens_out = calculate_tokens_out_after_fee(
+                    market.yes_reserve,
+                    market.no_reserve,
+                    icp_amount,
+                    fee_paid,
+                    matches!(token_type, TokenType::Yes),
+                )?;
+
+                // Calculate new price after this hypothetical trade
+                let (new_yes_reserve, new_no_reserve) = match token_type {
+                    TokenType::Yes => (
+                        add_or_overflow(market.yes_reserve, tokens_out)?,
+                        market.no_reserve - (icp_amount - fee_paid),
+                    ),
+                    TokenType::No => (
+                        market.yes_reserve - (icp_amount - fee_paid),
+                        add_or_overflow(market.no_reserve, tokens_out)?,
+                    ),
+                };
+                let new_price = price_after(new_yes_reserve, new_no_reserve, &token_type);
+                let price_before = price_after(market.yes_reserve, market.no_reserve, &token_type);
+
+                Ok(TradeResult {
+                    tokens_received: tokens_out,
+                    tokens_paid: icp_amount,
+                    fee_paid,
+                    new_price,
+                    fee_holiday_until: holiday.map(|holiday| holiday.end_time),
+                    market_id,
+                    token_type,
+                    average_price: average_price(icp_amount, tokens_out),
+                    price_impact: price_impact_pct(price_before, new_price),
+                    timestamp: now,
+                    position: None,
+                })
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })
+}
+
+/// Quote the ICP needed to buy `tokens_wanted` tokens (without executing the trade)
+/// `tokens_paid` is the ICP required; `tokens_received` can exceed `tokens_wanted` by rounding
+#[ic_cdk::query]
+fn get_buy_quote_exact_out(
+    market_id: u64,
+    tokens_wanted: u64,
+    token_type: TokenType,
+) -> Result<TradeResult, PredictionMarketError> {
+    let now = ic_cdk::api::time();
+    let icp_amount = required_icp_in(market_id, tokens_wanted, &token_type, now)?;
+    compute_buy_quote(market_id, icp_amount, token_type, now)
+}
+
+/// ICP a buy of `tokens_wanted` needs at current reserves and fees
+fn required_icp_in(
+    market_id: u64,
+    tokens_wanted: u64,
+    token_type: &TokenType,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        let market = markets_map
+            .get(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        if !is_trading_open(market, now) {
+            return Err(PredictionMarketError::MarketClosed);
+        }
+        calculate_icp_in(
+            market.yes_reserve,
+            market.no_reserve,
+            tokens_wanted,
+            matches!(token_type, TokenType::Yes),
+            |icp_amount| {
+                let impact_bps = buy_impact_bps(market, icp_amount, token_type);
+                market_trade_fee(market, icp_amount, impact_bps, None, now).0
+            },
+        )
+    })
+}
+
+/// Calculate quote for selling tokens (without executing the trade)
+#[ic_cdk::query]
+fn get_sell_quote(
+    market_id: u64,
+    token_amount: u64,
+    token_type: TokenType,
+) -> Result<TradeResult, PredictionMarketError> {
+    compute_sell_quote(market_id, token_amount, token_type, ic_cdk::api::time())
+}
+
+fn compute_sell_quote(
+    market_id: u64,
+    token_amount: u64,
+    token_type: TokenType,
+    now: u64,
+) -> Result<TradeResult, PredictionMarketError> {
+    MARKETS.with(|markets| {
+        let markets_map = markets.borrow();
+        match markets_map.get(&market_id) {
+            Some(market) => {
+                if !is_trading_open(market, now) {
+                    return Err(PredictionMarketError::MarketClosed);
+                }
+
+                let gross_icp_out = calculate_icp_out(
+                    market.yes_reserve,
+                    market.no_reserve,
+                    token_amount,
+                    matches!(token_type, TokenType::Yes),
+                )?;
+                check_min_trade_size(gross_icp_out)?;
+                // A sell may empty the pool but never pay out ICP it does not hold
+                if gross_icp_out > market.icp_liquidity_pool {
+                    return Err(PredictionMarketError::InsufficientLiquidity {
+                        max_trade: max_tokens_sellable(
+                            market,
+                            matches!(token_type, TokenType::Yes),
+                        ),
+                    });
+                }
+                let impact_bps = sell_impact_bps(market, token_amount, gross_icp_out, &token_type);
+                let (fee_paid, holiday) =
+                    market_trade_fee(market, gross_icp_out, impact_bps, None, now);
+                let icp_out = gross_icp_out.saturating_sub(fee_paid);
+
+                // Calculate new price after this hypothetical trade
+                let (new_yes_reserve, new_no_reserve) = match token_type {
+                    TokenType::Yes => (
+                        market.yes_reserve - token_amount,
+                        add_or_overflow(market.no_reserve, gross_icp_out)?,
+                    ),
+                    TokenType::No => (
+                        add_or_overflow(market.yes_reserve, gross_icp_out)?,
+                        market.no_reserve - token_amount,
+                    ),
+                };
+                let new_price = price_after(new_yes_reserve, new_no_reserve, &token_type);
+                let price_before = price_after(market.yes_reserve, market.no_reserve, &token_type);
+
+                Ok(TradeResult {
+                    tokens_received: icp_out,
+                    tokens_paid: token_amount,
+                    fee_paid,
+                    new_price,
+                    fee_holiday_until: holiday.map(|holiday| holiday.end_time),
+                    market_id,
+                    token_type,
+                    average_price: average_price(icp_out, token_amount),
+                    price_impact: price_impact_pct(price_before, new_price),
+                    timestamp: now,
+                    position: None,
+                })
+            }
+            None => Err(PredictionMarketError::MarketNotFound),
+        }
+    })
+}
+
+/// Marginal price of `token_type` for hypothetical reserves
+fn price_after(yes_reserve: u64, no_reserve: u64, token_type: &TokenType) -> f64 {
+    let total_reserves = yes_reserve as f64 + no_reserve as f64;
+    if total_reserves == 0.0 {
+        return 0.5;
+    }
+    match token_type {
+        TokenType::Yes => no_reserve as f64 / total_reserves,
+        TokenType::No => yes_reserve as f64 / total_reserves,
+    }
+}
+
+/// ICP per token over a fill
+fn average_price(icp: u64, tokens: u64) -> f64 {
+    if tokens == 0 {
+        return 0.0;
+    }
+    icp as f64 / tokens as f64
+}
+
+/// Size of a price move as a percentage of the starting price
+fn price_impact_pct(price_before: f64, price_after: f64) -> f64 {
+    if price_before <= 0.0 {
+        return 0.0;
+    }
+    ((price_after - price_before) / price_before * 100.0).abs()
+}
+
+/// Evaluate up to MAX_BULK_QUOTES buy/sell quotes across markets in one call
+/// Each quote is computed independently against current reserves; results keep the request order
+#[ic_cdk::query]
+fn get_quotes_bulk(
+    requests: Vec<QuoteRequest>,
+) -> Result<Vec<Result<TradeResult, PredictionMarketError>>, PredictionMarketError> {
+    compute_quotes_bulk(requests, ic_cdk::api::time())
+}
+
+fn compute_quotes_bulk(
+    requests: Vec<QuoteRequest>,
+    now: u64,
+) -> Result<Vec<Result<TradeResult, PredictionMarketError>>, PredictionMarketError> {
+    if requests.len() > MAX_BULK_QUOTES {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(requests
+        .into_iter()
+        .map(|request| match request.side {
+            OrderSide::Buy => {
+                compute_buy_quote(request.market_id, request.amount, request.token_type, now)
+            }
+            OrderSide::Sell => {
+                compute_sell_quote(request.market_id, request.amount, request.token_type, now)
+            }
+        })
+        .collect())
+}
+
+/// Alias of `get_quotes_bulk`, kept for list views that call it by this name
+#[ic_cdk::query]
+fn get_quotes(
+    requests: Vec<QuoteRequest>,
+) -> Result<Vec<Result<TradeResult, PredictionMarketError>>, PredictionMarketError> {
+    get_quotes_bulk(requests)
+}
+
+// =============================================================================
+// PLATFORM STATISTICS
+// =============================================================================
+
+/// Headline numbers for the whole platform
+/// Trade totals and last-activity times are maintained on every trade
+#[ic_cdk::query]
+fn get_platform_stats() -> PlatformStats {
+    build_platform_stats(ic_cdk::api::time())
+}
+
+fn build_platform_stats(now: u64) -> PlatformStats {
+    let totals = PLATFORM_TOTALS.with(|totals| totals.borrow().clone());
+    let (active_users_24h, active_users_7d) = LAST_TRADED_AT.with(|last_traded| {
+        let last_traded = last_traded.borrow();
+        let active_since =
+            |since: u64| last_traded.values().filter(|at| **at >= since).count() as u64;
+        (
+            active_since(now.saturating_sub(NANOS_PER_DAY)),
+            active_since(now.saturating_sub(7 * NANOS_PER_DAY)),
+        )
+    });
+    MARKETS.with(|markets| {
+        let markets = markets.borrow();
+        let mut counts = MarketStatusCounts::default();
+        let mut total_value_locked: u64 = 0;
+        for market in markets.values() {
+            match market.status {
+                MarketStatus::Open => counts.open += 1,
+                MarketStatus::Frozen => counts.frozen += 1,
+                MarketStatus::Resolved => counts.resolved += 1,
+                MarketStatus::Invalid => counts.invalid += 1,
+                MarketStatus::Cancelled => counts.cancelled += 1,
+            }
+            if matches!(market.status, MarketStatus::Open | MarketStatus::Frozen) {
+                total_value_locked = total_value_locked.saturating_add(market.icp_liquidity_pool);
+            }
+        }
+        let mut open: Vec<&AmmMarket> = markets
+            .values()
+            .filter(|market| market.status == MarketStatus::Open)
+            .collect();
+        open.sort_by(|a, b| {
+            b.icp_liquidity_pool
+                .cmp(&a.icp_liquidity_pool)
+                .then(a.id.cmp(&b.id))
+        });
+        PlatformStats {
+            markets: counts,
+            total_volume: totals.volume,
+            total_fees: totals.fees,
+            total_trades: totals.trades,
+            total_value_locked,
+            active_users_24h,
+            active_users_7d,
+            largest_markets: open
+                .into_iter()
+                .take(PLATFORM_TOP_MARKETS)
+                .map(build_market_listing)
+                .collect(),
+        }
+    })
+}
+
+/// Fold a trade into the platform totals and its trader's last-activity time
+fn update_platform_totals(record: &TradeRecord) {
+    PLATFORM_TOTALS.with(|totals| {
+        let mut totals = totals.borrow_mut();
+        totals.volume = totals.volume.saturating_add(record.icp_amount);
+        totals.fees = totals.fees.saturating_add(record.fee);
+        totals.trades += 1;
+    });
+    LAST_TRADED_AT.with(|last_traded| {
+        let mut last_traded = last_traded.borrow_mut();
+        let at = last_traded.entry(record.trader).or_insert(0);
+        *at = (*at).max(record.timestamp);
+    });
+}
+
+// =============================================================================
+// COST PROFILING
+// =============================================================================
+
+/// Record the instructions spent so far in the current message for an operation
+fn record_instructions(operation: CostOperation) {
+    record_cost_sample(operation, ic_cdk::api::instruction_counter());
+}
+
+fn record_cost_sample(operation: CostOperation, instructions: u64) {
+    COST_PROFILE.with(|profile| {
+        let mut profile_map = profile.borrow_mut();
+        let entry = profile_map.entry(operation).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 = entry.1.saturating_add(instructions);
+    });
+}
+
+/// Approximate cycles charged for an ingress update call executing `instructions`
+fn approximate_cycles(instructions: u64) -> u64 {
+    UPDATE_CALL_BASE_CYCLES
+        + INGRESS_MESSAGE_BASE_CYCLES
+        + instructions / 10 * CYCLES_PER_10_INSTRUCTIONS
+}
+
+/// Estimate the cost of an operation from the instruction counts measured on live calls
+/// Returns zero samples until the operation has been executed at least once
+#[ic_cdk::query]
+fn estimate_cost(operation: CostOperation) -> CostEstimate {
+    let (samples, total_instructions) =
+        COST_PROFILE.with(|profile| profile.borrow().get(&operation).copied().unwrap_or((0, 0)));
+    let avg_instructions = total_instructions.checked_div(samples).unwrap_or(0);
+
+    CostEstimate {
+        operation,
+        samples,
+        avg_instructions,
+        approx_cycles: approximate_cycles(avg_instructions),
+    }
+}
+
+// =============================================================================
+// ADMIN FUNCTIONS
+// =============================================================================
+
+/// Offer the admin role to another principal (admin only)
+/// Nothing changes until they call `accept_admin`; a new proposal replaces the pending one
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn propose_admin(new_admin: Principal) -> Result<String, PredictionMarketError> {
+    process_propose_admin(caller(), new_admin, ic_cdk::api::time())?;
+    Ok(format!(
+        "Admin handover proposed to {}; it takes effect once they accept",
+        new_admin
+    ))
+}
+
+fn process_propose_admin(
+    caller_principal: Principal,
+    new_admin: Principal,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    PENDING_ADMIN.with(|pending| *pending.borrow_mut() = Some(new_admin));
+    emit_event(now, EventKind::AdminProposed { admin: new_admin });
+    Ok(())
+}
+
+/// Take over the admin role proposed to the caller
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn accept_admin() -> Result<String, PredictionMarketError> {
+    process_accept_admin(caller(), ic_cdk::api::time())?;
+    Ok("Admin handover accepted".to_string())
+}
+
+fn process_accept_admin(
+    caller_principal: Principal,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    let proposed = PENDING_ADMIN.with(|pending| *pending.borrow() == Some(caller_principal));
+    if !proposed {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    PENDING_ADMIN.with(|pending| *pending.borrow_mut() = None);
+    ADMIN.with(|admin| *admin.borrow_mut() = Some(caller_principal));
+    emit_event(
+        now,
+        EventKind::AdminChanged {
+            admin: Some(caller_principal),
+        },
+    );
+    Ok(())
+}
+
+/// Get the principal an admin handover is waiting on, if any
+#[ic_cdk::query]
+fn get_pending_admin() -> Option<Principal> {
+    PENDING_ADMIN.with(|pending| *pending.borrow())
+}
+
+/// Clear the admin so the next deploy can claim it; only in builds with the `dev-admin-reset` feature
+#[cfg(feature = "dev-admin-reset")]
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn reset_admin() -> String {
+    ADMIN.with(|admin| *admin.borrow_mut() = None);
+    emit_event(ic_cdk::api::time(), EventKind::AdminChanged { admin: None });
+    "Admin reset - anyone can now become admin".to_string()
+}
+
+#[ic_cdk::query]
+fn get_admin() -> Option<Principal> {
+    ADMIN.with(|admin| *admin.borrow())
+}
+
+#[ic_cdk::query]
+fn is_admin() -> bool {
+    is_global_admin(caller())
+}
+
+fn is_global_admin(principal: Principal) -> bool {
+    ADMIN.with(|admin| {
+        admin
+            .borrow()
+            .is_some_and(|admin_principal| admin_principal == principal)
+    })
+}
+
+// =============================================================================
+// CIRCUIT BREAKER
+// =============================================================================
+
+/// Halt or resume market creation, trading, liquidity and claims (admin only)
+/// Queries, deposits and withdrawals stay available so users can exit during an incident
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_paused(paused: bool) -> Result<String, PredictionMarketError> {
+    process_set_paused(caller(), paused, ic_cdk::api::time())?;
+    Ok(if paused {
+        "Platform paused".to_string()
+    } else {
+        "Platform resumed".to_string()
+    })
+}
+
+fn process_set_paused(
+    caller_principal: Principal,
+    paused: bool,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    PAUSED.with(|flag| *flag.borrow_mut() = paused);
+    emit_event(now, EventKind::PauseChanged { paused });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn is_paused() -> bool {
+    PAUSED.with(|flag| *flag.borrow())
+}
+
+fn ensure_not_paused() -> Result<(), PredictionMarketError> {
+    if is_paused() {
+        return Err(PredictionMarketError::Paused);
+    }
+    Ok(())
+}
+
+// =============================================================================
+// TRADING LIMITS
+// =============================================================================
+
+/// Get platform-wide trading parameters
+#[ic_cdk::query]
+fn get_config() -> PlatformConfig {
+    let limits = TRADING_LIMITS.with(|limits| limits.borrow().clone());
+    PlatformConfig {
+        e8s_per_icp: E8S_PER_ICP,
+        trade_fee_bps: TRADE_FEE * 10,
+        protocol_fee_bps: PROTOCOL_FEE_BPS,
+        default_creator_fee_bps: DEFAULT_CREATOR_FEE_BPS,
+        min_deposit: MIN_DEPOSIT,
+        min_trade_size: limits.min_trade_size,
+        min_trade_fee: limits.min_trade_fee,
+        fee_holidays: FEE_HOLIDAYS.with(|holidays| holidays.borrow().values().cloned().collect()),
+        liquidity_matching: LIQUIDITY_MATCHING.with(|matching| matching.borrow().clone()),
+        edge_policies: EDGE_POLICIES.with(|policies| policies.borrow().clone()),
+        dispute: DISPUTE_CONFIG.with(|config| config.borrow().clone()),
+        paused: is_paused(),
+        question_gate: QUESTION_GATE.with(|gate| *gate.borrow()),
+        dynamic_fee: DYNAMIC_FEE.with(|fee| fee.borrow().clone()),
+        fee_tiers: FEE_TIERS.with(|tiers| tiers.borrow().clone()),
+        creator_bond: CREATOR_BOND_CONFIG.with(|config| config.borrow().clone()),
+        creator_allowlist: CREATOR_ALLOWLIST_MODE.with(|mode| *mode.borrow()),
+        creation_fee: CREATION_FEE.with(|fee| *fee.borrow()),
+        exposure_limits: EXPOSURE_LIMITS.with(|limits| limits.borrow().clone()),
+        shards: SHARD_CONFIG.with(|config| config.borrow().clone()),
+        text_limits: TEXT_LIMITS.with(|limits| limits.borrow().clone()),
+    }
+}
+
+/// Schedule how degenerate market states are settled (admin only)
+/// A resolved market cannot be extended, so `no_winning_supply` must refund or sweep
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_edge_policies(policies: EdgePolicies) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetEdgePolicies { policies },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn validate_edge_policies(policies: &EdgePolicies) -> Result<(), PredictionMarketError> {
+    if policies.no_winning_supply == EdgePolicy::ExtendMarket || policies.extension_ns == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn process_set_edge_policies(
+    caller_principal: Principal,
+    policies: EdgePolicies,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_edge_policies(&policies)?;
+
+    EDGE_POLICIES.with(|current| *current.borrow_mut() = policies.clone());
+    emit_event(now, EventKind::EdgePoliciesChanged { policies });
+    Ok(())
+}
+
+/// Schedule a new minimum trade size and flat fee floor (admin only)
+/// The fee floor must stay below the minimum size so every allowed trade moves some ICP
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_trading_limits(limits: TradingLimits) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetTradingLimits { limits },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn validate_trading_limits(limits: &TradingLimits) -> Result<(), PredictionMarketError> {
+    if limits.min_trade_fee >= limits.min_trade_size {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn process_set_trading_limits(
+    caller_principal: Principal,
+    limits: TradingLimits,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_trading_limits(&limits)?;
+
+    TRADING_LIMITS.with(|current| *current.borrow_mut() = limits.clone());
+    emit_event(
+        now,
+        EventKind::TradingLimitsChanged {
+            min_trade_size: limits.min_trade_size,
+            min_trade_fee: limits.min_trade_fee,
+        },
+    );
+    Ok(())
+}
+
+/// Schedule caps on the ICP a buy can put at risk; fields left empty are not enforced (admin only)
+/// Sells, complete sets and liquidity are not limited, so positions can always be reduced
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_exposure_limits(limits: ExposureLimits) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetExposureLimits { limits },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn validate_exposure_limits(limits: &ExposureLimits) -> Result<(), PredictionMarketError> {
+    let caps = [
+        limits.max_trade_icp,
+        limits.max_position_tokens,
+        limits.max_pool_size,
+    ];
+    if caps.contains(&Some(0)) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn process_set_exposure_limits(
+    actor: Principal,
+    limits: ExposureLimits,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_exposure_limits(&limits)?;
+
+    EXPOSURE_LIMITS.with(|current| *current.borrow_mut() = limits.clone());
+    emit_event(now, EventKind::ExposureLimitsChanged { limits });
+    Ok(())
+}
+
+fn check_trade_size_limit(
+    limits: &ExposureLimits,
+    icp_amount: u64,
+) -> Result<(), PredictionMarketError> {
+    match limits.max_trade_icp {
+        Some(limit) if icp_amount > limit => {
+            Err(PredictionMarketError::TradeSizeLimitExceeded { limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checked against the position and pool as they would stand after the buy
+fn check_exposure_limits(
+    limits: &ExposureLimits,
+    position_tokens: u64,
+    pool_size: u64,
+) -> Result<(), PredictionMarketError> {
+    if let Some(limit) = limits
+        .max_position_tokens
+        .filter(|limit| position_tokens > *limit)
+    {
+        return Err(PredictionMarketError::PositionLimitExceeded { limit });
+    }
+    if let Some(limit) = limits.max_pool_size.filter(|limit| pool_size > *limit) {
+        return Err(PredictionMarketError::PoolSizeLimitExceeded { limit });
+    }
+    Ok(())
+}
+
+// =============================================================================
+// FEE HOLIDAYS
+// =============================================================================
+
+/// Schedule a window of zero trading fees for one market or a whole category (admin only)
+/// With `reimburse_lps`, the treasury pays LPs the share of each waived fee they would have earned
+/// The holiday is added through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn schedule_fee_holiday(
+    scope: FeeHolidayScope,
+    start_time: u64,
+    end_time: u64,
+    reimburse_lps: bool,
+) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::AddFeeHoliday {
+            scope,
+            start_time,
+            end_time,
+            reimburse_lps,
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn add_fee_holiday(
+    caller_principal: Principal,
+    scope: FeeHolidayScope,
+    start_time: u64,
+    end_time: u64,
+    reimburse_lps: bool,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if end_time <= start_time || end_time <= now {
+        return Err(PredictionMarketError::InvalidFeeHoliday);
+    }
+    let scope = match scope {
+        FeeHolidayScope::Market(market_id) => {
+            if !MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+                return Err(PredictionMarketError::MarketNotFound);
+            }
+            FeeHolidayScope::Market(market_id)
+        }
+        FeeHolidayScope::Category(category) => FeeHolidayScope::Category(
+            normalize_category(Some(category)).ok_or(PredictionMarketError::InvalidFeeHoliday)?,
+        ),
+    };
+
+    let holiday_id = FEE_HOLIDAYS.with(|holidays| {
+        let mut holidays = holidays.borrow_mut();
+        let holiday_id = holidays.keys().next_back().map_or(1, |id| id + 1);
+        holidays.insert(
+            holiday_id,
+            FeeHoliday {
+                id: holiday_id,
+                scope: scope.clone(),
+                start_time,
+                end_time,
+                reimburse_lps,
+                reimbursed: 0,
+            },
+        );
+        holiday_id
+    });
+    emit_event(
+        now,
+        EventKind::FeeHolidayScheduled {
+            holiday_id,
+            scope,
+            start_time,
+            end_time,
+        },
+    );
+    Ok(holiday_id)
+}
+
+/// Cancel a fee holiday, scheduled or running (admin only)
+/// Applies at once: it only restores the normal fee, so a mistaken holiday can be stopped quickly
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_fee_holiday(holiday_id: u64) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    FEE_HOLIDAYS
+        .with(|holidays| holidays.borrow_mut().remove(&holiday_id))
+        .ok_or(PredictionMarketError::FeeHolidayNotFound)?;
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::FeeHolidayCancelled { holiday_id },
+    );
+    Ok(format!("Fee holiday {} cancelled", holiday_id))
+}
+
+/// The fee holiday covering `market` at `now`, if any; the earliest scheduled wins
+fn active_fee_holiday(market: &AmmMarket, now: u64) -> Option<FeeHoliday> {
+    FEE_HOLIDAYS.with(|holidays| {
+        holidays
+            .borrow()
+            .values()
+            .find(|holiday| {
+                holiday.start_time <= now
+                    && now < holiday.end_time
+                    && match &holiday.scope {
+                        FeeHolidayScope::Market(market_id) => *market_id == market.id,
+                        FeeHolidayScope::Category(category) => {
+                            market.category.as_ref() == Some(category)
+                        }
+                    }
+            })
+            .cloned()
+    })
+}
+
+/// Fee actually charged on a trade in `market`: nothing during a fee holiday, otherwise the
+/// trader's volume tier rate plus any dynamic surcharge for `impact_bps` of price movement
+/// Quotes pass no trader and assume the base rate, so they never understate the fee
+fn market_trade_fee(
+    market: &AmmMarket,
+    icp_amount: u64,
+    impact_bps: u64,
+    trader: Option<Principal>,
+    now: u64,
+) -> (u64, Option<FeeHoliday>) {
+    match active_fee_holiday(market, now) {
+        Some(holiday) => (0, Some(holiday)),
+        None => {
+            let base_bps = trader.map_or(TRADE_FEE * 10, trader_fee_bps);
+            (dynamic_trade_fee(icp_amount, impact_bps, base_bps), None)
+        }
+    }
+}
+
+/// Pay LPs their share of a fee waived by a reimbursing holiday, as far as the treasury allows
+/// The protocol and creator shares are simply forgone
+fn reimburse_waived_fee(market: &mut AmmMarket, holiday: Option<&FeeHoliday>, icp_amount: u64) {
+    let Some(holiday) = holiday.filter(|holiday| holiday.reimburse_lps) else {
+        return;
+    };
+    if market.total_lp_shares == 0 {
+        return;
+    }
+    let waived = trade_fee(icp_amount);
+    let lp_cut =
+        waived - waived * PROTOCOL_FEE_BPS / 10_000 - waived * market.creator_fee_bps / 10_000;
+    let paid = TREASURY_BALANCE.with(|treasury| {
+        let mut treasury_balance = treasury.borrow_mut();
+        let paid = lp_cut.min(*treasury_balance);
+        *treasury_balance -= paid;
+        paid
+    });
+    accrue_lp_fees(market, paid);
+    FEE_HOLIDAYS.with(|holidays| {
+        if let Some(holiday) = holidays.borrow_mut().get_mut(&holiday.id) {
+            holiday.reimbursed += paid;
+        }
+    });
+}
+
+// =============================================================================
+// DYNAMIC FEES
+// =============================================================================
+
+/// Schedule turning the price-impact fee surcharge on, retuning it, or turning it off with `None` (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_dynamic_fee(config: Option<DynamicFee>) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetDynamicFee { config },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_dynamic_fee(
+    caller_principal: Principal,
+    config: Option<DynamicFee>,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    validate_dynamic_fee(&config)?;
+    DYNAMIC_FEE.with(|fee| *fee.borrow_mut() = config.clone());
+    emit_event(now, EventKind::DynamicFeeChanged { config });
+    Ok(())
+}
+
+fn validate_dynamic_fee(config: &Option<DynamicFee>) -> Result<(), PredictionMarketError> {
+    match config {
+        Some(config)
+            if config.max_fee_bps < TRADE_FEE * 10 || config.max_fee_bps > MAX_DYNAMIC_FEE_BPS =>
+        {
+            Err(PredictionMarketError::InvalidAmount)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fee at the trader's base rate, raised by the dynamic surcharge when one is configured
+fn dynamic_trade_fee(icp_amount: u64, impact_bps: u64, base_bps: u64) -> u64 {
+    let Some(config) = DYNAMIC_FEE.with(|fee| fee.borrow().clone()) else {
+        return fee_at_rate(icp_amount, base_bps);
+    };
+    let surcharge_bps = impact_bps.saturating_mul(config.impact_share_bps) / 10_000;
+    let rate_bps = base_bps
+        .saturating_add(surcharge_bps)
+        .min(config.max_fee_bps)
+        .max(base_bps);
+    fee_at_rate(icp_amount, rate_bps)
+}
+
+/// How far moving the reserves to `yes_after`/`no_after` shifts the YES probability, in basis points
+fn price_impact_bps(market: &AmmMarket, yes_after: u64, no_after: u64) -> u64 {
+    let before = price_after(market.yes_reserve, market.no_reserve, &TokenType::Yes);
+    let after = price_after(yes_after, no_after, &TokenType::Yes);
+    ((after - before).abs() * 10_000.0).round() as u64
+}
+
+/// Price impact of a buy after the base fee; the surcharge itself only shrinks the move
+/// A buy the curve cannot fill reports no impact and fails on its own later
+fn buy_impact_bps(market: &AmmMarket, icp_amount: u64, token_type: &TokenType) -> u64 {
+    let buy_yes = matches!(token_type, TokenType::Yes);
+    let base_fee = trade_fee(icp_amount);
+    let Ok(tokens_out) = calculate_tokens_out_after_fee(
+        market.yes_reserve,
+        market.no_reserve,
+        icp_amount,
+        base_fee,
+        buy_yes,
+    ) else {
+        return 0;
+    };
+    let icp_after_fee = icp_amount - base_fee;
+    let (yes_after, no_after) = if buy_yes {
+        (
+            market.yes_reserve.saturating_add(tokens_out),
+            market.no_reserve - icp_after_fee,
+        )
+    } else {
+        (
+            market.yes_reserve - icp_after_fee,
+            market.no_reserve.saturating_add(tokens_out),
+        )
+    };
+    price_impact_bps(market, yes_after, no_after)
+}
+
+/// Price impact of selling `token_amount` tokens for `gross_icp_out`
+fn sell_impact_bps(
+    market: &AmmMarket,
+    token_amount: u64,
+    gross_icp_out: u64,
+    token_type: &TokenType,
+) -> u64 {
+    let (yes_after, no_after) = match token_type {
+        TokenType::Yes => (
+            market.yes_reserve.saturating_sub(token_amount),
+            market.no_reserve.saturating_add(gross_icp_out),
+        ),
+        TokenType::No => (
+            market.yes_reserve.saturating_add(gross_icp_out),
+            market.no_reserve.saturating_sub(token_amount),
+        ),
+    };
+    price_impact_bps(market, yes_after, no_after)
+}
+
+// =============================================================================
+// VOLUME FEE TIERS
+// =============================================================================
+
+/// Schedule replacing the volume fee tier table (admin only); an empty table charges everyone the base rate
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_fee_tiers(tiers: Vec<FeeTier>) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetFeeTiers { tiers },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_fee_tiers(
+    caller_principal: Principal,
+    tiers: Vec<FeeTier>,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let tiers = sorted_fee_tiers(tiers)?;
+    FEE_TIERS.with(|current| *current.borrow_mut() = tiers.clone());
+    emit_event(now, EventKind::FeeTiersChanged { tiers });
+    Ok(())
+}
+
+/// The tier table by ascending volume, or InvalidAmount if it cannot be used
+fn sorted_fee_tiers(mut tiers: Vec<FeeTier>) -> Result<Vec<FeeTier>, PredictionMarketError> {
+    tiers.sort_by_key(|tier| tier.min_volume);
+    let duplicate = tiers
+        .windows(2)
+        .any(|pair| pair[0].min_volume == pair[1].min_volume);
+    if tiers.len() > MAX_FEE_TIERS
+        || duplicate
+        || tiers.iter().any(|tier| tier.fee_bps > TRADE_FEE * 10)
+    {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    Ok(tiers)
+}
+
+/// Get the caller's cumulative volume, fee rate and the next tier to reach
+#[ic_cdk::query]
+fn get_my_fee_status() -> TraderFeeStatus {
+    build_fee_status(caller())
+}
+
+fn build_fee_status(trader: Principal) -> TraderFeeStatus {
+    let volume = trader_volume(trader);
+    TraderFeeStatus {
+        volume,
+        fee_bps: trader_fee_bps(trader),
+        next_tier: FEE_TIERS.with(|tiers| {
+            tiers
+                .borrow()
+                .iter()
+                .find(|tier| tier.min_volume > volume)
+                .cloned()
+        }),
+    }
+}
+
+fn trader_volume(trader: Principal) -> u64 {
+    USER_VOLUME.with(|volume| volume.borrow().get(&trader).copied().unwrap_or(0))
+}
+
+/// Rate of the highest tier the trader's volume reached, or the base rate
+fn trader_fee_bps(trader: Principal) -> u64 {
+    let volume = trader_volume(trader);
+    FEE_TIERS.with(|tiers| {
+        tiers
+            .borrow()
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.min_volume)
+            .map_or(TRADE_FEE * 10, |tier| tier.fee_bps)
+    })
+}
+
+// =============================================================================
+// REFERRALS
+// =============================================================================
+
+/// Register the caller as referred by `referrer`; a referral is permanent
+/// The referrer earns a share of the protocol's cut of every fee the caller pays from then on
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn register_referral(referrer: Principal) -> Result<String, PredictionMarketError> {
+    process_register_referral(caller(), referrer, ic_cdk::api::time())?;
+    Ok(format!("Referred by {}", referrer))
+}
+
+fn process_register_referral(
+    caller_principal: Principal,
+    referrer: Principal,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if referrer == caller_principal
+        || referrer == Principal::anonymous()
+        || caller_principal == Principal::anonymous()
+        || referrer_of(referrer) == Some(caller_principal)
+    {
+        return Err(PredictionMarketError::InvalidReferral);
+    }
+    if referrer_of(caller_principal).is_some() {
+        return Err(PredictionMarketError::AlreadyReferred);
+    }
+    REFERRERS.with(|referrers| referrers.borrow_mut().insert(caller_principal, referrer));
+    REFERRAL_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow_mut()
+            .entry(referrer)
+            .or_default()
+            .referred_count += 1
+    });
+    emit_event(
+        now,
+        EventKind::ReferralRegistered {
+            referrer,
+            referred: caller_principal,
+        },
+    );
+    Ok(())
+}
+
+/// Move the caller's claimable referral kickbacks to their balance
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn claim_referral_rewards() -> Result<u64, PredictionMarketError> {
+    process_referral_claim(caller(), ic_cdk::api::time())
+}
+
+fn process_referral_claim(
+    caller_principal: Principal,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    ensure_not_paused()?;
+    let amount = REFERRAL_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow_mut()
+            .get_mut(&caller_principal)
+            .map_or(0, |account| std::mem::take(&mut account.claimable))
+    });
+    if amount == 0 {
+        return Err(PredictionMarketError::NothingToClaim);
+    }
+    USER_BALANCES.with(|balances| {
+        *balances.borrow_mut().entry(caller_principal).or_insert(0) += amount;
+    });
+    emit_event(
+        now,
+        EventKind::ReferralRewardsClaimed {
+            referrer: caller_principal,
+            amount,
+        },
+    );
+    Ok(amount)
+}
+
+/// Get the caller's referrer and the kickbacks they earned as a referrer
+#[ic_cdk::query]
+fn get_my_referral_stats() -> ReferralStats {
+    build_referral_stats(caller())
+}
+
+fn build_referral_stats(user: Principal) -> ReferralStats {
+    let account = REFERRAL_ACCOUNTS
+        .with(|accounts| accounts.borrow().get(&user).cloned().unwrap_or_default());
+    ReferralStats {
+        referrer: referrer_of(user),
+        referred_count: account.referred_count,
+        total_earned: account.total_earned,
+        claimable: account.claimable,
+    }
+}
+
+fn referrer_of(user: Principal) -> Option<Principal> {
+    REFERRERS.with(|referrers| referrers.borrow().get(&user).copied())
+}
+
+/// Credit the trader's referrer their share of a protocol cut, returning the amount credited
+fn credit_referral_kickback(trader: Principal, protocol_cut: u64) -> u64 {
+    let Some(referrer) = referrer_of(trader) else {
+        return 0;
+    };
+    let kickback = protocol_cut * REFERRAL_SHARE_BPS / 10_000;
+    if kickback > 0 {
+        REFERRAL_ACCOUNTS.with(|accounts| {
+            let mut accounts = accounts.borrow_mut();
+            let account = accounts.entry(referrer).or_default();
+            account.total_earned += kickback;
+            account.claimable += kickback;
+        });
+    }
+    kickback
+}
+
+fn unclaimed_referral_rewards() -> u64 {
+    REFERRAL_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .values()
+            .map(|account| account.claimable)
+            .sum()
+    })
+}
+
+// =============================================================================
+// TREASURY LIQUIDITY MATCHING
+// =============================================================================
+
+/// Schedule new treasury matching of creator seed liquidity (admin only)
+/// `match_bps` is capped at 10_000, i.e. the treasury never puts in more than the creator
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_liquidity_matching(matching: LiquidityMatching) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetLiquidityMatching { matching },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_set_liquidity_matching(
+    caller_principal: Principal,
+    matching: LiquidityMatching,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if matching.match_bps > 10_000 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let mut categories: Vec<String> = matching
+        .categories
+        .into_iter()
+        .filter_map(|category| normalize_category(Some(category)))
+        .collect();
+    categories.sort();
+    categories.dedup();
+    let matching = LiquidityMatching {
+        categories,
+        ..matching
+    };
+
+    LIQUIDITY_MATCHING.with(|current| *current.borrow_mut() = matching.clone());
+    emit_event(
+        now,
+        EventKind::LiquidityMatchingChanged {
+            match_bps: matching.match_bps,
+            max_match: matching.max_match,
+        },
+    );
+    Ok(())
+}
+
+/// Co-fund a new market's seed liquidity from the treasury when its category is approved
+/// The match becomes a treasury-owned LP position and deepens reserves at the same 50/50 price;
+/// returns the amount matched, limited by the per-market cap and the treasury balance
+fn apply_liquidity_match(market: &mut AmmMarket) -> u64 {
+    let matching = LIQUIDITY_MATCHING.with(|matching| matching.borrow().clone());
+    let approved = market
+        .category
+        .as_ref()
+        .is_some_and(|category| matching.categories.contains(category));
+    if !approved || market.icp_liquidity_pool == 0 {
+        return 0;
+    }
+
+    let matched = TREASURY_BALANCE.with(|treasury| {
+        let mut treasury_balance = treasury.borrow_mut();
+        let matched = scale_by(market.icp_liquidity_pool, matching.match_bps, 10_000)
+            .min(matching.max_match)
+            .min(*treasury_balance);
+        *treasury_balance -= matched;
+        matched
+    });
+    if matched == 0 {
+        return 0;
+    }
+
+    let new_pool = market.icp_liquidity_pool + matched;
+    market.yes_reserve = scale_by(market.yes_reserve, new_pool, market.icp_liquidity_pool);
+    market.no_reserve = scale_by(market.no_reserve, new_pool, market.icp_liquidity_pool);
+    let shares = calculate_lp_shares(matched, lp_capital(market), market.total_lp_shares);
+    market.icp_liquidity_pool = new_pool;
+    market.total_lp_shares += shares;
+
+    TREASURY_LP_ACCOUNTS.with(|accounts| {
+        accounts.borrow_mut().insert(
+            market.id,
+            LpAccount {
+                shares,
+                fee_debt: accrued_fees(shares, market.acc_fee_per_share),
+                unclaimed_fees: 0,
+            },
+        );
+    });
+    matched
+}
+
+/// The treasury's matched LP position in a market, if it holds one
+#[ic_cdk::query]
+fn get_treasury_liquidity(market_id: u64) -> Option<LiquidityPosition> {
+    let (total_shares, acc_fee_per_share) = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&market_id)
+            .map(|market| (market.total_lp_shares, market.acc_fee_per_share))
+    })?;
+    let mut account =
+        TREASURY_LP_ACCOUNTS.with(|accounts| accounts.borrow().get(&market_id).cloned())?;
+    settle_lp_fees(&mut account, acc_fee_per_share);
+
+    Some(LiquidityPosition {
+        provider: ic_cdk::id(),
+        market_id,
+        shares: account.shares,
+        share_of_pool: if total_shares > 0 {
+            account.shares as f64 / total_shares as f64
+        } else {
+            0.0
+        },
+        claimable_fees: account.unclaimed_fees,
+    })
+}
+
+/// Return the treasury's matched position in a market to the treasury (admin only)
+/// While trading is open the matched capital is withdrawn along with its fees, without the
+/// early-exit haircut; after close the capital stays at risk in the pool like any LP's and
+/// only the fees earned so far are collected
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn settle_treasury_liquidity(market_id: u64) -> Result<u64, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    process_treasury_settlement(market_id, ic_cdk::api::time())
+}
+
+fn process_treasury_settlement(market_id: u64, now: u64) -> Result<u64, PredictionMarketError> {
+    let mut account = TREASURY_LP_ACCOUNTS
+        .with(|accounts| accounts.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::NothingToClaim)?;
+
+    let icp_amount = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let market = markets_map
+            .get_mut(&market_id)
+            .ok_or(PredictionMarketError::MarketNotFound)?;
+        settle_lp_fees(&mut account, market.acc_fee_per_share);
+        if account.shares == 0 || !is_trading_open(market, now) {
+            return Ok(0);
+        }
+        let icp_amount = withdraw_pool_share(market, account.shares)?;
+        account.shares = 0;
+        account.fee_debt = 0;
+        Ok(icp_amount)
+    })?;
+    let fees = std::mem::take(&mut account.unclaimed_fees);
+    if icp_amount == 0 && fees == 0 {
+        return Err(PredictionMarketError::NothingToClaim);
+    }
+
+    TREASURY_LP_ACCOUNTS.with(|accounts| {
+        let mut accounts_map = accounts.borrow_mut();
+        if account.shares == 0 {
+            accounts_map.remove(&market_id);
+        } else {
+            accounts_map.insert(market_id, account);
+        }
+    });
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += icp_amount + fees);
+    emit_event(
+        now,
+        EventKind::TreasuryLiquiditySettled {
+            market_id,
+            icp_amount,
+            fees,
+        },
+    );
+    Ok(icp_amount + fees)
+}
+
+// =============================================================================
+// PROTOCOL TREASURY
+// =============================================================================
+
+/// Get the protocol treasury balance accumulated from trading fees
+#[ic_cdk::query]
+fn get_treasury_balance() -> u64 {
+    TREASURY_BALANCE.with(|treasury| *treasury.borrow())
+}
+
+/// Schedule a withdrawal from the protocol treasury into a user's balance (admin only)
+/// Runs through `execute_action` once TIMELOCK_DELAY_NS has passed; the balance is checked then
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn withdraw_treasury(amount: u64, to: Principal) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::WithdrawTreasury { amount, to },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+fn process_withdraw_treasury(
+    caller_principal: Principal,
+    amount: u64,
+    to: Principal,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    TREASURY_BALANCE.with(|treasury| {
+        let mut treasury_balance = treasury.borrow_mut();
+        if *treasury_balance < amount {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: *treasury_balance,
+            });
+        }
+        *treasury_balance -= amount;
+        Ok(())
+    })?;
+
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&to).unwrap_or(&0);
+        balances_map.insert(to, current_balance + amount);
+    });
+    emit_event(now, EventKind::TreasuryWithdrawn { amount, to });
+    Ok(())
+}
+
+// =============================================================================
+// ADMIN TIMELOCK
+// =============================================================================
+// Fee, trading-limit, exposure, haircut, edge-policy, matching, dispute and bond settings,
+// fee holidays, treasury withdrawals, airdrops, resolution overrides and dispute overturns
+// all wait TIMELOCK_DELAY_NS
+// in a public queue, so users see them coming and can exit before they apply
+
+/// Queue a sensitive admin action (admin only); it can run once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn schedule_action(action: AdminAction) -> Result<PendingAction, PredictionMarketError> {
+    process_schedule_action(caller(), action, ic_cdk::api::time())
+}
+
+fn process_schedule_action(
+    actor: Principal,
+    action: AdminAction,
+    now: u64,
+) -> Result<PendingAction, PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    // Checks that do not depend on state at execution, so a doomed action is never queued
+    let action = match action {
+        AdminAction::SetDynamicFee { config } => {
+            validate_dynamic_fee(&config)?;
+            AdminAction::SetDynamicFee { config }
+        }
+        AdminAction::SetFeeTiers { tiers } => AdminAction::SetFeeTiers {
+            tiers: sorted_fee_tiers(tiers)?,
+        },
+        AdminAction::WithdrawTreasury { amount: 0, .. } => {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+        AdminAction::OverturnDispute { market_id } => {
+            pending_dispute(market_id).ok_or(PredictionMarketError::DisputeNotFound)?;
+            AdminAction::OverturnDispute { market_id }
+        }
+        AdminAction::SetTradingLimits { limits } => {
+            validate_trading_limits(&limits)?;
+            AdminAction::SetTradingLimits { limits }
+        }
+        AdminAction::AddFeeHoliday {
+            end_time,
+            start_time,
+            ..
+        } if end_time <= start_time || end_time <= now.saturating_add(TIMELOCK_DELAY_NS) => {
+            return Err(PredictionMarketError::InvalidFeeHoliday);
+        }
+        AdminAction::SetLiquidityMatching { matching } if matching.match_bps > 10_000 => {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+        AdminAction::SetCreatorFeeBps {
+            market_id,
+            creator_fee_bps,
+        } => {
+            if creator_fee_bps > MAX_CREATOR_FEE_BPS {
+                return Err(PredictionMarketError::InvalidAmount);
+            }
+            if !MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+                return Err(PredictionMarketError::MarketNotFound);
+            }
+            AdminAction::SetCreatorFeeBps {
+                market_id,
+                creator_fee_bps,
+            }
+        }
+        AdminAction::SetDisputeConfig { config } => {
+            validate_dispute_config(&config)?;
+            AdminAction::SetDisputeConfig { config }
+        }
+        AdminAction::SetCreatorBondConfig { config } if config.slash_bps > 10_000 => {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+        AdminAction::Airdrop { recipients } => {
+            airdrop_total(&recipients)?;
+            AdminAction::Airdrop { recipients }
+        }
+        AdminAction::SetEarlyExitHaircut {
+            market_id,
+            schedule,
+        } => {
+            validate_haircut_schedule(&schedule)?;
+            if !MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+                return Err(PredictionMarketError::MarketNotFound);
+            }
+            AdminAction::SetEarlyExitHaircut {
+                market_id,
+                schedule,
+            }
+        }
+        AdminAction::SetEdgePolicies { policies } => {
+            validate_edge_policies(&policies)?;
+            AdminAction::SetEdgePolicies { policies }
+        }
+        AdminAction::SetExposureLimits { limits } => {
+            validate_exposure_limits(&limits)?;
+            AdminAction::SetExposureLimits { limits }
+        }
+        AdminAction::SetAirdropQuorum { quorum } => AdminAction::SetAirdropQuorum {
+            quorum: normalized_quorum(quorum)?,
+        },
+        AdminAction::OverrideResolution {
+            market_id,
+            outcome,
+            evidence,
+        } => {
+            if !MARKETS.with(|markets| markets.borrow().contains_key(&market_id)) {
+                return Err(PredictionMarketError::MarketNotFound);
+            }
+            let evidence = evidence.trim().to_string();
+            if evidence.is_empty() || evidence.len() > MAX_RESOLUTION_EVIDENCE_LEN {
+                return Err(PredictionMarketError::MissingResolutionEvidence);
+            }
+            AdminAction::OverrideResolution {
+                market_id,
+                outcome,
+                evidence,
+            }
+        }
+        action => action,
+    };
+
+    let id = NEXT_ACTION_ID.with(|next| {
+        let id = *next.borrow();
+        *next.borrow_mut() = id + 1;
+        id
+    });
+    let pending = PendingAction {
+        id,
+        action,
+        scheduled_by: actor,
+        scheduled_at: now,
+        executable_at: now.saturating_add(TIMELOCK_DELAY_NS),
+        approvals: Vec::new(),
+    };
+    PENDING_ACTIONS.with(|actions| actions.borrow_mut().insert(id, pending.clone()));
+    emit_event(
+        now,
+        EventKind::ActionScheduled {
+            action: pending.clone(),
+        },
+    );
+    Ok(pending)
+}
+
+/// Run a scheduled action whose delay has passed (admin only)
+/// An action that fails stays queued, e.g. a withdrawal larger than the treasury holds
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn execute_action(action_id: u64) -> Result<String, PredictionMarketError> {
+    process_execute_action(caller(), action_id, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(format!("Action {} executed", action_id))
+}
+
+fn process_execute_action(
+    actor: Principal,
+    action_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let pending = PENDING_ACTIONS
+        .with(|actions| actions.borrow().get(&action_id).cloned())
+        .ok_or(PredictionMarketError::ActionNotFound)?;
+    if now < pending.executable_at {
+        return Err(PredictionMarketError::TimelockPending {
+            executable_at: pending.executable_at,
+        });
+    }
+    if matches!(pending.action, AdminAction::Airdrop { .. }) {
+        ensure_quorum_met(&pending)?;
+    }
+
+    match pending.action {
+        AdminAction::SetCreationFee { amount } => process_set_creation_fee(actor, amount, now),
+        AdminAction::SetDynamicFee { config } => process_set_dynamic_fee(actor, config, now),
+        AdminAction::SetFeeTiers { tiers } => process_set_fee_tiers(actor, tiers, now),
+        AdminAction::WithdrawTreasury { amount, to } => {
+            process_withdraw_treasury(actor, amount, to, now)
+        }
+        AdminAction::OverrideResolution {
+            market_id,
+            outcome,
+            evidence,
+        } => process_override_resolution(actor, market_id, outcome, evidence, now),
+        AdminAction::OverturnDispute { market_id } => {
+            process_dispute_settlement(actor, market_id, true, now).map(|_| ())
+        }
+        AdminAction::SetTradingLimits { limits } => process_set_trading_limits(actor, limits, now),
+        AdminAction::AddFeeHoliday {
+            scope,
+            start_time,
+            end_time,
+            reimburse_lps,
+        } => add_fee_holiday(actor, scope, start_time, end_time, reimburse_lps, now).map(|_| ()),
+        AdminAction::SetLiquidityMatching { matching } => {
+            process_set_liquidity_matching(actor, matching, now)
+        }
+        AdminAction::SetCreatorFeeBps {
+            market_id,
+            creator_fee_bps,
+        } => process_set_creator_fee_bps(actor, market_id, creator_fee_bps, now),
+        AdminAction::SetDisputeConfig { config } => process_set_dispute_config(actor, config, now),
+        AdminAction::SetCreatorBondConfig { config } => {
+            process_set_creator_bond_config(actor, config.amount, config.slash_bps, now)
+        }
+        AdminAction::Airdrop { recipients } => {
+            process_start_airdrop(actor, recipients, now).map(|_| ())
+        }
+        AdminAction::SetEarlyExitHaircut {
+            market_id,
+            schedule,
+        } => process_set_early_exit_haircut(actor, market_id, schedule, now),
+        AdminAction::SetEdgePolicies { policies } => {
+            process_set_edge_policies(actor, policies, now)
+        }
+        AdminAction::SetExposureLimits { limits } => {
+            process_set_exposure_limits(actor, limits, now)
+        }
+        AdminAction::SetAirdropQuorum { quorum } => process_set_airdrop_quorum(actor, quorum, now),
+    }?;
+    PENDING_ACTIONS.with(|actions| actions.borrow_mut().remove(&action_id));
+    emit_event(now, EventKind::ActionExecuted { action_id });
+    Ok(())
+}
+
+/// Drop a scheduled action before it runs (admin only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn cancel_action(action_id: u64) -> Result<String, PredictionMarketError> {
+    process_cancel_action(caller(), action_id, ic_cdk::api::time())?;
+    Ok(format!("Action {} cancelled", action_id))
+}
+
+fn process_cancel_action(
+    actor: Principal,
+    action_id: u64,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    PENDING_ACTIONS
+        .with(|actions| actions.borrow_mut().remove(&action_id))
+        .ok_or(PredictionMarketError::ActionNotFound)?;
+    emit_event(now, EventKind::ActionCancelled { action_id });
+    Ok(())
+}
+
+/// Every admin action waiting in the timelock, oldest first
+#[ic_cdk::query]
+fn get_pending_actions() -> Vec<PendingAction> {
+    PENDING_ACTIONS.with(|actions| actions.borrow().values().cloned().collect())
+}
+
+fn scheduled_message(pending: &PendingAction) -> String {
+    format!(
+        "Scheduled as action {}; it can be executed from {}",
+        pending.id, pending.executable_at
+    )
+}
+
+// =============================================================================
+// PLATFORM WIND-DOWN
+// =============================================================================
+
+/// New markets, liquidity, orders, offers and deposits are only accepted while active
+fn ensure_platform_active() -> Result<(), PredictionMarketError> {
+    if PLATFORM_PHASE.with(|phase| *phase.borrow() == PlatformPhase::Active) {
+        Ok(())
+    } else {
+        Err(PredictionMarketError::PlatformWindingDown)
+    }
+}
+
+#[ic_cdk::query]
+fn get_platform_phase() -> PlatformPhase {
+    PLATFORM_PHASE.with(|phase| phase.borrow().clone())
+}
+
+/// Start retiring the platform (admin only)
+/// Open markets keep trading until their close time and are then frozen as usual
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn begin_wind_down() -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    PLATFORM_PHASE.with(|phase| {
+        let mut current = phase.borrow_mut();
+        if *current != PlatformPhase::Active {
+            return Err(PredictionMarketError::InvalidPlatformPhase);
+        }
+        *current = PlatformPhase::WindDown;
+        Ok(())
+    })?;
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::PlatformPhaseChanged {
+            phase: PlatformPhase::WindDown,
+        },
+    );
+    Ok("Wind-down started: no new markets, liquidity or deposits are accepted".to_string())
+}
+
+/// Push-settle everything that can be settled without user action (admin only)
+/// Pays out winners of resolved markets, releases order and offer escrows and
+/// credits accrued LP and creator fees to user balances
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn settle_wind_down() -> Result<SettlementSummary, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if ensure_platform_active().is_ok() {
+        return Err(PredictionMarketError::InvalidPlatformPhase);
+    }
+    Ok(push_settle_all(ic_cdk::api::time()))
+}
+
+fn push_settle_all(now: u64) -> SettlementSummary {
+    let mut summary = SettlementSummary::default();
+
+    // Return sealed receipts to their owners so they settle like any other position
+    let receipts: Vec<PositionReceipt> =
+        POSITION_RECEIPTS.with(|receipts| receipts.borrow().values().cloned().collect());
+    for receipt in receipts {
+        release_receipt(&receipt, now);
+    }
+
+    // Pay every outstanding winning position in resolved markets
+    let pending_claims: Vec<(Principal, u64)> = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .values()
+            .filter(|position| has_unclaimed_winnings(position))
+            .map(|position| (position.user, position.market_id))
+            .collect()
+    });
+    for (user, market_id) in pending_claims {
+        if process_reward_claim(user, market_id, now).is_ok() {
+            summary.claims_paid += 1;
+        }
+    }
+    for (user, market_id) in pending_refunds() {
+        if process_refund_claim(user, market_id, now).is_ok() {
+            summary.claims_paid += 1;
+        }
+    }
+
+    // Release escrow of resting orders and offers
+    let open_orders: Vec<LimitOrder> = LIMIT_ORDERS.with(|orders| {
+        orders
+            .borrow_mut()
+            .values_mut()
+            .filter(|order| order.status == OrderStatus::Open)
+            .map(|order| {
+                order.status = OrderStatus::Cancelled;
+                order.clone()
+            })
+            .collect()
+    });
+    for order in open_orders {
+        credit_swap_asset(order.owner, &limit_order_escrow(&order));
+        emit_event(now, EventKind::LimitOrderCancelled { order_id: order.id });
+        summary.orders_cancelled += 1;
+    }
+
+    let open_offers: Vec<SwapOffer> = SWAP_OFFERS.with(|offers| {
+        offers
+            .borrow_mut()
+            .values_mut()
+            .filter(|offer| offer.status == SwapOfferStatus::Open)
+            .map(|offer| {
+                offer.status = SwapOfferStatus::Cancelled;
+                offer.clone()
+            })
+            .collect()
+    });
+    for offer in open_offers {
+        credit_swap_asset(offer.maker, &offer.give);
+        emit_event(now, EventKind::SwapOfferCancelled { offer_id: offer.id });
+        summary.offers_cancelled += 1;
+    }
+
+    // Credit accrued LP, creator and referral fees
+    let payouts: Vec<(Principal, u64)> = MARKETS.with(|markets| {
+        let mut markets_map = markets.borrow_mut();
+        let mut payouts = Vec::new();
+        LP_ACCOUNTS.with(|accounts| {
+            for ((provider, market_id), account) in accounts.borrow_mut().iter_mut() {
+                if let Some(market) = markets_map.get(market_id) {
+                    settle_lp_fees(account, market.acc_fee_per_share);
+                }
+                let amount = std::mem::take(&mut account.unclaimed_fees);
+                if amount > 0 {
+                    summary.lp_fees_paid += amount;
+                    payouts.push((*provider, amount));
+                }
+            }
+        });
+        // The treasury's matched positions pay their fees back to the treasury
+        let treasury_fees: u64 = TREASURY_LP_ACCOUNTS.with(|accounts| {
+            accounts
+                .borrow_mut()
+                .iter_mut()
+                .map(|(market_id, account)| {
+                    if let Some(market) = markets_map.get(market_id) {
+                        settle_lp_fees(account, market.acc_fee_per_share);
+                    }
+                    std::mem::take(&mut account.unclaimed_fees)
+                })
+                .sum()
+        });
+        TREASURY_BALANCE.with(|treasury| *treasury.borrow_mut() += treasury_fees);
+        for market in markets_map.values_mut() {
+            let amount = std::mem::take(&mut market.creator_fees_accrued);
+            if amount > 0 {
+                summary.creator_fees_paid += amount;
+                payouts.push((market.creator, amount));
+            }
+        }
+        REFERRAL_ACCOUNTS.with(|accounts| {
+            for (referrer, account) in accounts.borrow_mut().iter_mut() {
+                let amount = std::mem::take(&mut account.claimable);
+                if amount > 0 {
+                    summary.referral_rewards_paid += amount;
+                    payouts.push((*referrer, amount));
+                }
+            }
+        });
+        payouts
+    });
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        for (user, amount) in payouts {
+            *balances_map.entry(user).or_insert(0) += amount;
+        }
+    });
+
+    emit_event(
+        now,
+        EventKind::WindDownSettled {
+            claims_paid: summary.claims_paid,
+            orders_cancelled: summary.orders_cancelled,
+            offers_cancelled: summary.offers_cancelled,
+        },
+    );
+    summary
+}
+
+/// A position holds winning tokens of a resolved market that have not been claimed
+fn has_unclaimed_winnings(position: &UserPosition) -> bool {
+    if position.claimed_reward {
+        return false;
+    }
+    MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .get(&position.market_id)
+            .is_some_and(|market| {
+                matches!(market.status, MarketStatus::Resolved)
+                    && match market.winning_outcome {
+                        Some(TokenType::Yes) => position.yes_tokens > 0,
+                        Some(TokenType::No) => position.no_tokens > 0,
+                        None => false,
+                    }
+            })
+    })
+}
+
+/// Enter the final withdrawal-only period (admin only)
+/// Requires every market to be resolved and everything push-settled first
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn open_withdrawal_period() -> Result<WindDownReport, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if PLATFORM_PHASE.with(|phase| *phase.borrow() != PlatformPhase::WindDown) {
+        return Err(PredictionMarketError::InvalidPlatformPhase);
+    }
+
+    let report = build_wind_down_report(ic_cdk::api::time());
+    if report.open_markets > 0
+        || report.frozen_markets > 0
+        || report.unclaimed_winning_positions > 0
+        || report.unclaimed_refunds > 0
+        || report.open_orders > 0
+        || report.open_swap_offers > 0
+        || report.unclaimed_fees > 0
+    {
+        return Err(PredictionMarketError::SettlementIncomplete);
+    }
+
+    PLATFORM_PHASE.with(|phase| *phase.borrow_mut() = PlatformPhase::WithdrawalOnly);
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::PlatformPhaseChanged {
+            phase: PlatformPhase::WithdrawalOnly,
+        },
+    );
+    Ok(build_wind_down_report(ic_cdk::api::time()))
+}
+
+/// Snapshot of everything still outstanding; in the withdrawal-only phase this is the terminal state
+#[ic_cdk::query]
+fn get_wind_down_report() -> WindDownReport {
+    build_wind_down_report(ic_cdk::api::time())
+}
+
+fn build_wind_down_report(now: u64) -> WindDownReport {
+    let (open_markets, frozen_markets, resolved_markets, creator_fees) = MARKETS.with(|markets| {
+        markets.borrow().values().fold((0, 0, 0, 0), |acc, market| {
+            let (open, frozen, resolved, fees) = acc;
+            let fees = fees + market.creator_fees_accrued;
+            match market.status {
+                MarketStatus::Open => (open + 1, frozen, resolved, fees),
+                MarketStatus::Frozen => (open, frozen + 1, resolved, fees),
+                MarketStatus::Resolved | MarketStatus::Invalid | MarketStatus::Cancelled => {
+                    (open, frozen, resolved + 1, fees)
+                }
+            }
+        })
+    });
+
+    let unclaimed_winning_positions = USER_POSITIONS.with(|positions| {
+        positions
+            .borrow()
+            .values()
+            .filter(|position| has_unclaimed_winnings(position))
+            .count() as u64
+    });
+
+    let lp_fees = LP_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .map(|((_, market_id), account)| {
+                let mut account = account.clone();
+                if let Some(acc) =
+                    MARKETS.with(|m| m.borrow().get(market_id).map(|m| m.acc_fee_per_share))
+                {
+                    settle_lp_fees(&mut account, acc);
+                }
+                account.unclaimed_fees
+            })
+            .sum::<u64>()
+    }) + TREASURY_LP_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .map(|(market_id, account)| {
+                let mut account = account.clone();
+                if let Some(acc) =
+                    MARKETS.with(|m| m.borrow().get(market_id).map(|m| m.acc_fee_per_share))
+                {
+                    settle_lp_fees(&mut account, acc);
+                }
+                account.unclaimed_fees
+            })
+            .sum::<u64>()
+    });
+
+    let stranded_pool = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .filter(|market| matches!(market.status, MarketStatus::Resolved))
+            .filter(|market| {
+                !USER_POSITIONS.with(|positions| {
+                    positions.borrow().values().any(|position| {
+                        position.market_id == market.id && has_unclaimed_winnings(position)
+                    })
+                })
+            })
+            .map(|market| market.icp_liquidity_pool)
+            .sum::<u64>()
+    });
+
+    WindDownReport {
+        phase: get_platform_phase(),
+        generated_at: now,
+        open_markets,
+        frozen_markets,
+        resolved_markets,
+        unclaimed_winning_positions,
+        unclaimed_refunds: pending_refunds().len() as u64,
+        open_orders: LIMIT_ORDERS.with(|orders| {
+            orders
+                .borrow()
+                .values()
+                .filter(|order| order.status == OrderStatus::Open)
+                .count() as u64
+        }),
+        open_swap_offers: SWAP_OFFERS.with(|offers| {
+            offers
+                .borrow()
+                .values()
+                .filter(|offer| offer.status == SwapOfferStatus::Open)
+                .count() as u64
+        }),
+        unclaimed_fees: lp_fees + creator_fees + unclaimed_referral_rewards(),
+        stranded_pool,
+        total_user_balances: USER_BALANCES.with(|balances| balances.borrow().values().sum()),
+        treasury_balance: get_treasury_balance(),
+    }
+}
+
+// =============================================================================
+// AIRDROPS
+// =============================================================================
+
+/// Schedule an airdrop campaign crediting internal balances from the treasury (admin only)
+/// It needs `threshold` approvals from the airdrop quorum (`approve_action`) as well as
+/// the timelock; `execute_action` then takes the full amount from the treasury and
+/// credits the first chunk of AIRDROP_CHUNK_SIZE recipients; the AirdropCredited event
+/// names the campaign, and `resume_airdrop` credits the rest
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn airdrop(recipients: Vec<(Principal, u64)>) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::Airdrop { recipients },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+/// Total an airdrop pays out; every recipient must get something
+fn airdrop_total(recipients: &[(Principal, u64)]) -> Result<u64, PredictionMarketError> {
+    if recipients.is_empty() || recipients.iter().any(|(_, amount)| *amount == 0) {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+    recipients
+        .iter()
+        .try_fold(0u64, |total, (_, amount)| total.checked_add(*amount))
+        .ok_or(PredictionMarketError::InvalidAmount)
+}
+
+fn process_start_airdrop(
+    caller_principal: Principal,
+    recipients: Vec<(Principal, u64)>,
+    now: u64,
+) -> Result<AirdropStatus, PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+
+    ensure_platform_active()?;
+
+    let total_amount = airdrop_total(&recipients)?;
+
+    TREASURY_BALANCE.with(|treasury| {
+        let mut treasury_balance = treasury.borrow_mut();
+        if *treasury_balance < total_amount {
+            return Err(PredictionMarketError::InsufficientLiquidity {
+                max_trade: *treasury_balance,
+            });
+        }
+        *treasury_balance -= total_amount;
+        Ok(())
+    })?;
+
+    let campaign_id = NEXT_AIRDROP_ID.with(|id| {
+        let current_id = *id.borrow();
+        *id.borrow_mut() = current_id + 1;
+        current_id
+    });
+
+    AIRDROP_CAMPAIGNS.with(|campaigns| {
+        campaigns.borrow_mut().insert(
+            campaign_id,
+            AirdropCampaign {
+                id: campaign_id,
+                created_by: caller_principal,
+                recipients,
+                next_index: 0,
+                total_amount,
+                created_at: now,
+            },
+        );
+    });
+
+    Ok(process_airdrop_chunk(campaign_id, now))
+}
+
+/// Continue crediting the next chunk of an unfinished airdrop campaign (admin or approver)
+/// The campaign was funded and approved when it started; resuming cannot change its
+/// recipients or amounts, so it needs no fresh approval
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn resume_airdrop(campaign_id: u64) -> Result<AirdropStatus, PredictionMarketError> {
+    let caller_principal = caller();
+    if !is_global_admin(caller_principal) && !is_airdrop_approver(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if !AIRDROP_CAMPAIGNS.with(|campaigns| campaigns.borrow().contains_key(&campaign_id)) {
+        return Err(PredictionMarketError::AirdropNotFound);
+    }
+    Ok(process_airdrop_chunk(campaign_id, ic_cdk::api::time()))
+}
+
+/// Credit up to AIRDROP_CHUNK_SIZE recipients, recording one entry per recipient
+fn process_airdrop_chunk(campaign_id: u64, now: u64) -> AirdropStatus {
+    AIRDROP_CAMPAIGNS.with(|campaigns| {
+        let mut campaigns_map = campaigns.borrow_mut();
+        let campaign = campaigns_map
+            .get_mut(&campaign_id)
+            .expect("airdrop campaign must exist");
+
+        let start = campaign.next_index as usize;
+        let end = (start + AIRDROP_CHUNK_SIZE).min(campaign.recipients.len());
+
+        for &(recipient, amount) in &campaign.recipients[start..end] {
+            USER_BALANCES.with(|balances| {
+                let mut balances_map = balances.borrow_mut();
+                let current_balance = *balances_map.get(&recipient).unwrap_or(&0);
+                balances_map.insert(recipient, current_balance + amount);
+            });
+            AIRDROP_RECORDS.with(|records| {
+                records.borrow_mut().push(AirdropRecord {
+                    campaign_id,
+                    recipient,
+                    amount,
+                    credited_at: now,
+                });
+            });
+        }
+        campaign.next_index = end as u64;
+        emit_event(
+            now,
+            EventKind::AirdropCredited {
+                campaign_id,
+                recipients: (end - start) as u64,
+                amount: campaign.recipients[start..end]
+                    .iter()
+                    .map(|(_, amount)| amount)
+                    .sum(),
+            },
+        );
+
+        AirdropStatus {
+            campaign_id,
+            processed: campaign.next_index,
+            total_recipients: campaign.recipients.len() as u64,
+            completed: end == campaign.recipients.len(),
+        }
+    })
+}
+
+/// Schedule a new airdrop quorum: `threshold` of `approvers` must approve each airdrop (admin only)
+/// Takes effect through `execute_action` once TIMELOCK_DELAY_NS has passed
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_airdrop_quorum(
+    approvers: Vec<Principal>,
+    threshold: u64,
+) -> Result<String, PredictionMarketError> {
+    let pending = process_schedule_action(
+        caller(),
+        AdminAction::SetAirdropQuorum {
+            quorum: ApprovalQuorum {
+                approvers,
+                threshold,
+            },
+        },
+        ic_cdk::api::time(),
+    )?;
+    Ok(scheduled_message(&pending))
+}
+
+#[ic_cdk::query]
+fn get_airdrop_quorum() -> ApprovalQuorum {
+    AIRDROP_QUORUM.with(|quorum| quorum.borrow().clone())
+}
+
+/// Approvers sorted and deduplicated; the threshold must be reachable and at least one
+fn normalized_quorum(quorum: ApprovalQuorum) -> Result<ApprovalQuorum, PredictionMarketError> {
+    let mut approvers = quorum.approvers;
+    approvers.sort();
+    approvers.dedup();
+    if approvers.contains(&Principal::anonymous()) {
+        return Err(invalid_input(
+            "approvers",
+            "must not include the anonymous principal".to_string(),
+        ));
+    }
+    if quorum.threshold == 0 || quorum.threshold > approvers.len() as u64 {
+        return Err(invalid_input(
+            "threshold",
+            format!("must be between 1 and {}", approvers.len()),
+        ));
+    }
+    Ok(ApprovalQuorum {
+        approvers,
+        threshold: quorum.threshold,
+    })
+}
+
+fn process_set_airdrop_quorum(
+    caller_principal: Principal,
+    quorum: ApprovalQuorum,
+    now: u64,
+) -> Result<(), PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let quorum = normalized_quorum(quorum)?;
+    AIRDROP_QUORUM.with(|current| *current.borrow_mut() = quorum.clone());
+    emit_event(now, EventKind::AirdropQuorumChanged { quorum });
+    Ok(())
+}
+
+fn is_airdrop_approver(principal: Principal) -> bool {
+    AIRDROP_QUORUM.with(|quorum| quorum.borrow().approvers.contains(&principal))
+}
+
+/// Sign off on a scheduled airdrop (airdrop quorum members only); returns the approvals so far
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn approve_action(action_id: u64) -> Result<u64, PredictionMarketError> {
+    process_approve_action(caller(), action_id, ic_cdk::api::time())
+}
+
+fn process_approve_action(
+    approver: Principal,
+    action_id: u64,
+    now: u64,
+) -> Result<u64, PredictionMarketError> {
+    if !is_airdrop_approver(approver) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    let approvals = PENDING_ACTIONS.with(|actions| {
+        let mut actions_map = actions.borrow_mut();
+        let pending = actions_map
+            .get_mut(&action_id)
+            .ok_or(PredictionMarketError::ActionNotFound)?;
+        if !matches!(pending.action, AdminAction::Airdrop { .. }) {
+            return Err(invalid_input(
+                "action_id",
+                "only airdrops take approvals".to_string(),
+            ));
+        }
+        if pending.approvals.contains(&approver) {
+            return Err(PredictionMarketError::AlreadyApproved);
+        }
+        pending.approvals.push(approver);
+        Ok(pending.approvals.len() as u64)
+    })?;
+    emit_event(
+        now,
+        EventKind::ActionApproved {
+            action_id,
+            approver,
+        },
+    );
+    Ok(approvals)
+}
+
+/// Only approvals from current quorum members count, so removing an approver revokes theirs
+fn ensure_quorum_met(pending: &PendingAction) -> Result<(), PredictionMarketError> {
+    let quorum = AIRDROP_QUORUM.with(|quorum| quorum.borrow().clone());
+    let approvals = pending
+        .approvals
+        .iter()
+        .filter(|approver| quorum.approvers.contains(approver))
+        .count() as u64;
+    if quorum.threshold == 0 || approvals < quorum.threshold {
+        return Err(PredictionMarketError::QuorumNotMet {
+            approvals,
+            threshold: quorum.threshold,
+        });
+    }
+    Ok(())
+}
+
+/// Get the per-recipient credit trail of an airdrop campaign (admin only)
+#[ic_cdk::query]
+fn get_airdrop_records(
+    campaign_id: u64,
+    cursor: Option<u64>,
+) -> Result<Page<AirdropRecord>, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    Ok(AIRDROP_RECORDS.with(|records| {
+        let keyed = records
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, record)| record.campaign_id == campaign_id)
+            .map(|(index, record)| (index as u64, record.clone()))
+            .collect();
+        paginate(keyed, cursor)
+    }))
+}
+
+// =============================================================================
+// STATE BACKUP
+// =============================================================================
+// The whole persistent state, candid-encoded and served in chunks that fit a response.
+// Indexes, rolling stats and certified documents are rebuilt on import rather than
+// exported; pending webhook retries, cost samples and locks are dropped. The export
+// holds read-token and webhook secrets, so it is admin-only and should be kept private.
+
+/// Every persistent thread-local, by value
+#[derive(CandidType, Deserialize)]
+struct StateSnapshot {
+    markets: HashMap<u64, AmmMarket>,
+    user_positions: HashMap<(Principal, u64), UserPosition>,
+    reward_claims: Vec<RewardClaim>,
+    next_market_id: u64,
+    admin: Option<Principal>,
+    pending_admin: Option<Principal>,
+    paused: bool,
+    question_gate: bool,
+    resolution_drafts: HashMap<u64, ResolutionDraft>,
+    comments: HashMap<u64, BTreeMap<u64, Comment>>,
+    next_comment_id: u64,
+    moderators: Vec<Principal>,
+    creator_allowlist_mode: bool,
+    allowed_creators: Vec<Principal>,
+    creation_fee: u64,
+    dynamic_fee: Option<DynamicFee>,
+    fee_tiers: Vec<FeeTier>,
+    user_volume: HashMap<Principal, u64>,
+    referrers: HashMap<Principal, Principal>,
+    referral_accounts: HashMap<Principal, ReferralAccount>,
+    user_balances: HashMap<Principal, u64>,
+    lp_accounts: HashMap<(Principal, u64), LpAccount>,
+    swap_offers: HashMap<u64, SwapOffer>,
+    next_offer_id: u64,
+    limit_orders: HashMap<u64, LimitOrder>,
+    next_order_id: u64,
+    price_triggers: HashMap<u64, PriceTrigger>,
+    next_trigger_id: u64,
+    airdrop_campaigns: HashMap<u64, AirdropCampaign>,
+    airdrop_records: Vec<AirdropRecord>,
+    next_airdrop_id: u64,
+    platform_phase: PlatformPhase,
+    treasury_balance: u64,
+    user_regions: HashMap<Principal, String>,
+    trade_history: HashMap<u64, Vec<TradeRecord>>,
+    next_trade_id: u64,
+    position_history: HashMap<(Principal, u64), Vec<PositionSnapshot>>,
+    net_contributions: HashMap<(Principal, u64), u64>,
+    realized_pnl: HashMap<(Principal, u64), MarketPnl>,
+    forecasts: HashMap<Principal, BTreeMap<u64, f64>>,
+    outcome_transfers: Vec<OutcomeTransfer>,
+    position_receipts: BTreeMap<u64, PositionReceipt>,
+    next_receipt_id: u64,
+    dispute_config: DisputeConfig,
+    dispute_windows: HashMap<u64, DisputeWindow>,
+    creator_bond_config: CreatorBondConfig,
+    creator_bonds: BTreeMap<u64, CreatorBond>,
+    resolution_votes: HashMap<u64, BTreeMap<Principal, ResolutionVote>>,
+    vote_tallies: HashMap<u64, VoteTally>,
+    hourly_candles: HashMap<u64, BTreeMap<u64, Candle>>,
+    daily_candles: HashMap<u64, BTreeMap<u64, Candle>>,
+    price_observations: HashMap<u64, Vec<(u64, f64)>>,
+    balance_activity: HashMap<Principal, u64>,
+    auto_withdraw_days: HashMap<Principal, u64>,
+    read_tokens: HashMap<String, ReadToken>,
+    next_read_token_id: u64,
+    trading_limits: TradingLimits,
+    exposure_limits: ExposureLimits,
+    commit_reveal_markets: BTreeSet<u64>,
+    trade_commitments: HashMap<Vec<u8>, TradeCommitment>,
+    fee_holidays: BTreeMap<u64, FeeHoliday>,
+    liquidity_matching: LiquidityMatching,
+    edge_policies: EdgePolicies,
+    treasury_lp_accounts: HashMap<u64, LpAccount>,
+    lifecycle_hooks: HashMap<u64, LifecycleHook>,
+    lifecycle_outbox: Vec<(CallbackTarget, LifecycleNotification)>,
+    webhook_secrets: HashMap<u64, String>,
+    webhook_allowlist: Vec<String>,
+    market_oracles: BTreeMap<u64, OracleStatus>,
+    price_feeds: BTreeMap<u64, PriceFeed>,
+    events: Vec<Event>,
+    // Added after version 1; optional so older saved states still decode
+    shard_config: Option<ShardConfig>,
+    market_shards: Option<BTreeMap<u64, Principal>>,
+    router: Option<Principal>,
+    text_limits: Option<TextLimits>,
+    categories: Option<BTreeMap<String, Category>>,
+    featured_markets: Option<Vec<FeaturedMarket>>,
+    pending_resolvers: Option<BTreeMap<u64, Principal>>,
+    abandoned_markets: Option<BTreeSet<u64>>,
+    pending_actions: Option<BTreeMap<u64, PendingAction>>,
+    next_action_id: Option<u64>,
+    airdrop_quorum: Option<ApprovalQuorum>,
+}
+
+/// An import in progress, assembled chunk by chunk
+struct StateImport {
+    state_hash: Vec<u8>,
+    total_chunks: u64,
+    next_index: u64,
+    bytes: Vec<u8>,
+}
+
+/// Export one chunk of the full state (admin only)
+/// Pause the platform first: chunks are cut from the state as it is at each call, and
+/// `import_state` rejects an export whose chunks do not add up to the same hash
+#[ic_cdk::query]
+fn export_state(chunk_index: u64) -> Result<StateChunk, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    export_state_chunk(chunk_index, STATE_CHUNK_BYTES)
+}
+
+fn export_state_chunk(
+    chunk_index: u64,
+    chunk_bytes: usize,
+) -> Result<StateChunk, PredictionMarketError> {
+    let encoded = candid::encode_one(capture_state())
+        .map_err(|error| PredictionMarketError::CallFailed(error.to_string()))?;
+    let total_chunks = encoded.len().div_ceil(chunk_bytes).max(1) as u64;
+    if chunk_index >= total_chunks {
+        return Err(PredictionMarketError::InvalidStateChunk);
+    }
+    let start = chunk_index as usize * chunk_bytes;
+    let end = (start + chunk_bytes).min(encoded.len());
+    Ok(StateChunk {
+        index: chunk_index,
+        total_chunks,
+        state_hash: sha256(&[&encoded]).to_vec(),
+        bytes: encoded[start..end].to_vec(),
+    })
+}
+
+/// Restore an export on a fresh canister, one chunk at a time in order (admin only)
+/// The exported admin takes over once the last chunk is applied
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn import_state(chunk: StateChunk) -> Result<String, PredictionMarketError> {
+    let message = process_import_state(caller(), chunk, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(message)
+}
+
+fn process_import_state(
+    caller_principal: Principal,
+    chunk: StateChunk,
+    now: u64,
+) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller_principal) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if !holds_no_state() {
+        return Err(PredictionMarketError::InvalidStateChunk);
+    }
+    let complete = STATE_IMPORT.with(|import| {
+        let mut import = import.borrow_mut();
+        if chunk.index == 0 {
+            *import = Some(StateImport {
+                state_hash: chunk.state_hash.clone(),
+                total_chunks: chunk.total_chunks,
+                next_index: 0,
+                bytes: Vec::new(),
+            });
+        }
+        let Some(current) = import.as_mut() else {
+            return Err(PredictionMarketError::InvalidStateChunk);
+        };
+        if chunk.state_hash != current.state_hash
+            || chunk.total_chunks != current.total_chunks
+            || chunk.index != current.next_index
+        {
+            return Err(PredictionMarketError::InvalidStateChunk);
+        }
+        current.bytes.extend_from_slice(&chunk.bytes);
+        current.next_index += 1;
+        if chunk.index + 1 < current.total_chunks {
+            return Ok(None);
+        }
+        let finished = import.take();
+        Ok(finished.map(|finished| finished.bytes))
+    })?;
+
+    let Some(bytes) = complete else {
+        return Ok(format!(
+            "Imported chunk {} of {}",
+            chunk.index + 1,
+            chunk.total_chunks
+        ));
+    };
+    if sha256(&[&bytes]).to_vec() != chunk.state_hash {
+        return Err(PredictionMarketError::InvalidStateChunk);
+    }
+    let snapshot: StateSnapshot =
+        candid::decode_one(&bytes).map_err(|_| PredictionMarketError::InvalidStateChunk)?;
+    let market_count = snapshot.markets.len();
+    restore_state(snapshot, now);
+    Ok(format!("State imported with {} markets", market_count))
+}
+
+/// Step `n` rewrites a state saved at version `n + 1` into the version `n + 2` layout
+/// When a StateSnapshot change would not decode from the previous layout (a new field
+/// that is not an Option, a renamed or retyped one), bump STATE_VERSION and append a step
+/// that decodes the old layout, fills in the change and re-encodes it
+const STATE_MIGRATIONS: &[StateMigration] = &[];
+
+type StateMigration = fn(Vec<u8>) -> Result<Vec<u8>, String>;
+
+/// Bring a saved state up to STATE_VERSION one step at a time, then decode it
+fn migrate_state(version: u32, mut encoded: Vec<u8>) -> Result<StateSnapshot, String> {
+    if version == 0 || version > STATE_VERSION {
+        return Err(format!(
+            "Cannot restore state version {} with code at version {}",
+            version, STATE_VERSION
+        ));
+    }
+    for step in &STATE_MIGRATIONS[(version - 1) as usize..] {
+        encoded = step(encoded)?;
+    }
+    candid::decode_one(&encoded).map_err(|error| format!("Failed to decode state: {}", error))
+}
+
+/// A canister that has not created markets or credited anyone yet
+fn holds_no_state() -> bool {
+    MARKETS.with(|markets| markets.borrow().is_empty())
+        && USER_BALANCES.with(|balances| balances.borrow().is_empty())
+        && TRADE_HISTORY.with(|history| history.borrow().is_empty())
+}
+
+fn capture_state() -> StateSnapshot {
+    StateSnapshot {
+        markets: MARKETS.with(|state| state.borrow().clone()),
+        user_positions: USER_POSITIONS.with(|state| state.borrow().clone()),
+        reward_claims: REWARD_CLAIMS.with(|state| state.borrow().clone()),
+        next_market_id: NEXT_MARKET_ID.with(|state| *state.borrow()),
+        admin: ADMIN.with(|state| *state.borrow()),
+        pending_admin: PENDING_ADMIN.with(|state| *state.borrow()),
+        paused: PAUSED.with(|state| *state.borrow()),
+        question_gate: QUESTION_GATE.with(|state| *state.borrow()),
+        resolution_drafts: RESOLUTION_DRAFTS.with(|state| state.borrow().clone()),
+        comments: COMMENTS.with(|state| state.borrow().clone()),
+        next_comment_id: NEXT_COMMENT_ID.with(|state| *state.borrow()),
+        moderators: MODERATORS.with(|state| state.borrow().clone()),
+        creator_allowlist_mode: CREATOR_ALLOWLIST_MODE.with(|state| *state.borrow()),
+        allowed_creators: ALLOWED_CREATORS.with(|state| state.borrow().clone()),
+        creation_fee: CREATION_FEE.with(|state| *state.borrow()),
+        dynamic_fee: DYNAMIC_FEE.with(|state| state.borrow().clone()),
+        fee_tiers: FEE_TIERS.with(|state| state.borrow().clone()),
+        user_volume: USER_VOLUME.with(|state| state.borrow().clone()),
+        referrers: REFERRERS.with(|state| state.borrow().clone()),
+        referral_accounts: REFERRAL_ACCOUNTS.with(|state| state.borrow().clone()),
+        user_balances: USER_BALANCES.with(|state| state.borrow().clone()),
+        lp_accounts: LP_ACCOUNTS.with(|state| state.borrow().clone()),
+        swap_offers: SWAP_OFFERS.with(|state| state.borrow().clone()),
+        next_offer_id: NEXT_OFFER_ID.with(|state| *state.borrow()),
+        limit_orders: LIMIT_ORDERS.with(|state| state.borrow().clone()),
+        next_order_id: NEXT_ORDER_ID.with(|state| *state.borrow()),
+        price_triggers: PRICE_TRIGGERS.with(|state| state.borrow().clone()),
+        next_trigger_id: NEXT_TRIGGER_ID.with(|state| *state.borrow()),
+        airdrop_campaigns: AIRDROP_CAMPAIGNS.with(|state| state.borrow().clone()),
+        airdrop_records: AIRDROP_RECORDS.with(|state| state.borrow().clone()),
+        next_airdrop_id: NEXT_AIRDROP_ID.with(|state| *state.borrow()),
+        platform_phase: PLATFORM_PHASE.with(|state| state.borrow().clone()),
+        treasury_balance: TREASURY_BALANCE.with(|state| *state.borrow()),
+        user_regions: USER_REGIONS.with(|state| state.borrow().clone()),
+        trade_history: TRADE_HISTORY.with(|state| state.borrow().clone()),
+        next_trade_id: NEXT_TRADE_ID.with(|state| *state.borrow()),
+        position_history: POSITION_HISTORY.with(|state| state.borrow().clone()),
+        net_contributions: NET_CONTRIBUTIONS.with(|state| state.borrow().clone()),
+        realized_pnl: REALIZED_PNL.with(|state| state.borrow().clone()),
+        forecasts: FORECASTS.with(|state| state.borrow().clone()),
+        outcome_transfers: OUTCOME_TRANSFERS.with(|state| state.borrow().clone()),
+        position_receipts: POSITION_RECEIPTS.with(|state| state.borrow().clone()),
+        next_receipt_id: NEXT_RECEIPT_ID.with(|state| *state.borrow()),
+        dispute_config: DISPUTE_CONFIG.with(|state| state.borrow().clone()),
+        dispute_windows: DISPUTE_WINDOWS.with(|state| state.borrow().clone()),
+        creator_bond_config: CREATOR_BOND_CONFIG.with(|state| state.borrow().clone()),
+        creator_bonds: CREATOR_BONDS.with(|state| state.borrow().clone()),
+        resolution_votes: RESOLUTION_VOTES.with(|state| state.borrow().clone()),
+        vote_tallies: VOTE_TALLIES.with(|state| state.borrow().clone()),
+        hourly_candles: HOURLY_CANDLES.with(|state| state.borrow().clone()),
+        daily_candles: DAILY_CANDLES.with(|state| state.borrow().clone()),
+        price_observations: PRICE_OBSERVATIONS.with(|state| state.borrow().clone()),
+        balance_activity: BALANCE_ACTIVITY.with(|state| state.borrow().clone()),
+        auto_withdraw_days: AUTO_WITHDRAW_DAYS.with(|state| state.borrow().clone()),
+        read_tokens: READ_TOKENS.with(|state| state.borrow().clone()),
+        next_read_token_id: NEXT_READ_TOKEN_ID.with(|state| *state.borrow()),
+        trading_limits: TRADING_LIMITS.with(|state| state.borrow().clone()),
+        exposure_limits: EXPOSURE_LIMITS.with(|state| state.borrow().clone()),
+        commit_reveal_markets: COMMIT_REVEAL_MARKETS.with(|state| state.borrow().clone()),
+        trade_commitments: TRADE_COMMITMENTS.with(|state| state.borrow().clone()),
+        fee_holidays: FEE_HOLIDAYS.with(|state| state.borrow().clone()),
+        liquidity_matching: LIQUIDITY_MATCHING.with(|state| state.borrow().clone()),
+        edge_policies: EDGE_POLICIES.with(|state| state.borrow().clone()),
+        treasury_lp_accounts: TREASURY_LP_ACCOUNTS.with(|state| state.borrow().clone()),
+        lifecycle_hooks: LIFECYCLE_HOOKS.with(|state| state.borrow().clone()),
+        lifecycle_outbox: LIFECYCLE_OUTBOX.with(|state| state.borrow().clone()),
+        webhook_secrets: WEBHOOK_SECRETS.with(|state| state.borrow().clone()),
+        webhook_allowlist: WEBHOOK_ALLOWLIST.with(|state| state.borrow().clone()),
+        market_oracles: MARKET_ORACLES.with(|state| state.borrow().clone()),
+        price_feeds: PRICE_FEEDS.with(|state| state.borrow().clone()),
+        events: EVENTS.with(|state| state.borrow().clone()),
+        shard_config: Some(SHARD_CONFIG.with(|state| state.borrow().clone())),
+        text_limits: Some(TEXT_LIMITS.with(|state| state.borrow().clone())),
+        categories: Some(CATEGORIES.with(|state| state.borrow().clone())),
+        featured_markets: Some(FEATURED_MARKETS.with(|state| state.borrow().clone())),
+        pending_resolvers: Some(PENDING_RESOLVERS.with(|state| state.borrow().clone())),
+        abandoned_markets: Some(ABANDONED_MARKETS.with(|state| state.borrow().clone())),
+        pending_actions: Some(PENDING_ACTIONS.with(|state| state.borrow().clone())),
+        next_action_id: Some(NEXT_ACTION_ID.with(|state| *state.borrow())),
+        airdrop_quorum: Some(AIRDROP_QUORUM.with(|state| state.borrow().clone())),
+        market_shards: Some(MARKET_SHARDS.with(|state| state.borrow().clone())),
+        router: ROUTER.with(|state| *state.borrow()),
+    }
+}
+
+fn restore_state(snapshot: StateSnapshot, now: u64) {
+    MARKETS.with(|state| *state.borrow_mut() = snapshot.markets);
+    USER_POSITIONS.with(|state| *state.borrow_mut() = snapshot.user_positions);
+    REWARD_CLAIMS.with(|state| *state.borrow_mut() = snapshot.reward_claims);
+    NEXT_MARKET_ID.with(|state| *state.borrow_mut() = snapshot.next_market_id);
+    ADMIN.with(|state| *state.borrow_mut() = snapshot.admin);
+    PENDING_ADMIN.with(|state| *state.borrow_mut() = snapshot.pending_admin);
+    PAUSED.with(|state| *state.borrow_mut() = snapshot.paused);
+    QUESTION_GATE.with(|state| *state.borrow_mut() = snapshot.question_gate);
+    RESOLUTION_DRAFTS.with(|state| *state.borrow_mut() = snapshot.resolution_drafts);
+    COMMENTS.with(|state| *state.borrow_mut() = snapshot.comments);
+    NEXT_COMMENT_ID.with(|state| *state.borrow_mut() = snapshot.next_comment_id);
+    MODERATORS.with(|state| *state.borrow_mut() = snapshot.moderators);
+    CREATOR_ALLOWLIST_MODE.with(|state| *state.borrow_mut() = snapshot.creator_allowlist_mode);
+    ALLOWED_CREATORS.with(|state| *state.borrow_mut() = snapshot.allowed_creators);
+    CREATION_FEE.with(|state| *state.borrow_mut() = snapshot.creation_fee);
+    DYNAMIC_FEE.with(|state| *state.borrow_mut() = snapshot.dynamic_fee);
+    FEE_TIERS.with(|state| *state.borrow_mut() = snapshot.fee_tiers);
+    USER_VOLUME.with(|state| *state.borrow_mut() = snapshot.user_volume);
+    REFERRERS.with(|state| *state.borrow_mut() = snapshot.referrers);
+    REFERRAL_ACCOUNTS.with(|state| *state.borrow_mut() = snapshot.referral_accounts);
+    USER_BALANCES.with(|state| *state.borrow_mut() = snapshot.user_balances);
+    LP_ACCOUNTS.with(|state| *state.borrow_mut() = snapshot.lp_accounts);
+    SWAP_OFFERS.with(|state| *state.borrow_mut() = snapshot.swap_offers);
+    NEXT_OFFER_ID.with(|state| *state.borrow_mut() = snapshot.next_offer_id);
+    LIMIT_ORDERS.with(|state| *state.borrow_mut() = snapshot.limit_orders);
+    NEXT_ORDER_ID.with(|state| *state.borrow_mut() = snapshot.next_order_id);
+    PRICE_TRIGGERS.with(|state| *state.borrow_mut() = snapshot.price_triggers);
+    NEXT_TRIGGER_ID.with(|state| *state.borrow_mut() = snapshot.next_trigger_id);
+    AIRDROP_CAMPAIGNS.with(|state| *state.borrow_mut() = snapshot.airdrop_campaigns);
+    AIRDROP_RECORDS.with(|state| *state.borrow_mut() = snapshot.airdrop_records);
+    NEXT_AIRDROP_ID.with(|state| *state.borrow_mut() = snapshot.next_airdrop_id);
+    PLATFORM_PHASE.with(|state| *state.borrow_mut() = snapshot.platform_phase);
+    TREASURY_BALANCE.with(|state| *state.borrow_mut() = snapshot.treasury_balance);
+    USER_REGIONS.with(|state| *state.borrow_mut() = snapshot.user_regions);
+    TRADE_HISTORY.with(|state| *state.borrow_mut() = snapshot.trade_history);
+    NEXT_TRADE_ID.with(|state| *state.borrow_mut() = snapshot.next_trade_id);
+    POSITION_HISTORY.with(|state| *state.borrow_mut() = snapshot.position_history);
+    NET_CONTRIBUTIONS.with(|state| *state.borrow_mut() = snapshot.net_contributions);
+    REALIZED_PNL.with(|state| *state.borrow_mut() = snapshot.realized_pnl);
+    FORECASTS.with(|state| *state.borrow_mut() = snapshot.forecasts);
+    OUTCOME_TRANSFERS.with(|state| *state.borrow_mut() = snapshot.outcome_transfers);
+    POSITION_RECEIPTS.with(|state| *state.borrow_mut() = snapshot.position_receipts);
+    NEXT_RECEIPT_ID.with(|state| *state.borrow_mut() = snapshot.next_receipt_id);
+    DISPUTE_CONFIG.with(|state| *state.borrow_mut() = snapshot.dispute_config);
+    DISPUTE_WINDOWS.with(|state| *state.borrow_mut() = snapshot.dispute_windows);
+    CREATOR_BOND_CONFIG.with(|state| *state.borrow_mut() = snapshot.creator_bond_config);
+    CREATOR_BONDS.with(|state| *state.borrow_mut() = snapshot.creator_bonds);
+    RESOLUTION_VOTES.with(|state| *state.borrow_mut() = snapshot.resolution_votes);
+    VOTE_TALLIES.with(|state| *state.borrow_mut() = snapshot.vote_tallies);
+    HOURLY_CANDLES.with(|state| *state.borrow_mut() = snapshot.hourly_candles);
+    DAILY_CANDLES.with(|state| *state.borrow_mut() = snapshot.daily_candles);
+    PRICE_OBSERVATIONS.with(|state| *state.borrow_mut() = snapshot.price_observations);
+    BALANCE_ACTIVITY.with(|state| *state.borrow_mut() = snapshot.balance_activity);
+    AUTO_WITHDRAW_DAYS.with(|state| *state.borrow_mut() = snapshot.auto_withdraw_days);
+    READ_TOKENS.with(|state| *state.borrow_mut() = snapshot.read_tokens);
+    NEXT_READ_TOKEN_ID.with(|state| *state.borrow_mut() = snapshot.next_read_token_id);
+    TRADING_LIMITS.with(|state| *state.borrow_mut() = snapshot.trading_limits);
+    EXPOSURE_LIMITS.with(|state| *state.borrow_mut() = snapshot.exposure_limits);
+    COMMIT_REVEAL_MARKETS.with(|state| *state.borrow_mut() = snapshot.commit_reveal_markets);
+    TRADE_COMMITMENTS.with(|state| *state.borrow_mut() = snapshot.trade_commitments);
+    FEE_HOLIDAYS.with(|state| *state.borrow_mut() = snapshot.fee_holidays);
+    LIQUIDITY_MATCHING.with(|state| *state.borrow_mut() = snapshot.liquidity_matching);
+    EDGE_POLICIES.with(|state| *state.borrow_mut() = snapshot.edge_policies);
+    TREASURY_LP_ACCOUNTS.with(|state| *state.borrow_mut() = snapshot.treasury_lp_accounts);
+    LIFECYCLE_HOOKS.with(|state| *state.borrow_mut() = snapshot.lifecycle_hooks);
+    LIFECYCLE_OUTBOX.with(|state| *state.borrow_mut() = snapshot.lifecycle_outbox);
+    WEBHOOK_SECRETS.with(|state| *state.borrow_mut() = snapshot.webhook_secrets);
+    WEBHOOK_ALLOWLIST.with(|state| *state.borrow_mut() = snapshot.webhook_allowlist);
+    MARKET_ORACLES.with(|state| *state.borrow_mut() = snapshot.market_oracles);
+    PRICE_FEEDS.with(|state| *state.borrow_mut() = snapshot.price_feeds);
+    EVENTS.with(|state| *state.borrow_mut() = snapshot.events);
+    SHARD_CONFIG.with(|state| *state.borrow_mut() = snapshot.shard_config.unwrap_or_default());
+    TEXT_LIMITS.with(|state| *state.borrow_mut() = snapshot.text_limits.unwrap_or_default());
+    CATEGORIES.with(|state| *state.borrow_mut() = snapshot.categories.unwrap_or_default());
+    FEATURED_MARKETS
+        .with(|state| *state.borrow_mut() = snapshot.featured_markets.unwrap_or_default());
+    PENDING_RESOLVERS
+        .with(|state| *state.borrow_mut() = snapshot.pending_resolvers.unwrap_or_default());
+    ABANDONED_MARKETS
+        .with(|state| *state.borrow_mut() = snapshot.abandoned_markets.unwrap_or_default());
+    PENDING_ACTIONS
+        .with(|state| *state.borrow_mut() = snapshot.pending_actions.unwrap_or_default());
+    NEXT_ACTION_ID.with(|state| *state.borrow_mut() = snapshot.next_action_id.unwrap_or(1));
+    AIRDROP_QUORUM.with(|state| *state.borrow_mut() = snapshot.airdrop_quorum.unwrap_or_default());
+    MARKET_SHARDS.with(|state| *state.borrow_mut() = snapshot.market_shards.unwrap_or_default());
+    ROUTER.with(|state| *state.borrow_mut() = snapshot.router);
+    rebuild_derived_state(now);
+}
+
+/// Rebuild the trade indexes, rolling stats and certified documents from restored state
+fn rebuild_derived_state(now: u64) {
+    let mut trades: Vec<(u64, u64, usize)> = TRADE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .flat_map(|(market_id, records)| {
+                records
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, record)| (record.id, *market_id, index))
+            })
+            .collect()
+    });
+    trades.sort_unstable();
+    USER_TRADE_INDEX.with(|trade_index| trade_index.borrow_mut().clear());
+    ROLLING_STATS.with(|stats| stats.borrow_mut().clear());
+    PLATFORM_TOTALS.with(|totals| *totals.borrow_mut() = PlatformTotals::default());
+    LAST_TRADED_AT.with(|last_traded| last_traded.borrow_mut().clear());
+    for (_, market_id, index) in trades {
+        let record = TRADE_HISTORY.with(|history| history.borrow()[&market_id][index].clone());
+        update_rolling_stats(&record);
+        update_platform_totals(&record);
+        USER_TRADE_INDEX.with(|trade_index| {
+            trade_index
+                .borrow_mut()
+                .entry(record.trader)
+                .or_default()
+                .push((market_id, index))
+        });
+    }
+
+    CERTIFIED_DOCUMENTS.with(|documents| documents.borrow_mut().clear());
+    let market_ids: Vec<u64> = MARKETS.with(|markets| markets.borrow().keys().copied().collect());
+    for market_id in market_ids {
+        certify_market(market_id, now);
+    }
+}
+
+// =============================================================================
+// DEPLOYMENT SELF-TEST
+// =============================================================================
+
+/// Verify a deployment against live state without modifying it (admin only)
+/// Exercises quoting, market invariants, index consistency and the close timer
+#[ic_cdk::query]
+fn run_self_test() -> Result<SelfTestReport, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    Ok(build_self_test_report(ic_cdk::api::time()))
+}
+
+fn self_test_check(name: &str, failures: Vec<String>) -> SelfTestCheck {
+    SelfTestCheck {
+        name: name.to_string(),
+        passed: failures.is_empty(),
+        failures,
+    }
+}
+
+/// Round-trip quotes on both sides of a market at standard sizes
+/// Sizes the market is too shallow to fill are left out
+#[ic_cdk::query]
+fn get_price_band_diagnostics(
+    market_id: u64,
+) -> Result<Vec<PriceBandCheck>, PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    Ok(price_band_checks(&market))
+}
+
+fn price_band_checks(market: &AmmMarket) -> Vec<PriceBandCheck> {
+    let mut checks = Vec::new();
+    for token_type in [TokenType::Yes, TokenType::No] {
+        for size in PRICE_BAND_SIZES {
+            if let Ok(check) = check_price_band(
+                market.yes_reserve,
+                market.no_reserve,
+                size,
+                token_type.clone(),
+            ) {
+                checks.push(check);
+            }
+        }
+    }
+    checks
+}
+
+fn build_self_test_report(now: u64) -> SelfTestReport {
+    let markets: Vec<AmmMarket> =
+        MARKETS.with(|markets| markets.borrow().values().cloned().collect());
+    let market_exists = |market_id: u64| markets.iter().any(|market| market.id == market_id);
+
+    // Every tradable market must be able to quote a minimum-size buy on both sides
+    let min_trade_size = TRADING_LIMITS.with(|limits| limits.borrow().min_trade_size);
+    let mut quoting = Vec::new();
+    for market in markets.iter().filter(|market| is_trading_open(market, now)) {
+        for token_type in [TokenType::Yes, TokenType::No] {
+            if let Err(error) =
+                compute_buy_quote(market.id, min_trade_size, token_type.clone(), now)
+            {
+                quoting.push(format!(
+                    "market {}: {:?} buy quote failed with {:?}",
+                    market.id, token_type, error
+                ));
+            }
+        }
+    }
+
+    // Unresolved markets need both reserves to price trades
+    let reserves = markets
+        .iter()
+        .filter(|market| market.status != MarketStatus::Resolved)
+        .filter(|market| market.yes_reserve == 0 || market.no_reserve == 0)
+        .map(|market| format!("market {}: empty reserve", market.id))
+        .collect();
+
+    // Outstanding LP shares must equal the sum of every provider's shares
+    let lp_shares = LP_ACCOUNTS.with(|accounts| {
+        let accounts_map = accounts.borrow();
+        markets
+            .iter()
+            .filter_map(|market| {
+                let held: u64 = accounts_map
+                    .iter()
+                    .filter(|((_, market_id), _)| *market_id == market.id)
+                    .map(|(_, account)| account.shares)
+                    .sum::<u64>()
+                    + TREASURY_LP_ACCOUNTS.with(|treasury_accounts| {
+                        treasury_accounts
+                            .borrow()
+                            .get(&market.id)
+                            .map_or(0, |account| account.shares)
+                    });
+                (held != market.total_lp_shares).then(|| {
+                    format!(
+                        "market {}: {} shares outstanding but providers hold {}",
+                        market.id, market.total_lp_shares, held
+                    )
+                })
+            })
+            .collect()
+    });
+
+    // Positions, orders, triggers and LP accounts must point at existing markets
+    let mut index = Vec::new();
+    USER_POSITIONS.with(|positions| {
+        for (user, market_id) in positions.borrow().keys() {
+            if !market_exists(*market_id) {
+                index.push(format!(
+                    "position of {} on missing market {}",
+                    user, market_id
+                ));
+            }
+        }
+    });
+    LP_ACCOUNTS.with(|accounts| {
+        for (provider, market_id) in accounts.borrow().keys() {
+            if !market_exists(*market_id) {
+                index.push(format!(
+                    "LP account of {} on missing market {}",
+                    provider, market_id
+                ));
+            }
+        }
+    });
+    LIMIT_ORDERS.with(|orders| {
+        for order in orders.borrow().values() {
+            if !market_exists(order.market_id) {
+                index.push(format!(
+                    "order {} on missing market {}",
+                    order.id, order.market_id
+                ));
+            }
+        }
+    });
+    PRICE_TRIGGERS.with(|triggers| {
+        for trigger in triggers.borrow().values() {
+            if !market_exists(trigger.market_id) {
+                index.push(format!(
+                    "trigger {} on missing market {}",
+                    trigger.id, trigger.market_id
+                ));
+            }
+        }
+    });
+
+    // ID counters must stay ahead of every stored ID
+    let mut counters = Vec::new();
+    let mut check_counter = |name: &str, next_id: u64, max_id: Option<u64>| {
+        if let Some(max_id) = max_id.filter(|max_id| *max_id >= next_id) {
+            counters.push(format!(
+                "next {} ID {} is not above {}",
+                name, next_id, max_id
+            ));
+        }
+    };
+    check_counter(
+        "market",
+        NEXT_MARKET_ID.with(|id| *id.borrow()),
+        markets.iter().map(|market| market.id).max(),
+    );
+    check_counter(
+        "order",
+        NEXT_ORDER_ID.with(|id| *id.borrow()),
+        LIMIT_ORDERS.with(|orders| orders.borrow().keys().copied().max()),
+    );
+    check_counter(
+        "offer",
+        NEXT_OFFER_ID.with(|id| *id.borrow()),
+        SWAP_OFFERS.with(|offers| offers.borrow().keys().copied().max()),
+    );
+    check_counter(
+        "trigger",
+        NEXT_TRIGGER_ID.with(|id| *id.borrow()),
+        PRICE_TRIGGERS.with(|triggers| triggers.borrow().keys().copied().max()),
+    );
+
+    // An open market past its close time means the global timer did not fire
+    let close_timer = markets
+        .iter()
+        .filter(|market| market.status == MarketStatus::Open && market.close_time <= now)
+        .map(|market| format!("market {}: open past its close time", market.id))
+        .collect();
+
+    // Selling freshly bought tokens back must never pay out more than was spent
+    let mut price_band = Vec::new();
+    for market in markets.iter().filter(|market| is_trading_open(market, now)) {
+        for check in price_band_checks(market)
+            .into_iter()
+            .filter(|check| !check.within_band)
+        {
+            price_band.push(format!(
+                "market {}: {:?} round trip of {} ICP buys at {:.6} but sells at {:.6}",
+                market.id, check.token_type, check.size, check.buy_price, check.round_trip_price
+            ));
+        }
+    }
+
+    // Status, winning outcome and resolution state must agree
+    let resolution = markets
+        .iter()
+        .filter(|market| {
+            let is_final = market
+                .resolution
+                .as_ref()
+                .is_some_and(|state| state.phase == ResolutionPhase::Final);
+            let settled = market.status == MarketStatus::Resolved;
+            let outcome_matches = market.winning_outcome.as_ref()
+                == market
+                    .resolution
+                    .as_ref()
+                    .filter(|_| is_final)
+                    .map(|state| &state.outcome);
+            settled != is_final || !outcome_matches
+        })
+        .map(|market| format!("market {}: inconsistent resolution state", market.id))
+        .collect();
+
+    let checks = vec![
+        self_test_check("quoting", quoting),
+        self_test_check("reserves", reserves),
+        self_test_check("lp_shares", lp_shares),
+        self_test_check("index_consistency", index),
+        self_test_check("id_counters", counters),
+        self_test_check("close_timer", close_timer),
+        self_test_check("resolution", resolution),
+        self_test_check("price_band", price_band),
+    ];
+
+    SelfTestReport {
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+        ran_at: now,
+    }
+}
+
+// =============================================================================
+// UTILITY FUNCTIONS
+// =============================================================================
+
+/// Render an e8s amount as ICP with trailing zeros trimmed, e.g. 150_000_000 -> "1.5 ICP"
+fn format_e8s(e8s: u64) -> String {
+    format!("{} ICP", format_token_amount(e8s))
+}
+
+/// Render an 8-decimal amount with trailing zeros trimmed, e.g. 150_000_000 -> "1.5"
+fn format_token_amount(amount: u64) -> String {
+    let whole = amount / E8S_PER_ICP;
+    let fraction = amount % E8S_PER_ICP;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let digits = format!("{:08}", fraction);
+    format!("{}.{}", whole, digits.trim_end_matches('0'))
+}
+
+/// Deposit ICP to user balance (simulation - in production would involve real ICP transfers)
+/// `amount` is in e8s
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn deposit_icp(amount: u64) -> Result<String, PredictionMarketError> {
+    ensure_platform_active()?;
+
+    if amount < MIN_DEPOSIT {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let caller_principal = caller();
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&caller_principal).unwrap_or(&0);
+        balances_map.insert(caller_principal, current_balance + amount);
+    });
+    let now = ic_cdk::api::time();
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::Deposited {
+            user: caller_principal,
+            amount,
+        },
+    );
+
+    Ok(format!("Successfully deposited {}", format_e8s(amount)))
+}
+
+/// Withdraw ICP from user balance (simulation - in production would involve real ICP transfers)
+/// Always available, including during wind-down, so funds can never be stranded
+/// `amount` is in e8s
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn withdraw_icp(amount: u64) -> Result<String, PredictionMarketError> {
+    if amount == 0 {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let caller_principal = caller();
+    let _guard = OperationGuard::acquire(vec![LockKey::User(caller_principal)])?;
+    withdraw_balance(caller_principal, amount)?;
+    let now = ic_cdk::api::time();
+    touch_balance_activity(caller_principal, now);
+    emit_event(
+        now,
+        EventKind::Withdrawn {
+            user: caller_principal,
+            amount,
+            automatic: false,
+        },
+    );
+    Ok(format!("Successfully withdrew {}", format_e8s(amount)))
+}
+
+/// Debit a user's balance and push it back to their ledger account (simulated)
+/// Shared by manual withdrawals and the idle-balance sweep
+fn withdraw_balance(user: Principal, amount: u64) -> Result<(), PredictionMarketError> {
+    USER_BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let current_balance = *balances_map.get(&user).unwrap_or(&0);
+        if current_balance < amount {
+            return Err(PredictionMarketError::InsufficientDeposit {
+                required: amount,
+                available: current_balance,
+            });
+        }
+        balances_map.insert(user, current_balance - amount);
+        Ok(())
+    })
+}
+
+// =============================================================================
+// IDLE BALANCE SAFEGUARDS
+// =============================================================================
+
+/// Record that a user's balance was just used
+fn touch_balance_activity(user: Principal, now: u64) {
+    BALANCE_ACTIVITY.with(|activity| activity.borrow_mut().insert(user, now));
+}
+
+/// When an opted-in user's balance becomes due for automatic withdrawal
+fn auto_withdraw_due_at(user: Principal) -> Option<u64> {
+    let days = AUTO_WITHDRAW_DAYS.with(|settings| settings.borrow().get(&user).copied())?;
+    let last_activity = BALANCE_ACTIVITY.with(|activity| activity.borrow().get(&user).copied())?;
+    Some(last_activity.saturating_add(days.saturating_mul(NANOS_PER_DAY)))
+}
+
+/// Earliest auto-withdraw deadline among opted-in users with a balance, if any
+fn next_auto_withdraw_time() -> Option<u64> {
+    let users: Vec<Principal> =
+        AUTO_WITHDRAW_DAYS.with(|settings| settings.borrow().keys().copied().collect());
+    users
+        .into_iter()
+        .filter(|user| get_balance_of(*user) > 0)
+        .filter_map(auto_withdraw_due_at)
+        .min()
+}
+
+/// Opt in to (or, with `None`, out of) automatic withdrawal after `days` without activity
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_auto_withdraw(days: Option<u64>) -> Result<IdleBalanceStatus, PredictionMarketError> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+
+    match days {
+        Some(days) if days == 0 || days > MAX_AUTO_WITHDRAW_DAYS => {
+            return Err(PredictionMarketError::InvalidAmount);
+        }
+        Some(days) => {
+            AUTO_WITHDRAW_DAYS
+                .with(|settings| settings.borrow_mut().insert(caller_principal, days));
+            // Opting in counts as activity so the countdown starts now
+            touch_balance_activity(caller_principal, now);
+        }
+        None => {
+            AUTO_WITHDRAW_DAYS.with(|settings| settings.borrow_mut().remove(&caller_principal));
+        }
+    }
+    schedule_global_timer();
+    emit_event(
+        now,
+        EventKind::AutoWithdrawChanged {
+            user: caller_principal,
+            days,
+        },
+    );
+
+    Ok(build_idle_balance_status(caller_principal, now))
+}
+
+/// How long the caller's balance has been idle and when it will be auto-withdrawn
+#[ic_cdk::query]
+fn get_idle_balance_status() -> IdleBalanceStatus {
+    build_idle_balance_status(caller(), ic_cdk::api::time())
+}
+
+fn build_idle_balance_status(user: Principal, now: u64) -> IdleBalanceStatus {
+    let last_activity = BALANCE_ACTIVITY.with(|activity| activity.borrow().get(&user).copied());
+    IdleBalanceStatus {
+        balance: get_balance_of(user),
+        last_activity,
+        idle_for_ns: last_activity.map_or(0, |at| now.saturating_sub(at)),
+        auto_withdraw_after_days: AUTO_WITHDRAW_DAYS
+            .with(|settings| settings.borrow().get(&user).copied()),
+        auto_withdraw_at: auto_withdraw_due_at(user),
+    }
+}
+
+/// Withdraw the full balance of every opted-in user idle past their deadline
+/// Returns the users swept and the amounts withdrawn
+fn sweep_idle_balances(now: u64) -> Vec<(Principal, u64)> {
+    let users: Vec<Principal> =
+        AUTO_WITHDRAW_DAYS.with(|settings| settings.borrow().keys().copied().collect());
+    let mut swept = Vec::new();
+    for user in users {
+        if auto_withdraw_due_at(user).is_none_or(|due| due > now) {
+            continue;
+        }
+        let balance = get_balance_of(user);
+        if balance > 0 && withdraw_balance(user, balance).is_ok() {
+            touch_balance_activity(user, now);
+            emit_event(
+                now,
+                EventKind::Withdrawn {
+                    user,
+                    amount: balance,
+                    automatic: true,
+                },
+            );
+            swept.push((user, balance));
+        }
+    }
+    swept
+}
+
+// =============================================================================
+// HTTP GATEWAY
+// =============================================================================
+
+/// Plain HTTP request forwarded by the boundary nodes
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String, // Path and query string, e.g. "/markets?offset=20"
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Minimal JSON document, rendered without a serializer dependency
+enum Json {
+    Null,
+    Int(u64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn render(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Int(value) => out.push_str(&value.to_string()),
+            Json::Float(value) if value.is_finite() => out.push_str(&value.to_string()),
+            Json::Float(_) => out.push_str("null"),
+            Json::Str(value) => {
+                out.push('"');
+                for c in value.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.render(out);
+                }
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    Json::Str(key.to_string()).render(out);
+                    out.push(':');
+                    value.render(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        self.render(&mut out);
+        out
+    }
+
+    fn opt_str(value: &Option<String>) -> Json {
+        value.clone().map_or(Json::Null, Json::Str)
+    }
+}
+
+fn market_listing_json(listing: &MarketListing) -> Json {
+    Json::Object(vec![
+        ("id", Json::Int(listing.id)),
+        ("title", Json::Str(listing.title.clone())),
+        ("status", Json::Str(format!("{:?}", listing.status))),
+        ("yes_price", Json::Float(listing.yes_price)),
+        ("no_price", Json::Float(listing.no_price)),
+        ("icp_liquidity_pool", Json::Int(listing.icp_liquidity_pool)),
+        ("close_time", Json::Int(listing.close_time)),
+        ("category", Json::opt_str(&listing.category)),
+    ])
+}
+
+fn market_summary_json(summary: &MarketSummary, now: u64) -> Json {
+    let market = &summary.market;
+    Json::Object(vec![
+        ("as_of", Json::Int(now)), // When the 24h figures were computed
+        ("id", Json::Int(market.id)),
+        ("title", Json::Str(market.title.clone())),
+        ("description", Json::Str(market.description.clone())),
+        ("status", Json::Str(format!("{:?}", market.status))),
+        (
+            "winning_outcome",
+            Json::opt_str(&market.winning_outcome.as_ref().map(|o| format!("{:?}", o))),
+        ),
+        ("yes_price", Json::Float(summary.yes_price)),
+        ("no_price", Json::Float(summary.no_price)),
+        ("yes_reserve", Json::Int(market.yes_reserve)),
+        ("no_reserve", Json::Int(market.no_reserve)),
+        ("icp_liquidity_pool", Json::Int(market.icp_liquidity_pool)),
+        ("total_volume", Json::Int(summary.total_volume)),
+        ("volume_24h", Json::Int(summary.volume_24h)),
+        ("trade_count_24h", Json::Int(summary.trade_count_24h)),
+        ("unique_traders_24h", Json::Int(summary.unique_traders_24h)),
+        ("price_change_24h", Json::Float(summary.price_change_24h)),
+        ("creation_time", Json::Int(market.creation_time)),
+        ("close_time", Json::Int(market.close_time)),
+        ("category", Json::opt_str(&market.category)),
+    ])
+}
+
+fn json_response(status_code: u16, body: Json) -> HttpResponse {
+    json_bytes_response(status_code, body.encode().into_bytes())
+}
+
+fn json_bytes_response(status_code: u16, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                HTTP_JSON_CONTENT_TYPE.to_string(),
+            ),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+    }
+}
+
+fn json_error(status_code: u16, message: &str) -> HttpResponse {
+    json_response(
+        status_code,
+        Json::Object(vec![("error", Json::Str(message.to_string()))]),
+    )
+}
+
+/// Read an unsigned query parameter, e.g. `limit` from "offset=20&limit=50"
+fn query_param(query: &str, name: &str) -> Option<u64> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Route a GET request: `/markets` lists markets, `/market/<id>` returns one summary
+fn handle_http_get(url: &str, now: u64) -> HttpResponse {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let path = path.trim_end_matches('/');
+
+    if path == "/markets" {
+        let offset = query_param(query, "offset").unwrap_or(0);
+        let limit = query_param(query, "limit").unwrap_or(MAX_LIST_RESULTS as u64);
+        let page = get_markets_paginated(offset, limit);
+        return json_response(
+            200,
+            Json::Object(vec![
+                (
+                    "items",
+                    Json::Array(page.items.iter().map(market_listing_json).collect()),
+                ),
+                (
+                    "next_offset",
+                    page.next_cursor.map_or(Json::Null, Json::Int),
+                ),
+            ]),
+        );
+    }
+
+    if let Some(body) = certified_document_body(path) {
+        return json_bytes_response(200, body);
+    }
+
+    if let Some(id) = path.strip_prefix("/market/") {
+        let Ok(market_id) = id.parse::<u64>() else {
+            return json_error(400, "invalid market id");
+        };
+        let summary = MARKETS.with(|markets| {
+            markets
+                .borrow()
+                .get(&market_id)
+                .map(|market| build_market_summary(market, now))
+        });
+        return match summary {
+            Some(summary) => json_response(200, market_summary_json(&summary, now)),
+            None => json_error(404, "market not found"),
+        };
+    }
+
+    json_error(404, "not found")
+}
+
+/// Serve market data as JSON to plain HTTP clients through the gateway
+/// `/market/<id>` carries an IC-Certificate header so boundary nodes can verify it
+/// `/metrics` serves Prometheus text for monitoring
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if !request.method.eq_ignore_ascii_case("GET") {
+        return json_error(405, "method not allowed");
+    }
+    let path = request.url.split('?').next().unwrap_or_default();
+    if path == "/metrics" {
+        let body = render_metrics(ic_cdk::api::canister_balance128(), heap_memory_bytes());
+        return HttpResponse {
+            status_code: 200,
+            headers: vec![(
+                "Content-Type".to_string(),
+                HTTP_METRICS_CONTENT_TYPE.to_string(),
+            )],
+            body: body.into_bytes(),
+        };
+    }
+
+    let mut response = handle_http_get(&request.url, ic_cdk::api::time());
+
+    if let Some(certificate) = ic_cdk::api::data_certificate() {
+        if certified_document_body(path).is_some() {
+            response.headers.push((
+                "IC-Certificate".to_string(),
+                format!(
+                    "certificate=:{}:, tree=:{}:",
+                    data_encoding::BASE64.encode(&certificate),
+                    data_encoding::BASE64.encode(&certified_witness(path))
+                ),
+            ));
+        }
+    }
+    response
+}
+
+/// Render counters and gauges for a Prometheus scraper
+/// `/metrics` is not certified, so scrape it through the raw domain (<canister>.raw.icp0.io)
+fn render_metrics(cycles_balance: u128, heap_memory_bytes: u64) -> String {
+    let (markets_total, markets_open, fees_collected) = MARKETS.with(|markets| {
+        markets
+            .borrow()
+            .values()
+            .fold((0u64, 0u64, 0u64), |(total, open, fees), market| {
+                let open = open + u64::from(market.status == MarketStatus::Open);
+                (total + 1, open, fees + market.total_fees_collected)
+            })
+    });
+    let (trades_total, trade_volume) = TRADE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .values()
+            .flatten()
+            .fold((0u64, 0u64), |(count, volume), trade| {
+                (count + 1, volume + trade.icp_amount)
+            })
+    });
+    let treasury_balance = TREASURY_BALANCE.with(|treasury| *treasury.borrow());
+
+    let metrics: [(&str, &str, &str, u128); 8] = [
+        (
+            "markets_total",
+            "gauge",
+            "Markets ever created",
+            markets_total.into(),
+        ),
+        (
+            "markets_open",
+            "gauge",
+            "Markets open for trading",
+            markets_open.into(),
+        ),
+        (
+            "trades_total",
+            "counter",
+            "Trades executed",
+            trades_total.into(),
+        ),
+        (
+            "trade_volume_icp_total",
+            "counter",
+            "ICP moved by executed trades",
+            trade_volume.into(),
+        ),
+        (
+            "fees_collected_icp_total",
+            "counter",
+            "Trading fees collected across all markets",
+            fees_collected.into(),
+        ),
+        (
+            "treasury_balance_icp",
+            "gauge",
+            "Protocol treasury balance",
+            treasury_balance.into(),
+        ),
+        (
+            "cycles_balance",
+            "gauge",
+            "Canister cycles balance",
+            cycles_balance,
+        ),
+        (
+            "heap_memory_bytes",
+            "gauge",
+            "Wasm heap memory in use",
+            heap_memory_bytes.into(),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        out.push_str(&format!(
+            "# HELP prediction_market_{name} {help}\n# TYPE prediction_market_{name} {kind}\nprediction_market_{name} {value}\n"
+        ));
+    }
+    out
+}
+
+/// Get cycles, memory and record counts without calling the management canister
+#[ic_cdk::query]
+fn get_canister_stats() -> CanisterStats {
+    build_canister_stats(
+        ic_cdk::api::canister_balance128(),
+        heap_memory_bytes(),
+        ic_cdk::api::stable::stable_size() * 65_536,
+    )
+}
+
+fn build_canister_stats(
+    cycles_balance: u128,
+    heap_memory_bytes: u64,
+    stable_memory_bytes: u64,
+) -> CanisterStats {
+    CanisterStats {
+        cycles_balance,
+        heap_memory_bytes,
+        stable_memory_bytes,
+        market_count: MARKETS.with(|markets| markets.borrow().len() as u64),
+        position_count: USER_POSITIONS.with(|positions| positions.borrow().len() as u64),
+        claim_count: REWARD_CLAIMS.with(|claims| claims.borrow().len() as u64),
+        trade_count: TRADE_HISTORY.with(|history| {
+            history
+                .borrow()
+                .values()
+                .map(|trades| trades.len() as u64)
+                .sum()
+        }),
+        event_count: EVENTS.with(|events| events.borrow().len() as u64),
+        last_upgrade: LAST_UPGRADE.with(|last| *last.borrow()),
+        state_version: STATE_VERSION,
+    }
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return core::arch::wasm32::memory_size(0) as u64 * 65_536;
+    #[cfg(not(target_arch = "wasm32"))]
+    0
+}
+
+// =============================================================================
+// CERTIFIED MARKET DATA
+// =============================================================================
+
+/// A market's JSON document as of its last state change, with its hash in the certified tree
+struct CertifiedDocument {
+    body: Vec<u8>,
+    body_hash: [u8; 32],
+}
+
+/// A market document and the proof that the subnet signed it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CertifiedMarket {
+    pub body: Vec<u8>,        // JSON, identical to GET /market/<id>
+    pub certificate: Vec<u8>, // CBOR system certificate over the canister's certified data
+    pub tree: Vec<u8>, // CBOR hash tree witnessing sha256(body) at ["http_assets", "/market/<id>"]
+}
+
+/// Merkle tree in the Internet Computer's certification format
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned([u8; 32]),
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn domain_separator(name: &str) -> Vec<u8> {
+    let mut separator = vec![name.len() as u8];
+    separator.extend_from_slice(name.as_bytes());
+    separator
+}
+
+/// Append a CBOR byte string
+fn cbor_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    let len = bytes.len();
+    match len {
+        0..=23 => out.push(0x40 | len as u8),
+        24..=0xff => out.extend_from_slice(&[0x58, len as u8]),
+        0x100..=0xffff => {
+            out.push(0x59);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0x5a);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(bytes);
+}
+
+impl HashTree {
+    fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => sha256(&[&domain_separator("ic-hashtree-empty")]),
+            HashTree::Fork(left, right) => sha256(&[
+                &domain_separator("ic-hashtree-fork"),
+                &left.digest(),
+                &right.digest(),
+            ]),
+            HashTree::Labeled(label, subtree) => sha256(&[
+                &domain_separator("ic-hashtree-labeled"),
+                label,
+                &subtree.digest(),
+            ]),
+            HashTree::Leaf(value) => sha256(&[&domain_separator("ic-hashtree-leaf"), value]),
+            HashTree::Pruned(digest) => *digest,
+        }
+    }
+
+    fn encode_cbor(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => out.extend_from_slice(&[0x81, 0x00]),
+            HashTree::Fork(left, right) => {
+                out.extend_from_slice(&[0x83, 0x01]);
+                left.encode_cbor(out);
+                right.encode_cbor(out);
+            }
+            HashTree::Labeled(label, subtree) => {
+                out.extend_from_slice(&[0x83, 0x02]);
+                cbor_bytes(label, out);
+                subtree.encode_cbor(out);
+            }
+            HashTree::Leaf(value) => {
+                out.extend_from_slice(&[0x82, 0x03]);
+                cbor_bytes(value, out);
+            }
+            HashTree::Pruned(digest) => {
+                out.extend_from_slice(&[0x82, 0x04]);
+                cbor_bytes(digest, out);
+            }
+        }
+    }
+}
+
+/// Balanced fork tree over documents sorted by path, pruning branches without `keep`
+fn documents_tree(entries: &[(String, [u8; 32])], keep: Option<&str>) -> HashTree {
+    let tree = match entries {
+        [] => HashTree::Empty,
+        [(path, body_hash)] => HashTree::Labeled(
+            path.as_bytes().to_vec(),
+            Box::new(HashTree::Leaf(body_hash.to_vec())),
+        ),
+        _ => {
+            let (left, right) = entries.split_at(entries.len() / 2);
+            HashTree::Fork(
+                Box::new(documents_tree(left, keep)),
+                Box::new(documents_tree(right, keep)),
+            )
+        }
+    };
+    match keep {
+        Some(keep) if !entries.iter().any(|(path, _)| path == keep) => {
+            HashTree::Pruned(tree.digest())
+        }
+        _ => tree,
+    }
+}
+
+/// The whole certified tree, or a witness for one path when `keep` is set
+fn certified_tree(keep: Option<&str>) -> HashTree {
+    let entries: Vec<(String, [u8; 32])> = CERTIFIED_DOCUMENTS.with(|documents| {
+        documents
+            .borrow()
+            .iter()
+            .map(|(path, document)| (path.clone(), document.body_hash))
+            .collect()
+    });
+    HashTree::Labeled(
+        CERTIFIED_HTTP_LABEL.to_vec(),
+        Box::new(documents_tree(&entries, keep)),
+    )
+}
+
+/// Self-describing CBOR witness for one document path
+fn certified_witness(path: &str) -> Vec<u8> {
+    let mut out = vec![0xd9, 0xd9, 0xf7];
+    certified_tree(Some(path)).encode_cbor(&mut out);
+    out
+}
+
+fn certified_document_body(path: &str) -> Option<Vec<u8>> {
+    CERTIFIED_DOCUMENTS.with(|documents| {
+        documents
+            .borrow()
+            .get(path)
+            .map(|document| document.body.clone())
+    })
+}
+
+/// Rebuild a market's certified document and publish the new root hash
+fn certify_market(market_id: u64, now: u64) {
+    let path = format!("/market/{}", market_id);
+    let body = MARKETS.with(|markets| {
+        markets.borrow().get(&market_id).map(|market| {
+            market_summary_json(&build_market_summary(market, now), now)
+                .encode()
+                .into_bytes()
+        })
+    });
+    CERTIFIED_DOCUMENTS.with(|documents| {
+        let mut documents = documents.borrow_mut();
+        match body {
+            Some(body) => {
+                let body_hash = sha256(&[&body]);
+                documents.insert(path, CertifiedDocument { body, body_hash })
+            }
+            None => documents.remove(&path),
+        }
+    });
+    publish_certified_data(certified_tree(None).digest());
+}
+
+fn publish_certified_data(root_hash: [u8; 32]) {
+    // The system API only exists inside a canister; unit tests keep just the tree
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::set_certified_data(&root_hash);
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = root_hash;
+}
+
+/// Get a market's JSON document with a certificate clients can verify
+/// Only available in query calls; returns None for unknown markets
+#[ic_cdk::query]
+fn get_market_certified(market_id: u64) -> Option<CertifiedMarket> {
+    let path = format!("/market/{}", market_id);
+    let body = certified_document_body(&path)?;
+    Some(CertifiedMarket {
+        body,
+        certificate: ic_cdk::api::data_certificate()?,
+        tree: certified_witness(&path),
+    })
+}
+
+// =============================================================================
+// LLM INTEGRATION (KEEPING EXISTING FUNCTIONALITY)
+// =============================================================================
+
+/// Send a single prompt to the LLM canister
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn prompt(prompt_str: String) -> Result<String, PredictionMarketError> {
+    llm_chat(vec![ChatMessage::User {
+        content: prompt_str,
+    }])
+    .await
+}
+
+/// Send a conversation to the LLM canister and return the assistant's reply
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn chat(messages: Vec<ChatMessage>) -> Result<String, PredictionMarketError> {
+    llm_chat(messages).await
+}
+
+/// Generate AI-powered market analysis
+/// The model sees the market's reserves, price history and recent trades; when the LLM
+/// canister cannot be reached the deterministic AMM summary is returned instead
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn analyze_market(market_id: u64) -> Result<String, PredictionMarketError> {
+    let market_summary = get_market(market_id).ok_or(PredictionMarketError::MarketNotFound)?;
+    let messages = vec![
+        ChatMessage::System {
+            content: ANALYSIS_SYSTEM_PROMPT.to_string(),
+        },
+        ChatMessage::User {
+            content: market_analysis_context(&market_summary),
+        },
+    ];
+    match llm_chat(messages).await {
+        Ok(analysis) => Ok(analysis),
+        Err(_) => Ok(fallback_market_analysis(&market_summary)),
+    }
+}
+
+/// Check a market question before creating it: is it unambiguous, does it name a verifiable
+/// resolution source, does it have a clear deadline? Falls back to a keyword heuristic when
+/// the LLM canister is unavailable or its answer cannot be parsed
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn validate_market_question(title: String, description: String) -> QuestionReview {
+    let messages = vec![
+        ChatMessage::System {
+            content: QUESTION_REVIEW_PROMPT.to_string(),
+        },
+        ChatMessage::User {
+            content: format!("Question: {}\nDescription: {}", title, description),
+        },
+    ];
+    llm_chat(messages)
+        .await
+        .ok()
+        .and_then(|answer| parse_question_review(&answer))
+        .unwrap_or_else(|| heuristic_question_review(&title, &description))
+}
+
+/// Require new markets to pass the heuristic question review unless they carry structured
+/// resolution criteria (admin only); the review never calls the LLM, so creation stays synchronous
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_question_gate(enabled: bool) -> Result<String, PredictionMarketError> {
+    if !is_global_admin(caller()) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    QUESTION_GATE.with(|gate| *gate.borrow_mut() = enabled);
+    emit_event(
+        ic_cdk::api::time(),
+        EventKind::QuestionGateChanged { enabled },
+    );
+    Ok(format!(
+        "Question gate {}",
+        if enabled { "enabled" } else { "disabled" }
+    ))
+}
+
+fn ensure_question_gate(
+    title: &str,
+    description: &str,
+    has_criteria: bool,
+) -> Result<(), PredictionMarketError> {
+    if has_criteria || !QUESTION_GATE.with(|gate| *gate.borrow()) {
+        return Ok(());
+    }
+    let review = heuristic_question_review(title, description);
+    if review.passes() {
+        Ok(())
+    } else {
+        Err(PredictionMarketError::QuestionNeedsWork(review.suggestions))
+    }
+}
+
+/// Read the model's `KEY: yes|no` and `SUGGESTION:` lines; None if a verdict is missing
+fn parse_question_review(answer: &str) -> Option<QuestionReview> {
+    let (mut unambiguous, mut source, mut deadline) = (None, None, None);
+    let mut suggestions = Vec::new();
+    for line in answer.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let verdict = value.to_lowercase().starts_with("yes");
+        match key.trim().to_uppercase().as_str() {
+            "UNAMBIGUOUS" => unambiguous = Some(verdict),
+            "RESOLUTION_SOURCE" => source = Some(verdict),
+            "DEADLINE" => deadline = Some(verdict),
+            "SUGGESTION" if !value.is_empty() => suggestions.push(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(QuestionReview {
+        unambiguous: unambiguous?,
+        has_resolution_source: source?,
+        has_deadline: deadline?,
+        suggestions,
+        model_generated: true,
+    })
+}
+
+/// Deterministic keyword review used as the LLM fallback and by the creation gate
+fn heuristic_question_review(title: &str, description: &str) -> QuestionReview {
+    let text = format!("{} {}", title, description).to_lowercase();
+    let mentions = |terms: &[&str]| terms.iter().any(|term| text.contains(term));
+    let has_year = text
+        .split(|c: char| !c.is_ascii_digit())
+        .any(|digits| digits.len() == 4 && (digits.starts_with("19") || digits.starts_with("20")));
+
+    let unambiguous = title.trim_end().ends_with('?') && !mentions(&VAGUE_QUESTION_TERMS);
+    let has_resolution_source = mentions(&RESOLUTION_SOURCE_TERMS);
+    let has_deadline = has_year || mentions(&DEADLINE_TERMS);
+
+    let mut suggestions = Vec::new();
+    if !unambiguous {
+        suggestions.push(
+            "Phrase the title as a yes/no question with measurable terms instead of words like \"significant\" or \"soon\"".to_string(),
+        );
+    }
+    if !has_resolution_source {
+        suggestions.push(
+            "Name the source that settles the outcome, e.g. \"according to the official results\""
+                .to_string(),
+        );
+    }
+    if !has_deadline {
+        suggestions.push("State the date by which the event must happen".to_string());
+    }
+    QuestionReview {
+        unambiguous,
+        has_resolution_source,
+        has_deadline,
+        suggestions,
+        model_generated: false,
+    }
+}
+
+/// Call the LLM canister directly so a failed call surfaces as an error instead of a trap
+async fn llm_chat(messages: Vec<ChatMessage>) -> Result<String, PredictionMarketError> {
+    let llm_canister = Principal::from_text(LLM_CANISTER_ID).expect("valid LLM canister id");
+    let request = LlmChatRequest {
+        model: LLM_MODEL.to_string(),
+        messages,
+    };
+    let (response,): (LlmResponse,) = ic_cdk::call(llm_canister, "v1_chat", (request,))
+        .await
+        .map_err(|(_, message)| PredictionMarketError::CallFailed(message))?;
+    response
+        .message
+        .content
+        .filter(|content| !content.trim().is_empty())
+        .ok_or_else(|| PredictionMarketError::CallFailed("Empty LLM response".to_string()))
+}
+
+/// Market data handed to the model: pricing, reserves, price history and recent trades
+fn market_analysis_context(market_summary: &MarketSummary) -> String {
+    let market = &market_summary.market;
+    let mut context = format!(
+        "Market: \"{}\"\nDescription: {}\nStatus: {:?}\nCloses at (ns): {}\n\
+        YES price: {:.4} ICP, NO price: {:.4} ICP\n\
+        Reserves: {} YES / {} NO tokens, ICP pool: {} e8s\n\
+        Total volume: {} e8s, last 24h: {} e8s over {} trades by {} traders, YES price change: {:+.4}\n\
+        Price impact of a 1 ICP trade: {:.2}%\n",
+        market.title,
+        market.description,
+        market.status,
+        market.close_time,
+        market_summary.yes_price,
+        market_summary.no_price,
+        market.yes_reserve,
+        market.no_reserve,
+        market.icp_liquidity_pool,
+        market_summary.total_volume,
+        market_summary.volume_24h,
+        market_summary.trade_count_24h,
+        market_summary.unique_traders_24h,
+        market_summary.price_change_24h,
+        market_summary.price_impact,
+    );
+
+    let observations: Vec<(u64, f64)> = PRICE_OBSERVATIONS.with(|observations| {
+        observations
+            .borrow()
+            .get(&market.id)
+            .map(|points| {
+                let skip = points.len().saturating_sub(ANALYSIS_PRICE_POINTS);
+                points[skip..].to_vec()
+            })
+            .unwrap_or_default()
+    });
+    if !observations.is_empty() {
+        context.push_str("YES price history (timestamp ns, price), oldest first:\n");
+        for (timestamp, price) in observations {
+            context.push_str(&format!("- {}, {:.4}\n", timestamp, price));
+        }
+    }
+
+    let trades: Vec<TradeRecord> = TRADE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .get(&market.id)
+            .map(|trades| trades.iter().rev().take(ANALYSIS_TRADES).cloned().collect())
+            .unwrap_or_default()
+    });
+    if !trades.is_empty() {
+        context.push_str("Recent trades, newest first:\n");
+        for trade in trades {
+            context.push_str(&format!(
+                "- {:?} {} {:?} tokens for {} e8s, price after {:.4}\n",
+                trade.side,
+                trade.token_amount,
+                trade.token_type,
+                trade.icp_amount,
+                trade.price_after
+            ));
+        }
+    }
+    context
+}
+
+/// Deterministic AMM summary used when the LLM canister is unavailable
+fn fallback_market_analysis(market_summary: &MarketSummary) -> String {
+    let analysis = format!(
+        "📊 AMM Market Analysis\n\n\
+        Market: \"{}\"\n\n\
+        💰 Current Pricing (AMM-based):\n\
+        🟢 YES tokens: {:.1}% probability ({:.4} ICP each)\n\
+        🔴 NO tokens: {:.1}% probability ({:.4} ICP each)\n\n\
+        📈 Market Liquidity:\n\
+        - Total ICP pool: {}\n\
+        - YES token reserve: {} tokens\n\
+        - NO token reserve: {} tokens\n\
+        - Price impact (1 ICP trade): {:.2}%\n\n\
+        🔧 AMM Mechanics:\n\
+        This market uses a constant product formula (x * y = k) where:\n\
+        - x = YES token reserve, y = NO token reserve\n\
+        - k = constant product ({} × {} = {})\n\
+        - Price updates automatically with each trade\n\
+        - Trading fee: {}%\n\n\
+        📊 Market Sentiment:\n\
+        The current token distribution suggests traders are {}.\n\n\
+        ⚠️ Risk Considerations:\n\
+        - Higher price impact indicates lower liquidity\n\
+        - Large trades may face significant slippage\n\
+        - Market status: {:?}",
+        market_summary.market.title,
+        market_summary.yes_price * 100.0,
+        market_summary.yes_price,
+        market_summary.no_price * 100.0,
+        market_summary.no_price,
+        format_e8s(market_summary.market.icp_liquidity_pool),
+        market_summary.market.yes_reserve,
+        market_summary.market.no_reserve,
+        market_summary.price_impact,
+        market_summary.market.yes_reserve,
+        market_summary.market.no_reserve,
+        // Widened so two e8s-scale reserves cannot overflow
+        market_summary.market.yes_reserve as u128 * market_summary.market.no_reserve as u128,
+        TRADE_FEE as f64 / 10.0,
+        if market_summary.yes_price > 0.6 {
+            "more confident in a YES outcome based on trading activity"
+        } else if market_summary.no_price > 0.6 {
+            "more confident in a NO outcome based on trading activity"
+        } else {
+            "uncertain, with balanced confidence in both outcomes"
+        },
+        market_summary.market.status
+    );
+
+    analysis
+}
+
+// =============================================================================
+// LLM RESOLUTION DRAFTS
+// =============================================================================
+
+/// Ask the LLM to propose an outcome for a market past its deadline (resolver only)
+/// The proposal is stored as a draft; nothing resolves until the resolver confirms it
+#[ic_cdk::update(guard = "reject_anonymous")]
+async fn suggest_resolution(
+    market_id: u64,
+    evidence: Option<String>,
+) -> Result<ResolutionDraft, PredictionMarketError> {
+    let caller_principal = caller();
+    let market = ensure_can_draft(caller_principal, market_id, ic_cdk::api::time())?;
+    if evidence
+        .as_ref()
+        .is_some_and(|evidence| evidence.chars().count() > MAX_EVIDENCE_LEN)
+    {
+        return Err(PredictionMarketError::InvalidAmount);
+    }
+
+    let mut context = format!(
+        "Question: {}\nDescription: {}\nDeadline (ns since epoch): {}\n",
+        market.title, market.description, market.close_time
+    );
+    if let Some(criteria) = &market.resolution_criteria {
+        context.push_str(&format!("Resolution criteria: {:?}\n", criteria));
+    }
+    context.push_str(&format!(
+        "Evidence: {}",
+        evidence.as_deref().unwrap_or("none provided")
+    ));
+    let answer = llm_chat(vec![
+        ChatMessage::System {
+            content: RESOLUTION_DRAFT_PROMPT.to_string(),
+        },
+        ChatMessage::User { content: context },
+    ])
+    .await?;
+
+    // Authorization is re-checked: the market may have moved on during the call
+    let now = ic_cdk::api::time();
+    ensure_can_draft(caller_principal, market_id, now)?;
+    Ok(store_resolution_draft(
+        market_id,
+        caller_principal,
+        &answer,
+        evidence,
+        now,
+    ))
+}
+
+/// Resolve the market with the pending draft's outcome (resolver only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn confirm_resolution_draft(market_id: u64) -> Result<ResolutionDraft, PredictionMarketError> {
+    let draft = process_draft_review(caller(), market_id, true, ic_cdk::api::time())?;
+    schedule_global_timer();
+    Ok(draft)
+}
+
+/// Discard the pending draft (resolver only)
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn reject_resolution_draft(market_id: u64) -> Result<ResolutionDraft, PredictionMarketError> {
+    process_draft_review(caller(), market_id, false, ic_cdk::api::time())
+}
+
+#[ic_cdk::query]
+fn get_resolution_draft(market_id: u64) -> Option<ResolutionDraft> {
+    RESOLUTION_DRAFTS.with(|drafts| drafts.borrow().get(&market_id).cloned())
+}
+
+/// Drafts are for the market's resolver, once trading has closed and before it settles
+fn ensure_can_draft(
+    actor: Principal,
+    market_id: u64,
+    now: u64,
+) -> Result<AmmMarket, PredictionMarketError> {
+    let market = MARKETS
+        .with(|markets| markets.borrow().get(&market_id).cloned())
+        .ok_or(PredictionMarketError::MarketNotFound)?;
+    if !is_global_admin(actor) && !is_market_resolver(&market, actor) {
+        return Err(PredictionMarketError::Unauthorized);
+    }
+    if is_settled(&market.status) {
+        return Err(PredictionMarketError::MarketResolved);
+    }
+    if now < market.close_time {
+        return Err(PredictionMarketError::InvalidCloseTime);
+    }
+    Ok(market)
+}
+
+/// Parse the model's answer into a pending draft, replacing any earlier draft
+fn store_resolution_draft(
+    market_id: u64,
+    requested_by: Principal,
+    answer: &str,
+    evidence: Option<String>,
+    now: u64,
+) -> ResolutionDraft {
+    let mut outcome = None;
+    let mut reasoning = String::new();
+    for line in answer.lines() {
+        match line.split_once(':') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("OUTCOME") => {
+                outcome = match value.trim().to_uppercase().as_str() {
+                    "YES" => Some(TokenType::Yes),
+                    "NO" => Some(TokenType::No),
+                    _ => None,
+                };
+            }
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("REASONING") => {
+                reasoning = value.trim().to_string();
+            }
+            _ if !reasoning.is_empty() => {
+                reasoning.push('\n');
+                reasoning.push_str(line);
+            }
+            _ => {}
+        }
+    }
+    if reasoning.is_empty() {
+        reasoning = answer.trim().to_string();
+    }
+
+    let draft = ResolutionDraft {
+        market_id,
+        outcome: outcome.clone(),
+        reasoning,
+        evidence,
+        requested_by,
+        created_at: now,
+        status: DraftStatus::Pending,
+        reviewed_at: None,
+    };
+    RESOLUTION_DRAFTS.with(|drafts| drafts.borrow_mut().insert(market_id, draft.clone()));
+    emit_event(now, EventKind::ResolutionDrafted { market_id, outcome });
+    draft
+}
+
+fn process_draft_review(
+    actor: Principal,
+    market_id: u64,
+    confirm: bool,
+    now: u64,
+) -> Result<ResolutionDraft, PredictionMarketError> {
+    ensure_can_draft(actor, market_id, now)?;
+    let draft = RESOLUTION_DRAFTS
+        .with(|drafts| drafts.borrow().get(&market_id).cloned())
+        .filter(|draft| draft.status == DraftStatus::Pending)
+        .ok_or(PredictionMarketError::DraftNotFound)?;
+
+    let status = if confirm {
+        // An inconclusive draft has nothing to confirm
+        let outcome = draft
+            .outcome
+            .clone()
+            .ok_or(PredictionMarketError::InvalidResolutionTransition)?;
+        transition_resolution(
+            market_id,
+            current_resolution_revision(market_id),
+            ResolutionTransition::Resolve(outcome),
+            actor,
+            now,
+        )?;
+        record_resolution_evidence(
+            market_id,
+            draft
+                .evidence
+                .clone()
+                .unwrap_or_else(|| draft.reasoning.clone()),
+        );
+        DraftStatus::Confirmed
+    } else {
+        DraftStatus::Rejected
+    };
+
+    let reviewed = RESOLUTION_DRAFTS.with(|drafts| {
+        let mut drafts = drafts.borrow_mut();
+        let stored = drafts.get_mut(&market_id).expect("draft read above");
+        stored.status = status.clone();
+        stored.reviewed_at = Some(now);
+        stored.clone()
+    });
+    emit_event(
+        now,
+        EventKind::ResolutionDraftReviewed { market_id, status },
+    );
+    Ok(reviewed)
+}
+
+// =============================================================================
+// LEGACY FUNCTIONS (FOR COMPATIBILITY)
+// =============================================================================
+
+thread_local! {
+    static COUNTER: RefCell<u64> = const { RefCell::new(0) };
+}
+
+#[ic_cdk::query]
+fn greet(name: String) -> String {
+    format!("Hello, {}! Welcome to the AMM Prediction Market!", name)
+}
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn increment() -> u64 {
+    COUNTER.with(|counter| {
+        let val = *counter.borrow() + 1;
+        *counter.borrow_mut() = val;
+        val
+    })
+}
+
+#[ic_cdk::query]
+fn get_count() -> u64 {
+    COUNTER.with(|counter| *counter.borrow())
+}
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+fn set_count(value: u64) -> u64 {
+    COUNTER.with(|counter| {
+        *counter.borrow_mut() = value;
+        value
+    })
+}
+
+// =============================================================================
+// UNIT TESTS FOR AMM PREDICTION MARKET
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fixtures' reserves are a few hundred e8s deep, far below the production minimum
+    const TEST_MIN_TRADE_SIZE: u64 = 10;
+
+    // Helper function to create a test principal
+    fn test_principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    // Helper function to reset state between tests
+    fn reset_state() {
+        MARKETS.with(|m| m.borrow_mut().clear());
+        USER_POSITIONS.with(|p| p.borrow_mut().clear());
+        USER_BALANCES.with(|b| b.borrow_mut().clear());
+        REWARD_CLAIMS.with(|c| c.borrow_mut().clear());
+        LP_ACCOUNTS.with(|a| a.borrow_mut().clear());
+        COST_PROFILE.with(|p| p.borrow_mut().clear());
+        SWAP_OFFERS.with(|o| o.borrow_mut().clear());
+        NEXT_OFFER_ID.with(|id| *id.borrow_mut() = 1);
+        AIRDROP_CAMPAIGNS.with(|c| c.borrow_mut().clear());
+        AIRDROP_QUORUM.with(|q| *q.borrow_mut() = ApprovalQuorum::default());
+        AIRDROP_RECORDS.with(|r| r.borrow_mut().clear());
+        USER_REGIONS.with(|r| r.borrow_mut().clear());
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 0);
+        LIMIT_ORDERS.with(|o| o.borrow_mut().clear());
+        PLATFORM_PHASE.with(|p| *p.borrow_mut() = PlatformPhase::Active);
+        NEXT_ORDER_ID.with(|id| *id.borrow_mut() = 1);
+        PRICE_TRIGGERS.with(|t| t.borrow_mut().clear());
+        TRADE_HISTORY.with(|h| h.borrow_mut().clear());
+        ROLLING_STATS.with(|s| s.borrow_mut().clear());
+        PLATFORM_TOTALS.with(|t| *t.borrow_mut() = PlatformTotals::default());
+        LAST_TRADED_AT.with(|l| l.borrow_mut().clear());
+        USER_TRADE_INDEX.with(|i| i.borrow_mut().clear());
+        POSITION_HISTORY.with(|h| h.borrow_mut().clear());
+        NET_CONTRIBUTIONS.with(|c| c.borrow_mut().clear());
+        PENDING_ADMIN.with(|p| *p.borrow_mut() = None);
+        PAUSED.with(|p| *p.borrow_mut() = false);
+        QUESTION_GATE.with(|g| *g.borrow_mut() = false);
+        RESOLUTION_DRAFTS.with(|d| d.borrow_mut().clear());
+        COMMENTS.with(|c| c.borrow_mut().clear());
+        NEXT_COMMENT_ID.with(|id| *id.borrow_mut() = 1);
+        MODERATORS.with(|m| m.borrow_mut().clear());
+        DYNAMIC_FEE.with(|f| *f.borrow_mut() = None);
+        FEE_TIERS.with(|t| t.borrow_mut().clear());
+        USER_VOLUME.with(|v| v.borrow_mut().clear());
+        REFERRERS.with(|r| r.borrow_mut().clear());
+        REFERRAL_ACCOUNTS.with(|a| a.borrow_mut().clear());
+        REALIZED_PNL.with(|p| p.borrow_mut().clear());
+        FORECASTS.with(|f| f.borrow_mut().clear());
+        OUTCOME_TRANSFERS.with(|t| t.borrow_mut().clear());
+        POSITION_RECEIPTS.with(|r| r.borrow_mut().clear());
+        NEXT_RECEIPT_ID.with(|id| *id.borrow_mut() = 1);
+        DISPUTE_WINDOWS.with(|w| w.borrow_mut().clear());
+        RESOLUTION_VOTES.with(|v| v.borrow_mut().clear());
+        VOTE_TALLIES.with(|t| t.borrow_mut().clear());
+        DISPUTE_CONFIG.with(|c| {
+            *c.borrow_mut() = DisputeConfig {
+                window_ns: DEFAULT_DISPUTE_WINDOW_NS,
+                bond: DEFAULT_DISPUTE_BOND,
+            }
+        });
+        READ_TOKENS.with(|t| t.borrow_mut().clear());
+        EVENTS.with(|e| e.borrow_mut().clear());
+        LAST_UPGRADE.with(|l| *l.borrow_mut() = None);
+        LIFECYCLE_HOOKS.with(|h| h.borrow_mut().clear());
+        FEE_HOLIDAYS.with(|h| h.borrow_mut().clear());
+        TREASURY_LP_ACCOUNTS.with(|a| a.borrow_mut().clear());
+        EDGE_POLICIES.with(|p| {
+            *p.borrow_mut() = EdgePolicies {
+                untraded_at_close: EdgePolicy::Refund,
+                no_winning_supply: EdgePolicy::Refund,
+                extension_ns: DEFAULT_CLOSE_EXTENSION_NS,
+            }
+        });
+        LIQUIDITY_MATCHING.with(|m| {
+            *m.borrow_mut() = LiquidityMatching {
+                categories: Vec::new(),
+                match_bps: 10_000,
+                max_match: DEFAULT_LIQUIDITY_MATCH_CAP,
+            }
+        });
+        LIFECYCLE_OUTBOX.with(|o| o.borrow_mut().clear());
+        WEBHOOK_RETRIES.with(|r| r.borrow_mut().clear());
+        WEBHOOK_SECRETS.with(|s| s.borrow_mut().clear());
+        WEBHOOK_ALLOWLIST.with(|a| a.borrow_mut().clear());
+        MARKET_ORACLES.with(|o| o.borrow_mut().clear());
+        PRICE_FEEDS.with(|f| f.borrow_mut().clear());
+        CREATOR_BONDS.with(|b| b.borrow_mut().clear());
+        CREATOR_ALLOWLIST_MODE.with(|m| *m.borrow_mut() = false);
+        ALLOWED_CREATORS.with(|c| c.borrow_mut().clear());
+        CREATION_FEE.with(|f| *f.borrow_mut() = 0);
+        EXPOSURE_LIMITS.with(|l| *l.borrow_mut() = ExposureLimits::default());
+        TEXT_LIMITS.with(|l| *l.borrow_mut() = TextLimits::default());
+        CATEGORIES.with(|c| c.borrow_mut().clear());
+        FEATURED_MARKETS.with(|f| f.borrow_mut().clear());
+        PENDING_RESOLVERS.with(|p| p.borrow_mut().clear());
+        ABANDONED_MARKETS.with(|a| a.borrow_mut().clear());
+        PENDING_ACTIONS.with(|a| a.borrow_mut().clear());
+        NEXT_ACTION_ID.with(|n| *n.borrow_mut() = 1);
+        COMMIT_REVEAL_MARKETS.with(|m| m.borrow_mut().clear());
+        TRADE_COMMITMENTS.with(|c| c.borrow_mut().clear());
+        OPERATION_LOCKS.with(|l| l.borrow_mut().clear());
+        STATE_IMPORT.with(|i| *i.borrow_mut() = None);
+        SHARD_CONFIG.with(|c| *c.borrow_mut() = ShardConfig::default());
+        MARKET_SHARDS.with(|s| s.borrow_mut().clear());
+        ROUTER.with(|r| *r.borrow_mut() = None);
+        CREATOR_BOND_CONFIG.with(|c| {
+            *c.borrow_mut() = CreatorBondConfig {
+                amount: 0,
+                slash_bps: DEFAULT_CREATOR_BOND_SLASH_BPS,
+            }
+        });
+        CERTIFIED_DOCUMENTS.with(|d| d.borrow_mut().clear());
+        TRADING_LIMITS.with(|l| {
+            *l.borrow_mut() = TradingLimits {
+                min_trade_size: TEST_MIN_TRADE_SIZE,
+                min_trade_fee: DEFAULT_MIN_TRADE_FEE,
+            }
+        });
+        HOURLY_CANDLES.with(|c| c.borrow_mut().clear());
+        PRICE_OBSERVATIONS.with(|o| o.borrow_mut().clear());
+        DAILY_CANDLES.with(|c| c.borrow_mut().clear());
+        NEXT_TRADE_ID.with(|id| *id.borrow_mut() = 1);
+        BALANCE_ACTIVITY.with(|a| a.borrow_mut().clear());
+        AUTO_WITHDRAW_DAYS.with(|s| s.borrow_mut().clear());
+        NEXT_TRIGGER_ID.with(|id| *id.borrow_mut() = 1);
+        NEXT_MARKET_ID.with(|id| *id.borrow_mut() = 1);
+        ADMIN.with(|a| *a.borrow_mut() = None);
     }
 
     // Helper to set up a test market with liquidity
@@ -1221,153 +15419,5231 @@ mod tests {
         reset_state();
 
         let admin = test_principal(1);
-        let creator = test_principal(2);
+        let creator = test_principal(2);
+
+        // Set admin
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+
+        // Give creator some ICP
+        USER_BALANCES.with(|balances| {
+            balances.borrow_mut().insert(creator, 10000);
+        });
+
+        let market_id = NEXT_MARKET_ID.with(|id| {
+            let current_id = *id.borrow();
+            *id.borrow_mut() = current_id + 1;
+            current_id
+        });
+
+        let market = AmmMarket {
+            id: market_id,
+            title: "Test Market".to_string(),
+            description: "A test prediction market".to_string(),
+            yes_reserve: 500,
+            no_reserve: 500,
+            icp_liquidity_pool: 5000,
+            status: MarketStatus::Open,
+            winning_outcome: None,
+            creator,
+            admin,
+            total_fees_collected: 0,
+            creation_time: 1000000,
+            close_time: 2000000,
+            total_lp_shares: 5000,
+            acc_fee_per_share: 0,
+            creator_fee_bps: DEFAULT_CREATOR_FEE_BPS,
+            creator_fees_accrued: 0,
+            restricted_regions: Vec::new(),
+            require_attested_region: false,
+            category: Some("weather".to_string()),
+            early_exit_haircut: default_exit_haircut(),
+            resolution_criteria: None,
+            resolution: None,
+            close_extensions: 0,
+            resolution_mode: ResolutionMode::AdminOnly,
+            resolver: None,
+            resolution_source: "https://weather.example.com".to_string(),
+            resolution_evidence: None,
+            metadata: None,
+        };
+
+        MARKETS.with(|markets| {
+            markets.borrow_mut().insert(market_id, market);
+        });
+
+        // Seed liquidity mints the creator's LP shares, as in create_market
+        LP_ACCOUNTS.with(|accounts| {
+            accounts.borrow_mut().insert(
+                (creator, market_id),
+                LpAccount {
+                    shares: 5000,
+                    ..Default::default()
+                },
+            );
+        });
+
+        market_id
+    }
+
+    #[test]
+    fn test_amm_price_calculation() {
+        reset_state();
+        let market_id = setup_test_market();
+
+        // Test initial pricing - should be 0.5 for both tokens
+        let yes_price = get_token_price(market_id, TokenType::Yes).unwrap();
+        let no_price = get_token_price(market_id, TokenType::No).unwrap();
+
+        assert_eq!(yes_price, 0.5);
+        assert_eq!(no_price, 0.5);
+
+        // Test pricing with unequal reserves
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.yes_reserve = 300; // Less YES tokens
+            market.no_reserve = 700; // More NO tokens
+        });
+
+        let new_yes_price = get_token_price(market_id, TokenType::Yes).unwrap();
+        let new_no_price = get_token_price(market_id, TokenType::No).unwrap();
+
+        // YES tokens should be more expensive (higher price) due to scarcity
+        assert!(new_yes_price > 0.5);
+        assert!(new_no_price < 0.5);
+        assert!((new_yes_price + new_no_price - 1.0).abs() < 0.001); // Should sum to 1
+    }
+
+    #[test]
+    fn test_token_calculation_amm_formula() {
+        reset_state();
+        setup_test_market();
+
+        let yes_reserve = 1000u64;
+        let no_reserve = 1000u64;
+        let icp_in = 100u64;
+
+        // Test buying YES tokens
+        let tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, true).unwrap();
+
+        // Calculate expected result manually
+        let icp_after_fee = (icp_in * (1000 - TRADE_FEE)) / 1000;
+        let k = yes_reserve * no_reserve;
+        let new_no_reserve = no_reserve - icp_after_fee;
+        let expected_new_yes_reserve = k / new_no_reserve;
+        let expected_tokens_out = expected_new_yes_reserve - yes_reserve;
+
+        assert_eq!(tokens_out, expected_tokens_out);
+
+        // Test buying NO tokens
+        let no_tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, false).unwrap();
+        let new_yes_reserve = yes_reserve - icp_after_fee;
+        let expected_new_no_reserve = k / new_yes_reserve;
+        let expected_no_tokens_out = expected_new_no_reserve - no_reserve;
+
+        assert_eq!(no_tokens_out, expected_no_tokens_out);
+    }
+
+    #[test]
+    fn test_reward_distribution_calculation() {
+        reset_state();
+        let market_id = setup_test_market();
+        let user1 = test_principal(3);
+        let user2 = test_principal(4);
+        let user3 = test_principal(5);
+
+        // Set up user positions manually for testing
+        USER_POSITIONS.with(|positions| {
+            let mut positions_map = positions.borrow_mut();
+
+            // User1 has 300 YES tokens
+            positions_map.insert(
+                (user1, market_id),
+                UserPosition {
+                    user: user1,
+                    market_id,
+                    yes_tokens: 300,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            );
+
+            // User2 has 200 YES tokens
+            positions_map.insert(
+                (user2, market_id),
+                UserPosition {
+                    user: user2,
+                    market_id,
+                    yes_tokens: 200,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            );
+
+            // User3 has 100 NO tokens (will lose)
+            positions_map.insert(
+                (user3, market_id),
+                UserPosition {
+                    user: user3,
+                    market_id,
+                    yes_tokens: 0,
+                    no_tokens: 100,
+                    claimed_reward: false,
+                },
+            );
+        });
+
+        // Resolve market with YES outcome
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.status = MarketStatus::Resolved;
+            market.winning_outcome = Some(TokenType::Yes);
+        });
+
+        // Test reward calculation
+        let total_winning_tokens = 300 + 200; // Only YES tokens
+        let total_icp_pool = 5000;
+
+        // User1 should get (300/500) * 5000 = 3000 ICP
+        let expected_user1_reward = (300u128 * 5000u128 / total_winning_tokens as u128) as u64;
+
+        // User2 should get (200/500) * 5000 = 2000 ICP
+        let expected_user2_reward = (200u128 * 5000u128 / total_winning_tokens as u128) as u64;
+
+        assert_eq!(expected_user1_reward, 3000);
+        assert_eq!(expected_user2_reward, 2000);
+
+        // Verify total rewards equal the liquidity pool
+        assert_eq!(
+            expected_user1_reward + expected_user2_reward,
+            total_icp_pool
+        );
+    }
+
+    #[test]
+    fn test_double_claim_protection() {
+        reset_state();
+        let market_id = setup_test_market();
+        let user = test_principal(3);
+
+        // Set up user position
+        USER_POSITIONS.with(|positions| {
+            positions.borrow_mut().insert(
+                (user, market_id),
+                UserPosition {
+                    user,
+                    market_id,
+                    yes_tokens: 100,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            );
+        });
+
+        // Resolve market
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.status = MarketStatus::Resolved;
+            market.winning_outcome = Some(TokenType::Yes);
+        });
+
+        // Mark as already claimed
+        USER_POSITIONS.with(|positions| {
+            positions
+                .borrow_mut()
+                .get_mut(&(user, market_id))
+                .unwrap()
+                .claimed_reward = true;
+        });
+
+        // Test that double claim is detected
+        let (_, already_claimed) = USER_POSITIONS.with(|positions| {
+            let positions_map = positions.borrow();
+            if let Some(position) = positions_map.get(&(user, market_id)) {
+                (position.yes_tokens, position.claimed_reward)
+            } else {
+                (0, false)
+            }
+        });
+
+        assert!(
+            already_claimed,
+            "Double claim protection should detect already claimed reward"
+        );
+    }
+
+    #[test]
+    fn test_trading_fees_collection() {
+        reset_state();
+        setup_test_market();
+
+        let yes_reserve = 1000u64;
+        let no_reserve = 1000u64;
+        let icp_in = 1000u64; // Large trade to make fee calculation clear
+
+        // Calculate expected fee
+        let expected_fee = (icp_in * TRADE_FEE) / 1000;
+        let icp_after_fee = icp_in - expected_fee;
+
+        // Calculate tokens using AMM formula
+        let tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, true).unwrap();
+
+        // Verify fee calculation in the formula
+        let k = yes_reserve * no_reserve;
+        let new_no_reserve = no_reserve - icp_after_fee;
+        let new_yes_reserve = k / new_no_reserve;
+        let expected_tokens = new_yes_reserve - yes_reserve;
+
+        assert_eq!(tokens_out, expected_tokens);
+        assert_eq!(expected_fee, 3); // 0.3% of 1000 = 3
+    }
+
+    #[test]
+    fn test_insufficient_liquidity_scenarios() {
+        reset_state();
+        setup_test_market();
+
+        let yes_reserve = 100u64;
+        let no_reserve = 100u64;
+        let large_icp_in = 150u64; // Larger than available reserve
+
+        // This should fail because it would drain more than available reserve
+        let result = calculate_tokens_out(yes_reserve, no_reserve, large_icp_in, true);
+        assert!(result.is_err(), "Should fail due to insufficient liquidity");
+
+        // Test zero reserves
+        let zero_result = calculate_tokens_out(0, 100, 50, true);
+        assert!(zero_result.is_err(), "Should fail with zero reserves");
+    }
+
+    #[test]
+    fn test_market_freezes_at_close_time() {
+        reset_state();
+        let market_id = setup_test_market();
+
+        // Before the deadline the market keeps trading
+        assert!(freeze_expired_markets(1999999).is_empty());
+        MARKETS.with(|markets| {
+            let markets_map = markets.borrow();
+            let market = markets_map.get(&market_id).unwrap();
+            assert!(is_trading_open(market, 1999999));
+            assert!(!is_trading_open(market, 2000000));
+        });
+        assert_eq!(next_market_close_time(), Some(2000000));
+
+        // Once the deadline passes the market is frozen and no longer scheduled
+        assert_eq!(freeze_expired_markets(2000000), vec![market_id]);
+        MARKETS.with(|markets| {
+            let markets_map = markets.borrow();
+            let market = markets_map.get(&market_id).unwrap();
+            assert!(matches!(market.status, MarketStatus::Frozen));
+            assert!(!is_trading_open(market, 0));
+        });
+        assert_eq!(next_market_close_time(), None);
+    }
+
+    #[test]
+    fn test_lp_share_accounting() {
+        // First provider receives shares 1:1
+        assert_eq!(calculate_lp_shares(5000, 0, 0), 5000);
+
+        // Later providers receive shares proportional to their contribution
+        assert_eq!(calculate_lp_shares(2500, 5000, 5000), 2500);
+        assert_eq!(calculate_lp_shares(1000, 10000, 5000), 500);
+
+        // Deepening reserves proportionally keeps the price unchanged
+        let (yes_reserve, no_reserve) = (300u64, 700u64);
+        let new_yes = scale_by(yes_reserve, 7500, 5000);
+        let new_no = scale_by(no_reserve, 7500, 5000);
+        assert_eq!((new_yes, new_no), (450, 1050));
+        let old_price = no_reserve as f64 / (yes_reserve + no_reserve) as f64;
+        let new_price = new_no as f64 / (new_yes + new_no) as f64;
+        assert!((old_price - new_price).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_lp_fee_accumulator_splits_by_share_and_entry_time() {
+        reset_state();
+        let market_id = setup_test_market();
+
+        let mut early = LpAccount {
+            shares: 5000,
+            ..Default::default()
+        };
+
+        // 100 ICP of fees with a single provider all belong to them
+        MARKETS.with(|markets| {
+            accrue_lp_fees(markets.borrow_mut().get_mut(&market_id).unwrap(), 100);
+        });
+        let acc = MARKETS.with(|m| m.borrow().get(&market_id).unwrap().acc_fee_per_share);
+
+        // A second provider joins with the same number of shares
+        let mut late = LpAccount {
+            shares: 5000,
+            fee_debt: accrued_fees(5000, acc),
+            unclaimed_fees: 0,
+        };
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.total_lp_shares += 5000;
+            accrue_lp_fees(market, 100);
+        });
+        let acc = MARKETS.with(|m| m.borrow().get(&market_id).unwrap().acc_fee_per_share);
+
+        // The early provider earns all of the first batch and half of the second
+        settle_lp_fees(&mut early, acc);
+        settle_lp_fees(&mut late, acc);
+        assert_eq!(early.unclaimed_fees, 150);
+        assert_eq!(late.unclaimed_fees, 50);
+
+        // Settling again without new fees does not double count
+        settle_lp_fees(&mut early, acc);
+        assert_eq!(early.unclaimed_fees, 150);
+    }
+
+    #[test]
+    fn test_trade_fee_split_between_treasury_creator_and_lps() {
+        reset_state();
+        let market_id = setup_test_market();
+
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.creator_fee_bps = 1_000; // 10% of the fee
+            distribute_trade_fee(market, 300, test_principal(3));
+
+            assert_eq!(market.creator_fees_accrued, 30);
+            // The remaining 240 is spread over the 5000 LP shares
+            assert_eq!(accrued_fees(5000, market.acc_fee_per_share), 240);
+        });
+        assert_eq!(get_treasury_balance(), 30);
+    }
+
+    #[test]
+    fn test_treasury_withdrawal_pays_out_only_what_it_holds() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let recipient = test_principal(4);
+        MARKETS.with(|m| {
+            distribute_trade_fee(
+                m.borrow_mut().get_mut(&market_id).unwrap(),
+                5_000,
+                recipient,
+            )
+        });
+        assert_eq!(get_treasury_balance(), 500);
+
+        assert!(matches!(
+            process_withdraw_treasury(recipient, 100, recipient, 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            process_withdraw_treasury(admin, 0, recipient, 10),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        assert!(matches!(
+            process_withdraw_treasury(admin, 501, recipient, 10),
+            Err(PredictionMarketError::InsufficientLiquidity { max_trade: 500 })
+        ));
+        assert_eq!(get_treasury_balance(), 500);
+        assert!(USER_BALANCES.with(|b| b.borrow().get(&recipient).is_none()));
+
+        process_withdraw_treasury(admin, 200, recipient, 10).unwrap();
+        process_withdraw_treasury(admin, 300, recipient, 20).unwrap();
+        assert_eq!(get_treasury_balance(), 0);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&recipient]), 500);
+        assert!(EVENTS.with(|e| e.borrow().iter().any(|event| matches!(
+            event.kind,
+            EventKind::TreasuryWithdrawn { amount: 300, to } if to == recipient
+        ))));
+    }
+
+    #[test]
+    fn test_region_restrictions() {
+        reset_state();
+        let market_id = setup_test_market();
+        let us_user = test_principal(3);
+        let fr_user = test_principal(4);
+        let unattested = test_principal(5);
+
+        assert_eq!(normalize_region(" us ").unwrap(), "US");
+        assert!(normalize_region("USA").is_err());
+
+        USER_REGIONS.with(|r| {
+            r.borrow_mut().insert(us_user, "US".to_string());
+            r.borrow_mut().insert(fr_user, "FR".to_string());
+        });
+
+        MARKETS.with(|markets| {
+            let mut markets_map = markets.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+
+            // Unrestricted market lets everyone trade
+            assert!(check_region_allowed(market, unattested).is_ok());
+
+            market.restricted_regions = vec!["US".to_string()];
+            assert!(check_region_allowed(market, us_user).is_err());
+            assert!(check_region_allowed(market, fr_user).is_ok());
+            assert!(check_region_allowed(market, unattested).is_ok());
+
+            market.require_attested_region = true;
+            assert!(check_region_allowed(market, unattested).is_err());
+        });
+    }
+
+    #[test]
+    fn test_limit_order_trigger_direction() {
+        // Buy orders wait for the price to fall to the limit
+        assert!(limit_order_triggered(&OrderSide::Buy, 0.4, 0.4));
+        assert!(limit_order_triggered(&OrderSide::Buy, 0.4, 0.35));
+        assert!(!limit_order_triggered(&OrderSide::Buy, 0.4, 0.5));
+
+        // Sell orders wait for the price to rise to the limit
+        assert!(limit_order_triggered(&OrderSide::Sell, 0.6, 0.6));
+        assert!(limit_order_triggered(&OrderSide::Sell, 0.6, 0.7));
+        assert!(!limit_order_triggered(&OrderSide::Sell, 0.6, 0.5));
+    }
+
+    #[test]
+    fn test_resting_orders_never_fill_past_their_limit() {
+        let market_id = setup_test_market();
+        let order = |side, limit_price, amount| LimitOrder {
+            id: 1,
+            owner: test_principal(3),
+            market_id,
+            token_type: TokenType::Yes,
+            side,
+            limit_price,
+            amount,
+            status: OrderStatus::Open,
+            created_at: 0,
+        };
+        let set_reserves = |yes_reserve, no_reserve| {
+            MARKETS.with(|m| {
+                let mut markets = m.borrow_mut();
+                let market = markets.get_mut(&market_id).unwrap();
+                market.yes_reserve = yes_reserve;
+                market.no_reserve = no_reserve;
+            })
+        };
+        let tokens_out = || {
+            compute_buy_quote(market_id, 20, TokenType::Yes, 1_500_000)
+                .unwrap()
+                .tokens_received
+        };
+        let buy = order(OrderSide::Buy, 0.7, 20);
+        assert_eq!(limit_order_min_out(&buy), 28);
+
+        // At YES 0.4 the order fires and its fill honours the limit
+        set_reserves(6_000, 4_000);
+        let price = get_token_price(market_id, TokenType::Yes).unwrap();
+        assert!(limit_order_triggered(&buy.side, buy.limit_price, price));
+        assert!(tokens_out() >= limit_order_min_out(&buy));
+
+        // A large trade moves YES to 0.55: the order still fires, but the fill would average
+        // past the limit, so execute_trade refuses it and the order keeps resting
+        set_reserves(4_500, 5_500);
+        let price = get_token_price(market_id, TokenType::Yes).unwrap();
+        assert!(limit_order_triggered(&buy.side, buy.limit_price, price));
+        assert!(tokens_out() < limit_order_min_out(&buy));
+
+        // Sell orders need at least tokens * limit ICP
+        assert_eq!(limit_order_min_out(&order(OrderSide::Sell, 0.55, 300)), 165);
+
+        let trigger = |action| PriceTrigger {
+            id: 1,
+            owner: test_principal(3),
+            market_id,
+            token_type: TokenType::Yes,
+            trigger_price: 0.5,
+            action,
+            status: TriggerStatus::Active,
+            created_at: 0,
+        };
+        assert_eq!(
+            trigger_min_out(&trigger(TriggerAction::TakeProfit), 1_000),
+            500
+        );
+        assert_eq!(
+            trigger_min_out(&trigger(TriggerAction::StopLoss), 1_000),
+            475
+        );
+    }
+
+    #[test]
+    fn test_paginate_offset_windows() {
+        let items: Vec<u64> = (0..10).collect();
+
+        let page = paginate_offset(items.clone(), 0, 4);
+        assert_eq!(page.items, vec![0, 1, 2, 3]);
+        assert_eq!(page.next_cursor, Some(4));
+
+        let page = paginate_offset(items.clone(), 8, 4);
+        assert_eq!(page.items, vec![8, 9]);
+        assert!(!page.truncated);
+        assert_eq!(page.next_cursor, None);
+
+        assert!(paginate_offset(items.clone(), 20, 4).items.is_empty());
+
+        // Limits above the cap are clamped
+        let many: Vec<u64> = (0..(MAX_LIST_RESULTS as u64 + 5)).collect();
+        let page = paginate_offset(many, 0, u64::MAX);
+        assert_eq!(page.items.len(), MAX_LIST_RESULTS);
+        assert_eq!(page.next_cursor, Some(MAX_LIST_RESULTS as u64));
+    }
+
+    #[test]
+    fn test_simulate_resolution_matches_claim_formula() {
+        let market_id = setup_test_market();
+        let alice = test_principal(3);
+        let bob = test_principal(4);
+        for (user, yes_tokens, no_tokens) in [(alice, 300, 0), (bob, 100, 50)] {
+            USER_POSITIONS.with(|positions| {
+                positions.borrow_mut().insert(
+                    (user, market_id),
+                    UserPosition {
+                        user,
+                        market_id,
+                        yes_tokens,
+                        no_tokens,
+                        claimed_reward: false,
+                    },
+                );
+            });
+        }
+        let pool = MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool);
+
+        let yes = build_resolution_simulation(alice, market_id, TokenType::Yes).unwrap();
+        assert_eq!(yes.total_winning_tokens, 400);
+        assert_eq!(yes.winner_count, 2);
+        assert_eq!(yes.caller_winning_tokens, 300);
+        assert_eq!(yes.caller_payout, pool * 3 / 4);
+
+        let no = build_resolution_simulation(alice, market_id, TokenType::No).unwrap();
+        assert_eq!(no.winner_count, 1);
+        assert_eq!(no.caller_payout, 0);
+
+        // Wrapped tokens count on both sides of the division, as they do when claiming
+        process_wrap_position(bob, market_id, TokenType::Yes, 60, 1_500_000).unwrap();
+        process_wrap_position(alice, market_id, TokenType::Yes, 100, 1_500_000).unwrap();
+        let yes = build_resolution_simulation(alice, market_id, TokenType::Yes).unwrap();
+        assert_eq!(yes.total_winning_tokens, 400);
+        assert_eq!(yes.winner_count, 2);
+        assert_eq!(yes.caller_winning_tokens, 300);
+        assert_eq!(yes.caller_payout, pool * 3 / 4);
+
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::Yes),
+            test_principal(1),
+            10,
+        )
+        .unwrap();
+        close_dispute_windows(10 + DEFAULT_DISPUTE_WINDOW_NS);
+        let claim = process_reward_claim(alice, market_id, 10 + DEFAULT_DISPUTE_WINDOW_NS).unwrap();
+        assert_eq!(claim.reward_amount, yes.caller_payout);
+
+        MARKETS
+            .with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Resolved);
+        assert!(matches!(
+            build_resolution_simulation(alice, market_id, TokenType::Yes),
+            Err(PredictionMarketError::MarketResolved)
+        ));
+    }
+
+    #[test]
+    fn test_idle_balances_swept_only_after_opt_in_deadline() {
+        reset_state();
+        let opted_in = test_principal(3);
+        let not_opted_in = test_principal(4);
+        for user in [opted_in, not_opted_in] {
+            USER_BALANCES.with(|b| b.borrow_mut().insert(user, 5_000));
+            touch_balance_activity(user, 0);
+        }
+        AUTO_WITHDRAW_DAYS.with(|s| s.borrow_mut().insert(opted_in, 30));
+
+        assert_eq!(next_auto_withdraw_time(), Some(30 * NANOS_PER_DAY));
+        assert!(sweep_idle_balances(30 * NANOS_PER_DAY - 1).is_empty());
+
+        let swept = sweep_idle_balances(30 * NANOS_PER_DAY);
+        assert_eq!(swept, vec![(opted_in, 5_000)]);
+        assert_eq!(get_balance_of(opted_in), 0);
+        assert_eq!(get_balance_of(not_opted_in), 5_000);
+
+        // Nothing left to sweep, so the timer no longer needs to wake for it
+        assert_eq!(next_auto_withdraw_time(), None);
+    }
+
+    #[test]
+    fn test_market_filter() {
+        let market_id = setup_test_market();
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+
+        assert!(market_matches_filter(&market, &MarketFilter::default()));
+        assert!(market_matches_filter(
+            &market,
+            &MarketFilter {
+                category: Some(" Weather ".to_string()),
+                creator: Some(test_principal(2)),
+                open_only: true,
+                ..Default::default()
+            }
+        ));
+        assert!(!market_matches_filter(
+            &market,
+            &MarketFilter {
+                category: Some("sports".to_string()),
+                ..Default::default()
+            }
+        ));
+        assert!(!market_matches_filter(
+            &market,
+            &MarketFilter {
+                creator: Some(test_principal(3)),
+                ..Default::default()
+            }
+        ));
+        assert!(!market_matches_filter(
+            &market,
+            &MarketFilter {
+                resolved_only: true,
+                ..Default::default()
+            }
+        ));
+        assert!(market_matches_filter(
+            &market,
+            &MarketFilter {
+                status: Some(MarketStatus::Open),
+                ..Default::default()
+            }
+        ));
+
+        assert_eq!(normalize_category(Some("  ".to_string())), None);
+    }
+
+    #[test]
+    fn test_bulk_quotes_keep_order_and_report_errors_per_entry() {
+        let market_id = setup_test_market();
+        let request = |market_id, side| QuoteRequest {
+            market_id,
+            token_type: TokenType::Yes,
+            side,
+            amount: 100,
+        };
+
+        let quotes = compute_quotes_bulk(
+            vec![
+                request(market_id, OrderSide::Buy),
+                request(999, OrderSide::Buy),
+                request(market_id, OrderSide::Sell),
+            ],
+            0,
+        )
+        .unwrap();
+        assert_eq!(quotes.len(), 3);
+        assert_eq!(
+            quotes[0].as_ref().unwrap().tokens_received,
+            compute_buy_quote(market_id, 100, TokenType::Yes, 0)
+                .unwrap()
+                .tokens_received
+        );
+        assert!(matches!(
+            quotes[1],
+            Err(PredictionMarketError::MarketNotFound)
+        ));
+        assert_eq!(quotes[2].as_ref().unwrap().tokens_paid, 100);
+
+        // A batch at the cap is quoted in full; one past it is refused as a whole
+        let at_cap = vec![request(market_id, OrderSide::Buy); MAX_BULK_QUOTES];
+        let quotes = compute_quotes_bulk(at_cap, 0).unwrap();
+        assert_eq!(quotes.len(), MAX_BULK_QUOTES);
+        assert!(quotes.iter().all(Result::is_ok));
+        let too_many = vec![request(market_id, OrderSide::Buy); MAX_BULK_QUOTES + 1];
+        assert!(matches!(
+            compute_quotes_bulk(too_many, 0),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+    }
+
+    #[test]
+    fn test_market_search_matches_all_terms_case_insensitively() {
+        let market_id = setup_test_market();
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        let terms = |query: &str| -> Vec<String> {
+            query.split_whitespace().map(str::to_lowercase).collect()
+        };
+
+        assert!(market_matches_search(&market, &terms("TEST")));
+        assert!(market_matches_search(&market, &terms("test prediction")));
+        assert!(!market_matches_search(&market, &terms("test election")));
+        assert!(market_matches_search(&market, &terms("")));
+    }
+
+    #[test]
+    fn test_self_test_reports_broken_invariants() {
+        let market_id = setup_test_market();
+
+        let report = build_self_test_report(0);
+        assert!(report.passed, "{:?}", report.checks);
+
+        // Drift the LP share total and let the market run past its close time
+        MARKETS.with(|m| m.borrow_mut().get_mut(&market_id).unwrap().total_lp_shares += 1);
+        let close_time = MARKETS.with(|m| m.borrow()[&market_id].close_time);
+
+        let report = build_self_test_report(close_time);
+        assert!(!report.passed);
+        let failed: Vec<&str> = report
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.name.as_str())
+            .collect();
+        assert_eq!(failed, vec!["lp_shares", "close_timer"]);
+    }
+
+    fn test_trade(
+        market_id: u64,
+        trader: Principal,
+        side: OrderSide,
+        timestamp: u64,
+    ) -> TradeRecord {
+        TradeRecord {
+            id: next_trade_id(),
+            market_id,
+            trader,
+            side,
+            token_type: TokenType::Yes,
+            icp_amount: 100,
+            token_amount: 150,
+            fee: 0,
+            price_after: 0.5,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_trade_history_newest_first_per_market() {
+        reset_state();
+        let trader = test_principal(3);
+        record_trade(test_trade(1, trader, OrderSide::Buy, 10));
+        record_trade(test_trade(2, trader, OrderSide::Buy, 20));
+        record_trade(test_trade(1, trader, OrderSide::Sell, 30));
+
+        let page = get_trade_history(1, 0, 10);
+        let ids: Vec<u64> = page.items.iter().map(|trade| trade.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+        assert_eq!(page.items[0].side, OrderSide::Sell);
+
+        let page = get_trade_history(1, 1, 1);
+        assert_eq!(page.items[0].id, 1);
+        assert!(page.next_cursor.is_none());
+
+        assert!(get_trade_history(99, 0, 10).items.is_empty());
+    }
+
+    #[test]
+    fn test_user_trades_indexed_across_markets() {
+        reset_state();
+        let alice = test_principal(3);
+        let bob = test_principal(4);
+        record_trade(test_trade(1, alice, OrderSide::Buy, 10));
+        record_trade(test_trade(2, bob, OrderSide::Buy, 20));
+        record_trade(test_trade(2, alice, OrderSide::Buy, 30));
+        record_trade(test_trade(1, alice, OrderSide::Sell, 40));
+
+        let page = trades_of(alice, 0, 10);
+        let ids: Vec<u64> = page.items.iter().map(|trade| trade.id).collect();
+        assert_eq!(ids, vec![4, 3, 1]);
+        assert!(page.items.iter().all(|trade| trade.trader == alice));
+
+        let page = trades_of(alice, 0, 2);
+        assert_eq!(page.next_cursor, Some(2));
+        assert_eq!(trades_of(bob, 0, 10).items.len(), 1);
+    }
+
+    #[test]
+    fn test_position_history_tracks_average_cost_basis() {
+        reset_state();
+        let trader = test_principal(3);
+        let set_position = |yes_tokens| {
+            USER_POSITIONS.with(|positions| {
+                positions.borrow_mut().insert(
+                    (trader, 1),
+                    UserPosition {
+                        user: trader,
+                        market_id: 1,
+                        yes_tokens,
+                        no_tokens: 0,
+                        claimed_reward: false,
+                    },
+                );
+            })
+        };
+
+        // Buy 150 YES for 100 ICP, then another 150 for 200 ICP
+        set_position(150);
+        record_trade(test_trade(1, trader, OrderSide::Buy, 10));
+        set_position(300);
+        let mut second = test_trade(1, trader, OrderSide::Buy, 20);
+        second.icp_amount = 200;
+        record_trade(second);
+
+        // Sell half: half the cost basis is released
+        set_position(150);
+        record_trade(test_trade(1, trader, OrderSide::Sell, 30));
+
+        let history = POSITION_HISTORY.with(|h| h.borrow()[&(trader, 1)].clone());
+        let basis: Vec<(u64, u64)> = history
+            .iter()
+            .map(|snapshot| (snapshot.yes_tokens, snapshot.yes_cost_basis))
+            .collect();
+        assert_eq!(basis, vec![(150, 100), (300, 300), (150, 150)]);
+        assert!(history.iter().all(|snapshot| snapshot.no_cost_basis == 0));
+    }
+
+    #[test]
+    fn test_read_token_scopes_expiry_and_revocation() {
+        reset_state();
+        let owner = test_principal(3);
+        READ_TOKENS.with(|tokens| {
+            tokens.borrow_mut().insert(
+                "secret".to_string(),
+                ReadToken {
+                    id: 1,
+                    owner,
+                    label: None,
+                    scopes: vec![ReadScope::Positions],
+                    created_at: 0,
+                    expires_at: Some(100),
+                    revoked: false,
+                },
+            );
+        });
+
+        assert_eq!(
+            authorize_read_token("secret", ReadScope::Positions, 50).unwrap(),
+            owner
+        );
+        assert!(matches!(
+            authorize_read_token("secret", ReadScope::Trades, 50),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            authorize_read_token("secret", ReadScope::Positions, 100),
+            Err(PredictionMarketError::InvalidReadToken)
+        ));
+        assert!(matches!(
+            authorize_read_token("guess", ReadScope::Positions, 50),
+            Err(PredictionMarketError::InvalidReadToken)
+        ));
+
+        READ_TOKENS.with(|t| t.borrow_mut().get_mut("secret").unwrap().revoked = true);
+        assert!(authorize_read_token("secret", ReadScope::Positions, 50).is_err());
+    }
+
+    #[test]
+    fn test_price_candles_roll_up_by_hour_and_day() {
+        reset_state();
+        let trader = test_principal(3);
+        let hour = NANOS_PER_HOUR;
+        for (timestamp, price) in [(0, 0.5), (hour / 2, 0.7), (hour, 0.4), (2 * hour, 0.6)] {
+            let mut trade = test_trade(1, trader, OrderSide::Buy, timestamp);
+            trade.price_after = price;
+            record_trade(trade);
+        }
+
+        let hourly = get_price_history(1, CandleResolution::Hour, 0, 3 * hour);
+        let ohlc: Vec<(u64, f64, f64, f64, f64)> = hourly
+            .items
+            .iter()
+            .map(|c| (c.start, c.open, c.high, c.low, c.close))
+            .collect();
+        assert_eq!(
+            ohlc,
+            vec![
+                (0, 0.5, 0.7, 0.5, 0.7),
+                (hour, 0.4, 0.4, 0.4, 0.4),
+                (2 * hour, 0.6, 0.6, 0.6, 0.6)
+            ]
+        );
+        assert_eq!(hourly.items[0].volume, 200);
+
+        // The range is half-open on `to`
+        assert_eq!(
+            get_price_history(1, CandleResolution::Hour, hour, 2 * hour)
+                .items
+                .len(),
+            1
+        );
+
+        let daily = get_price_history(1, CandleResolution::Day, 0, NANOS_PER_DAY);
+        assert_eq!(daily.items.len(), 1);
+        let day = &daily.items[0];
+        assert_eq!(
+            (day.open, day.high, day.low, day.close),
+            (0.5, 0.7, 0.4, 0.6)
+        );
+        assert_eq!(day.trades, 4);
+    }
+
+    #[test]
+    fn test_time_weighted_average_price() {
+        let observations = [(100, 0.7), (300, 0.3)];
+
+        // 0.5 for 100ns, 0.7 for 200ns, 0.3 for 100ns
+        let twap = time_weighted_average(&observations, 0.5, 0, 400);
+        assert!((twap - (0.5 * 100.0 + 0.7 * 200.0 + 0.3 * 100.0) / 400.0).abs() < 1e-9);
+
+        // A window starting mid-step uses the price in force at its start
+        let twap = time_weighted_average(&observations, 0.5, 200, 400);
+        assert!((twap - 0.5).abs() < 1e-9);
+
+        // A single spike barely moves a long window
+        let twap = time_weighted_average(&[(999, 0.99)], 0.5, 0, 1_000);
+        assert!(twap < 0.51);
+
+        assert_eq!(time_weighted_average(&observations, 0.5, 350, 350), 0.3);
+    }
+
+    #[test]
+    fn test_rolling_stats_expire_after_24_hours() {
+        reset_state();
+        let trader = test_principal(3);
+        let hour = NANOS_PER_HOUR;
+
+        let mut first = test_trade(1, trader, OrderSide::Buy, 0);
+        first.price_after = 0.6;
+        record_trade(first);
+        let mut second = test_trade(1, trader, OrderSide::Buy, 5 * hour);
+        second.token_type = TokenType::No;
+        second.price_after = 0.3; // YES at 0.7
+        record_trade(second);
+
+        let (volume, trades, change) = rolling_stats_24h(1, 5 * hour);
+        assert_eq!((volume, trades), (200, 2));
+        assert!((change - 0.2).abs() < 1e-9);
+        assert_eq!(unique_traders_24h(1, 5 * hour), 1);
+
+        // The first trade's hour has left the window, and its ring slot gets recycled
+        let (volume, trades, change) = rolling_stats_24h(1, 24 * hour);
+        assert_eq!((volume, trades), (100, 1));
+        assert!((change - 0.1).abs() < 1e-9);
+
+        record_trade(test_trade(1, trader, OrderSide::Sell, 24 * hour));
+        record_trade(test_trade(1, test_principal(4), OrderSide::Buy, 24 * hour));
+        let (volume, trades, _) = rolling_stats_24h(1, 24 * hour);
+        assert_eq!((volume, trades), (300, 3));
+        assert_eq!(unique_traders_24h(1, 24 * hour), 2);
+
+        assert_eq!(rolling_stats_24h(1, 60 * hour), (0, 0, 0.0));
+        assert_eq!(unique_traders_24h(1, 60 * hour), 0);
+        // Lifetime volume never expires
+        assert_eq!(total_traded_volume(1), 400);
+    }
+
+    #[test]
+    fn test_market_summary_counts_each_recent_trader_once() {
+        let market_id = setup_test_market();
+        let hour = NANOS_PER_HOUR;
+        let (alice, bob, carol) = (test_principal(3), test_principal(4), test_principal(5));
+        let summary = || {
+            let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+            build_market_summary(&market, 30 * hour)
+        };
+
+        // An untraded market reports no volume, even though its pool holds the seed
+        let untraded = summary();
+        assert_eq!((untraded.total_volume, untraded.unique_traders_24h), (0, 0));
+
+        record_trade(test_trade(market_id, carol, OrderSide::Buy, 2 * hour));
+        record_trade(test_trade(market_id, alice, OrderSide::Buy, 10 * hour));
+        record_trade(test_trade(market_id, alice, OrderSide::Sell, 20 * hour));
+        record_trade(test_trade(market_id, bob, OrderSide::Buy, 29 * hour));
+        // Other markets' traders are not counted here
+        record_trade(test_trade(market_id + 1, carol, OrderSide::Buy, 29 * hour));
+
+        let summary = summary();
+        // Carol traded here only before the window; Alice's two trades count once
+        assert_eq!(summary.unique_traders_24h, 2);
+        assert_eq!(summary.trade_count_24h, 3);
+        assert_eq!(summary.total_volume, 400);
+        assert_ne!(summary.total_volume, summary.market.icp_liquidity_pool);
+    }
+
+    #[test]
+    fn test_platform_stats() {
+        let market_id = setup_test_market();
+        let second = market_id + 1;
+        MARKETS.with(|markets| {
+            let mut markets = markets.borrow_mut();
+            let mut market = markets[&market_id].clone();
+            market.id = second;
+            market.icp_liquidity_pool = 9_000;
+            markets.insert(second, market);
+            let mut resolved = markets[&market_id].clone();
+            resolved.id = second + 1;
+            resolved.status = MarketStatus::Resolved;
+            markets.insert(second + 1, resolved);
+        });
+        let day = NANOS_PER_DAY;
+        let mut paid = test_trade(market_id, test_principal(3), OrderSide::Buy, day);
+        paid.fee = 3;
+        record_trade(paid);
+        record_trade(test_trade(
+            second,
+            test_principal(4),
+            OrderSide::Buy,
+            5 * day,
+        ));
+        record_trade(test_trade(
+            second,
+            test_principal(4),
+            OrderSide::Sell,
+            8 * day,
+        ));
+
+        let stats = build_platform_stats(8 * day);
+        assert_eq!(stats.markets.open, 2);
+        assert_eq!(stats.markets.resolved, 1);
+        assert_eq!(
+            (stats.total_volume, stats.total_fees, stats.total_trades),
+            (300, 3, 3)
+        );
+        assert_eq!(stats.total_value_locked, 14_000);
+        // The first trader was last seen seven days ago, just inside the week
+        assert_eq!((stats.active_users_24h, stats.active_users_7d), (1, 2));
+        let largest: Vec<u64> = stats
+            .largest_markets
+            .iter()
+            .map(|listing| listing.id)
+            .collect();
+        assert_eq!(largest, vec![second, market_id]);
+    }
+
+    #[test]
+    fn test_early_exit_haircut_goes_to_remaining_lps() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        let leaver = test_principal(3);
+        let close_time = MARKETS.with(|m| m.borrow()[&market_id].close_time);
+
+        // The leaver owns half the pool and both LPs have earned 1000 in fees
+        MARKETS.with(|m| {
+            let mut markets_map = m.borrow_mut();
+            let market = markets_map.get_mut(&market_id).unwrap();
+            market.total_lp_shares = 10_000;
+            market.icp_liquidity_pool = 10_000;
+            accrue_lp_fees(market, 2_000);
+        });
+        LP_ACCOUNTS.with(|a| {
+            a.borrow_mut().insert(
+                (leaver, market_id),
+                LpAccount {
+                    shares: 5_000,
+                    ..Default::default()
+                },
+            )
+        });
+
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(exit_haircut_bps(&market, 0), 5_000); // close is under a day away
+        assert_eq!(exit_haircut_bps(&market, close_time), 5_000);
+
+        // Withdrawing half the shares haircuts half of the 1000 accrued by 50%
+        let icp_out = process_remove_liquidity(leaver, market_id, 2_500, 0).unwrap();
+        assert_eq!(icp_out, 2_500);
+
+        let acc = MARKETS.with(|m| m.borrow()[&market_id].acc_fee_per_share);
+        let mut leaver_account = LP_ACCOUNTS.with(|a| a.borrow()[&(leaver, market_id)].clone());
+        let mut creator_account = LP_ACCOUNTS.with(|a| a.borrow()[&(creator, market_id)].clone());
+        settle_lp_fees(&mut leaver_account, acc);
+        settle_lp_fees(&mut creator_account, acc);
+        assert_eq!(leaver_account.unclaimed_fees, 750);
+        assert_eq!(creator_account.unclaimed_fees, 1_000 + 250 * 5_000 / 7_500);
+
+        // Far from close no tier applies
+        MARKETS
+            .with(|m| m.borrow_mut().get_mut(&market_id).unwrap().close_time = 30 * NANOS_PER_DAY);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(exit_haircut_bps(&market, 0), 0);
+        assert_eq!(exit_haircut_bps(&market, 25 * NANOS_PER_DAY), 2_000);
+    }
+
+    #[test]
+    fn test_resolution_criteria_validation_and_evaluation() {
+        let criteria = ResolutionCriteria {
+            source_url: "https://api.example.com/btc".to_string(),
+            metric: "BTC/USD close".to_string(),
+            comparator: Comparator::Above,
+            threshold: 100_000.0,
+            timezone: "UTC".to_string(),
+            tiebreak: TokenType::No,
+        };
+        assert!(validate_resolution_criteria(&criteria).is_ok());
+
+        assert_eq!(
+            evaluate_resolution_criteria(&criteria, 100_001.0),
+            TokenType::Yes
+        );
+        assert_eq!(
+            evaluate_resolution_criteria(&criteria, 99_999.0),
+            TokenType::No
+        );
+        assert_eq!(
+            evaluate_resolution_criteria(&criteria, 100_000.0),
+            TokenType::No
+        );
+
+        let below = ResolutionCriteria {
+            comparator: Comparator::Below,
+            tiebreak: TokenType::Yes,
+            ..criteria.clone()
+        };
+        assert_eq!(evaluate_resolution_criteria(&below, 1.0), TokenType::Yes);
+        assert_eq!(
+            evaluate_resolution_criteria(&below, 100_000.0),
+            TokenType::Yes
+        );
+
+        for invalid in [
+            ResolutionCriteria {
+                source_url: "http://api.example.com".to_string(),
+                ..criteria.clone()
+            },
+            ResolutionCriteria {
+                source_url: "https://".to_string(),
+                ..criteria.clone()
+            },
+            ResolutionCriteria {
+                metric: " ".to_string(),
+                ..criteria.clone()
+            },
+            ResolutionCriteria {
+                threshold: f64::NAN,
+                ..criteria.clone()
+            },
+        ] {
+            assert!(validate_resolution_criteria(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_event_log_ids_and_paging() {
+        let market_id = setup_test_market();
+        let trader = test_principal(3);
+        // A held position keeps the close an ordinary freeze rather than an untraded-market settlement
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut().insert(
+                (trader, market_id),
+                UserPosition {
+                    user: trader,
+                    market_id,
+                    yes_tokens: 10,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            )
+        });
+
+        freeze_expired_markets(2_000_000);
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 10));
+        emit_event(
+            20,
+            EventKind::Deposited {
+                user: trader,
+                amount: 1_000,
+            },
+        );
+
+        let page = get_events(1, 10);
+        let ids: Vec<u64> = page.items.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(page.items[0].kind, EventKind::MarketFrozen { market_id });
+        assert!(matches!(
+            page.items[1].kind,
+            EventKind::TradeExecuted { trade_id: 1, .. }
+        ));
+
+        let page = get_events(2, 1);
+        assert_eq!(page.items[0].id, 2);
+        assert_eq!(page.next_cursor, Some(3));
+        assert!(get_events(4, 10).items.is_empty());
+    }
+
+    #[test]
+    fn test_resolution_transitions_compare_and_set() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let stranger = test_principal(9);
+
+        assert!(matches!(
+            transition_resolution(
+                market_id,
+                0,
+                ResolutionTransition::Propose(TokenType::Yes),
+                stranger,
+                10
+            ),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        let proposed = transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Propose(TokenType::Yes),
+            admin,
+            10,
+        )
+        .unwrap();
+        assert_eq!(proposed.revision, 1);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Frozen);
+        assert_eq!(market.winning_outcome, None);
+
+        // A second path that read revision 0 cannot finalize a different outcome
+        assert!(matches!(
+            transition_resolution(
+                market_id,
+                0,
+                ResolutionTransition::Resolve(TokenType::No),
+                admin,
+                11
+            ),
+            Err(PredictionMarketError::ResolutionConflict)
+        ));
+
+        transition_resolution(market_id, 1, ResolutionTransition::Challenge, admin, 12).unwrap();
+        assert!(matches!(
+            transition_resolution(market_id, 2, ResolutionTransition::Finalize, admin, 13),
+            Err(PredictionMarketError::InvalidResolutionTransition)
+        ));
+        transition_resolution(
+            market_id,
+            2,
+            ResolutionTransition::Propose(TokenType::No),
+            admin,
+            14,
+        )
+        .unwrap();
+        let settled =
+            transition_resolution(market_id, 3, ResolutionTransition::Finalize, admin, 15).unwrap();
+        assert_eq!(settled.phase, ResolutionPhase::Final);
+
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Resolved);
+        assert_eq!(market.winning_outcome, Some(TokenType::No));
+        assert!(matches!(
+            transition_resolution(market_id, 4, ResolutionTransition::Challenge, admin, 16),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+    }
+
+    #[test]
+    fn test_http_gateway_routes() {
+        let market_id = setup_test_market();
+        MARKETS.with(|m| {
+            m.borrow_mut().get_mut(&market_id).unwrap().title = "Rain \"today\"?".to_string()
+        });
+
+        let response = handle_http_get("/markets?limit=1", 0);
+        assert_eq!(response.status_code, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.starts_with("{\"items\":[{\"id\":1,\"title\":\"Rain \\\"today\\\"?\""));
+        assert!(body.ends_with("\"next_offset\":null}"));
+
+        let response = handle_http_get(&format!("/market/{}", market_id), 0);
+        assert_eq!(response.status_code, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("\"yes_price\":0.5"));
+        assert!(body.contains("\"winning_outcome\":null"));
+
+        assert_eq!(handle_http_get("/market/99", 0).status_code, 404);
+        assert_eq!(handle_http_get("/market/abc", 0).status_code, 400);
+        assert_eq!(handle_http_get("/unknown", 0).status_code, 404);
+    }
+
+    #[test]
+    fn test_certified_witness_matches_root() {
+        let first = setup_test_market();
+        let second = first + 1;
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = AmmMarket {
+                id: second,
+                ..markets[&first].clone()
+            };
+            markets.insert(second, market);
+        });
+        certify_market(first, 0);
+        let root_one = certified_tree(None).digest();
+        certify_market(second, 0);
+        let root = certified_tree(None).digest();
+        assert_ne!(root, root_one);
+
+        // A witness prunes the other market but still hashes to the published root
+        assert_eq!(certified_tree(Some("/market/1")).digest(), root);
+        assert_eq!(certified_tree(Some("/market/2")).digest(), root);
+        let witness = certified_witness("/market/1");
+        assert_eq!(&witness[..3], &[0xd9, 0xd9, 0xf7]);
+
+        // Served bodies are the certified ones and change with the market
+        let body = certified_document_body("/market/1").unwrap();
+        assert_eq!(handle_http_get("/market/1", 99).body, body);
+        MARKETS.with(|m| m.borrow_mut().get_mut(&first).unwrap().yes_reserve += 1);
+        emit_event(5, EventKind::MarketFrozen { market_id: first });
+        assert_ne!(certified_tree(None).digest(), root);
+    }
+
+    #[test]
+    fn test_trade_fee_floor_and_minimum_size() {
+        reset_state();
+        assert_eq!(trade_fee(1_000), 3);
+        assert_eq!(trade_fee(500), 2); // 1.5 rounds up
+        assert_eq!(trade_fee(50), DEFAULT_MIN_TRADE_FEE);
+
+        TRADING_LIMITS.with(|limits| {
+            *limits.borrow_mut() = TradingLimits {
+                min_trade_size: 200,
+                min_trade_fee: 5,
+            }
+        });
+        assert_eq!(trade_fee(1_000), 5);
+        assert!(matches!(
+            calculate_tokens_out(1_000, 1_000, 199, true),
+            Err(PredictionMarketError::TradeBelowMinimum)
+        ));
+        let tokens_out = calculate_tokens_out(1_000, 1_000, 200, true).unwrap();
+        assert_eq!(tokens_out, 1_000 * 1_000 / (1_000 - 195) - 1_000);
+
+        // Sells are measured by the ICP the pool releases
+        let gross = calculate_icp_out(1_000, 1_000, 100, true).unwrap();
+        assert!(gross < 200);
+        assert!(check_min_trade_size(gross).is_err());
+    }
+
+    #[test]
+    fn test_price_band_round_trip() {
+        reset_state();
+        for (yes_reserve, no_reserve) in [(1_000, 1_000), (400, 2_500), (50_000, 9_000)] {
+            for size in [10, 100, 350] {
+                for token_type in [TokenType::Yes, TokenType::No] {
+                    let check = check_price_band(yes_reserve, no_reserve, size, token_type.clone())
+                        .unwrap();
+                    assert!(
+                        check.within_band,
+                        "{:?} size {} on ({}, {}): {:?}",
+                        token_type, size, yes_reserve, no_reserve, check
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_metrics_exposition() {
+        let market_id = setup_test_market();
+        record_trade(test_trade(market_id, test_principal(3), OrderSide::Buy, 10));
+        record_trade(test_trade(
+            market_id,
+            test_principal(4),
+            OrderSide::Sell,
+            20,
+        ));
+
+        let body = render_metrics(7_000_000_000_000, 65_536);
+        assert!(body.contains("# TYPE prediction_market_trades_total counter\n"));
+        assert!(body.contains("\nprediction_market_markets_total 1\n"));
+        assert!(body.contains("\nprediction_market_markets_open 1\n"));
+        assert!(body.contains("\nprediction_market_trades_total 2\n"));
+        assert!(body.contains("\nprediction_market_trade_volume_icp_total 200\n"));
+        assert!(body.contains("\nprediction_market_cycles_balance 7000000000000\n"));
+        assert!(body.ends_with("prediction_market_heap_memory_bytes 65536\n"));
+    }
+
+    #[test]
+    fn test_canister_stats_counts() {
+        let market_id = setup_test_market();
+        record_trade(test_trade(market_id, test_principal(3), OrderSide::Buy, 10));
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut().insert(
+                (test_principal(3), market_id),
+                UserPosition {
+                    user: test_principal(3),
+                    market_id,
+                    yes_tokens: 150,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            )
+        });
+
+        let stats = build_canister_stats(1_000, 2_000, 0);
+        assert_eq!(stats.cycles_balance, 1_000);
+        assert_eq!(stats.heap_memory_bytes, 2_000);
+        assert_eq!(stats.market_count, 1);
+        assert_eq!(stats.position_count, 1);
+        assert_eq!(stats.claim_count, 0);
+        assert_eq!(stats.trade_count, 1);
+        assert_eq!(stats.event_count, 1);
+        assert_eq!(stats.last_upgrade, None);
+    }
+
+    #[test]
+    fn test_lifecycle_hooks_queue_registered_stages() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        let target = CallbackTarget::Https {
+            url: "https://example.com/hooks".to_string(),
+        };
+
+        assert!(matches!(
+            register_lifecycle_hook(
+                test_principal(9),
+                market_id,
+                Some(target.clone()),
+                vec![LifecycleStage::Frozen]
+            ),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            register_lifecycle_hook(
+                creator,
+                market_id,
+                Some(CallbackTarget::Https {
+                    url: "http://example.com".to_string()
+                }),
+                vec![LifecycleStage::Frozen]
+            ),
+            Err(PredictionMarketError::InvalidCallbackTarget)
+        ));
+        register_lifecycle_hook(
+            creator,
+            market_id,
+            Some(target),
+            vec![
+                LifecycleStage::FirstTrade,
+                LifecycleStage::NearClose,
+                LifecycleStage::Frozen,
+            ],
+        )
+        .unwrap();
+
+        // Only the first trade notifies
+        record_trade(test_trade(market_id, test_principal(3), OrderSide::Buy, 10));
+        record_trade(test_trade(market_id, test_principal(3), OrderSide::Buy, 20));
+
+        // Created at 1_000_000 and closing at 2_000_000, so NearClose is due at 1_800_000
+        assert_eq!(next_near_close_time(), Some(1_800_000));
+        notify_near_close(1_799_999);
+        notify_near_close(1_800_000);
+        notify_near_close(1_900_000);
+        assert_eq!(next_near_close_time(), None);
+
+        freeze_expired_markets(2_000_000);
+        emit_event(
+            2_000_001,
+            EventKind::MarketResolved {
+                market_id,
+                outcome: TokenType::Yes,
+            },
+        );
+
+        let stages: Vec<LifecycleStage> = LIFECYCLE_OUTBOX.with(|o| {
+            o.borrow()
+                .iter()
+                .map(|(_, notification)| notification.stage.clone())
+                .collect()
+        });
+        assert_eq!(
+            stages,
+            vec![
+                LifecycleStage::FirstTrade,
+                LifecycleStage::NearClose,
+                LifecycleStage::Frozen
+            ]
+        );
+    }
+
+    #[test]
+    fn test_amm_math_near_u64_limits() {
+        reset_state();
+        // Reserves near 2^32 each used to overflow k = yes * no in u64
+        let reserve = 1u64 << 33;
+        let tokens_out = calculate_tokens_out(reserve, reserve, 1_000_000, true).unwrap();
+        assert!(tokens_out > 0);
+        let icp_out = calculate_icp_out(reserve, reserve, 1_000_000, true).unwrap();
+        assert!(icp_out > 0);
+
+        // A result beyond u64 is reported instead of trapping
+        assert!(matches!(
+            constant_product_reserve(u64::MAX, u64::MAX, 2),
+            Err(PredictionMarketError::Overflow)
+        ));
+        assert!(matches!(
+            calculate_tokens_out(u64::MAX, u64::MAX / 2, u64::MAX / 4, true),
+            Err(PredictionMarketError::Overflow)
+        ));
+        assert!(matches!(
+            add_or_overflow(u64::MAX, 1),
+            Err(PredictionMarketError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_format_e8s() {
+        assert_eq!(format_e8s(0), "0 ICP");
+        assert_eq!(format_e8s(E8S_PER_ICP), "1 ICP");
+        assert_eq!(format_e8s(150_000_000), "1.5 ICP");
+        assert_eq!(format_e8s(MIN_DEPOSIT), "0.01 ICP");
+        assert_eq!(format_e8s(1), "0.00000001 ICP");
+    }
+
+    #[test]
+    fn test_fee_holiday_waives_fee_in_window() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let start = 1_500_000;
+        let end = 1_600_000;
+
+        assert!(matches!(
+            add_fee_holiday(
+                test_principal(3),
+                FeeHolidayScope::Market(market_id),
+                start,
+                end,
+                false,
+                1_000_000
+            ),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            add_fee_holiday(
+                admin,
+                FeeHolidayScope::Market(market_id),
+                end,
+                start,
+                false,
+                1_000_000
+            ),
+            Err(PredictionMarketError::InvalidFeeHoliday)
+        ));
+        add_fee_holiday(
+            admin,
+            FeeHolidayScope::Category(" Weather ".to_string()),
+            start,
+            end,
+            true,
+            1_000_000,
+        )
+        .unwrap();
+
+        // Before the window the normal fee applies and no holiday is reported
+        let before = compute_buy_quote(market_id, 100, TokenType::Yes, start - 1).unwrap();
+        assert_eq!(before.fee_paid, trade_fee(100));
+        assert_eq!(before.fee_holiday_until, None);
+
+        // During the window the fee is waived, which buys more tokens
+        let during = compute_buy_quote(market_id, 100, TokenType::Yes, start).unwrap();
+        assert_eq!(during.fee_paid, 0);
+        assert_eq!(during.fee_holiday_until, Some(end));
+        assert!(during.tokens_received > before.tokens_received);
+
+        // The end time is exclusive
+        let after = compute_buy_quote(market_id, 100, TokenType::Yes, end).unwrap();
+        assert_eq!(after.fee_holiday_until, None);
+    }
+
+    #[test]
+    fn test_fee_holiday_reimburses_lps_from_treasury() {
+        let market_id = setup_test_market();
+        let holiday_id = add_fee_holiday(
+            test_principal(1),
+            FeeHolidayScope::Market(market_id),
+            1_500_000,
+            1_600_000,
+            true,
+            1_000_000,
+        )
+        .unwrap();
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 1_000);
+
+        MARKETS.with(|markets| {
+            let mut markets = markets.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            let holiday = active_fee_holiday(market, 1_500_000);
+            let acc_before = market.acc_fee_per_share;
+            reimburse_waived_fee(market, holiday.as_ref(), 100_000);
+            assert!(market.acc_fee_per_share > acc_before);
+        });
+
+        // 300 fee waived: 10% protocol and 1% creator shares are forgone, LPs get the rest
+        let waived = trade_fee(100_000);
+        let lp_cut =
+            waived - waived * PROTOCOL_FEE_BPS / 10_000 - waived * DEFAULT_CREATOR_FEE_BPS / 10_000;
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 1_000 - lp_cut);
+        let holiday = FEE_HOLIDAYS.with(|h| h.borrow()[&holiday_id].clone());
+        assert_eq!(holiday.reimbursed, lp_cut);
+    }
+
+    #[test]
+    fn test_liquidity_matching_seeds_treasury_position() {
+        let market_id = setup_test_market();
+        let mut market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        market.id = market_id + 1;
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 3_000);
+
+        // Categories that are not approved get no match
+        assert_eq!(apply_liquidity_match(&mut market.clone()), 0);
+
+        LIQUIDITY_MATCHING.with(|m| {
+            *m.borrow_mut() = LiquidityMatching {
+                categories: vec!["weather".to_string()],
+                match_bps: 10_000,
+                max_match: 2_000,
+            }
+        });
+        let (yes_price, _) = market_prices(&market);
+        let matched = apply_liquidity_match(&mut market);
+
+        // 1:1 on a 5000 seed, capped at 2000
+        assert_eq!(matched, 2_000);
+        assert_eq!(market.icp_liquidity_pool, 7_000);
+        assert_eq!(market.total_lp_shares, 7_000);
+        assert_eq!(market_prices(&market).0, yes_price);
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 1_000);
+        let shares = TREASURY_LP_ACCOUNTS.with(|a| a.borrow()[&market.id].shares);
+        assert_eq!(shares, 2_000);
+    }
+
+    #[test]
+    fn test_treasury_liquidity_settlement() {
+        let market_id = setup_test_market();
+        LIQUIDITY_MATCHING.with(|m| m.borrow_mut().categories = vec!["weather".to_string()]);
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 5_000);
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            apply_liquidity_match(market);
+            distribute_trade_fee(market, 1_000, test_principal(3));
+        });
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 100); // 10% protocol cut of the fee
+        let report = build_self_test_report(0);
+        assert!(report.passed, "{:?}", report.checks);
+
+        // After close only the fees come back; the matched capital stays in the pool
+        let fees = process_treasury_settlement(market_id, 2_000_000).unwrap();
+        assert!(fees > 0);
+        let pool = MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool);
+        assert_eq!(pool, 10_000);
+        assert!(matches!(
+            process_treasury_settlement(market_id, 2_000_000),
+            Err(PredictionMarketError::NothingToClaim)
+        ));
+
+        // While trading is open the matched capital is withdrawn as well
+        MARKETS.with(|m| {
+            distribute_trade_fee(
+                m.borrow_mut().get_mut(&market_id).unwrap(),
+                1_000,
+                test_principal(3),
+            )
+        });
+        let returned = process_treasury_settlement(market_id, 1_500_000).unwrap();
+        assert!(returned > 5_000);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.icp_liquidity_pool, 5_000);
+        assert_eq!(market.total_lp_shares, 5_000);
+        assert!(TREASURY_LP_ACCOUNTS.with(|a| a.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_mint_and_redeem_complete_sets() {
+        let market_id = setup_test_market();
+        let trader = test_principal(3);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(trader, 1_000));
+
+        let position = process_mint_complete_set(trader, market_id, 400, 1_500_000).unwrap();
+        assert_eq!((position.yes_tokens, position.no_tokens), (400, 400));
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&trader]), 600);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            5_400
+        );
+
+        // Minting needs an open market and enough balance
+        assert!(matches!(
+            process_mint_complete_set(trader, market_id, 100, 2_000_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_mint_complete_set(trader, market_id, 601, 1_500_000),
+            Err(PredictionMarketError::InsufficientDeposit {
+                required: 601,
+                available: 600
+            })
+        ));
+
+        // Only matched pairs can be redeemed
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut()
+                .get_mut(&(trader, market_id))
+                .unwrap()
+                .no_tokens = 250
+        });
+        assert!(matches!(
+            process_redeem_complete_set(trader, market_id, 251, 1_500_000),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+
+        // Redeeming still works after trading closes, at parity and without a fee
+        let position = process_redeem_complete_set(trader, market_id, 250, 2_000_000).unwrap();
+        assert_eq!((position.yes_tokens, position.no_tokens), (150, 0));
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&trader]), 850);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            5_150
+        );
+
+        MARKETS
+            .with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Resolved);
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut()
+                .get_mut(&(trader, market_id))
+                .unwrap()
+                .no_tokens = 150
+        });
+        assert!(matches!(
+            process_redeem_complete_set(trader, market_id, 150, 2_000_000),
+            Err(PredictionMarketError::MarketResolved)
+        ));
+    }
+
+    #[test]
+    fn test_untraded_market_extends_then_refunds() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        EDGE_POLICIES.with(|p| p.borrow_mut().untraded_at_close = EdgePolicy::ExtendMarket);
+
+        // Each close with reserves still 50/50 and no holders pushes the deadline back
+        let mut close_time = 2_000_000;
+        for extension in 1..=MAX_CLOSE_EXTENSIONS {
+            assert!(freeze_expired_markets(close_time).is_empty());
+            let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+            assert_eq!(market.close_extensions, extension);
+            assert_eq!(market.close_time, close_time + DEFAULT_CLOSE_EXTENSION_NS);
+            assert!(is_trading_open(&market, close_time));
+            close_time = market.close_time;
+        }
+
+        // Out of extensions, the pool goes back to the LPs
+        assert_eq!(freeze_expired_markets(close_time), vec![market_id]);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Frozen);
+        assert_eq!(market.icp_liquidity_pool, 0);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 15_000);
+    }
+
+    #[test]
+    fn test_untraded_market_treasury_sweep() {
+        let market_id = setup_test_market();
+        EDGE_POLICIES.with(|p| p.borrow_mut().untraded_at_close = EdgePolicy::TreasurySweep);
+
+        assert_eq!(freeze_expired_markets(2_000_000), vec![market_id]);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            0
+        );
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 5_000);
+        assert!(EVENTS.with(|e| e.borrow().iter().any(|event| event.kind
+            == EventKind::DegeneratePoolSettled {
+                market_id,
+                policy: EdgePolicy::TreasurySweep,
+                amount: 5_000,
+            })));
+    }
+
+    #[test]
+    fn test_resolution_without_winning_supply_settles_pool() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        let trader = test_principal(3);
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut().insert(
+                (trader, market_id),
+                UserPosition {
+                    user: trader,
+                    market_id,
+                    yes_tokens: 0,
+                    no_tokens: 40,
+                    claimed_reward: false,
+                },
+            )
+        });
+
+        // Only NO is held, so a YES outcome leaves nobody to pay; the default policy refunds LPs
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::Yes),
+            admin,
+            10,
+        )
+        .unwrap();
+        // Settled once the outcome can no longer be disputed
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            5_000
+        );
+        let window_end = 10 + DEFAULT_DISPUTE_WINDOW_NS;
+        assert_eq!(close_dispute_windows(window_end), vec![market_id]);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            0
+        );
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 15_000);
+        assert!(matches!(
+            process_reward_claim(trader, market_id, window_end),
+            Err(PredictionMarketError::NoWinningTokens)
+        ));
+    }
+
+    #[test]
+    fn test_quotes_describe_the_fill() {
+        let market_id = setup_test_market();
+        let quote = compute_buy_quote(market_id, 100, TokenType::No, 1_500_000).unwrap();
+        assert_eq!(quote.market_id, market_id);
+        assert_eq!(quote.token_type, TokenType::No);
+        assert_eq!(quote.timestamp, 1_500_000);
+        assert!(quote.position.is_none());
+        assert!((quote.average_price - 100.0 / quote.tokens_received as f64).abs() < 1e-12);
+        // NO starts at 0.5, so the impact is the relative move from there
+        let expected_impact = ((quote.new_price - 0.5) / 0.5 * 100.0).abs();
+        assert!((quote.price_impact - expected_impact).abs() < 1e-9);
+        assert!(quote.price_impact > 0.0);
+    }
+
+    #[test]
+    fn test_slippage_floor_from_spot_price() {
+        let market_id = setup_test_market();
+        // Both sides start at 0.5, so 100 ICP buys 200 tokens at spot
+        assert_eq!(
+            slippage_floor(market_id, &OrderSide::Buy, &TokenType::Yes, 100, 100).unwrap(),
+            198
+        );
+        assert_eq!(
+            slippage_floor(market_id, &OrderSide::Sell, &TokenType::No, 200, 50).unwrap(),
+            99
+        );
+        assert!(matches!(
+            slippage_floor(market_id, &OrderSide::Buy, &TokenType::Yes, 100, 10_001),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        // A 1% tolerance is tighter than the AMM's own impact and fee on this pool
+        let quote = compute_buy_quote(market_id, 100, TokenType::Yes, 1_500_000).unwrap();
+        let floor = slippage_floor(market_id, &OrderSide::Buy, &TokenType::Yes, 100, 100).unwrap();
+        assert!(quote.tokens_received < floor);
+    }
+
+    #[test]
+    fn test_errors_carry_the_amounts_involved() {
+        let market_id = setup_test_market();
+        let trader = test_principal(3);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(trader, 40));
+
+        let new_market = NewMarket {
+            title: "Rain tomorrow?".to_string(),
+            description: "Details".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT - 1,
+            close_time: 3_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+        assert!(matches!(
+            process_create_market(test_principal(2), new_market, 1_000_000),
+            Err(PredictionMarketError::InsufficientDeposit { required, available })
+                if required == MIN_DEPOSIT && available == MIN_DEPOSIT - 1
+        ));
+        assert!(matches!(
+            execute_buy_trade(trader, market_id, 100, 0, TokenType::Yes),
+            Err(PredictionMarketError::InsufficientDeposit {
+                required: 100,
+                available: 40
+            })
+        ));
+
+        assert!(check_min_out(150, 150).is_ok());
+        assert!(matches!(
+            check_min_out(150, 149),
+            Err(PredictionMarketError::SlippageExceeded {
+                min_out: 150,
+                actual_out: 149
+            })
+        ));
+
+        // A buy can take out at most all but one token of the side it draws from
+        assert!(matches!(
+            calculate_tokens_out(500, 500, 1_000, true),
+            Err(PredictionMarketError::InsufficientLiquidity { max_trade: 499 })
+        ));
+        assert!(matches!(
+            calculate_icp_out(0, 500, 10, true),
+            Err(PredictionMarketError::InsufficientLiquidity { max_trade: 0 })
+        ));
+        assert_eq!(largest_fitting(100, |amount| amount <= 37), 37);
+        assert_eq!(largest_fitting(100, |_| false), 0);
+        assert_eq!(largest_fitting(100, |_| true), 100);
+    }
+
+    #[test]
+    fn test_unified_trade_routes_by_side() {
+        let market_id = setup_test_market();
+        let trader = test_principal(3);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(trader, 50));
+
+        // A buy spends ICP, so it fails on the balance; a sell spends tokens the trader lacks
+        assert!(matches!(
+            execute_trade(trader, market_id, OrderSide::Buy, TokenType::No, 100, 0),
+            Err(PredictionMarketError::InsufficientDeposit {
+                required: 100,
+                available: 50
+            })
+        ));
+        assert!(matches!(
+            execute_trade(trader, market_id, OrderSide::Sell, TokenType::No, 100, 0),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        for side in [OrderSide::Buy, OrderSide::Sell] {
+            assert!(matches!(
+                execute_trade(trader, market_id, side, TokenType::Yes, 0, 0),
+                Err(PredictionMarketError::InvalidAmount)
+            ));
+        }
+
+        // Both sides stop while paused, and neither bypasses commit-reveal
+        PAUSED.with(|p| *p.borrow_mut() = true);
+        for side in [OrderSide::Buy, OrderSide::Sell] {
+            assert!(matches!(
+                execute_trade(trader, market_id, side, TokenType::Yes, 20, 0),
+                Err(PredictionMarketError::Paused)
+            ));
+        }
+        PAUSED.with(|p| *p.borrow_mut() = false);
+        assert!(ensure_direct_trading(market_id).is_ok());
+        COMMIT_REVEAL_MARKETS.with(|m| m.borrow_mut().insert(market_id));
+        assert!(matches!(
+            ensure_direct_trading(market_id),
+            Err(PredictionMarketError::CommitRevealRequired)
+        ));
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&trader]), 50);
+    }
+
+    #[test]
+    fn test_sell_cannot_overdraw_pool() {
+        let market_id = setup_test_market();
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .icp_liquidity_pool = 50
+        });
+
+        // The AMM would release more than the pool holds, and says how much it can take
+        let Err(PredictionMarketError::InsufficientLiquidity { max_trade }) =
+            compute_sell_quote(market_id, 100, TokenType::Yes, 1_500_000)
+        else {
+            panic!("oversized sell was quoted");
+        };
+        assert!(compute_sell_quote(market_id, max_trade, TokenType::Yes, 1_500_000).is_ok());
+        assert!(compute_sell_quote(market_id, max_trade + 1, TokenType::Yes, 1_500_000).is_err());
+    }
+
+    #[test]
+    fn test_exact_output_buy_is_minimal() {
+        reset_state();
+        for (yes_reserve, no_reserve) in [(500, 500), (800, 300), (10_000, 25_000)] {
+            for buy_yes in [true, false] {
+                for wanted in [1, 7, 50, 120] {
+                    let icp_in =
+                        calculate_icp_in(yes_reserve, no_reserve, wanted, buy_yes, trade_fee)
+                            .unwrap();
+                    let got =
+                        calculate_tokens_out(yes_reserve, no_reserve, icp_in, buy_yes).unwrap();
+                    assert!(got >= wanted);
+                    // One e8 less would fall short, unless the minimum trade size set the amount
+                    if icp_in > TEST_MIN_TRADE_SIZE {
+                        let short =
+                            calculate_tokens_out(yes_reserve, no_reserve, icp_in - 1, buy_yes);
+                        assert!(short.map_or(true, |tokens| tokens < wanted));
+                    }
+                }
+            }
+        }
+
+        // Without a fee the inverse needs less ICP
+        let with_fee = calculate_icp_in(500, 500, 100, true, trade_fee).unwrap();
+        let fee_free = calculate_icp_in(500, 500, 100, true, |_| 0).unwrap();
+        assert!(fee_free < with_fee);
+
+        // More tokens than the curve can give out
+        assert!(matches!(
+            calculate_icp_in(500, 500, u64::MAX - 500, true, trade_fee),
+            Err(PredictionMarketError::InsufficientLiquidity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_claim_all_rewards() {
+        let first = setup_test_market();
+        let user = test_principal(3);
+        let other = test_principal(4);
+        let template = MARKETS.with(|m| m.borrow()[&first].clone());
+        for market_id in [first + 1, first + 2, first + 3] {
+            MARKETS.with(|m| {
+                m.borrow_mut().insert(
+                    market_id,
+                    AmmMarket {
+                        id: market_id,
+                        ..template.clone()
+                    },
+                )
+            });
+        }
+        for market_id in [first, first + 1, first + 2] {
+            MARKETS.with(|m| {
+                let mut markets = m.borrow_mut();
+                let market = markets.get_mut(&market_id).unwrap();
+                market.status = MarketStatus::Resolved;
+                market.winning_outcome = Some(TokenType::Yes);
+            });
+        }
+        let position = |market_id, yes_tokens, no_tokens| UserPosition {
+            user,
+            market_id,
+            yes_tokens,
+            no_tokens,
+            claimed_reward: false,
+        };
+        USER_POSITIONS.with(|p| {
+            let mut positions = p.borrow_mut();
+            positions.insert((user, first), position(first, 100, 0));
+            positions.insert((user, first + 1), position(first + 1, 0, 100)); // Lost
+            positions.insert((user, first + 2), position(first + 2, 100, 0));
+            positions.insert((user, first + 3), position(first + 3, 100, 0)); // Still open
+            positions.insert(
+                (other, first + 2),
+                UserPosition {
+                    user: other,
+                    ..position(first + 2, 100, 0)
+                },
+            );
+        });
+
+        let result = process_claim_all_rewards(user, 10);
+        let claimed: Vec<(u64, u64)> = result
+            .claims
+            .iter()
+            .map(|claim| (claim.market_id, claim.reward_amount))
+            .collect();
+        assert_eq!(claimed, vec![(first, 5_000), (first + 2, 2_500)]);
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].market_id, first + 1);
+        assert!(matches!(
+            result.skipped[0].reason,
+            PredictionMarketError::NoWinningTokens
+        ));
+        assert!(!result.truncated);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&user]), 7_500);
+
+        // Claimed markets are not revisited
+        let again = process_claim_all_rewards(user, 20);
+        assert!(again.claims.is_empty());
+        assert_eq!(again.skipped.len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_market_refunds_contributions_pro_rata() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        let minter = test_principal(3);
+        let trader = test_principal(4);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(minter, 1_000));
+        process_mint_complete_set(minter, market_id, 1_000, 1_100_000).unwrap();
+        // Bought twice and sold once: 100 e8s net
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 1_200_000));
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 1_300_000));
+        record_trade(test_trade(market_id, trader, OrderSide::Sell, 1_400_000));
+
+        assert!(matches!(
+            process_invalidation(market_id, trader, 1_500_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            process_refund_claim(minter, market_id, 1_500_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+
+        // Contributions are covered in full; the other 4,900 goes back to the creator's LP shares
+        assert_eq!(
+            process_invalidation(market_id, admin, 1_500_000).unwrap(),
+            1_100
+        );
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Invalid);
+        assert_eq!(market.icp_liquidity_pool, 1_100);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 14_900);
+        assert!(matches!(
+            process_invalidation(market_id, admin, 1_600_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_redeem_complete_set(minter, market_id, 1_000, 1_600_000),
+            Err(PredictionMarketError::MarketResolved)
+        ));
+
+        let claim = process_refund_claim(minter, market_id, 1_600_000).unwrap();
+        assert_eq!((claim.contributed, claim.refund_amount), (1_000, 1_000));
+        let position = USER_POSITIONS.with(|p| p.borrow()[&(minter, market_id)].clone());
+        assert_eq!((position.yes_tokens, position.no_tokens), (0, 0));
+        assert!(matches!(
+            process_refund_claim(minter, market_id, 1_700_000),
+            Err(PredictionMarketError::NothingToClaim)
+        ));
+        assert_eq!(
+            process_refund_claim(trader, market_id, 1_700_000)
+                .unwrap()
+                .refund_amount,
+            100
+        );
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            0
+        );
+    }
+
+    #[test]
+    fn test_invalid_market_refunds_share_a_short_pool() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (first, second) = (test_principal(3), test_principal(4));
+        adjust_contribution(first, market_id, 3_000, 0);
+        adjust_contribution(second, market_id, 1_000, 0);
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .icp_liquidity_pool = 2_000
+        });
+
+        assert_eq!(
+            process_invalidation(market_id, admin, 1_500_000).unwrap(),
+            2_000
+        );
+        assert_eq!(
+            process_refund_claim(second, market_id, 1_600_000)
+                .unwrap()
+                .refund_amount,
+            500
+        );
+        assert_eq!(
+            process_refund_claim(first, market_id, 1_600_000)
+                .unwrap()
+                .refund_amount,
+            1_500
+        );
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow().get(&first).copied()),
+            Some(1_500)
+        );
+    }
+
+    #[test]
+    fn test_cancel_market_refunds_traders_and_seed() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        let trader = test_principal(3);
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 1_200_000));
+        // The buy added 100 less a 10 e8s fee to the pool
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .icp_liquidity_pool = 5_090
+        });
+
+        assert!(matches!(
+            process_cancellation(market_id, trader, 1_500_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert_eq!(
+            process_cancellation(market_id, creator, 1_500_000).unwrap(),
+            100
+        );
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Cancelled);
+        assert!(!is_trading_open(&market, 1_500_000));
+        // The seed less the fee that went to the LP fee accumulator
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 14_990);
+        assert!(matches!(
+            process_cancellation(market_id, creator, 1_600_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_invalidation(market_id, test_principal(1), 1_600_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+
+        let claim = process_refund_claim(trader, market_id, 1_600_000).unwrap();
+        assert_eq!((claim.contributed, claim.refund_amount), (100, 100));
+        assert_eq!(build_wind_down_report(1_600_000).unclaimed_refunds, 0);
+    }
+
+    #[test]
+    fn test_dispute_window_locks_claims_until_settled() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (holder, disputer) = (test_principal(3), test_principal(4));
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut().insert(
+                (holder, market_id),
+                UserPosition {
+                    user: holder,
+                    market_id,
+                    yes_tokens: 100,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            )
+        });
+        USER_BALANCES.with(|b| b.borrow_mut().insert(disputer, DEFAULT_DISPUTE_BOND));
+
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::No),
+            admin,
+            10,
+        )
+        .unwrap();
+        let window_end = 10 + DEFAULT_DISPUTE_WINDOW_NS;
+        assert!(matches!(
+            process_reward_claim(holder, market_id, 20),
+            Err(PredictionMarketError::DisputeWindowOpen)
+        ));
+
+        process_dispute(disputer, market_id, 20).unwrap();
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&disputer]), 0);
+        assert!(matches!(
+            process_dispute(holder, market_id, 30),
+            Err(PredictionMarketError::AlreadyDisputed)
+        ));
+        // A pending dispute keeps claims locked past the window
+        assert!(close_dispute_windows(window_end).is_empty());
+        assert!(matches!(
+            process_dispute_settlement(disputer, market_id, true, window_end),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        // Overturning flips the outcome, so it waits out the timelock like other admin actions
+        let pending = process_schedule_action(
+            admin,
+            AdminAction::OverturnDispute { market_id },
+            window_end,
+        )
+        .unwrap();
+        assert!(matches!(
+            process_execute_action(admin, pending.id, window_end + 1),
+            Err(PredictionMarketError::TimelockPending { .. })
+        ));
+        assert!(matches!(
+            process_reward_claim(holder, market_id, window_end + 1),
+            Err(PredictionMarketError::DisputeWindowOpen)
+        ));
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].winning_outcome.clone()),
+            Some(TokenType::No)
+        );
+
+        let executed_at = pending.executable_at;
+        process_execute_action(admin, pending.id, executed_at).unwrap();
+        let window = DISPUTE_WINDOWS.with(|w| w.borrow()[&market_id].clone());
+        assert!(window.closed);
+        assert_eq!(
+            window.dispute.unwrap().verdict,
+            Some(DisputeVerdict::Overturned)
+        );
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&disputer]),
+            DEFAULT_DISPUTE_BOND
+        );
+        let claim = process_reward_claim(holder, market_id, executed_at).unwrap();
+        assert_eq!(claim.reward_amount, 5_000);
+        assert!(matches!(
+            process_dispute(disputer, market_id, executed_at),
+            Err(PredictionMarketError::DisputeWindowClosed)
+        ));
+        assert!(matches!(
+            process_schedule_action(
+                admin,
+                AdminAction::OverturnDispute { market_id },
+                executed_at
+            ),
+            Err(PredictionMarketError::DisputeNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_community_vote_resolves_by_token_weight() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (yes_voter, no_voter, outsider) =
+            (test_principal(3), test_principal(4), test_principal(5));
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            market.resolution_mode = ResolutionMode::CommunityVote;
+            market.admin = test_principal(2);
+        });
+        USER_POSITIONS.with(|p| {
+            let mut positions = p.borrow_mut();
+            for (user, yes_tokens, no_tokens) in [(yes_voter, 300, 0), (no_voter, 50, 200)] {
+                positions.insert(
+                    (user, market_id),
+                    UserPosition {
+                        user,
+                        market_id,
+                        yes_tokens,
+                        no_tokens,
+                        claimed_reward: false,
+                    },
+                );
+            }
+        });
+
+        // Not before close, not without tokens, and the market admin cannot preempt the vote
+        assert!(matches!(
+            process_resolution_vote(yes_voter, market_id, TokenType::Yes, 1_500_000),
+            Err(PredictionMarketError::VotingClosed)
+        ));
+        assert!(matches!(
+            process_resolution_vote(outsider, market_id, TokenType::No, 2_000_000),
+            Err(PredictionMarketError::NotEligibleToVote)
+        ));
+        assert!(matches!(
+            transition_resolution(
+                market_id,
+                0,
+                ResolutionTransition::Resolve(TokenType::No),
+                test_principal(2),
+                2_000_000
+            ),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        process_resolution_vote(yes_voter, market_id, TokenType::No, 2_000_000).unwrap();
+        process_resolution_vote(yes_voter, market_id, TokenType::Yes, 2_000_001).unwrap();
+        process_resolution_vote(no_voter, market_id, TokenType::No, 2_000_002).unwrap();
+        assert_eq!(get_resolution_votes(market_id).len(), 2);
+
+        let window_end = 2_000_000 + VOTING_WINDOW_NS;
+        assert!(tally_resolution_votes(window_end - 1, admin).is_empty());
+        assert_eq!(next_vote_tally_time(), Some(window_end));
+        assert_eq!(tally_resolution_votes(window_end, admin), vec![market_id]);
+        let tally = get_vote_tally(market_id).unwrap();
+        assert_eq!((tally.yes_weight, tally.no_weight), (300, 250));
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.status, MarketStatus::Resolved);
+        assert_eq!(market.winning_outcome, Some(TokenType::Yes));
+        assert!(matches!(
+            process_resolution_vote(no_voter, market_id, TokenType::No, window_end),
+            Err(PredictionMarketError::VotingClosed)
+        ));
+        assert!(tally_resolution_votes(window_end + 1, admin).is_empty());
+    }
+
+    #[test]
+    fn test_designated_resolver_replaces_market_admin() {
+        let market_id = setup_test_market();
+        let global_admin = test_principal(1);
+        let market_admin = test_principal(2);
+        let resolver = test_principal(5);
+        ADMIN.with(|a| *a.borrow_mut() = Some(global_admin));
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            market.admin = market_admin;
+            market.resolver = Some(resolver);
+        });
+        let resolve = |transition, actor| {
+            transition_resolution(
+                market_id,
+                current_resolution_revision(market_id),
+                transition,
+                actor,
+                1_500_000,
+            )
+        };
+
+        assert!(matches!(
+            resolve(ResolutionTransition::Propose(TokenType::Yes), market_admin),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            process_invalidation(market_id, market_admin, 1_500_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        let proposed = resolve(ResolutionTransition::Propose(TokenType::Yes), resolver).unwrap();
+        assert_eq!(proposed.proposed_by, resolver);
+        // The global admin only steps in once the proposal is disputed, and then through an override
+        assert!(matches!(
+            resolve(ResolutionTransition::Resolve(TokenType::No), global_admin),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        resolve(ResolutionTransition::Challenge, global_admin).unwrap();
+        assert!(matches!(
+            resolve(ResolutionTransition::Resolve(TokenType::No), global_admin),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        let resolved =
+            resolve(ResolutionTransition::Override(TokenType::No), global_admin).unwrap();
+        assert_eq!(resolved.proposed_by, global_admin);
+    }
+
+    #[test]
+    fn test_two_step_admin_transfer() {
+        reset_state();
+        let (admin, successor, intruder) =
+            (test_principal(1), test_principal(2), test_principal(3));
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+
+        assert!(matches!(
+            process_propose_admin(intruder, intruder, 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_propose_admin(admin, successor, 10).unwrap();
+        // The current admin stays in charge until the handover is accepted
+        assert!(is_global_admin(admin));
+        assert!(matches!(
+            process_accept_admin(intruder, 20),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        process_accept_admin(successor, 20).unwrap();
+        assert!(is_global_admin(successor));
+        assert!(!is_global_admin(admin));
+        assert_eq!(get_pending_admin(), None);
+        assert!(matches!(
+            process_accept_admin(successor, 30),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_pause_blocks_trades_and_claims() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let user = test_principal(3);
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        USER_BALANCES.with(|b| b.borrow_mut().insert(user, 1_000));
+        let creator = test_principal(2);
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .creator_fees_accrued = 40
+        });
+        LP_ACCOUNTS.with(|a| {
+            a.borrow_mut()
+                .get_mut(&(creator, market_id))
+                .unwrap()
+                .unclaimed_fees = 60
+        });
+        let creator_balance = USER_BALANCES.with(|b| b.borrow()[&creator]);
+
+        assert!(matches!(
+            process_set_paused(user, true, 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_set_paused(admin, true, 10).unwrap();
+        assert!(get_config().paused);
+        assert!(matches!(
+            execute_buy_trade(user, market_id, 100, 0, TokenType::Yes),
+            Err(PredictionMarketError::Paused)
+        ));
+        assert!(matches!(
+            process_mint_complete_set(user, market_id, 100, 1_100_000),
+            Err(PredictionMarketError::Paused)
+        ));
+        assert!(matches!(
+            process_reward_claim(user, market_id, 1_100_000),
+            Err(PredictionMarketError::Paused)
+        ));
+        // Nothing else can leave while paused either: LP capital, LP fees or creator fees
+        assert!(matches!(
+            process_remove_liquidity(creator, market_id, 100, 1_100_000),
+            Err(PredictionMarketError::Paused)
+        ));
+        assert!(matches!(
+            process_claim_lp_fees(creator, market_id, 1_100_000),
+            Err(PredictionMarketError::Paused)
+        ));
+        assert!(matches!(
+            process_claim_creator_fees(creator, market_id, 1_100_000),
+            Err(PredictionMarketError::Paused)
+        ));
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&user]), 1_000);
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&creator]),
+            creator_balance
+        );
+
+        process_set_paused(admin, false, 20).unwrap();
+        process_mint_complete_set(user, market_id, 100, 1_100_000).unwrap();
+        assert_eq!(
+            process_claim_lp_fees(creator, market_id, 1_100_000).unwrap(),
+            60
+        );
+        assert_eq!(
+            process_claim_creator_fees(creator, market_id, 1_100_000).unwrap(),
+            40
+        );
+    }
+
+    #[test]
+    fn test_pause_blocks_liquidity_and_fee_claims() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        let provider = test_principal(3);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(provider, 1_000));
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .creator_fees_accrued = 40
+        });
+        LP_ACCOUNTS.with(|a| {
+            a.borrow_mut()
+                .get_mut(&(creator, market_id))
+                .unwrap()
+                .unclaimed_fees = 60
+        });
+        let pool = || MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool);
+        let (pool_before, creator_balance) = (pool(), USER_BALANCES.with(|b| b.borrow()[&creator]));
+
+        process_set_paused(admin, true, 10).unwrap();
+        let paused = |result: Result<u64, PredictionMarketError>| {
+            matches!(result, Err(PredictionMarketError::Paused))
+        };
+        assert!(paused(process_add_liquidity(
+            provider, market_id, 500, 1_100_000
+        )));
+        assert!(paused(process_remove_liquidity(
+            creator, market_id, 100, 1_100_000
+        )));
+        assert!(paused(process_claim_lp_fees(creator, market_id, 1_100_000)));
+        assert!(paused(process_claim_creator_fees(
+            creator, market_id, 1_100_000
+        )));
+        assert_eq!(pool(), pool_before);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&provider]), 1_000);
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&creator]),
+            creator_balance
+        );
+
+        // Every endpoint works again once unpaused
+        process_set_paused(admin, false, 20).unwrap();
+        let shares = process_add_liquidity(provider, market_id, 500, 1_100_000).unwrap();
+        assert_eq!(pool(), pool_before + 500);
+        assert!(process_remove_liquidity(provider, market_id, shares, 1_100_000).is_ok());
+        assert_eq!(
+            process_claim_lp_fees(creator, market_id, 1_100_000).unwrap(),
+            60
+        );
+        assert_eq!(
+            process_claim_creator_fees(creator, market_id, 1_100_000).unwrap(),
+            40
+        );
+    }
+
+    #[test]
+    fn test_dynamic_fee_grows_with_price_impact() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        let quote_fee = |icp_amount| {
+            compute_buy_quote(market_id, icp_amount, TokenType::Yes, 1_500_000)
+                .unwrap()
+                .fee_paid
+        };
+        let (small_base, large_base) = (quote_fee(10), quote_fee(100));
+
+        assert!(matches!(
+            process_set_dynamic_fee(
+                admin,
+                Some(DynamicFee {
+                    impact_share_bps: 1_000,
+                    max_fee_bps: MAX_DYNAMIC_FEE_BPS + 1,
+                }),
+                10
+            ),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        process_set_dynamic_fee(
+            admin,
+            Some(DynamicFee {
+                impact_share_bps: 5_000,
+                max_fee_bps: MAX_DYNAMIC_FEE_BPS,
+            }),
+            10,
+        )
+        .unwrap();
+
+        // A trade that barely moves the price keeps the base fee; a large one pays a surcharge
+        assert_eq!(quote_fee(10), small_base);
+        let large = quote_fee(100);
+        assert!(large > large_base);
+        assert!(large <= 100 * MAX_DYNAMIC_FEE_BPS / 10_000);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert!(
+            buy_impact_bps(&market, 100, &TokenType::Yes)
+                > buy_impact_bps(&market, 10, &TokenType::Yes)
+        );
+
+        process_set_dynamic_fee(admin, None, 20).unwrap();
+        assert_eq!(quote_fee(100), large_base);
+    }
+
+    #[test]
+    fn test_volume_fee_tiers_discount_trades() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let trader = test_principal(3);
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        let tier = |min_volume, fee_bps| FeeTier {
+            min_volume,
+            fee_bps,
+        };
+
+        assert!(matches!(
+            process_set_fee_tiers(admin, vec![tier(1_000, 40)], 10),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        assert!(matches!(
+            process_set_fee_tiers(admin, vec![tier(1_000, 20), tier(1_000, 10)], 10),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        process_set_fee_tiers(admin, vec![tier(1_000_000, 10), tier(200, 20)], 10).unwrap();
+        assert_eq!(get_config().fee_tiers[0], tier(200, 20));
+
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        let fee_for = |trader| market_trade_fee(&market, 100_000, 0, Some(trader), 1_500_000).0;
+        assert_eq!(fee_for(trader), 300);
+
+        // Two recorded trades of 100 e8s reach the first tier
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 1_200_000));
+        record_trade(test_trade(market_id, trader, OrderSide::Sell, 1_300_000));
+        let status = build_fee_status(trader);
+        assert_eq!((status.volume, status.fee_bps), (200, 20));
+        assert_eq!(status.next_tier, Some(tier(1_000_000, 10)));
+        assert_eq!(fee_for(trader), 200);
+        // Quotes stay at the base rate
+        assert_eq!(
+            market_trade_fee(&market, 100_000, 0, None, 1_500_000).0,
+            300
+        );
+    }
+
+    #[test]
+    fn test_referral_kickbacks() {
+        let market_id = setup_test_market();
+        let referrer = test_principal(3);
+        let trader = test_principal(4);
+
+        assert!(matches!(
+            process_register_referral(trader, trader, 10),
+            Err(PredictionMarketError::InvalidReferral)
+        ));
+        process_register_referral(trader, referrer, 10).unwrap();
+        assert!(matches!(
+            process_register_referral(trader, test_principal(5), 10),
+            Err(PredictionMarketError::AlreadyReferred)
+        ));
+        // The referrer cannot in turn be referred by their own referral
+        assert!(matches!(
+            process_register_referral(referrer, trader, 10),
+            Err(PredictionMarketError::InvalidReferral)
+        ));
+
+        // 10% protocol cut of 1_000 is 100, the referrer gets 20% of it
+        MARKETS.with(|m| {
+            distribute_trade_fee(m.borrow_mut().get_mut(&market_id).unwrap(), 1_000, trader)
+        });
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 80);
+        let stats = build_referral_stats(referrer);
+        assert_eq!(
+            (stats.referred_count, stats.total_earned, stats.claimable),
+            (1, 20, 20)
+        );
+        assert_eq!(build_referral_stats(trader).referrer, Some(referrer));
+
+        assert_eq!(process_referral_claim(referrer, 20).unwrap(), 20);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&referrer]), 20);
+        assert_eq!(build_referral_stats(referrer).total_earned, 20);
+        assert!(matches!(
+            process_referral_claim(referrer, 30),
+            Err(PredictionMarketError::NothingToClaim)
+        ));
+    }
+
+    #[test]
+    fn test_leaderboard_ranks_realized_pnl() {
+        let market_id = setup_test_market();
+        let (winner, loser, trader) = (test_principal(3), test_principal(4), test_principal(5));
+        let day = NANOS_PER_DAY;
+
+        record_trade(test_trade(market_id, winner, OrderSide::Buy, 1_200_000));
+        record_trade(test_trade(market_id, loser, OrderSide::Buy, 1_200_000));
+        record_trade(test_trade(market_id, trader, OrderSide::Buy, 1_200_000));
+        record_trade(test_trade(market_id, trader, OrderSide::Sell, 1_300_000));
+        record_pnl(winner, market_id, 0, 250, 3 * day);
+
+        let board = build_leaderboard(
+            LeaderboardPeriod::AllTime,
+            10,
+            LeaderboardSort::Pnl,
+            3 * day,
+        );
+        let ranking: Vec<_> = board
+            .iter()
+            .map(|e| (e.rank, e.user, e.realized_pnl))
+            .collect();
+        assert_eq!(
+            ranking,
+            vec![(1, winner, 150), (2, trader, 0), (3, loser, -100)]
+        );
+        // Only the winner's claim falls within the last day
+        let daily = build_leaderboard(LeaderboardPeriod::Day, 10, LeaderboardSort::Pnl, 3 * day);
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].user, winner);
+
+        // Win rates count settled markets only
+        assert_eq!(board[0].settled_markets, 0);
+        MARKETS
+            .with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Resolved);
+        let board = build_leaderboard(
+            LeaderboardPeriod::AllTime,
+            2,
+            LeaderboardSort::WinRate,
+            3 * day,
+        );
+        assert_eq!(board.len(), 2);
+        assert_eq!((board[0].user, board[0].win_rate), (winner, 1.0));
+        assert_eq!(board[1].win_rate, 0.0);
+    }
+
+    #[test]
+    fn test_forecaster_reputation_follows_outcome() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (bull, hedger) = (test_principal(3), test_principal(4));
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        USER_POSITIONS.with(|positions| {
+            let mut positions = positions.borrow_mut();
+            for (user, yes_tokens, no_tokens) in [(bull, 100, 0), (hedger, 50, 50)] {
+                positions.insert(
+                    (user, market_id),
+                    UserPosition {
+                        market_id,
+                        user,
+                        yes_tokens,
+                        no_tokens,
+                        claimed_reward: false,
+                    },
+                );
+            }
+        });
+        assert_eq!(get_user_reputation(bull).brier_score, None);
+
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::No),
+            admin,
+            2_100_000,
+        )
+        .unwrap();
+        let bull_reputation = get_user_reputation(bull);
+        assert_eq!(bull_reputation.markets_scored, 1);
+        assert_eq!(bull_reputation.brier_score, Some(1.0));
+        assert_eq!(get_user_reputation(hedger).brier_score, Some(0.25));
+
+        // Overturning the outcome rescores the market
+        MARKETS.with(|m| {
+            m.borrow_mut().get_mut(&market_id).unwrap().winning_outcome = Some(TokenType::Yes)
+        });
+        assert_eq!(get_user_reputation(bull).brier_score, Some(0.0));
+    }
+
+    #[test]
+    fn test_outcome_token_transfers() {
+        let market_id = setup_test_market();
+        let (holder, recipient) = (test_principal(3), test_principal(4));
+        let token = OutcomeToken {
+            market_id,
+            token_type: TokenType::Yes,
+        };
+        credit_swap_asset(
+            holder,
+            &SwapAsset::Tokens {
+                market_id,
+                token_type: TokenType::Yes,
+                amount: 100,
+            },
+        );
+        let transfer = |amount: u64, created_at_time| TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: recipient,
+                subaccount: Some(vec![0; 32]),
+            },
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time,
+        };
+
+        let block = process_outcome_transfer(
+            holder,
+            token.clone(),
+            transfer(40, Some(1_500_000)),
+            1_500_000,
+        );
+        assert_eq!(block, Ok(Nat::from(0u8)));
+        let balance = |owner| {
+            outcome_token_balance_of(
+                token.clone(),
+                Account {
+                    owner,
+                    subaccount: None,
+                },
+            )
+        };
+        assert_eq!(
+            (balance(holder), balance(recipient)),
+            (Nat::from(60u8), Nat::from(40u8))
+        );
+        assert_eq!(outcome_token_total_supply(token.clone()), Nat::from(100u8));
+
+        assert_eq!(
+            process_outcome_transfer(
+                holder,
+                token.clone(),
+                transfer(40, Some(1_500_000)),
+                1_600_000
+            ),
+            Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(0u8)
+            })
+        );
+        assert_eq!(
+            process_outcome_transfer(holder, token.clone(), transfer(61, None), 1_600_000),
+            Err(TransferError::InsufficientFunds {
+                balance: Nat::from(60u8)
+            })
+        );
+        let mut bad_fee = transfer(10, None);
+        bad_fee.fee = Some(Nat::from(1u8));
+        assert!(matches!(
+            process_outcome_transfer(holder, token.clone(), bad_fee, 1_600_000),
+            Err(TransferError::BadFee { .. })
+        ));
+        // Tokens stop moving once trading closes
+        assert!(matches!(
+            process_outcome_transfer(holder, token, transfer(10, None), 2_000_000),
+            Err(TransferError::GenericError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_position_receipts_wrap_transfer_unwrap() {
+        let market_id = setup_test_market();
+        let (holder, buyer) = (test_principal(3), test_principal(4));
+        credit_swap_asset(
+            holder,
+            &SwapAsset::Tokens {
+                market_id,
+                token_type: TokenType::Yes,
+                amount: 1_000,
+            },
+        );
+
+        let receipt_id =
+            process_wrap_position(holder, market_id, TokenType::Yes, 600, 1_500_000).unwrap();
+        assert_eq!(
+            outcome_balance(
+                &OutcomeToken {
+                    market_id,
+                    token_type: TokenType::Yes
+                },
+                holder
+            ),
+            400
+        );
+        // Sealed tokens still count toward the winning supply
+        assert_eq!(winning_supply(market_id, &TokenType::Yes), 1_000);
+        let receipt = &get_position_receipts(holder)[0];
+        assert_eq!((receipt.amount, receipt.entry_price), (600, 0.5));
+
+        let transfer = NftTransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: buyer,
+                subaccount: None,
+            },
+            token_id: Nat::from(receipt_id),
+            memo: None,
+            created_at_time: None,
+        };
+        assert_eq!(
+            process_receipt_transfer(buyer, transfer.clone(), 1_600_000),
+            Err(NftTransferError::Unauthorized)
+        );
+        assert!(process_receipt_transfer(holder, transfer, 1_600_000).is_ok());
+        assert_eq!(
+            icrc7_owner_of(vec![Nat::from(receipt_id), Nat::from(99u8)]),
+            vec![
+                Some(Account {
+                    owner: buyer,
+                    subaccount: None
+                }),
+                None
+            ]
+        );
+        assert!(matches!(
+            process_unwrap_position(holder, receipt_id, 1_700_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        let position = process_unwrap_position(buyer, receipt_id, 1_700_000).unwrap();
+        assert_eq!(position.yes_tokens, 600);
+        assert_eq!(icrc7_total_supply(), Nat::from(0u8));
+    }
+
+    #[test]
+    fn test_receipts_cannot_recycle_resolution_votes() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (voter, accomplice) = (test_principal(3), test_principal(4));
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            market.resolution_mode = ResolutionMode::CommunityVote;
+            market.admin = test_principal(2);
+        });
+        credit_swap_asset(
+            voter,
+            &SwapAsset::Tokens {
+                market_id,
+                token_type: TokenType::Yes,
+                amount: 400,
+            },
+        );
+        let receipt_id =
+            process_wrap_position(voter, market_id, TokenType::Yes, 100, 1_500_000).unwrap();
+
+        process_resolution_vote(voter, market_id, TokenType::Yes, 2_000_000).unwrap();
+        // Once trading stops, nothing can move the voted tokens to another principal
+        assert!(matches!(
+            process_wrap_position(voter, market_id, TokenType::Yes, 300, 2_000_001),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        let transfer = NftTransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: accomplice,
+                subaccount: None,
+            },
+            token_id: Nat::from(receipt_id),
+            memo: None,
+            created_at_time: None,
+        };
+        assert!(matches!(
+            process_receipt_transfer(voter, transfer, 2_000_001),
+            Err(NftTransferError::GenericError { .. })
+        ));
+        assert!(matches!(
+            process_unwrap_position(voter, receipt_id, 2_000_001),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_resolution_vote(accomplice, market_id, TokenType::Yes, 2_000_002),
+            Err(PredictionMarketError::NotEligibleToVote)
+        ));
+
+        let window_end = 2_000_000 + VOTING_WINDOW_NS;
+        assert_eq!(tally_resolution_votes(window_end, admin), vec![market_id]);
+        assert_eq!(get_vote_tally(market_id).unwrap().yes_weight, 300);
+
+        // The receipt still held at resolution is redeemed by the holder's claim
+        let claims_open = window_end + DEFAULT_DISPUTE_WINDOW_NS;
+        close_dispute_windows(claims_open);
+        let claim = process_reward_claim(voter, market_id, claims_open).unwrap();
+        assert_eq!((claim.winning_tokens, claim.reward_amount), (400, 5_000));
+        assert_eq!(icrc7_total_supply(), Nat::from(0u8));
+    }
+
+    #[test]
+    fn test_sell_offer_settles_outside_the_amm() {
+        let market_id = setup_test_market();
+        let (seller, buyer) = (test_principal(3), test_principal(4));
+        credit_swap_asset(
+            seller,
+            &SwapAsset::Tokens {
+                market_id,
+                token_type: TokenType::No,
+                amount: 1_000,
+            },
+        );
+        USER_BALANCES.with(|b| b.borrow_mut().insert(buyer, 700));
+        let reserves = MARKETS.with(|m| {
+            let market = &m.borrow()[&market_id];
+            (market.yes_reserve, market.no_reserve)
+        });
+
+        let sell = SwapAsset::Tokens {
+            market_id,
+            token_type: TokenType::No,
+            amount: 1_000,
+        };
+        let offer_id =
+            process_create_swap_offer(seller, sell, SwapAsset::Icp { amount: 600 }, 1_500_000)
+                .unwrap();
+        assert_eq!(
+            outcome_balance(
+                &OutcomeToken {
+                    market_id,
+                    token_type: TokenType::No
+                },
+                seller
+            ),
+            0
+        );
+        assert!(matches!(
+            process_accept_swap_offer(seller, offer_id, 1_600_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        let filled = process_accept_swap_offer(buyer, offer_id, 1_600_000).unwrap();
+        assert!(matches!(filled.status, SwapOfferStatus::Filled { taker, .. } if taker == buyer));
+        assert_eq!(
+            outcome_balance(
+                &OutcomeToken {
+                    market_id,
+                    token_type: TokenType::No
+                },
+                buyer
+            ),
+            1_000
+        );
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&buyer]), 100);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&seller]), 600);
+        let after = MARKETS.with(|m| {
+            let market = &m.borrow()[&market_id];
+            (market.yes_reserve, market.no_reserve)
+        });
+        assert_eq!(after, reserves);
+        assert!(matches!(
+            process_accept_swap_offer(buyer, offer_id, 1_700_000),
+            Err(PredictionMarketError::OfferNotOpen)
+        ));
+    }
+
+    #[test]
+    fn test_market_analysis_context_includes_trading_data() {
+        let market_id = setup_test_market();
+        record_trade(test_trade(
+            market_id,
+            test_principal(3),
+            OrderSide::Buy,
+            1_200_000,
+        ));
+        let summary = MARKETS.with(|m| build_market_summary(&m.borrow()[&market_id], 1_300_000));
+
+        let context = market_analysis_context(&summary);
+        assert!(context.contains("Reserves: 500 YES / 500 NO tokens, ICP pool: 5000 e8s"));
+        assert!(context.contains("YES price history"));
+        assert!(context.contains("- Buy 150 Yes tokens for 100 e8s"));
+
+        let fallback = fallback_market_analysis(&summary);
+        assert!(fallback.contains(&summary.market.title));
+    }
+
+    #[test]
+    fn test_market_question_review() {
+        reset_state();
+        let answer = "UNAMBIGUOUS: yes\nRESOLUTION_SOURCE: no\nDeadline: Yes\n\
+                      SUGGESTION: Cite the national weather service";
+        let review = parse_question_review(answer).unwrap();
+        assert!(review.unambiguous && !review.has_resolution_source && review.has_deadline);
+        assert_eq!(
+            review.suggestions,
+            vec!["Cite the national weather service"]
+        );
+        assert!(review.model_generated);
+        assert_eq!(parse_question_review("The question looks fine."), None);
+
+        let vague = heuristic_question_review("Will BTC go up significantly soon", "");
+        assert!(!vague.passes());
+        assert_eq!(vague.suggestions.len(), 3);
+        let clear = heuristic_question_review(
+            "Will it rain in Paris on 1 June 2026?",
+            "Resolves YES according to Meteo-France records.",
+        );
+        assert!(clear.passes(), "{:?}", clear);
+
+        // The gate only applies once enabled, and structured criteria bypass it
+        assert!(ensure_question_gate("Vague?", "", false).is_ok());
+        QUESTION_GATE.with(|g| *g.borrow_mut() = true);
+        assert!(matches!(
+            ensure_question_gate("Vague?", "", false),
+            Err(PredictionMarketError::QuestionNeedsWork(suggestions)) if suggestions.len() == 2
+        ));
+        assert!(ensure_question_gate("Vague?", "", true).is_ok());
+    }
+
+    #[test]
+    fn test_resolution_drafts_need_resolver_confirmation() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let outsider = test_principal(3);
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+
+        assert!(matches!(
+            ensure_can_draft(admin, market_id, 1_500_000),
+            Err(PredictionMarketError::InvalidCloseTime)
+        ));
+        assert!(matches!(
+            ensure_can_draft(outsider, market_id, 2_100_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        let draft = store_resolution_draft(
+            market_id,
+            admin,
+            "OUTCOME: unclear\nREASONING: No report yet.",
+            None,
+            2_100_000,
+        );
+        assert_eq!(
+            (draft.outcome, draft.reasoning.as_str()),
+            (None, "No report yet.")
+        );
+        assert!(matches!(
+            process_draft_review(admin, market_id, true, 2_100_000),
+            Err(PredictionMarketError::InvalidResolutionTransition)
+        ));
+        let rejected = process_draft_review(admin, market_id, false, 2_100_000).unwrap();
+        assert_eq!(rejected.status, DraftStatus::Rejected);
+
+        store_resolution_draft(
+            market_id,
+            admin,
+            "OUTCOME: YES\nREASONING: It rained.\nThe station logged 4mm.",
+            Some("Station log".to_string()),
+            2_200_000,
+        );
+        let confirmed = process_draft_review(admin, market_id, true, 2_200_000).unwrap();
+        assert_eq!(confirmed.reasoning, "It rained.\nThe station logged 4mm.");
+        assert_eq!(confirmed.status, DraftStatus::Confirmed);
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.winning_outcome, Some(TokenType::Yes));
+        assert!(matches!(
+            process_draft_review(admin, market_id, false, 2_300_000),
+            Err(PredictionMarketError::MarketResolved)
+        ));
+    }
+
+    #[test]
+    fn test_comments_and_moderation() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (author, moderator, other) = (test_principal(3), test_principal(4), test_principal(5));
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+
+        assert!(matches!(
+            process_post_comment(author, market_id, "   ".to_string(), 10),
+            Err(PredictionMarketError::InvalidComment)
+        ));
+        assert!(matches!(
+            process_post_comment(author, 99, "Hi".to_string(), 10),
+            Err(PredictionMarketError::MarketNotFound)
+        ));
+        let first =
+            process_post_comment(author, market_id, " Radar shows rain ".to_string(), 10).unwrap();
+        assert_eq!(first.text, "Radar shows rain");
+        let second = process_post_comment(other, market_id, "Spam".to_string(), 20).unwrap();
+        assert_eq!(get_comments(market_id, None).items.len(), 2);
+
+        assert!(matches!(
+            process_delete_comment(other, market_id, first.id, 30),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_set_moderator(admin, moderator, true, 30).unwrap();
+        process_delete_comment(moderator, market_id, second.id, 40).unwrap();
+        process_delete_comment(author, market_id, first.id, 40).unwrap();
+        assert!(get_comments(market_id, None).items.is_empty());
+        assert!(matches!(
+            process_delete_comment(author, market_id, first.id, 50),
+            Err(PredictionMarketError::CommentNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_webhook_signing_allowlist_and_retries() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+
+        // RFC 4231 test case 2
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        process_set_webhook_allowlist(admin, vec!["Hooks.Example.com".to_string()], 10).unwrap();
+        assert!(is_webhook_host_allowed(
+            "https://hooks.example.com:443/market"
+        ));
+        assert!(!is_webhook_host_allowed(
+            "https://evil.example/hooks.example.com"
+        ));
+        assert!(matches!(
+            register_lifecycle_hook(
+                creator,
+                market_id,
+                Some(CallbackTarget::Https {
+                    url: "https://other.example/hook".to_string()
+                }),
+                vec![LifecycleStage::LargeTrade]
+            ),
+            Err(PredictionMarketError::InvalidCallbackTarget)
+        ));
+        register_lifecycle_hook(
+            creator,
+            market_id,
+            Some(CallbackTarget::Https {
+                url: "https://hooks.example.com/market".to_string(),
+            }),
+            vec![LifecycleStage::LargeTrade],
+        )
+        .unwrap();
+
+        // Only trades of at least LARGE_TRADE_E8S notify
+        record_trade(test_trade(
+            market_id,
+            test_principal(3),
+            OrderSide::Buy,
+            1_100_000,
+        ));
+        let mut large = test_trade(market_id, test_principal(3), OrderSide::Buy, 1_200_000);
+        large.icp_amount = LARGE_TRADE_E8S;
+        let large_id = large.id;
+        record_trade(large);
+        let (_, notification) = LIFECYCLE_OUTBOX.with(|o| o.borrow_mut().pop().unwrap());
+        assert_eq!(notification.stage, LifecycleStage::LargeTrade);
+        assert_eq!(notification.trade_id, Some(large_id));
+        assert!(LIFECYCLE_OUTBOX.with(|o| o.borrow().is_empty()));
+
+        // Failed deliveries back off exponentially until the attempts run out
+        let mut delivery = WebhookDelivery {
+            url: "https://hooks.example.com/market".to_string(),
+            notification,
+            attempt: 0,
+            next_attempt_at: 0,
+        };
+        let mut now = 0;
+        let mut delays = Vec::new();
+        while queue_webhook_retry(delivery.clone(), now) {
+            let next = next_webhook_retry_time().unwrap();
+            delays.push(next - now);
+            assert!(take_due_webhook_retries(next - 1).is_empty());
+            delivery = take_due_webhook_retries(next).pop().unwrap();
+            now = next;
+        }
+        let base = WEBHOOK_RETRY_BASE_NS;
+        assert_eq!(delays, vec![base, 2 * base, 4 * base, 8 * base]);
+        assert_eq!(next_webhook_retry_time(), None);
+    }
+
+    #[test]
+    fn test_oracle_resolves_market_after_close() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let oracle = test_principal(9);
+        let config = OracleConfig {
+            url: "https://api.example.com/price?ids=bitcoin".to_string(),
+            json_path: "bitcoin.quotes.1.usd".to_string(),
+            reading: OracleReading::Threshold {
+                comparator: Comparator::Above,
+                threshold: 60_000.0,
+                tiebreak: TokenType::No,
+            },
+        };
+
+        assert!(matches!(
+            process_set_market_oracle(
+                test_principal(3),
+                market_id,
+                Some(config.clone()),
+                1_500_000
+            ),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        let insecure = OracleConfig {
+            url: "http://api.example.com/price".to_string(),
+            ..config.clone()
+        };
+        assert!(matches!(
+            process_set_market_oracle(admin, market_id, Some(insecure), 1_500_000),
+            Err(PredictionMarketError::InvalidResolutionCriteria)
+        ));
+        process_set_market_oracle(admin, market_id, Some(config.clone()), 1_500_000).unwrap();
+
+        // Nothing is polled until the deadline freezes the market
+        assert!(take_due_oracle_polls(2_000_000).is_empty());
+        MARKETS.with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Frozen);
+        assert_eq!(next_oracle_poll_time(), Some(2_000_000));
+        assert_eq!(
+            take_due_oracle_polls(2_000_000),
+            vec![(market_id, config.clone())]
+        );
+        assert!(take_due_oracle_polls(2_000_001).is_empty());
+
+        // An unreadable answer leaves the market frozen and records why
+        let missing = br#"{"bitcoin": {"quotes": []}}"#.to_vec();
+        assert!(apply_oracle_response(market_id, &config, Ok(missing), oracle, 2_000_002).is_err());
+        let status = get_market_oracle(market_id).unwrap();
+        assert_eq!(status.attempts, 1);
+        assert_eq!(
+            status.last_error,
+            Some("Nothing found at bitcoin.quotes.1.usd".to_string())
+        );
+
+        let body = br#" {"bitcoin": {"name": "Bit\"coin\u0021", "quotes": [{"usd": 1}, {"usd": "61234.5"}]}} "#;
+        assert_eq!(
+            apply_oracle_response(market_id, &config, Ok(body.to_vec()), oracle, 2_000_003),
+            Ok(TokenType::Yes)
+        );
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.status, MarketStatus::Resolved);
+        assert_eq!(market.winning_outcome, Some(TokenType::Yes));
+        assert_eq!(get_market_oracle(market_id).unwrap().last_error, None);
+        assert_eq!(next_oracle_poll_time(), None);
+
+        let parsed = ParsedJson::parse(std::str::from_utf8(body).unwrap()).unwrap();
+        assert_eq!(
+            parsed.lookup("bitcoin.name"),
+            Some(&ParsedJson::Str("Bit\"coin!".to_string()))
+        );
+        assert_eq!(ParsedJson::parse("{\"a\": [1,]}"), None);
+    }
+
+    #[test]
+    fn test_price_market_resolves_from_exchange_rate() {
+        reset_state();
+        let creator = test_principal(2);
+        let oracle = test_principal(9);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let asset = |symbol: &str, class: XrcAssetClass| XrcAsset {
+            symbol: symbol.to_string(),
+            class,
+        };
+
+        assert!(matches!(
+            process_create_price_market(
+                creator,
+                asset("ICP", XrcAssetClass::Cryptocurrency),
+                asset("ICP", XrcAssetClass::Cryptocurrency),
+                Comparator::Above,
+                10.0,
+                MIN_DEPOSIT,
+                2_000_000,
+                None,
+                1_000_000
+            ),
+            Err(PredictionMarketError::InvalidResolutionCriteria)
+        ));
+        let market_id = process_create_price_market(
+            creator,
+            asset("icp", XrcAssetClass::Cryptocurrency),
+            asset("USD", XrcAssetClass::FiatCurrency),
+            Comparator::Above,
+            10.0,
+            MIN_DEPOSIT,
+            120_000_000_000,
+            None,
+            1_000_000,
+        )
+        .unwrap();
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(
+            market.title,
+            "Will ICP/USD be above 10 at the trading deadline?"
+        );
+        assert_eq!(market.resolution_criteria.unwrap().threshold, 10.0);
+
+        // Polls start once the deadline freezes the market, asking for the rate at close
+        assert!(take_due_price_feeds(120_000_000_000).is_empty());
+        MARKETS.with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Frozen);
+        let due = take_due_price_feeds(120_000_000_000);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1.timestamp, Some(120));
+        assert_eq!(
+            next_price_feed_poll_time(),
+            Some(120_000_000_000 + XRC_RETRY_NS)
+        );
+
+        assert!(apply_exchange_rate(
+            market_id,
+            Err("Pending".to_string()),
+            oracle,
+            120_000_000_001
+        )
+        .is_err());
+        assert_eq!(
+            get_price_feed(market_id).unwrap().last_error,
+            Some("Pending".to_string())
+        );
+
+        let usd = asset("USD", XrcAssetClass::FiatCurrency);
+        let rate = ExchangeRate {
+            base_asset: asset("ICP", XrcAssetClass::Cryptocurrency),
+            quote_asset: usd,
+            timestamp: 120,
+            rate: 12_500_000_000,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_received_rates: 5,
+                base_asset_num_queried_sources: 6,
+                quote_asset_num_received_rates: 4,
+                quote_asset_num_queried_sources: 4,
+                standard_deviation: 0,
+                forex_timestamp: None,
+            },
+        };
+        assert_eq!(
+            apply_exchange_rate(market_id, Ok(rate), oracle, 180_000_000_000),
+            Ok(TokenType::Yes)
+        );
+        let feed = get_price_feed(market_id).unwrap();
+        assert_eq!(feed.last_error, None);
+        let evidence = feed.evidence.unwrap();
+        assert_eq!(evidence.rate, 12.5);
+        assert_eq!((evidence.base_sources, evidence.quote_sources), (5, 4));
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.winning_outcome, Some(TokenType::Yes));
+        assert_eq!(next_price_feed_poll_time(), None);
+    }
+
+    #[test]
+    fn test_unresolved_market_is_voided_after_grace() {
+        let market_id = setup_test_market();
+        let close_time = 2_000_000;
+        let deadline = close_time + AUTO_VOID_GRACE_NS;
+        MARKETS.with(|m| m.borrow_mut().get_mut(&market_id).unwrap().status = MarketStatus::Frozen);
+        assert_eq!(next_auto_void_time(), Some(deadline));
+
+        assert!(void_stale_markets(deadline - 1).is_empty());
+        assert_eq!(void_stale_markets(deadline), vec![market_id]);
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.status, MarketStatus::Invalid);
+        assert!(EVENTS.with(|e| e.borrow().iter().any(|event| matches!(
+            event.kind,
+            EventKind::MarketInvalidated { market_id: id, .. } if id == market_id
+        ))));
+
+        // Settled markets are left alone
+        assert_eq!(next_auto_void_time(), None);
+        assert!(void_stale_markets(deadline + 1).is_empty());
+    }
+
+    #[test]
+    fn test_freeze_market_early() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let trader = test_principal(3);
+
+        assert!(matches!(
+            process_freeze_market(trader, market_id, 1_500_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_freeze_market(admin, market_id, 1_500_000).unwrap();
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.status, MarketStatus::Frozen);
+        assert_eq!(market.close_time, 2_000_000);
+
+        // Frozen blocks trading and claims until the market is resolved
+        assert!(matches!(
+            ensure_trading_open(market_id, 1_500_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_reward_claim(trader, market_id, 1_500_000),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+        assert!(matches!(
+            process_freeze_market(admin, market_id, 1_500_001),
+            Err(PredictionMarketError::MarketClosed)
+        ));
+
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::No),
+            admin,
+            1_600_000,
+        )
+        .unwrap();
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.status, MarketStatus::Resolved);
+    }
+
+    #[test]
+    fn test_resolve_market_requires_evidence() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+
+        assert!(matches!(
+            process_resolve_market(
+                admin,
+                market_id,
+                TokenType::Yes,
+                "  ".to_string(),
+                1_500_000
+            ),
+            Err(PredictionMarketError::MissingResolutionEvidence)
+        ));
+        assert!(matches!(
+            process_resolve_market(
+                admin,
+                market_id,
+                TokenType::Yes,
+                "x".repeat(MAX_RESOLUTION_EVIDENCE_LEN + 1),
+                1_500_000
+            ),
+            Err(PredictionMarketError::MissingResolutionEvidence)
+        ));
+        let market = MARKETS.with(|m| m.borrow().get(&market_id).cloned().unwrap());
+        assert_eq!(market.status, MarketStatus::Open);
+
+        process_resolve_market(
+            admin,
+            market_id,
+            TokenType::Yes,
+            " Station report: 12mm of rain ".to_string(),
+            1_500_000,
+        )
+        .unwrap();
+        let summary =
+            MARKETS.with(|m| build_market_summary(m.borrow().get(&market_id).unwrap(), 1_500_000));
+        assert_eq!(
+            summary.market.resolution_source,
+            "https://weather.example.com"
+        );
+        assert_eq!(
+            summary.market.resolution_evidence,
+            Some("Station report: 12mm of rain".to_string())
+        );
+    }
+
+    #[test]
+    fn test_creator_bond_slashed_on_bad_resolutions() {
+        let _ = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        let disputer = test_principal(3);
+        let balance = |user| USER_BALANCES.with(|b| b.borrow().get(&user).copied().unwrap_or(0));
+        let start = 10 * MIN_DEPOSIT;
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, start));
+        process_set_creator_bond_config(admin, 1_000, 5_000, 1_000_000).unwrap();
+        let new_market = |title: &str| NewMarket {
+            title: title.to_string(),
+            description: "Bonded market".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 2_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: Some("https://example.com".to_string()),
+            metadata: None,
+        };
+
+        let voided = process_create_market(creator, new_market("Voided?"), 1_000_000).unwrap();
+        assert_eq!(balance(creator), start - MIN_DEPOSIT - 1_000);
+        assert_eq!(get_creator_bond(voided).unwrap().amount, 1_000);
+
+        // Voiding for ambiguity sends half the bond to the treasury and returns the rest
+        let treasury_before = TREASURY_BALANCE.with(|t| *t.borrow());
+        process_invalidation(voided, admin, 1_500_000).unwrap();
+        let bond = get_creator_bond(voided).unwrap();
+        assert_eq!((bond.slashed, bond.released), (500, true));
+        assert!(TREASURY_BALANCE.with(|t| *t.borrow()) >= treasury_before + 500);
+        // Nobody traded, so the seed liquidity also comes back to the creator
+        assert_eq!(balance(creator), start - 500);
+
+        // An overturned resolution pays the slashed share to the disputer
+        let disputed = process_create_market(creator, new_market("Disputed?"), 1_000_000).unwrap();
+        transition_resolution(
+            disputed,
+            0,
+            ResolutionTransition::Resolve(TokenType::Yes),
+            creator,
+            1_500_000,
+        )
+        .unwrap();
+        assert!(!get_creator_bond(disputed).unwrap().released);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(disputer, DEFAULT_DISPUTE_BOND));
+        process_dispute(disputer, disputed, 1_600_000).unwrap();
+        process_dispute_settlement(admin, disputed, true, 1_700_000).unwrap();
+        assert_eq!(balance(disputer), DEFAULT_DISPUTE_BOND + 500);
+
+        let creator_before = balance(creator);
+        close_dispute_windows(1_500_000 + DEFAULT_DISPUTE_WINDOW_NS);
+        assert!(get_creator_bond(disputed).unwrap().released);
+        // No winning tokens exist, so closing the window also refunds the pool to its LP
+        assert_eq!(balance(creator), creator_before + MIN_DEPOSIT + 500);
+    }
+
+    #[test]
+    fn test_creator_allowlist_mode() {
+        let _ = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let new_market = || NewMarket {
+            title: "Allowlisted?".to_string(),
+            description: "Created under allowlist mode".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 2_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+
+        CREATOR_ALLOWLIST_MODE.with(|m| *m.borrow_mut() = true);
+        assert!(matches!(
+            process_create_market(creator, new_market(), 1_000_000),
+            Err(PredictionMarketError::CreatorNotAllowed)
+        ));
+        assert!(matches!(
+            process_set_creator(creator, creator, true, 1_000_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        process_set_creator(admin, creator, true, 1_000_000).unwrap();
+        process_set_creator(admin, creator, true, 1_000_000).unwrap();
+        assert_eq!(list_creators(), vec![creator]);
+        assert!(process_create_market(creator, new_market(), 1_000_000).is_ok());
+
+        process_set_creator(admin, creator, false, 1_000_001).unwrap();
+        assert!(list_creators().is_empty());
+        assert!(matches!(
+            process_create_market(creator, new_market(), 1_000_002),
+            Err(PredictionMarketError::CreatorNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn test_creation_fee_goes_to_treasury() {
+        let _ = setup_test_market();
+        let creator = test_principal(2);
+        let start = 10 * MIN_DEPOSIT;
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, start));
+        CREATION_FEE.with(|f| *f.borrow_mut() = 500);
+        let new_market = |liquidity| NewMarket {
+            title: "Fee?".to_string(),
+            description: "Pays the creation fee".to_string(),
+            initial_icp_liquidity: liquidity,
+            close_time: 2_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+
+        // Seed liquidity and fee together must fit in the balance
+        assert!(matches!(
+            process_create_market(creator, new_market(start - 400), 1_000_000),
+            Err(PredictionMarketError::InsufficientDeposit { .. })
+        ));
+        let treasury_before = TREASURY_BALANCE.with(|t| *t.borrow());
+        process_create_market(creator, new_market(MIN_DEPOSIT), 1_000_000).unwrap();
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&creator]),
+            start - MIN_DEPOSIT - 500
+        );
+        assert_eq!(
+            TREASURY_BALANCE.with(|t| *t.borrow()),
+            treasury_before + 500
+        );
+        assert_eq!(get_config().creation_fee, 500);
+    }
+
+    #[test]
+    fn test_exposure_limits() {
+        let _ = setup_test_market();
+        let admin = test_principal(1);
+        let user = test_principal(3);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(user, 10_000));
+        let limits = ExposureLimits {
+            max_trade_icp: Some(1_000),
+            max_position_tokens: Some(5_000),
+            max_pool_size: Some(50_000),
+        };
+
+        assert!(matches!(
+            process_set_exposure_limits(user, limits.clone(), 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            process_set_exposure_limits(
+                admin,
+                ExposureLimits {
+                    max_pool_size: Some(0),
+                    ..limits.clone()
+                },
+                10
+            ),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        process_set_exposure_limits(admin, limits.clone(), 10).unwrap();
+        assert_eq!(get_config().exposure_limits, limits);
+
+        // The trade size cap is checked before the market is touched
+        assert!(matches!(
+            execute_buy_trade(user, 1, 1_001, 0, TokenType::Yes),
+            Err(PredictionMarketError::TradeSizeLimitExceeded { limit: 1_000 })
+        ));
+        assert!(check_trade_size_limit(&limits, 1_000).is_ok());
+        assert!(check_exposure_limits(&limits, 5_000, 50_000).is_ok());
+        assert!(matches!(
+            check_exposure_limits(&limits, 5_001, 50_000),
+            Err(PredictionMarketError::PositionLimitExceeded { limit: 5_000 })
+        ));
+        assert!(matches!(
+            check_exposure_limits(&limits, 5_000, 50_001),
+            Err(PredictionMarketError::PoolSizeLimitExceeded { limit: 50_000 })
+        ));
+        assert!(check_exposure_limits(&ExposureLimits::default(), u64::MAX, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_trade_deadline() {
+        assert!(check_trade_deadline(None, u64::MAX).is_ok());
+        assert!(check_trade_deadline(Some(1_000), 1_000).is_ok());
+        assert!(matches!(
+            check_trade_deadline(Some(1_000), 1_001),
+            Err(PredictionMarketError::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_commit_reveal_trading() {
+        let market_id = setup_test_market();
+        let user = test_principal(3);
+        let other = test_principal(4);
+        assert!(matches!(
+            process_set_commit_reveal(user, market_id, true),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_set_commit_reveal(test_principal(1), market_id, true).unwrap();
+        assert!(matches!(
+            ensure_direct_trading(market_id),
+            Err(PredictionMarketError::CommitRevealRequired)
+        ));
+        assert!(MARKETS
+            .with(|m| build_market_summary(&m.borrow()[&market_id], 1_500_000).commit_reveal));
+
+        let trade = RevealedTrade {
+            market_id,
+            side: OrderSide::Buy,
+            token_type: TokenType::Yes,
+            amount: 100,
+            min_out: 0,
+        };
+        let salt = vec![7u8; MIN_COMMIT_SALT_LEN];
+        let hash = trade_commitment_hash(user, &trade, &salt).unwrap();
+        assert!(matches!(
+            process_commit_trade(user, vec![0; 31], 1_000),
+            Err(PredictionMarketError::InvalidCommitment)
+        ));
+        let commitment = process_commit_trade(user, hash.clone(), 1_000).unwrap();
+        assert_eq!(commitment.expires_at, 1_000 + COMMIT_REVEAL_WINDOW_NS);
+        assert!(matches!(
+            process_commit_trade(user, hash, 1_000),
+            Err(PredictionMarketError::InvalidCommitment)
+        ));
+
+        // Only the committer, with the committed parameters, in a later round
+        assert!(matches!(
+            process_reveal_trade(other, trade.clone(), salt.clone(), 2_000),
+            Err(PredictionMarketError::CommitmentNotFound)
+        ));
+        assert!(matches!(
+            process_reveal_trade(
+                user,
+                RevealedTrade {
+                    amount: 200,
+                    ..trade.clone()
+                },
+                salt.clone(),
+                2_000
+            ),
+            Err(PredictionMarketError::CommitmentNotFound)
+        ));
+        assert!(matches!(
+            process_reveal_trade(user, trade.clone(), salt.clone(), 1_000),
+            Err(PredictionMarketError::RevealTooEarly)
+        ));
+        // An expired commitment is used up
+        let late = 1_000 + COMMIT_REVEAL_WINDOW_NS + 1;
+        assert!(matches!(
+            process_reveal_trade(user, trade.clone(), salt.clone(), late),
+            Err(PredictionMarketError::Expired)
+        ));
+        assert!(matches!(
+            process_reveal_trade(user, trade, salt, late),
+            Err(PredictionMarketError::CommitmentNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_operation_guard_blocks_overlapping_calls() {
+        reset_state();
+        let user = test_principal(3);
+        let other = test_principal(4);
+        {
+            let _guard = OperationGuard::market_and_user(1, user).unwrap();
+            assert!(matches!(
+                OperationGuard::market_and_user(1, other),
+                Err(PredictionMarketError::Busy)
+            ));
+            assert!(matches!(
+                OperationGuard::acquire(vec![LockKey::User(user)]),
+                Err(PredictionMarketError::Busy)
+            ));
+            // A failed acquire takes none of its keys
+            assert!(matches!(
+                OperationGuard::market_and_user(2, user),
+                Err(PredictionMarketError::Busy)
+            ));
+            let _unrelated = OperationGuard::market_and_user(2, other).unwrap();
+        }
+        // Dropping the guards releases everything
+        assert!(OPERATION_LOCKS.with(|l| l.borrow().is_empty()));
+        assert!(OperationGuard::market_and_user(1, other).is_ok());
+    }
+
+    #[test]
+    fn test_state_export_import_round_trip() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let trader = test_principal(3);
+        record_trade(TradeRecord {
+            id: next_trade_id(),
+            market_id,
+            trader,
+            side: OrderSide::Buy,
+            token_type: TokenType::Yes,
+            icp_amount: 400,
+            token_amount: 700,
+            fee: 1,
+            price_after: 0.6,
+            timestamp: 1_500_000,
+        });
+        USER_BALANCES.with(|b| b.borrow_mut().insert(trader, 1_234));
 
-        // Set admin
+        // Small chunks so the export spans several of them
+        let first = export_state_chunk(0, 1_000).unwrap();
+        assert!(first.total_chunks > 1);
+        let chunks: Vec<StateChunk> = (0..first.total_chunks)
+            .map(|index| export_state_chunk(index, 1_000).unwrap())
+            .collect();
+        assert!(matches!(
+            export_state_chunk(first.total_chunks, 1_000),
+            Err(PredictionMarketError::InvalidStateChunk)
+        ));
+
+        // A canister with state refuses imports
+        assert!(matches!(
+            process_import_state(admin, chunks[0].clone(), 2_000_000),
+            Err(PredictionMarketError::InvalidStateChunk)
+        ));
+
+        reset_state();
         ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        assert!(matches!(
+            process_import_state(trader, chunks[0].clone(), 2_000_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        // Chunks must arrive in order
+        assert!(matches!(
+            process_import_state(admin, chunks[1].clone(), 2_000_000),
+            Err(PredictionMarketError::InvalidStateChunk)
+        ));
+        for chunk in chunks {
+            process_import_state(admin, chunk, 2_000_000).unwrap();
+        }
 
-        // Give creator some ICP
-        USER_BALANCES.with(|balances| {
-            balances.borrow_mut().insert(creator, 10000);
+        assert!(MARKETS.with(|m| m.borrow().contains_key(&market_id)));
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&trader]), 1_234);
+        assert_eq!(trades_of(trader, 0, 10).items.len(), 1);
+        assert_eq!(rolling_stats_24h(market_id, 1_600_000).1, 1);
+        assert!(certified_document_body(&format!("/market/{}", market_id)).is_some());
+    }
+
+    #[test]
+    fn test_state_migration_pipeline() {
+        assert_eq!(STATE_MIGRATIONS.len() as u32, STATE_VERSION - 1);
+        let market_id = setup_test_market();
+        let encoded = candid::encode_one(capture_state()).unwrap();
+
+        assert!(migrate_state(0, encoded.clone()).is_err());
+        assert!(migrate_state(STATE_VERSION + 1, encoded.clone()).is_err());
+        let snapshot = migrate_state(STATE_VERSION, encoded).unwrap();
+        reset_state();
+        restore_state(snapshot, 1_500_000);
+        assert!(MARKETS.with(|m| m.borrow().contains_key(&market_id)));
+    }
+
+    #[test]
+    fn test_sharded_market_routing() {
+        let _ = setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        let (worker_a, worker_b) = (test_principal(8), test_principal(9));
+        assert_eq!(shard_for_new_market(), None);
+        assert!(matches!(
+            process_set_shard_config(
+                admin,
+                ShardConfig {
+                    workers: Vec::new(),
+                    local_market_limit: Some(1),
+                },
+                10
+            ),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        let config = ShardConfig {
+            workers: vec![worker_a, worker_b],
+            local_market_limit: Some(1),
+        };
+        process_set_shard_config(admin, config.clone(), 10).unwrap();
+        assert_eq!(get_config().shards, config);
+
+        // The fixture market fills the local share, so new markets go to the emptiest worker
+        let start = 10 * MIN_DEPOSIT;
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, start));
+        let new_market = NewMarket {
+            title: "Sharded?".to_string(),
+            description: "Lives on a worker".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 2_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+        assert_eq!(shard_for_new_market(), Some(worker_a));
+        let (first_id, funding) =
+            reserve_sharded_market(creator, worker_a, &new_market, 1_000_000).unwrap();
+        assert_eq!(funding, MIN_DEPOSIT);
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&creator]),
+            start - MIN_DEPOSIT
+        );
+        assert_eq!(get_market_shard(first_id), Some(worker_a));
+        assert_eq!(shard_for_new_market(), Some(worker_b));
+
+        // A worker in use cannot be dropped from the configuration
+        assert!(matches!(
+            process_set_shard_config(
+                admin,
+                ShardConfig {
+                    workers: vec![worker_b],
+                    local_market_limit: Some(1),
+                },
+                10
+            ),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+
+        // A failed worker call gives the ID's registration and the funds back
+        release_sharded_market(creator, first_id, funding);
+        assert_eq!(get_market_shard(first_id), None);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), start);
+    }
+
+    #[test]
+    fn test_routed_worker_creates_market_under_router_id() {
+        reset_state();
+        let router = test_principal(7);
+        let creator = test_principal(2);
+        ROUTER.with(|r| *r.borrow_mut() = Some(router));
+        let new_market = || NewMarket {
+            title: "Routed?".to_string(),
+            description: "Created for the router".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 2_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+        assert!(matches!(
+            ensure_not_routed_worker(),
+            Err(PredictionMarketError::RoutedWorker)
+        ));
+        assert!(matches!(
+            process_create_routed_market(creator, creator, 42, 2_000, new_market(), 1_000_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert_eq!(
+            process_create_routed_market(
+                router,
+                creator,
+                42,
+                MIN_DEPOSIT + 500,
+                new_market(),
+                1_000_000
+            )
+            .unwrap(),
+            42
+        );
+        assert_eq!(MARKETS.with(|m| m.borrow()[&42].creator), creator);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 500);
+        assert_eq!(NEXT_MARKET_ID.with(|id| *id.borrow()), 1);
+        // A rejected market leaves no credit behind
+        assert!(
+            process_create_routed_market(router, creator, 43, 100, new_market(), 3_000_000)
+                .is_err()
+        );
+        assert_eq!(USER_BALANCES.with(|b| b.borrow()[&creator]), 500);
+    }
+
+    #[test]
+    fn test_merge_shard_pages_stops_at_truncated_shard() {
+        let summary = |id: u64| {
+            let mut market = MARKETS.with(|m| m.borrow()[&1].clone());
+            market.id = id;
+            build_market_summary(&market, 1_500_000)
+        };
+        let _ = setup_test_market();
+        let page = |ids: &[u64], next_cursor: Option<u64>| Page {
+            items: ids.iter().map(|id| summary(*id)).collect(),
+            truncated: next_cursor.is_some(),
+            next_cursor,
+        };
+        let merged = merge_shard_pages(
+            vec![
+                page(&[1, 4, 9], None),
+                page(&[2, 3], Some(5)),
+                page(&[6], None),
+            ],
+            None,
+        );
+        let ids: Vec<u64> = merged.items.iter().map(|s| s.market.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(merged.next_cursor, Some(5));
+        assert!(merged.truncated);
+    }
+
+    #[test]
+    fn test_list_markets_sort_orders() {
+        let first = setup_test_market();
+        let creator = test_principal(2);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let new_market = |title: &str, close_time, seed| NewMarket {
+            title: title.to_string(),
+            description: "Sorting".to_string(),
+            initial_icp_liquidity: seed,
+            close_time,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+        let second = process_create_market(
+            creator,
+            new_market("Second", 3_000_000, MIN_DEPOSIT),
+            1_100_000,
+        )
+        .unwrap();
+        let third = process_create_market(
+            creator,
+            new_market("Third", 2_500_000, 2 * MIN_DEPOSIT),
+            1_200_000,
+        )
+        .unwrap();
+        let trade = |market_id, icp_amount, price_after| {
+            record_trade(TradeRecord {
+                id: next_trade_id(),
+                market_id,
+                trader: test_principal(3),
+                side: OrderSide::Buy,
+                token_type: TokenType::Yes,
+                icp_amount,
+                token_amount: icp_amount,
+                fee: 0,
+                price_after,
+                timestamp: 1_300_000,
+            })
+        };
+        trade(second, 900, 0.9);
+        trade(third, 300, 0.55);
+
+        let ids = |sort| {
+            compute_market_listing(MarketFilter::default(), None, Some(sort), 1_400_000)
+                .items
+                .iter()
+                .map(|listing| listing.id)
+                .collect::<Vec<u64>>()
+        };
+        assert_eq!(ids(SortBy::Newest), vec![third, second, first]);
+        assert_eq!(ids(SortBy::ClosingSoonest), vec![first, third, second]);
+        assert_eq!(ids(SortBy::HighestVolume), vec![second, third, first]);
+        assert_eq!(ids(SortBy::MostLiquid), vec![third, second, first]);
+        assert_eq!(ids(SortBy::BiggestMove24h), vec![second, third, first]);
+
+        // Sorted pages are offsets into the order
+        let page = compute_market_listing(
+            MarketFilter::default(),
+            Some(1),
+            Some(SortBy::Newest),
+            1_400_000,
+        );
+        assert_eq!(page.items[0].id, second);
+    }
+
+    #[test]
+    fn test_list_markets_pages_by_offset_with_or_without_sort() {
+        let first = setup_test_market();
+        let template = MARKETS.with(|m| m.borrow()[&first].clone());
+        let last = first + MAX_LIST_RESULTS as u64 + 5;
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            for id in first + 1..=last {
+                markets.insert(
+                    id,
+                    AmmMarket {
+                        id,
+                        creation_time: template.creation_time + id,
+                        ..template.clone()
+                    },
+                );
+            }
+        });
+        let walk = |sort: Option<SortBy>| {
+            let mut ids = Vec::new();
+            let mut offset = None;
+            loop {
+                let page = compute_market_listing(MarketFilter::default(), offset, sort.clone(), 0);
+                ids.extend(page.items.iter().map(|listing| listing.id));
+                match page.next_cursor {
+                    Some(next) => offset = Some(next),
+                    None => return ids,
+                }
+            }
+        };
+
+        // Following next_cursor visits every market once, in the requested order
+        assert_eq!(walk(None), (first..=last).collect::<Vec<u64>>());
+        assert_eq!(
+            walk(Some(SortBy::Newest)),
+            (first..=last).rev().collect::<Vec<u64>>()
+        );
+        let second_page = compute_market_listing(
+            MarketFilter::default(),
+            Some(MAX_LIST_RESULTS as u64),
+            Some(SortBy::Newest),
+            0,
+        );
+        assert_eq!(second_page.items.len(), 6);
+        assert_eq!(second_page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_market_metadata_is_validated_and_stored() {
+        setup_test_market();
+        let creator = test_principal(2);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let new_market = |metadata| NewMarket {
+            title: "Will it snow in Paris on New Year's Day?".to_string(),
+            description: "Per Meteo-France".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 3_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: Some(metadata),
+        };
+        let metadata = MarketMetadata {
+            image_url: Some(" https://example.com/snow.png ".to_string()),
+            rules: Some("  ".to_string()),
+            links: vec!["https://meteofrance.com".to_string()],
+        };
+        let market_id = process_create_market(creator, new_market(metadata), 1_000_000).unwrap();
+        let stored = MARKETS
+            .with(|m| m.borrow()[&market_id].metadata.clone())
+            .unwrap();
+        assert_eq!(
+            stored.image_url.as_deref(),
+            Some("https://example.com/snow.png")
+        );
+        assert_eq!(stored.rules, None);
+
+        let rejected = [
+            MarketMetadata {
+                image_url: Some("http://example.com/snow.png".to_string()),
+                ..Default::default()
+            },
+            MarketMetadata {
+                rules: Some("x".repeat(MAX_MARKET_RULES_LEN + 1)),
+                ..Default::default()
+            },
+            MarketMetadata {
+                links: vec!["https://example.com".to_string(); MAX_MARKET_LINKS + 1],
+                ..Default::default()
+            },
+        ];
+        for metadata in rejected {
+            assert!(matches!(
+                process_create_market(creator, new_market(metadata), 1_000_000),
+                Err(PredictionMarketError::InvalidInput { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_market_text_is_cleaned_and_bounded() {
+        setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let new_market = |title: &str, description: &str| NewMarket {
+            title: title.to_string(),
+            description: description.to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 3_000_000,
+            category: None,
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+        let field_of = |result: Result<u64, PredictionMarketError>| match result {
+            Err(PredictionMarketError::InvalidInput { field, .. }) => field,
+            other => panic!("expected InvalidInput, got {:?}", other),
+        };
+
+        let market_id = process_create_market(
+            creator,
+            new_market(" Rain\u{7}\u{0} tomorrow? ", "Line one\nLine\u{1b} two"),
+            1_000_000,
+        )
+        .unwrap();
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.title, "Rain tomorrow?");
+        assert_eq!(market.description, "Line one\nLine two");
+
+        let blank = process_create_market(creator, new_market(" \t\n ", "Details"), 1_000_000);
+        assert_eq!(field_of(blank), "title");
+        let long = "x".repeat(DEFAULT_MAX_TITLE_LEN as usize + 1);
+        let too_long = process_create_market(creator, new_market(&long, "Details"), 1_000_000);
+        assert_eq!(field_of(too_long), "title");
+
+        let limits = TextLimits {
+            min_description_len: 20,
+            ..TextLimits::default()
+        };
+        assert!(matches!(
+            process_set_text_limits(creator, limits.clone(), 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        process_set_text_limits(admin, limits, 10).unwrap();
+        let short = process_create_market(creator, new_market("Rain?", "Too short"), 1_000_000);
+        assert_eq!(field_of(short), "description");
+        let inverted = TextLimits {
+            min_title_len: 300,
+            ..TextLimits::default()
+        };
+        assert!(process_set_text_limits(admin, inverted, 10).is_err());
+    }
+
+    #[test]
+    fn test_update_market_until_first_trade() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        assert!(matches!(
+            process_update_market(test_principal(3), market_id, None, None, None, 1_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            process_update_market(creator, market_id, None, None, Some(1_000), 1_000),
+            Err(PredictionMarketError::InvalidCloseTime)
+        ));
+
+        process_update_market(
+            creator,
+            market_id,
+            Some("Will it rain in Paris tomorrow?".to_string()),
+            None,
+            Some(3_000_000),
+            1_000,
+        )
+        .unwrap();
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.title, "Will it rain in Paris tomorrow?");
+        assert_eq!(market.close_time, 3_000_000);
+
+        record_trade(test_trade(
+            market_id,
+            test_principal(3),
+            OrderSide::Buy,
+            2_000,
+        ));
+        assert!(matches!(
+            process_update_market(
+                creator,
+                market_id,
+                Some("Typo".to_string()),
+                None,
+                None,
+                3_000
+            ),
+            Err(PredictionMarketError::MarketAlreadyTraded)
+        ));
+    }
+
+    /// Per-market state an untraded market can carry besides its pool
+    fn attach_market_side_state(market_id: u64) {
+        MARKET_ORACLES.with(|o| {
+            o.borrow_mut().insert(
+                market_id,
+                OracleStatus {
+                    market_id,
+                    config: OracleConfig {
+                        url: "https://example.com/result".to_string(),
+                        json_path: "result".to_string(),
+                        reading: OracleReading::Outcome,
+                    },
+                    attempts: 0,
+                    next_poll_at: 2_000_000,
+                    last_error: None,
+                },
+            )
+        });
+        let asset = |symbol: &str| XrcAsset {
+            symbol: symbol.to_string(),
+            class: XrcAssetClass::Cryptocurrency,
+        };
+        PRICE_FEEDS.with(|f| {
+            f.borrow_mut().insert(
+                market_id,
+                PriceFeed {
+                    market_id,
+                    base_asset: asset("ICP"),
+                    quote_asset: asset("USDT"),
+                    attempts: 0,
+                    next_poll_at: 2_000_000,
+                    last_error: None,
+                    evidence: None,
+                },
+            )
+        });
+        register_lifecycle_hook(
+            test_principal(2),
+            market_id,
+            Some(CallbackTarget::Canister {
+                canister_id: test_principal(9),
+                method: "on_market".to_string(),
+            }),
+            vec![LifecycleStage::Resolved],
+        )
+        .unwrap();
+        WEBHOOK_SECRETS.with(|s| s.borrow_mut().insert(market_id, "secret".to_string()));
+        DISPUTE_WINDOWS.with(|w| {
+            w.borrow_mut().insert(
+                market_id,
+                DisputeWindow {
+                    market_id,
+                    closes_at: 0,
+                    dispute: None,
+                    closed: false,
+                },
+            )
+        });
+        RESOLUTION_DRAFTS.with(|d| {
+            d.borrow_mut().insert(
+                market_id,
+                ResolutionDraft {
+                    market_id,
+                    outcome: None,
+                    reasoning: "Inconclusive".to_string(),
+                    evidence: None,
+                    requested_by: test_principal(2),
+                    created_at: 0,
+                    status: DraftStatus::Pending,
+                    reviewed_at: None,
+                },
+            )
+        });
+        TRADE_HISTORY.with(|h| h.borrow_mut().insert(market_id, Vec::new()));
+    }
+
+    #[test]
+    fn test_delete_untraded_market_refunds_seed() {
+        let market_id = setup_test_market();
+        let creator = test_principal(2);
+        let balance_before = USER_BALANCES.with(|b| b.borrow()[&creator]);
+        assert!(matches!(
+            process_delete_market(test_principal(3), market_id, 1_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        let refunded = process_delete_market(creator, market_id, 1_000).unwrap();
+        assert_eq!(refunded, 5_000);
+        assert_eq!(
+            USER_BALANCES.with(|b| b.borrow()[&creator]),
+            balance_before + 5_000
+        );
+        assert!(MARKETS.with(|m| m.borrow().get(&market_id).is_none()));
+        assert!(LP_ACCOUNTS.with(|a| a.borrow().keys().all(|(_, id)| *id != market_id)));
+
+        // Oracles, hooks, dispute and draft state go with the market instead of outliving it
+        let market_id = setup_test_market();
+        attach_market_side_state(market_id);
+        process_delete_market(creator, market_id, 1_000).unwrap();
+        assert!(MARKET_ORACLES.with(|o| !o.borrow().contains_key(&market_id)));
+        assert!(PRICE_FEEDS.with(|f| !f.borrow().contains_key(&market_id)));
+        assert!(LIFECYCLE_HOOKS.with(|h| !h.borrow().contains_key(&market_id)));
+        assert!(WEBHOOK_SECRETS.with(|s| !s.borrow().contains_key(&market_id)));
+        assert!(DISPUTE_WINDOWS.with(|w| !w.borrow().contains_key(&market_id)));
+        assert!(RESOLUTION_DRAFTS.with(|d| !d.borrow().contains_key(&market_id)));
+        assert!(TRADE_HISTORY.with(|h| !h.borrow().contains_key(&market_id)));
+
+        // A traded market stays
+        let market_id = setup_test_market();
+        record_trade(test_trade(
+            market_id,
+            test_principal(3),
+            OrderSide::Buy,
+            500,
+        ));
+        assert!(matches!(
+            process_delete_market(test_principal(1), market_id, 1_000),
+            Err(PredictionMarketError::MarketAlreadyTraded)
+        ));
+    }
+
+    #[test]
+    fn test_category_registry_gates_new_markets() {
+        setup_test_market();
+        let admin = test_principal(1);
+        let creator = test_principal(2);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(creator, 10 * MIN_DEPOSIT));
+        let new_market = |category: &str| NewMarket {
+            title: "Rain tomorrow?".to_string(),
+            description: "Details".to_string(),
+            initial_icp_liquidity: MIN_DEPOSIT,
+            close_time: 3_000_000,
+            category: Some(category.to_string()),
+            resolution_criteria: None,
+            resolution_mode: None,
+            resolver: None,
+            resolution_source: None,
+            metadata: None,
+        };
+
+        // Free-form until the admin registers the first category
+        process_create_market(creator, new_market("Anything"), 1_000_000).unwrap();
+        assert!(matches!(
+            process_add_category(creator, "Weather".to_string(), 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        let weather = process_add_category(admin, " Weather ".to_string(), 10).unwrap();
+        assert_eq!(weather.slug, "weather");
+        assert!(process_add_category(admin, "WEATHER".to_string(), 10).is_err());
+
+        let market_id = process_create_market(creator, new_market("WEATHER"), 1_000_000).unwrap();
+        let market = MARKETS.with(|m| m.borrow()[&market_id].clone());
+        assert_eq!(market.category.as_deref(), Some("weather"));
+        assert!(matches!(
+            process_create_market(creator, new_market("Anything"), 1_000_000),
+            Err(PredictionMarketError::InvalidInput { .. })
+        ));
+
+        let renamed =
+            process_rename_category(admin, "weather".to_string(), "Climate".to_string(), 20)
+                .unwrap();
+        assert_eq!(
+            (renamed.slug.as_str(), renamed.name.as_str()),
+            ("weather", "Climate")
+        );
+        process_archive_category(admin, "weather".to_string(), 30).unwrap();
+        assert!(process_create_market(creator, new_market("weather"), 1_000_000).is_err());
+        assert!(list_categories()[0].archived);
+    }
+
+    #[test]
+    fn test_featured_markets_keep_their_order() {
+        let first = setup_test_market();
+        let admin = test_principal(1);
+        USER_BALANCES.with(|b| b.borrow_mut().insert(test_principal(2), MIN_DEPOSIT));
+        let second = process_create_market(
+            test_principal(2),
+            NewMarket {
+                title: "Snow tomorrow?".to_string(),
+                description: "Details".to_string(),
+                initial_icp_liquidity: MIN_DEPOSIT,
+                close_time: 3_000_000,
+                category: None,
+                resolution_criteria: None,
+                resolution_mode: None,
+                resolver: None,
+                resolution_source: None,
+                metadata: None,
+            },
+            1_000,
+        )
+        .unwrap();
+        assert!(matches!(
+            process_feature_market(test_principal(2), first, 5_000, 1_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(process_feature_market(admin, first, 1_000, 1_000).is_err());
+
+        process_feature_market(admin, second, 5_000, 1_000).unwrap();
+        process_feature_market(admin, first, 9_000, 1_000).unwrap();
+        // Re-featuring extends the expiry without moving the entry
+        process_feature_market(admin, second, 7_000, 1_000).unwrap();
+        let ids = |now| -> Vec<u64> { featured_markets(now).iter().map(|s| s.market.id).collect() };
+        assert_eq!(ids(2_000), vec![second, first]);
+        assert_eq!(ids(8_000), vec![first]);
+
+        process_unfeature_market(admin, first, 2_000).unwrap();
+        assert_eq!(ids(2_000), vec![second]);
+        let snapshot = capture_state();
+        assert_eq!(snapshot.featured_markets.map(|f| f.len()), Some(1));
+    }
+
+    #[test]
+    fn test_resolver_must_accept_and_admin_covers_abandonment() {
+        let market_id = setup_test_market();
+        let (admin, creator, resolver) = (test_principal(1), test_principal(2), test_principal(5));
+        let resolve = |actor, now| {
+            process_resolve_market(
+                actor,
+                market_id,
+                TokenType::Yes,
+                "Observed".to_string(),
+                now,
+            )
+        };
+        MARKETS.with(|m| m.borrow_mut().get_mut(&market_id).unwrap().admin = creator);
+        assert!(process_set_market_resolver(creator, market_id, creator, 10).is_err());
+        process_set_market_resolver(creator, market_id, resolver, 10).unwrap();
+        assert_eq!(get_pending_resolver(market_id), Some(resolver));
+        assert!(matches!(
+            process_accept_market_resolver(test_principal(3), market_id, 20),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        process_accept_market_resolver(resolver, market_id, 20).unwrap();
+        assert_eq!(get_pending_resolver(market_id), None);
+        assert!(matches!(
+            process_set_market_resolver(creator, market_id, test_principal(6), 30),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            resolve(creator, 1_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            resolve(admin, 1_000),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        // Sitting on a closed market past the grace period hands it to the admin
+        assert!(resolve(admin, 2_000_000 + RESOLVER_GRACE_NS).is_ok());
+
+        let market_id = setup_test_market();
+        let resolve = |actor| {
+            process_resolve_market(
+                actor,
+                market_id,
+                TokenType::No,
+                "Observed".to_string(),
+                1_000,
+            )
+        };
+        process_set_market_resolver(admin, market_id, resolver, 10).unwrap();
+        process_accept_market_resolver(resolver, market_id, 20).unwrap();
+        process_abandon_market_resolver(resolver, market_id, 30).unwrap();
+        assert!(matches!(
+            resolve(resolver),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(matches!(
+            resolve(creator),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(resolve(admin).is_ok());
+    }
+
+    #[test]
+    fn test_sensitive_admin_actions_wait_out_the_timelock() {
+        setup_test_market();
+        let admin = test_principal(1);
+        let recipient = test_principal(4);
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 1_000);
+        let withdraw = |amount| AdminAction::WithdrawTreasury {
+            amount,
+            to: recipient,
+        };
+
+        assert!(matches!(
+            process_schedule_action(recipient, withdraw(500), 10),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert!(process_schedule_action(admin, withdraw(0), 10).is_err());
+        let pending = process_schedule_action(admin, withdraw(500), 10).unwrap();
+        assert_eq!(pending.executable_at, 10 + TIMELOCK_DELAY_NS);
+        assert_eq!(get_pending_actions(), vec![pending.clone()]);
+
+        assert!(matches!(
+            process_execute_action(admin, pending.id, 20),
+            Err(PredictionMarketError::TimelockPending { .. })
+        ));
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 1_000);
+        process_execute_action(admin, pending.id, pending.executable_at).unwrap();
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 500);
+        assert!(get_pending_actions().is_empty());
+
+        // A failing action stays queued until it succeeds or is cancelled
+        let too_much = process_schedule_action(admin, withdraw(900), 30).unwrap();
+        assert!(process_execute_action(admin, too_much.id, too_much.executable_at).is_err());
+        assert_eq!(get_pending_actions().len(), 1);
+        process_cancel_action(admin, too_much.id, 40).unwrap();
+        assert!(matches!(
+            process_execute_action(admin, too_much.id, too_much.executable_at),
+            Err(PredictionMarketError::ActionNotFound)
+        ));
+
+        let fee = process_schedule_action(admin, AdminAction::SetCreationFee { amount: 250 }, 50)
+            .unwrap();
+        process_execute_action(admin, fee.id, fee.executable_at).unwrap();
+        assert_eq!(CREATION_FEE.with(|f| *f.borrow()), 250);
+
+        // Settings that move fees or collateral are checked when queued and applied on execution
+        let limits = |min_trade_size, min_trade_fee| AdminAction::SetTradingLimits {
+            limits: TradingLimits {
+                min_trade_size,
+                min_trade_fee,
+            },
+        };
+        assert!(matches!(
+            process_schedule_action(admin, limits(100, 100), 60),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        let raise = process_schedule_action(admin, limits(1_000, 10), 60).unwrap();
+        assert_eq!(
+            TRADING_LIMITS.with(|l| l.borrow().min_trade_size),
+            TEST_MIN_TRADE_SIZE
+        );
+        process_execute_action(admin, raise.id, raise.executable_at).unwrap();
+        assert_eq!(TRADING_LIMITS.with(|l| l.borrow().min_trade_size), 1_000);
+
+        let holiday = |end_time| AdminAction::AddFeeHoliday {
+            scope: FeeHolidayScope::Market(1),
+            start_time: 70,
+            end_time,
+            reimburse_lps: false,
+        };
+        assert!(matches!(
+            process_schedule_action(admin, holiday(80), 70),
+            Err(PredictionMarketError::InvalidFeeHoliday)
+        ));
+        let holiday = process_schedule_action(admin, holiday(3 * TIMELOCK_DELAY_NS), 70).unwrap();
+        assert!(FEE_HOLIDAYS.with(|h| h.borrow().is_empty()));
+        process_execute_action(admin, holiday.id, holiday.executable_at).unwrap();
+        assert_eq!(FEE_HOLIDAYS.with(|h| h.borrow().len()), 1);
+    }
+
+    #[test]
+    fn test_fee_and_collateral_settings_only_change_through_the_timelock() {
+        let market_id = setup_test_market();
+        let (admin, outsider) = (test_principal(1), test_principal(3));
+        type Applied = fn() -> bool;
+        let cases: Vec<(AdminAction, Applied)> = vec![
+            (
+                AdminAction::SetTradingLimits {
+                    limits: TradingLimits {
+                        min_trade_size: 1_000,
+                        min_trade_fee: 10,
+                    },
+                },
+                || TRADING_LIMITS.with(|l| l.borrow().min_trade_size == 1_000),
+            ),
+            (
+                AdminAction::AddFeeHoliday {
+                    scope: FeeHolidayScope::Market(1),
+                    start_time: 0,
+                    end_time: 10 * TIMELOCK_DELAY_NS,
+                    reimburse_lps: false,
+                },
+                || FEE_HOLIDAYS.with(|h| !h.borrow().is_empty()),
+            ),
+            (
+                AdminAction::SetLiquidityMatching {
+                    matching: LiquidityMatching {
+                        categories: vec!["weather".to_string()],
+                        match_bps: 5_000,
+                        max_match: 1_000,
+                    },
+                },
+                || LIQUIDITY_MATCHING.with(|m| m.borrow().match_bps == 5_000),
+            ),
+            (
+                AdminAction::SetCreatorFeeBps {
+                    market_id,
+                    creator_fee_bps: MAX_CREATOR_FEE_BPS,
+                },
+                || MARKETS.with(|m| m.borrow()[&1].creator_fee_bps == MAX_CREATOR_FEE_BPS),
+            ),
+            (
+                AdminAction::SetDisputeConfig {
+                    config: DisputeConfig {
+                        window_ns: 5,
+                        bond: 7,
+                    },
+                },
+                || DISPUTE_CONFIG.with(|c| c.borrow().bond == 7),
+            ),
+            (
+                AdminAction::SetCreatorBondConfig {
+                    config: CreatorBondConfig {
+                        amount: 9,
+                        slash_bps: 100,
+                    },
+                },
+                || CREATOR_BOND_CONFIG.with(|c| c.borrow().amount == 9),
+            ),
+            (
+                AdminAction::SetEarlyExitHaircut {
+                    market_id,
+                    schedule: vec![HaircutTier {
+                        within_ns: 1_000,
+                        haircut_bps: MAX_EXIT_HAIRCUT_BPS,
+                    }],
+                },
+                || MARKETS.with(|m| m.borrow()[&1].early_exit_haircut.len() == 1),
+            ),
+            (
+                AdminAction::SetEdgePolicies {
+                    policies: EdgePolicies {
+                        untraded_at_close: EdgePolicy::TreasurySweep,
+                        no_winning_supply: EdgePolicy::TreasurySweep,
+                        extension_ns: 5,
+                    },
+                },
+                || {
+                    EDGE_POLICIES
+                        .with(|p| p.borrow().no_winning_supply == EdgePolicy::TreasurySweep)
+                },
+            ),
+            (
+                AdminAction::SetExposureLimits {
+                    limits: ExposureLimits {
+                        max_trade_icp: Some(1_000),
+                        ..Default::default()
+                    },
+                },
+                || EXPOSURE_LIMITS.with(|l| l.borrow().max_trade_icp == Some(1_000)),
+            ),
+        ];
+
+        for (action, applied) in cases {
+            assert!(matches!(
+                process_schedule_action(outsider, action.clone(), 100),
+                Err(PredictionMarketError::Unauthorized)
+            ));
+            let pending = process_schedule_action(admin, action.clone(), 100).unwrap();
+            assert!(!applied(), "{:?} applied when scheduled", action);
+            assert!(matches!(
+                process_execute_action(admin, pending.id, pending.executable_at - 1),
+                Err(PredictionMarketError::TimelockPending { .. })
+            ));
+            assert!(matches!(
+                process_execute_action(outsider, pending.id, pending.executable_at),
+                Err(PredictionMarketError::Unauthorized)
+            ));
+            assert!(!applied(), "{:?} applied before the delay", action);
+            process_execute_action(admin, pending.id, pending.executable_at).unwrap();
+            assert!(applied(), "{:?} not applied after the delay", action);
+        }
+
+        // Invalid settings are refused when queued, not when they come due
+        let invalid = [
+            AdminAction::SetEarlyExitHaircut {
+                market_id,
+                schedule: vec![HaircutTier {
+                    within_ns: 1,
+                    haircut_bps: MAX_EXIT_HAIRCUT_BPS + 1,
+                }],
+            },
+            AdminAction::SetEdgePolicies {
+                policies: EdgePolicies {
+                    untraded_at_close: EdgePolicy::Refund,
+                    no_winning_supply: EdgePolicy::ExtendMarket,
+                    extension_ns: 5,
+                },
+            },
+            AdminAction::SetExposureLimits {
+                limits: ExposureLimits {
+                    max_pool_size: Some(0),
+                    ..Default::default()
+                },
+            },
+        ];
+        for action in invalid {
+            assert!(matches!(
+                process_schedule_action(admin, action, 200),
+                Err(PredictionMarketError::InvalidAmount)
+            ));
+        }
+        assert!(get_pending_actions().is_empty());
+    }
+
+    #[test]
+    fn test_lp_withdrawal_leaves_trader_collateral_for_winners() {
+        let market_id = setup_test_market();
+        let (admin, creator, trader) = (test_principal(1), test_principal(2), test_principal(3));
+        // 20_000 of buys on top of the 5_000 seed
+        adjust_contribution(trader, market_id, 20_000, 0);
+        MARKETS.with(|m| {
+            m.borrow_mut()
+                .get_mut(&market_id)
+                .unwrap()
+                .icp_liquidity_pool = 25_000
+        });
+        USER_POSITIONS.with(|p| {
+            p.borrow_mut().insert(
+                (trader, market_id),
+                UserPosition {
+                    user: trader,
+                    market_id,
+                    yes_tokens: 30_000,
+                    no_tokens: 0,
+                    claimed_reward: false,
+                },
+            )
+        });
+
+        let icp_out = process_remove_liquidity(creator, market_id, 4_990, 1_000).unwrap();
+        assert_eq!(icp_out, 4_990);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            20_010
+        );
+
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::Yes),
+            admin,
+            1_500_000,
+        )
+        .unwrap();
+        let window_end = 1_500_000 + DEFAULT_DISPUTE_WINDOW_NS;
+        close_dispute_windows(window_end);
+        let claim = process_reward_claim(trader, market_id, window_end).unwrap();
+        assert!(claim.reward_amount >= 20_000);
+    }
+
+    #[test]
+    fn test_escrowed_winners_keep_their_share_of_the_pool() {
+        let market_id = setup_test_market();
+        let admin = test_principal(1);
+        let (claimer, escrower) = (test_principal(3), test_principal(4));
+        let position = |user, yes_tokens| UserPosition {
+            user,
+            market_id,
+            yes_tokens,
+            no_tokens: 0,
+            claimed_reward: false,
+        };
+        USER_POSITIONS.with(|p| {
+            let mut positions = p.borrow_mut();
+            positions.insert((claimer, market_id), position(claimer, 600));
+            positions.insert((escrower, market_id), position(escrower, 0));
+        });
+        let escrowed = SwapAsset::Tokens {
+            market_id,
+            token_type: TokenType::Yes,
+            amount: 200,
+        };
+        SWAP_OFFERS.with(|o| {
+            o.borrow_mut().insert(
+                1,
+                SwapOffer {
+                    id: 1,
+                    maker: escrower,
+                    give: escrowed,
+                    want: SwapAsset::Icp { amount: 150 },
+                    status: SwapOfferStatus::Open,
+                    created_at: 0,
+                },
+            )
         });
-
-        let market_id = NEXT_MARKET_ID.with(|id| {
-            let current_id = *id.borrow();
-            *id.borrow_mut() = current_id + 1;
-            current_id
+        LIMIT_ORDERS.with(|o| {
+            o.borrow_mut().insert(
+                1,
+                LimitOrder {
+                    id: 1,
+                    owner: escrower,
+                    market_id,
+                    token_type: TokenType::Yes,
+                    side: OrderSide::Sell,
+                    limit_price: 0.9,
+                    amount: 200,
+                    status: OrderStatus::Open,
+                    created_at: 0,
+                },
+            )
         });
+        assert_eq!(winning_supply(market_id, &TokenType::Yes), 1_000);
 
-        let market = AmmMarket {
-            id: market_id,
-            title: "Test Market".to_string(),
-            description: "A test prediction market".to_string(),
-            yes_reserve: INITIAL_LIQUIDITY,
-            no_reserve: INITIAL_LIQUIDITY,
-            icp_liquidity_pool: 5000,
-            status: MarketStatus::Open,
-            winning_outcome: None,
-            creator,
+        transition_resolution(
+            market_id,
+            0,
+            ResolutionTransition::Resolve(TokenType::Yes),
             admin,
-            total_fees_collected: 0,
-            creation_time: 1000000,
-        };
+            10,
+        )
+        .unwrap();
+        let window_end = 10 + DEFAULT_DISPUTE_WINDOW_NS;
+        close_dispute_windows(window_end);
 
-        MARKETS.with(|markets| {
-            markets.borrow_mut().insert(market_id, market);
-        });
+        // The early claimer is paid for their 600 of 1_000 tokens, not the whole pool
+        let claim = process_reward_claim(claimer, market_id, window_end).unwrap();
+        assert_eq!(claim.reward_amount, 3_000);
 
-        market_id
+        // Escrow still comes back after resolution, and is paid against what is left
+        process_cancel_swap_offer(escrower, 1, window_end).unwrap();
+        let claim = process_reward_claim(escrower, market_id, window_end).unwrap();
+        assert_eq!(claim.reward_amount, 1_000);
+        assert_eq!(
+            MARKETS.with(|m| m.borrow()[&market_id].icp_liquidity_pool),
+            1_000
+        );
+    }
+
+    fn consent_request(method: &str, arg: Vec<u8>) -> ConsentMessageRequest {
+        ConsentMessageRequest {
+            method: method.to_string(),
+            arg,
+            user_preferences: ConsentMessageSpec {
+                metadata: ConsentMessageMetadata {
+                    language: "en".to_string(),
+                    utc_offset_minutes: None,
+                },
+                device_spec: None,
+            },
+        }
     }
 
     #[test]
-    fn test_amm_price_calculation() {
-        reset_state();
+    fn test_consent_message_describes_buy_with_slippage() {
         let market_id = setup_test_market();
-
-        // Test initial pricing - should be 0.5 for both tokens
-        let yes_price = get_token_price(market_id, TokenType::Yes).unwrap();
-        let no_price = get_token_price(market_id, TokenType::No).unwrap();
-
-        assert_eq!(yes_price, 0.5);
-        assert_eq!(no_price, 0.5);
-
-        // Test pricing with unequal reserves
-        MARKETS.with(|markets| {
-            let mut markets_map = markets.borrow_mut();
-            let market = markets_map.get_mut(&market_id).unwrap();
-            market.yes_reserve = 300; // Less YES tokens
-            market.no_reserve = 700; // More NO tokens
+        MARKETS.with(|m| {
+            let mut markets = m.borrow_mut();
+            let market = markets.get_mut(&market_id).unwrap();
+            market.yes_reserve = 100 * E8S_PER_ICP;
+            market.no_reserve = 100 * E8S_PER_ICP;
+            market.icp_liquidity_pool = 100 * E8S_PER_ICP;
         });
+        let quote = compute_buy_quote(market_id, 500_000_000, TokenType::Yes, 1_500_000).unwrap();
+        // A minimum 2% under the quote
+        let min_tokens_out = quote.tokens_received - quote.tokens_received.div_ceil(50);
+        let arg = candid::encode_args((market_id, 500_000_000u64, min_tokens_out)).unwrap();
+        let info =
+            build_consent_message(consent_request("buy_yes_tokens", arg), 1_500_000).unwrap();
+        assert_eq!(
+            info.consent_message,
+            ConsentMessage::GenericDisplayMessage(format!(
+                "Buy ~{} YES tokens on market #{} for 5 ICP, max slippage 2%",
+                format_token_amount(quote.tokens_received),
+                market_id
+            ))
+        );
+        assert_eq!(info.metadata.language, "en");
 
-        let new_yes_price = get_token_price(market_id, TokenType::Yes).unwrap();
-        let new_no_price = get_token_price(market_id, TokenType::No).unwrap();
-
-        // YES tokens should be more expensive (higher price) due to scarcity
-        assert!(new_yes_price > 0.5);
-        assert!(new_no_price < 0.5);
-        assert!((new_yes_price + new_no_price - 1.0).abs() < 0.001); // Should sum to 1
+        // The unified endpoint reads the same as its shim
+        let arg = candid::encode_args((
+            market_id,
+            OrderSide::Buy,
+            TokenType::Yes,
+            500_000_000u64,
+            min_tokens_out,
+            None::<u64>,
+        ))
+        .unwrap();
+        let unified = build_consent_message(consent_request("trade", arg), 1_500_000).unwrap();
+        assert_eq!(unified.consent_message, info.consent_message);
     }
 
     #[test]
-    fn test_token_calculation_amm_formula() {
-        reset_state();
-        setup_test_market();
+    fn test_consent_message_rejects_unknown_calls() {
+        let market_id = setup_test_market();
+        let arg = candid::encode_args((market_id,)).unwrap();
+        assert!(matches!(
+            build_consent_message(consent_request("withdraw_treasury", arg.clone()), 1_500_000),
+            Err(ConsentError::UnsupportedCanisterCall(_))
+        ));
+        // Arguments that do not decode as the method's parameters
+        assert!(matches!(
+            build_consent_message(consent_request("sell_no_tokens", arg.clone()), 1_500_000),
+            Err(ConsentError::UnsupportedCanisterCall(_))
+        ));
+        assert!(build_consent_message(consent_request("claim_reward", arg), 1_500_000).is_ok());
+    }
 
-        let yes_reserve = 1000u64;
-        let no_reserve = 1000u64;
-        let icp_in = 100u64;
+    #[test]
+    fn test_consent_message_line_display_pages() {
+        let pages = paginate_consent_text(
+            "Claim your winnings on market #7 and burn your winning tokens",
+            20,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            pages,
+            vec![
+                ConsentMessagePage {
+                    lines: vec![
+                        "Claim your winnings".to_string(),
+                        "on market #7 and".to_string()
+                    ],
+                },
+                ConsentMessagePage {
+                    lines: vec!["burn your winning".to_string(), "tokens".to_string()],
+                },
+            ]
+        );
+        assert!(paginate_consent_text("text", 0, 2).is_err());
+    }
 
-        // Test buying YES tokens
-        let tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, true).unwrap();
+    #[test]
+    fn test_price_trigger_direction() {
+        // Stop-losses fire once the price has fallen to the trigger
+        assert!(price_trigger_fired(&TriggerAction::StopLoss, 0.3, 0.3));
+        assert!(price_trigger_fired(&TriggerAction::StopLoss, 0.3, 0.2));
+        assert!(!price_trigger_fired(&TriggerAction::StopLoss, 0.3, 0.5));
 
-        // Calculate expected result manually
-        let icp_after_fee = (icp_in * (1000 - TRADE_FEE)) / 1000;
-        let k = yes_reserve * no_reserve;
-        let new_no_reserve = no_reserve - icp_after_fee;
-        let expected_new_yes_reserve = k / new_no_reserve;
-        let expected_tokens_out = expected_new_yes_reserve - yes_reserve;
+        // Take-profits fire once the price has risen to the trigger
+        assert!(price_trigger_fired(&TriggerAction::TakeProfit, 0.8, 0.8));
+        assert!(price_trigger_fired(&TriggerAction::TakeProfit, 0.8, 0.9));
+        assert!(!price_trigger_fired(&TriggerAction::TakeProfit, 0.8, 0.5));
+    }
 
-        assert_eq!(tokens_out, expected_tokens_out);
+    #[test]
+    fn test_triggers_cancelled_once_market_stops_trading() {
+        let market_id = setup_test_market();
+        let trader = test_principal(3);
+        PRICE_TRIGGERS.with(|triggers| {
+            triggers.borrow_mut().insert(
+                1,
+                PriceTrigger {
+                    id: 1,
+                    owner: trader,
+                    market_id,
+                    token_type: TokenType::Yes,
+                    trigger_price: 0.4,
+                    action: TriggerAction::StopLoss,
+                    status: TriggerStatus::Active,
+                    created_at: 0,
+                },
+            );
+        });
+        assert!(has_active_triggers());
 
-        // Test buying NO tokens
-        let no_tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, false).unwrap();
-        let new_yes_reserve = yes_reserve - icp_after_fee;
-        let expected_new_no_reserve = k / new_yes_reserve;
-        let expected_no_tokens_out = expected_new_no_reserve - no_reserve;
+        let close_time = MARKETS.with(|m| m.borrow()[&market_id].close_time);
+        evaluate_price_triggers(close_time);
 
-        assert_eq!(no_tokens_out, expected_no_tokens_out);
+        let status = PRICE_TRIGGERS.with(|t| t.borrow()[&1].status.clone());
+        assert_eq!(status, TriggerStatus::Cancelled);
+        assert!(!has_active_triggers());
     }
 
     #[test]
-    fn test_reward_distribution_calculation() {
+    fn test_wind_down_push_settles_winners_and_escrow() {
         reset_state();
         let market_id = setup_test_market();
-        let user1 = test_principal(3);
-        let user2 = test_principal(4);
-        let user3 = test_principal(5);
+        let winner = test_principal(3);
+        let maker = test_principal(4);
 
-        // Set up user positions manually for testing
         USER_POSITIONS.with(|positions| {
-            let mut positions_map = positions.borrow_mut();
-
-            // User1 has 300 YES tokens
-            positions_map.insert(
-                (user1, market_id),
-                UserPosition {
-                    user: user1,
-                    market_id,
-                    yes_tokens: 300,
-                    no_tokens: 0,
-                    claimed_reward: false,
-                },
-            );
-
-            // User2 has 200 YES tokens
-            positions_map.insert(
-                (user2, market_id),
+            positions.borrow_mut().insert(
+                (winner, market_id),
                 UserPosition {
-                    user: user2,
+                    user: winner,
                     market_id,
-                    yes_tokens: 200,
+                    yes_tokens: 100,
                     no_tokens: 0,
                     claimed_reward: false,
                 },
             );
-
-            // User3 has 100 NO tokens (will lose)
-            positions_map.insert(
-                (user3, market_id),
-                UserPosition {
-                    user: user3,
-                    market_id,
-                    yes_tokens: 0,
-                    no_tokens: 100,
-                    claimed_reward: false,
+        });
+        SWAP_OFFERS.with(|offers| {
+            offers.borrow_mut().insert(
+                1,
+                SwapOffer {
+                    id: 1,
+                    maker,
+                    give: SwapAsset::Icp { amount: 50 },
+                    want: SwapAsset::Icp { amount: 60 },
+                    status: SwapOfferStatus::Open,
+                    created_at: 0,
                 },
             );
         });
-
-        // Resolve market with YES outcome
         MARKETS.with(|markets| {
             let mut markets_map = markets.borrow_mut();
             let market = markets_map.get_mut(&market_id).unwrap();
@@ -1375,121 +20651,284 @@ mod tests {
             market.winning_outcome = Some(TokenType::Yes);
         });
 
-        // Test reward calculation
-        let total_winning_tokens = 300 + 200; // Only YES tokens
-        let total_icp_pool = 5000;
+        let before = build_wind_down_report(0);
+        assert_eq!(before.unclaimed_winning_positions, 1);
+        assert_eq!(before.open_swap_offers, 1);
 
-        // User1 should get (300/500) * 5000 = 3000 ICP
-        let expected_user1_reward = (300u128 * 5000u128 / total_winning_tokens as u128) as u64;
+        let summary = push_settle_all(0);
+        assert_eq!(summary.claims_paid, 1);
+        assert_eq!(summary.offers_cancelled, 1);
+        assert_eq!(get_balance_of(winner), 5000);
+        assert_eq!(get_balance_of(maker), 50);
 
-        // User2 should get (200/500) * 5000 = 2000 ICP
-        let expected_user2_reward = (200u128 * 5000u128 / total_winning_tokens as u128) as u64;
+        let after = build_wind_down_report(0);
+        assert_eq!(after.unclaimed_winning_positions, 0);
+        assert_eq!(after.open_swap_offers, 0);
+        assert_eq!(after.resolved_markets, 1);
+    }
 
-        assert_eq!(expected_user1_reward, 3000);
-        assert_eq!(expected_user2_reward, 2000);
+    #[test]
+    fn test_paginate_caps_results_and_resumes_from_cursor() {
+        let keyed: Vec<(u64, u64)> = (0..(MAX_LIST_RESULTS as u64 + 5))
+            .rev()
+            .map(|key| (key * 2, key))
+            .collect();
 
-        // Verify total rewards equal the liquidity pool
-        assert_eq!(
-            expected_user1_reward + expected_user2_reward,
-            total_icp_pool
-        );
+        let first = paginate(keyed.clone(), None);
+        assert_eq!(first.items.len(), MAX_LIST_RESULTS);
+        assert!(first.truncated);
+        assert_eq!(first.items[0], 0);
+        assert_eq!(first.next_cursor, Some(MAX_LIST_RESULTS as u64 * 2));
+
+        let second = paginate(keyed, first.next_cursor);
+        assert_eq!(second.items.len(), 5);
+        assert!(!second.truncated);
+        assert_eq!(second.next_cursor, None);
+        assert_eq!(second.items[0], MAX_LIST_RESULTS as u64);
     }
 
     #[test]
-    fn test_double_claim_protection() {
+    fn test_swap_escrow_debits_only_when_held() {
         reset_state();
         let market_id = setup_test_market();
-        let user = test_principal(3);
+        let maker = test_principal(3);
 
-        // Set up user position
-        USER_POSITIONS.with(|positions| {
-            positions.borrow_mut().insert(
-                (user, market_id),
-                UserPosition {
-                    user,
-                    market_id,
-                    yes_tokens: 100,
-                    no_tokens: 0,
-                    claimed_reward: false,
+        USER_BALANCES.with(|b| b.borrow_mut().insert(maker, 100));
+        let tokens = SwapAsset::Tokens {
+            market_id,
+            token_type: TokenType::Yes,
+            amount: 40,
+        };
+
+        // No position yet: escrow fails and nothing changes
+        assert!(debit_swap_asset(maker, &tokens).is_err());
+
+        credit_swap_asset(maker, &tokens);
+        assert!(debit_swap_asset(maker, &tokens).is_ok());
+        assert!(debit_swap_asset(maker, &tokens).is_err());
+        let position = USER_POSITIONS.with(|p| p.borrow().get(&(maker, market_id)).cloned());
+        assert_eq!(position.unwrap().yes_tokens, 0);
+
+        // ICP legs check the internal balance
+        assert!(debit_swap_asset(maker, &SwapAsset::Icp { amount: 101 }).is_err());
+        assert!(debit_swap_asset(maker, &SwapAsset::Icp { amount: 100 }).is_ok());
+        assert_eq!(USER_BALANCES.with(|b| *b.borrow().get(&maker).unwrap()), 0);
+    }
+
+    #[test]
+    fn test_airdrop_processes_in_resumable_chunks() {
+        reset_state();
+        let admin = test_principal(1);
+        let recipients: Vec<(Principal, u64)> = (0..AIRDROP_CHUNK_SIZE + 10)
+            .map(|i| (Principal::from_slice(&(i as u32).to_be_bytes()), 10))
+            .collect();
+
+        AIRDROP_CAMPAIGNS.with(|campaigns| {
+            campaigns.borrow_mut().insert(
+                1,
+                AirdropCampaign {
+                    id: 1,
+                    created_by: admin,
+                    total_amount: 10 * recipients.len() as u64,
+                    recipients: recipients.clone(),
+                    next_index: 0,
+                    created_at: 0,
                 },
             );
         });
 
-        // Resolve market
-        MARKETS.with(|markets| {
-            let mut markets_map = markets.borrow_mut();
-            let market = markets_map.get_mut(&market_id).unwrap();
-            market.status = MarketStatus::Resolved;
-            market.winning_outcome = Some(TokenType::Yes);
-        });
+        let first = process_airdrop_chunk(1, 0);
+        assert_eq!(first.processed, AIRDROP_CHUNK_SIZE as u64);
+        assert!(!first.completed);
 
-        // Mark as already claimed
-        USER_POSITIONS.with(|positions| {
-            positions
-                .borrow_mut()
-                .get_mut(&(user, market_id))
-                .unwrap()
-                .claimed_reward = true;
-        });
+        let second = process_airdrop_chunk(1, 0);
+        assert_eq!(second.processed, recipients.len() as u64);
+        assert!(second.completed);
 
-        // Test that double claim is detected
-        let (_, already_claimed) = USER_POSITIONS.with(|positions| {
-            let positions_map = positions.borrow();
-            if let Some(position) = positions_map.get(&(user, market_id)) {
-                (position.yes_tokens, position.claimed_reward)
-            } else {
-                (0, false)
-            }
-        });
+        // Every recipient is credited exactly once and has a record
+        for (recipient, amount) in &recipients {
+            assert_eq!(get_balance_of(*recipient), *amount);
+        }
+        assert_eq!(AIRDROP_RECORDS.with(|r| r.borrow().len()), recipients.len());
 
-        assert!(
-            already_claimed,
-            "Double claim protection should detect already claimed reward"
-        );
+        // Resuming a finished campaign is a no-op
+        assert!(process_airdrop_chunk(1, 0).completed);
+        assert_eq!(AIRDROP_RECORDS.with(|r| r.borrow().len()), recipients.len());
     }
 
     #[test]
-    fn test_trading_fees_collection() {
+    fn test_airdrop_is_paid_from_the_treasury_after_the_timelock() {
         reset_state();
-        setup_test_market();
+        let admin = test_principal(1);
+        let (alice, bob) = (test_principal(3), test_principal(4));
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        USER_BALANCES.with(|b| b.borrow_mut().insert(admin, 1_000));
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 500);
+        let (first, second, third) = (test_principal(5), test_principal(6), test_principal(7));
+        AIRDROP_QUORUM.with(|q| {
+            *q.borrow_mut() = ApprovalQuorum {
+                approvers: vec![first, second, third],
+                threshold: 2,
+            }
+        });
+        let airdrop = |amount| AdminAction::Airdrop {
+            recipients: vec![(alice, amount), (bob, 100)],
+        };
 
-        let yes_reserve = 1000u64;
-        let no_reserve = 1000u64;
-        let icp_in = 1000u64; // Large trade to make fee calculation clear
+        assert!(matches!(
+            process_schedule_action(admin, airdrop(0), 10),
+            Err(PredictionMarketError::InvalidAmount)
+        ));
+        let too_large = process_schedule_action(admin, airdrop(450), 10).unwrap();
+        process_approve_action(first, too_large.id, 11).unwrap();
+        process_approve_action(second, too_large.id, 11).unwrap();
+        assert!(matches!(
+            process_execute_action(admin, too_large.id, too_large.executable_at),
+            Err(PredictionMarketError::InsufficientLiquidity { max_trade: 500 })
+        ));
+        process_cancel_action(admin, too_large.id, 20).unwrap();
 
-        // Calculate expected fee
-        let expected_fee = (icp_in * TRADE_FEE) / 1000;
-        let icp_after_fee = icp_in - expected_fee;
+        let pending = process_schedule_action(admin, airdrop(300), 30).unwrap();
+        assert!(matches!(
+            process_execute_action(admin, pending.id, 40),
+            Err(PredictionMarketError::TimelockPending { .. })
+        ));
+        assert_eq!(get_balance_of(alice), 0);
 
-        // Calculate tokens using AMM formula
-        let tokens_out = calculate_tokens_out(yes_reserve, no_reserve, icp_in, true).unwrap();
+        // The timelock alone is not enough: 2 of the 3 approvers must sign off
+        let due = pending.executable_at;
+        assert!(matches!(
+            process_execute_action(admin, pending.id, due),
+            Err(PredictionMarketError::QuorumNotMet {
+                approvals: 0,
+                threshold: 2
+            })
+        ));
+        assert!(matches!(
+            process_approve_action(alice, pending.id, 50),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+        assert_eq!(process_approve_action(first, pending.id, 50).unwrap(), 1);
+        assert!(matches!(
+            process_approve_action(first, pending.id, 60),
+            Err(PredictionMarketError::AlreadyApproved)
+        ));
+        assert!(matches!(
+            process_execute_action(admin, pending.id, due),
+            Err(PredictionMarketError::QuorumNotMet {
+                approvals: 1,
+                threshold: 2
+            })
+        ));
+        assert_eq!(get_balance_of(alice), 0);
+        assert_eq!(process_approve_action(third, pending.id, 70).unwrap(), 2);
 
-        // Verify fee calculation in the formula
-        let k = yes_reserve * no_reserve;
-        let new_no_reserve = no_reserve - icp_after_fee;
-        let new_yes_reserve = k / new_no_reserve;
-        let expected_tokens = new_yes_reserve - yes_reserve;
+        process_execute_action(admin, pending.id, due).unwrap();
+        assert_eq!((get_balance_of(alice), get_balance_of(bob)), (300, 100));
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 100);
+        // The admin's own balance is never touched
+        assert_eq!(get_balance_of(admin), 1_000);
+    }
 
-        assert_eq!(tokens_out, expected_tokens);
-        assert_eq!(expected_fee, 3); // 0.3% of 1000 = 3
+    #[test]
+    fn test_airdrop_quorum_is_required_and_set_through_the_timelock() {
+        reset_state();
+        let admin = test_principal(1);
+        let (first, second) = (test_principal(5), test_principal(6));
+        ADMIN.with(|a| *a.borrow_mut() = Some(admin));
+        TREASURY_BALANCE.with(|t| *t.borrow_mut() = 500);
+        let quorum = |approvers: Vec<Principal>, threshold| AdminAction::SetAirdropQuorum {
+            quorum: ApprovalQuorum {
+                approvers,
+                threshold,
+            },
+        };
+
+        // Without a quorum no airdrop can run, whatever the admin does
+        let airdrop = process_schedule_action(
+            admin,
+            AdminAction::Airdrop {
+                recipients: vec![(test_principal(3), 100)],
+            },
+            10,
+        )
+        .unwrap();
+        assert!(matches!(
+            process_execute_action(admin, airdrop.id, airdrop.executable_at),
+            Err(PredictionMarketError::QuorumNotMet {
+                approvals: 0,
+                threshold: 0
+            })
+        ));
+        assert!(matches!(
+            process_approve_action(admin, airdrop.id, 20),
+            Err(PredictionMarketError::Unauthorized)
+        ));
+
+        for invalid in [
+            quorum(vec![first, second], 0),
+            quorum(vec![first, first], 2),
+            quorum(vec![first, Principal::anonymous()], 1),
+        ] {
+            assert!(matches!(
+                process_schedule_action(admin, invalid, 30),
+                Err(PredictionMarketError::InvalidInput { .. })
+            ));
+        }
+        let change = process_schedule_action(admin, quorum(vec![second, first], 1), 30).unwrap();
+        assert!(matches!(
+            process_execute_action(admin, change.id, change.executable_at - 1),
+            Err(PredictionMarketError::TimelockPending { .. })
+        ));
+        process_execute_action(admin, change.id, change.executable_at).unwrap();
+        assert_eq!(
+            get_airdrop_quorum(),
+            ApprovalQuorum {
+                approvers: vec![first, second],
+                threshold: 1
+            }
+        );
+
+        // Approvals from principals since dropped from the quorum no longer count
+        process_approve_action(first, airdrop.id, 40).unwrap();
+        AIRDROP_QUORUM.with(|q| q.borrow_mut().approvers = vec![second]);
+        assert!(matches!(
+            process_execute_action(admin, airdrop.id, change.executable_at),
+            Err(PredictionMarketError::QuorumNotMet {
+                approvals: 0,
+                threshold: 1
+            })
+        ));
+        process_approve_action(second, airdrop.id, 50).unwrap();
+        process_execute_action(admin, airdrop.id, change.executable_at).unwrap();
+        assert_eq!(TREASURY_BALANCE.with(|t| *t.borrow()), 400);
     }
 
     #[test]
-    fn test_insufficient_liquidity_scenarios() {
+    fn test_cost_estimate_averages_samples() {
         reset_state();
-        setup_test_market();
 
-        let yes_reserve = 100u64;
-        let no_reserve = 100u64;
-        let large_icp_in = 150u64; // Larger than available reserve
+        // No samples yet: only the flat message fees are reported
+        let empty = estimate_cost(CostOperation::Buy);
+        assert_eq!(empty.samples, 0);
+        assert_eq!(
+            empty.approx_cycles,
+            UPDATE_CALL_BASE_CYCLES + INGRESS_MESSAGE_BASE_CYCLES
+        );
 
-        // This should fail because it would drain more than available reserve
-        let result = calculate_tokens_out(yes_reserve, no_reserve, large_icp_in, true);
-        assert!(result.is_err(), "Should fail due to insufficient liquidity");
+        record_cost_sample(CostOperation::Buy, 1_000_000);
+        record_cost_sample(CostOperation::Buy, 3_000_000);
 
-        // Test zero reserves
-        let zero_result = calculate_tokens_out(0, 100, 50, true);
-        assert!(zero_result.is_err(), "Should fail with zero reserves");
+        let estimate = estimate_cost(CostOperation::Buy);
+        assert_eq!(estimate.samples, 2);
+        assert_eq!(estimate.avg_instructions, 2_000_000);
+        assert_eq!(
+            estimate.approx_cycles,
+            UPDATE_CALL_BASE_CYCLES + INGRESS_MESSAGE_BASE_CYCLES + 800_000
+        );
+
+        // Other operations are tracked independently
+        assert_eq!(estimate_cost(CostOperation::Sell).samples, 0);
     }
 
     #[test]
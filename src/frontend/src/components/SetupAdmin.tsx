@@ -1,5 +1,4 @@
 import { useState } from "react";
-import { Principal } from "@dfinity/principal";
 import { adminAuthService } from "../services/adminAuth";
 import { walletService } from "../services/wallet";
 
@@ -24,19 +23,15 @@ export function SetupAdmin({ onAdminSet }: SetupAdminProps) {
       setLoading(true);
       setError(null);
 
-      // Use a fixed principal for demo mode
-      const adminPrincipal = Principal.fromText(
-        "hu4mr-xdpm5-tho4x-tyiqd-nl4og-yiavx-ftzje-toyfl-vwavt-fbpbq-7ae",
-      );
-
-      await adminAuthService.setAdmin(adminPrincipal);
+      // The current admin must have proposed this principal first
+      await adminAuthService.acceptAdmin();
 
       setSuccess(true);
       setTimeout(() => {
         onAdminSet();
       }, 500); // Réduit de 2000ms à 500ms pour une redirection plus rapide
     } catch (err) {
-      setError(err instanceof Error ? err.message : "Failed to set admin");
+      setError(err instanceof Error ? err.message : "Failed to accept admin");
     } finally {
       setLoading(false);
     }
@@ -114,8 +109,8 @@ export function SetupAdmin({ onAdminSet }: SetupAdminProps) {
           </h2>
 
           <p className="mb-6 text-white/70">
-            <strong>Hackathon Demo:</strong> Click the button below to become
-            admin instantly and access all features!
+            <strong>Hackathon Demo:</strong> Once the current admin has
+            proposed this principal, click below to accept the admin role.
             <br />
             <small className="text-white/50">
               Perfect for presentations and demos
@@ -150,10 +145,10 @@ export function SetupAdmin({ onAdminSet }: SetupAdminProps) {
                 {loading ? (
                   <div className="flex items-center justify-center space-x-2">
                     <div className="h-4 w-4 animate-spin rounded-full border-2 border-white/30 border-t-white"></div>
-                    <span>Setting Admin...</span>
+                    <span>Accepting Admin...</span>
                   </div>
                 ) : (
-                  "🚀 Become Admin (Demo Mode)"
+                  "🚀 Accept Admin Role (Demo Mode)"
                 )}
               </button>
             </div>
@@ -5,7 +5,8 @@ import { backendService } from "./backendService";
 export interface AdminAuthService {
   isCurrentUserAdmin(): Promise<boolean>;
   getCurrentUserPrincipal(): Promise<Principal | null>;
-  setAdmin(principal: Principal): Promise<string>;
+  proposeAdmin(principal: Principal): Promise<string>;
+  acceptAdmin(): Promise<string>;
   getAdminPrincipal(): Promise<Principal | null>; // Added this method
 }
 
@@ -46,18 +47,37 @@ class AdminAuthServiceImpl implements AdminAuthService {
   }
 
   /**
-   * Set admin principal (only current admin can do this)
+   * Propose a new admin principal (only current admin can do this)
    */
-  async setAdmin(principal: Principal): Promise<string> {
+  async proposeAdmin(principal: Principal): Promise<string> {
     try {
-      const result = await backendService.setAdmin(principal);
+      const result = await backendService.proposeAdmin(principal);
       if ("Ok" in result) {
         return result.Ok;
       } else {
-        throw new Error(`Failed to set admin: ${Object.keys(result.Err)[0]}`);
+        throw new Error(
+          `Failed to propose admin: ${Object.keys(result.Err)[0]}`,
+        );
       }
     } catch (error) {
-      console.error("Failed to set admin:", error);
+      console.error("Failed to propose admin:", error);
+      throw error;
+    }
+  }
+
+  /**
+   * Accept the admin handover proposed to the current caller
+   */
+  async acceptAdmin(): Promise<string> {
+    try {
+      const result = await backendService.acceptAdmin();
+      if ("Ok" in result) {
+        return result.Ok;
+      } else {
+        throw new Error(`Failed to accept admin: ${Object.keys(result.Err)[0]}`);
+      }
+    } catch (error) {
+      console.error("Failed to accept admin:", error);
       throw error;
     }
   }
@@ -59,12 +59,20 @@ export const backendService = {
   },
 
   /**
-   * Set admin principal
-   * @param admin New admin principal
+   * Propose a new admin principal (current admin only)
+   * @param admin Principal that must accept the handover
    * @returns Promise with result
    */
-  async setAdmin(admin: Principal): Promise<{ Ok: string } | { Err: any }> {
-    return await backend.set_admin(admin);
+  async proposeAdmin(admin: Principal): Promise<{ Ok: string } | { Err: any }> {
+    return await backend.propose_admin(admin);
+  },
+
+  /**
+   * Accept an admin handover proposed to the caller
+   * @returns Promise with result
+   */
+  async acceptAdmin(): Promise<{ Ok: string } | { Err: any }> {
+    return await backend.accept_admin();
   },
 
   /**
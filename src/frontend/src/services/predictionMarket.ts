@@ -9,20 +9,49 @@ import type {
 // Re-export types from the generated declarations
 export type { AmmMarket, MarketSummary, UserPosition };
 
+// The backend denominates every ICP amount in e8s, like the ICP ledger
+export const E8S_PER_ICP = 100_000_000;
+
+/**
+ * Convert an ICP amount to e8s, rounding down to the nearest e8
+ */
+export function icpToE8s(icp: number): bigint {
+  return BigInt(Math.floor(icp * E8S_PER_ICP));
+}
+
+/**
+ * Convert an e8s amount from the backend to ICP
+ */
+export function e8sToIcp(e8s: bigint): number {
+  return Number(e8s) / E8S_PER_ICP;
+}
+
+// Trades not executed within this window are rejected instead of filling at a stale price
+const TRADE_DEADLINE_MS = 5 * 60 * 1000;
+
+/**
+ * Deadline for a trade submitted now, in nanoseconds, as the optional candid argument
+ */
+function tradeDeadline(): [bigint] {
+  return [BigInt(Date.now() + TRADE_DEADLINE_MS) * BigInt(1_000_000)];
+}
+
 export class PredictionMarketService {
   /**
-   * Set the admin principal for the prediction market
+   * Propose a new admin; the current admin stays until they accept
    */
-  static async setAdmin(adminPrincipal: Principal): Promise<string> {
+  static async proposeAdmin(adminPrincipal: Principal): Promise<string> {
     try {
-      const result = await backend.set_admin(adminPrincipal);
+      const result = await backend.propose_admin(adminPrincipal);
       if ("Ok" in result) {
         return result.Ok;
       } else {
-        throw new Error(`Failed to set admin: ${Object.keys(result.Err)[0]}`);
+        throw new Error(
+          `Failed to propose admin: ${Object.keys(result.Err)[0]}`,
+        );
       }
     } catch (error) {
-      console.error("Failed to set admin:", error);
+      console.error("Failed to propose admin:", error);
       throw error;
     }
   }
@@ -35,11 +64,17 @@ export class PredictionMarketService {
   }
 
   /**
-   * Get all markets with pricing information
+   * Get all markets with pricing information, following page cursors
    */
   static async getMarkets(): Promise<MarketSummary[]> {
     try {
-      const markets = await backend.get_markets();
+      const markets: MarketSummary[] = [];
+      let cursor: [] | [bigint] = [];
+      do {
+        const page = await backend.get_markets(cursor);
+        markets.push(...page.items);
+        cursor = page.next_cursor;
+      } while (cursor.length > 0);
       return markets;
     } catch (error) {
       console.error("Failed to get markets:", error);
@@ -65,7 +100,14 @@ export class PredictionMarketService {
    */
   static async getUserPositions(_user: Principal): Promise<UserPosition[]> {
     try {
-      return await backend.get_all_user_positions();
+      const positions: UserPosition[] = [];
+      let cursor: [] | [bigint] = [];
+      do {
+        const page = await backend.get_all_user_positions(cursor);
+        positions.push(...page.items);
+        cursor = page.next_cursor;
+      } while (cursor.length > 0);
+      return positions;
     } catch (error) {
       console.error("Failed to get user positions:", error);
       throw error;
@@ -89,7 +131,7 @@ export class PredictionMarketService {
   }
 
   /**
-   * Get user balance by principal
+   * Get user balance by principal, in e8s
    */
   static async getUserBalance(user: Principal): Promise<bigint> {
     try {
@@ -101,11 +143,11 @@ export class PredictionMarketService {
   }
 
   /**
-   * Deposit ICP to user balance
+   * Deposit ICP to user balance; `amount` is in ICP
    */
   static async deposit(amount: number): Promise<{ Ok: string } | { Err: any }> {
     try {
-      return await backend.deposit_icp(BigInt(amount));
+      return await backend.deposit_icp(icpToE8s(amount));
     } catch (error) {
       console.error("Failed to deposit:", error);
       throw error;
@@ -121,6 +163,8 @@ export class PredictionMarketService {
       if ("Closed" in status) return "Closed";
       if ("Resolved" in status) return "Resolved";
       if ("Frozen" in status) return "Frozen";
+      if ("Invalid" in status) return "Invalid";
+      if ("Cancelled" in status) return "Cancelled";
     }
     return "Unknown";
   }
@@ -136,19 +180,23 @@ export class PredictionMarketService {
     title: string,
     description: string,
     initialLiquidity: number = 5000,
+    closeTime: Date = new Date(Date.now() + 7 * 24 * 60 * 60 * 1000),
+    category?: string,
   ): Promise<number> {
     try {
       console.log("🏗️ Creating market:", {
         title,
         description,
         initialLiquidity,
+        closeTime,
+        category,
       });
 
       // First, automatically deposit enough ICP for demo purposes
       const depositAmount = initialLiquidity + 2000; // Extra buffer for safety
       console.log("💰 Depositing ICP:", depositAmount);
 
-      const depositResult = await backend.deposit_icp(BigInt(depositAmount));
+      const depositResult = await backend.deposit_icp(icpToE8s(depositAmount));
       console.log("💰 Deposit result:", depositResult);
 
       if ("Err" in depositResult) {
@@ -159,7 +207,14 @@ export class PredictionMarketService {
       const result = await backend.create_market(
         title,
         description,
-        BigInt(initialLiquidity),
+        icpToE8s(initialLiquidity),
+        BigInt(closeTime.getTime()) * BigInt(1_000_000),
+        category ? [category] : [],
+        [],
+        [],
+        [],
+        [],
+        [],
       );
 
       console.log("🏗️ Market creation result:", result);
@@ -198,13 +253,15 @@ export class PredictionMarketService {
         side === "Yes"
           ? await backend.buy_yes_tokens(
               BigInt(marketId),
-              BigInt(icpAmount),
+              icpToE8s(icpAmount),
               BigInt(minTokensOut),
+              tradeDeadline(),
             )
           : await backend.buy_no_tokens(
               BigInt(marketId),
-              BigInt(icpAmount),
+              icpToE8s(icpAmount),
               BigInt(minTokensOut),
+              tradeDeadline(),
             );
 
       if ("Ok" in result) {
@@ -225,6 +282,7 @@ export class PredictionMarketService {
   static async closeMarket(
     marketId: number,
     result: "Yes" | "No",
+    evidence: string,
   ): Promise<string> {
     try {
       console.log(`🔒 Closing market ${marketId} with result: ${result}`);
@@ -233,6 +291,7 @@ export class PredictionMarketService {
       const resolveResult = await backend.resolve_market(
         BigInt(marketId),
         outcome,
+        evidence,
       );
 
       if ("Ok" in resolveResult) {
@@ -269,12 +328,14 @@ export class PredictionMarketService {
           ? await backend.sell_yes_tokens(
               BigInt(marketId),
               BigInt(tokenAmount),
-              BigInt(minIcpOut),
+              icpToE8s(minIcpOut),
+              tradeDeadline(),
             )
           : await backend.sell_no_tokens(
               BigInt(marketId),
               BigInt(tokenAmount),
-              BigInt(minIcpOut),
+              icpToE8s(minIcpOut),
+              tradeDeadline(),
             );
 
       if ("Ok" in result) {
@@ -300,7 +361,7 @@ export class PredictionMarketService {
     try {
       const result = await backend.get_buy_quote(
         BigInt(marketId),
-        BigInt(icpAmount),
+        icpToE8s(icpAmount),
         tokenType === "Yes" ? { Yes: null } : { No: null },
       );
 
@@ -363,6 +424,16 @@ export class PredictionMarketService {
     );
   }
 
+  /**
+   * Helper function to format an e8s amount as ICP, abbreviating thousands
+   */
+  static formatIcp(e8s: bigint): string {
+    const icp = e8sToIcp(e8s);
+    return icp >= 1000
+      ? `${(icp / 1000).toFixed(1)}k ICP`
+      : `${Number(icp.toFixed(4))} ICP`;
+  }
+
   /**
    * Helper function to format prices as percentages
    */
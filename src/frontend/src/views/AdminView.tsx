@@ -98,11 +98,21 @@ export function AdminView() {
     try {
       setError(null);
 
+      // The backend stores the evidence so users can verify the resolution
+      const evidence = window.prompt(
+        "Evidence for this resolution (source link, quote or observed value):",
+      );
+      if (!evidence || !evidence.trim()) {
+        setError("Resolution evidence is required to close a market");
+        return;
+      }
+
       // La distribution se fait automatiquement dans le backend maintenant
       console.log(`🔒 Closing market ${marketId} with result: ${result}`);
       const closeResult = await PredictionMarketService.closeMarket(
         Number(marketId),
         result,
+        evidence.trim(),
       );
 
       console.log("✅ Market closed with automatic distribution:", closeResult);
@@ -844,9 +854,8 @@ function MarketManagementCard({
           <div className="glass rounded-lg p-3 text-center">
             <div className="mb-1 text-xs font-bold text-white/60">Volume</div>
             <div className="text-lg font-bold text-white">
-              {total_volume.toLocaleString()}
+              {PredictionMarketService.formatIcp(total_volume)}
             </div>
-            <div className="mt-1 text-xs text-white/60">tokens</div>
           </div>
         </div>
 
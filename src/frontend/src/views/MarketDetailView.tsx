@@ -396,11 +396,8 @@ export function MarketDetailView() {
             <div className="glass card-hover flex flex-col justify-center rounded-xl p-4 text-center">
               <div className="mb-2 text-sm font-bold text-white/80">Volume</div>
               <div className="text-2xl font-bold text-white">
-                {Number(market.total_volume) > 1000
-                  ? `${(Number(market.total_volume) / 1000).toFixed(1)}k`
-                  : Number(market.total_volume)}
+                {PredictionMarketService.formatIcp(market.total_volume)}
               </div>
-              <div className="text-xs text-white/60">tokens</div>
             </div>
           </div>
         </div>
@@ -448,7 +445,9 @@ export function MarketDetailView() {
                 Total Liquidity
               </div>
               <div className="text-lg font-bold text-white">
-                {market.market.icp_liquidity_pool.toLocaleString()}
+                {PredictionMarketService.formatIcp(
+                  market.market.icp_liquidity_pool,
+                )}
               </div>
               <div className="text-xs text-white/60">ICP backing</div>
             </div>
@@ -215,18 +215,13 @@ function MarketCard({ marketSummary }: { marketSummary: MarketSummary }) {
           <div className="flex justify-between">
             <span>Volume</span>
             <span className="font-bold text-white">
-              {Number(total_volume) > 1000
-                ? `${(Number(total_volume) / 1000).toFixed(1)}k`
-                : Number(total_volume)}
+              {PredictionMarketService.formatIcp(total_volume)}
             </span>
           </div>
           <div className="flex justify-between">
             <span>Liquidity</span>
             <span className="font-bold text-white">
-              {Number(market.icp_liquidity_pool) > 1000
-                ? `${(Number(market.icp_liquidity_pool) / 1000).toFixed(1)}k`
-                : market.icp_liquidity_pool}{" "}
-              ICP
+              {PredictionMarketService.formatIcp(market.icp_liquidity_pool)}
             </span>
           </div>
         </div>
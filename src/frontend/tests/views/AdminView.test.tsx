@@ -245,6 +245,7 @@ describe("AdminView", () => {
     vi.mocked(PredictionMarketService.closeMarket).mockResolvedValue(
       "Market closed successfully",
     );
+    vi.spyOn(window, "prompt").mockReturnValue("Official results");
 
     render(
       <StrictMode>
@@ -265,6 +266,7 @@ describe("AdminView", () => {
       expect(PredictionMarketService.closeMarket).toHaveBeenCalledWith(
         1,
         expect.any(Object),
+        "Official results",
       );
     });
   });
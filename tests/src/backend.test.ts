@@ -1,7 +1,7 @@
 import { describe, beforeEach, afterEach, it, expect, inject } from "vitest";
 import { resolve, dirname } from "path";
 import { fileURLToPath } from "url";
-import { PocketIc, type Actor } from "@dfinity/pic";
+import { PocketIc, createIdentity, type Actor } from "@dfinity/pic";
 import { Principal } from "@dfinity/principal";
 
 // Import generated types for your canister
@@ -21,6 +21,9 @@ export const WASM_PATH = resolve(
   "backend.wasm",
 );
 
+// Update calls from the anonymous principal are rejected, so tests deploy and call as this identity
+const deployer = createIdentity("deployer");
+
 // The `describe` function is used to group tests together
 describe("Vibe Coding Template Backend", () => {
   // Define variables to hold our PocketIC instance, canister ID,
@@ -39,10 +42,12 @@ describe("Vibe Coding Template Backend", () => {
     const fixture = await pic.setupCanister<_SERVICE>({
       idlFactory,
       wasm: WASM_PATH,
+      sender: deployer.getPrincipal(),
     });
 
     // Save the actor and canister ID for use in tests
     actor = fixture.actor;
+    actor.setIdentity(deployer);
     canisterId = fixture.canisterId;
   });
 
@@ -92,30 +97,47 @@ describe("Prediction Market Backend", () => {
     const fixture = await pic.setupCanister<_SERVICE>({
       idlFactory,
       wasm: WASM_PATH,
+      sender: deployer.getPrincipal(),
     });
 
     actor = fixture.actor;
+    actor.setIdentity(deployer);
     adminPrincipal = Principal.fromText("rdmx6-jaaaa-aaaaa-aaadq-cai"); // Test principal
 
-    // Set admin for testing
-    const setAdminResult = await actor.set_admin(adminPrincipal);
-    expect("Ok" in setAdminResult).toBe(true);
+    // The deployer is the admin and hands over to the test principal
+    const proposeResult = await actor.propose_admin(adminPrincipal);
+    expect("Ok" in proposeResult).toBe(true);
   });
 
   afterEach(async () => {
     await pic.tearDown();
   });
 
-  it("should set and get admin", async () => {
+  // Markets need a trading deadline in the future, in nanoseconds
+  const oneDayFromNow = async (): Promise<bigint> => {
+    const nowMs = BigInt(Math.trunc(await pic.getTime()));
+    return (nowMs + BigInt(24 * 60 * 60 * 1000)) * BigInt(1_000_000);
+  };
+
+  it("should keep the admin until the proposed one accepts", async () => {
+    const pending = await actor.get_pending_admin();
+    expect(pending.length).toBe(1);
+    expect(pending[0]).toEqual(adminPrincipal);
+
     const admin = await actor.get_admin();
     expect(admin.length).toBe(1);
-    expect(admin[0]).toEqual(adminPrincipal);
+    expect(admin[0]).not.toEqual(adminPrincipal);
+
+    // Only the proposed principal can accept
+    const acceptResult = await actor.accept_admin();
+    expect("Err" in acceptResult).toBe(true);
   });
 
   it("should get empty markets list initially", async () => {
-    const markets = await actor.get_markets();
-    expect(Array.isArray(markets)).toBe(true);
-    expect(markets.length).toBe(0);
+    const markets = await actor.get_markets([]);
+    expect(Array.isArray(markets.items)).toBe(true);
+    expect(markets.items.length).toBe(0);
+    expect(markets.truncated).toBe(false);
   });
 
   it("should get user balance", async () => {
@@ -125,19 +147,24 @@ describe("Prediction Market Backend", () => {
     expect(balance).toEqual(BigInt(0));
   });
 
+  it("should reject update calls from the anonymous principal", async () => {
+    actor.setPrincipal(Principal.anonymous());
+    await expect(actor.deposit_icp(BigInt(1000))).rejects.toThrow();
+  });
+
   it("should allow depositing funds", async () => {
-    const depositAmount = BigInt(1000);
+    const depositAmount = BigInt(1_000_000); // e8s
     const result = await actor.deposit_icp(depositAmount);
     expect("Ok" in result).toBe(true);
     if ("Ok" in result) {
-      expect(result.Ok).toBe("Successfully deposited 1000 ICP");
+      expect(result.Ok).toBe("Successfully deposited 0.01 ICP");
     }
   });
 
   it("should get empty user positions initially", async () => {
-    const positions = await actor.get_all_user_positions();
-    expect(Array.isArray(positions)).toBe(true);
-    expect(positions.length).toBe(0);
+    const positions = await actor.get_all_user_positions([]);
+    expect(Array.isArray(positions.items)).toBe(true);
+    expect(positions.items.length).toBe(0);
   });
 
   it("should get empty market for non-existent market", async () => {
@@ -154,15 +181,22 @@ describe("Prediction Market Backend", () => {
     const title = "Will it rain tomorrow?";
     const description =
       "This market resolves to YES if it rains in New York City tomorrow.";
-    const liquidityAmount = BigInt(1000);
+    const liquidityAmount = BigInt(1_000_000);
 
     // First deposit some ICP
-    await actor.deposit_icp(BigInt(2000));
+    await actor.deposit_icp(BigInt(2_000_000));
 
     const result = await actor.create_market(
       title,
       description,
       liquidityAmount,
+      await oneDayFromNow(),
+      ["weather"],
+      [],
+      [],
+      [],
+      [],
+      [],
     );
     expect("Ok" in result).toBe(true);
     if ("Ok" in result) {
@@ -172,8 +206,19 @@ describe("Prediction Market Backend", () => {
 
   it("should get token price for existing market", async () => {
     // Create a market first
-    await actor.deposit_icp(BigInt(2000));
-    await actor.create_market("Test Market", "Test Description", BigInt(1000));
+    await actor.deposit_icp(BigInt(2_000_000));
+    await actor.create_market(
+      "Test Market",
+      "Test Description",
+      BigInt(1_000_000),
+      await oneDayFromNow(),
+      [],
+      [],
+      [],
+      [],
+      [],
+      [],
+    );
 
     const yesPrice = await actor.get_token_price(BigInt(1), { Yes: null });
     expect("Ok" in yesPrice).toBe(true);